@@ -0,0 +1,130 @@
+//! Compares buffered and `O_DIRECT` block reads over the same `FileReadPlan` workload.
+//!
+//! Run with `cargo bench --bench block_read`.
+//! Each block is its own file, as in the cache, and their page cache is dropped before each run so both modes start
+//! cold.
+//! Set `STAVKA_BENCH_DIR` to benchmark on a specific filesystem; it defaults to the system temp directory.
+
+#[path = "../src/cachestate.rs"]
+#[allow(dead_code)]
+mod cachestate;
+#[path = "../src/constant.rs"]
+#[allow(dead_code)]
+mod constant;
+#[path = "../src/directio.rs"]
+#[allow(dead_code)]
+mod directio;
+#[path = "../src/hash.rs"]
+#[allow(dead_code)]
+mod hash;
+
+use cachestate::{FileReadPlan, FileReadPlanStepKind, LoadedCoverageMap};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The block size to benchmark with.
+const BLOCK_SIZE: u32 = 1024 * 1024;
+
+/// The number of blocks in the fake object.
+const BLOCK_COUNT: u64 = 512;
+
+/// Every Nth block is left uncovered so the plan alternates between cache and origin steps.
+const UNCOVERED_EVERY: u64 = 8;
+
+fn main() {
+    let dir = std::env::var_os("STAVKA_BENCH_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let path = dir.join("stavka-bench-blocks");
+
+    let align = directio::validate_block_size(&dir, BLOCK_SIZE)
+        .expect("bench block size is not usable for direct I/O here");
+
+    std::fs::create_dir_all(&path).expect("failed to create block directory");
+    let block = vec![0x5au8; BLOCK_SIZE as usize];
+    for block_num in 0..BLOCK_COUNT {
+        std::fs::write(block_path(&path, block_num), &block).expect("failed to write block file");
+    }
+
+    monoio::RuntimeBuilder::<monoio::IoUringDriver>::new()
+        .build()
+        .expect("failed to build runtime")
+        .block_on(async {
+            for direct in [false, true] {
+                let (elapsed, bytes) = run(&path, direct, align as usize).await;
+                let gbyte = bytes as f64 / elapsed.as_secs_f64() / 1e9;
+                println!(
+                    "{}: {} bytes in {:?} ({:.2} GB/s)",
+                    if direct { "O_DIRECT" } else { "buffered" },
+                    bytes,
+                    elapsed,
+                    gbyte
+                );
+            }
+        });
+
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+fn block_path(dir: &Path, block_num: u64) -> PathBuf {
+    dir.join(block_num.to_string())
+}
+
+fn coverage_map() -> LoadedCoverageMap {
+    LoadedCoverageMap(
+        (0..BLOCK_COUNT)
+            .map(|n| n % UNCOVERED_EVERY != UNCOVERED_EVERY - 1)
+            .collect(),
+    )
+}
+
+async fn run(dir: &Path, direct: bool, align: usize) -> (Duration, u64) {
+    // Drop any cached pages so both runs read from the device.
+    for block_num in 0..BLOCK_COUNT {
+        let f = std::fs::File::open(block_path(dir, block_num)).unwrap();
+        unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+
+    let mut opts = monoio::fs::OpenOptions::new();
+    opts.read(true);
+    if direct {
+        opts.custom_flags(libc::O_DIRECT);
+    }
+
+    let pool = directio::AlignedBufPool::new(BLOCK_SIZE as usize, align, 4);
+    let mut plain_buf = Vec::with_capacity(BLOCK_SIZE as usize);
+
+    let file_size = BLOCK_SIZE as u64 * BLOCK_COUNT;
+    let plan = FileReadPlan::new(0, file_size, file_size, BLOCK_SIZE as u64, coverage_map());
+
+    let mut bytes = 0u64;
+    let start = Instant::now();
+    for step in plan {
+        if !matches!(step.kind, FileReadPlanStepKind::CACHE) {
+            continue;
+        }
+
+        for block_num in step.block_start_num..step.block_end_num.min(BLOCK_COUNT) {
+            let file = opts
+                .open(block_path(dir, block_num))
+                .await
+                .expect("failed to open block file");
+            if direct {
+                let buf = directio::read_block(&file, &pool)
+                    .await
+                    .expect("direct read failed");
+                bytes += buf.as_slice().len() as u64;
+                pool.give(buf);
+            } else {
+                plain_buf.clear();
+                let (res, buf) = file.read_at(plain_buf, 0).await;
+                bytes += res.expect("buffered read failed") as u64;
+                plain_buf = buf;
+            }
+        }
+    }
+
+    (start.elapsed(), bytes)
+}
@@ -0,0 +1,75 @@
+//! Compares serving cached block data to a socket with splice against the buffered copy path.
+//!
+//! Run with `cargo bench --bench block_serve`.
+//! The block file is written to the system temp directory and removed afterwards.
+
+#[path = "../src/zerocopy.rs"]
+#[allow(dead_code)]
+mod zerocopy;
+
+use monoio::io::AsyncReadRent;
+use monoio::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use zerocopy::BlockServeMode;
+
+/// The size of the fake block file.
+const FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The number of times the full file is sent per mode.
+const ITERATIONS: u32 = 8;
+
+fn main() {
+    let path = std::env::temp_dir().join("stavka-bench-block");
+    std::fs::write(&path, vec![0xa5u8; FILE_SIZE as usize]).expect("failed to write block file");
+
+    monoio::RuntimeBuilder::<monoio::IoUringDriver>::new()
+        .build()
+        .expect("failed to build runtime")
+        .block_on(async {
+            for mode in [BlockServeMode::Buffered, BlockServeMode::Splice] {
+                let elapsed = run(mode, &path).await;
+                let bytes = FILE_SIZE * ITERATIONS as u64;
+                let gbit = (bytes * 8) as f64 / elapsed.as_secs_f64() / 1e9;
+                println!("{:?}: {} bytes in {:?} ({:.2} Gbit/s)", mode, bytes, elapsed, gbit);
+            }
+        });
+
+    let _ = std::fs::remove_file(&path);
+}
+
+async fn run(mode: BlockServeMode, path: &std::path::Path) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+
+    // Drain everything on a separate task so the sender is never stalled by a full socket buffer.
+    let reader = monoio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("failed to accept");
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut total = 0u64;
+        loop {
+            let (res, returned) = stream.read(buf).await;
+            buf = returned;
+            match res {
+                Ok(0) | Err(_) => break,
+                Ok(n) => total += n as u64,
+            }
+        }
+        total
+    });
+
+    let mut stream = TcpStream::connect(addr).await.expect("failed to connect");
+    let file = monoio::fs::File::open(path).await.expect("failed to open block file");
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        zerocopy::send_block_range(mode, &mut stream, &file, 0, FILE_SIZE)
+            .await
+            .expect("failed to send block range");
+    }
+    drop(stream);
+    let received = reader.await;
+    let elapsed = start.elapsed();
+
+    assert_eq!(received, FILE_SIZE * ITERATIONS as u64);
+    elapsed
+}
@@ -0,0 +1,112 @@
+//! Makes private videos return 404 without the origin having to delete or rename the file.
+//!
+//! Videos live at `/videos/<id>/...`. A video is private if:
+//!  - it is in the list pushed with `POST /wasm/event?name=private-videos` (newline-separated ids), or
+//!  - the origin answers a request for it with `x-video-private: 1`.
+//!
+//! Either way the decision is remembered in the key/value store, so later requests for the video are answered
+//! without asking the origin again.
+//!
+//! Build with `cargo build -p stavka-sdk --example private_videos --target wasm32-unknown-unknown --release`.
+
+use stavka_sdk::resident::{Event, EventKind};
+use stavka_sdk::{kv, resident, CacheKey, Request, Response, Result};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// How long a private decision learned from the origin is remembered.
+const PRIVATE_TTL: Duration = Duration::from_secs(300);
+
+/// How long public videos stay fresh in the cache.
+const VIDEO_TTL: Duration = Duration::from_secs(3600);
+
+const NOT_FOUND: &[u8] = b"not found";
+
+thread_local! {
+    /// The pushed private list, held by the resident instance.
+    static PRIVATE_LIST: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Returns the video id from a path like `/videos/123/720p.mp4`.
+fn video_id(path: &str) -> Option<&str> {
+    let id = path.strip_prefix("/videos/")?.split(['/', '?']).next()?;
+    (!id.is_empty()).then_some(id)
+}
+
+fn private_key(id: &str) -> Vec<u8> {
+    format!("private:{}", id).into_bytes()
+}
+
+fn on_request(req: Request) -> Result<()> {
+    let path = req.path()?;
+    let Some(id) = video_id(&path) else {
+        return Ok(());
+    };
+
+    let private = kv::get(&private_key(id))?.is_some()
+        || resident::call("is_private", id.as_bytes())?.as_deref() == Some(b"1");
+    if private {
+        return req.respond(404, NOT_FOUND);
+    }
+
+    // Only the quality parameter changes the content; everything else is tracking noise.
+    let key = CacheKey::from_request(&req)?.keep_query_params(&["quality"]);
+    req.set_cache_key(&key)?;
+    req.set_ttl(VIDEO_TTL)
+}
+
+fn on_origin_response(res: Response) -> Result<()> {
+    if res.header("x-video-private")?.as_deref() != Some("1") {
+        return Ok(());
+    }
+
+    let path = res.request_path()?;
+    if let Some(id) = video_id(&path) {
+        kv::set(&private_key(id), b"1", Some(PRIVATE_TTL))?;
+    }
+    res.respond(404, NOT_FOUND)
+}
+
+stavka_sdk::hooks! {
+    on_request => on_request,
+    on_origin_response => on_origin_response,
+}
+
+fn on_event(event: Event) -> Result<()> {
+    match event.kind {
+        EventKind::Config if event.name()? == "private-videos" => {
+            let data = event.data()?;
+            let ids = String::from_utf8_lossy(&data)
+                .lines()
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_owned)
+                .collect();
+            PRIVATE_LIST.with(|list| *list.borrow_mut() = ids);
+        }
+        EventKind::Purge if event.name()? == "url" => {
+            // A purged video may have been made public again, so forget what the origin told us about it.
+            let url = String::from_utf8_lossy(&event.data()?).into_owned();
+            let path = url.splitn(4, '/').nth(3).map(|p| format!("/{}", p));
+            if let Some(id) = path.as_deref().and_then(video_id) {
+                kv::delete(&private_key(id))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_private(id: &[u8]) -> Result<Vec<u8>> {
+    let id = String::from_utf8_lossy(id);
+    let private = PRIVATE_LIST.with(|list| list.borrow().contains(id.as_ref()));
+    Ok(if private {
+        b"1".to_vec()
+    } else {
+        b"0".to_vec()
+    })
+}
+
+stavka_sdk::on_event!(on_event);
+stavka_sdk::resident_fn!(resident_is_private => is_private);
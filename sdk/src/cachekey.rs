@@ -0,0 +1,93 @@
+use crate::{Request, Result};
+
+/// Builds a cache key from parts of the request.
+///
+/// Keys stay in the `scheme://host/path?query` form stavka uses by default, so URL and prefix purges keep matching
+/// them. Anything the response varies on is appended as a `#name=value` fragment, which never appears in a
+/// request URL.
+pub struct CacheKey {
+    host: String,
+    path: String,
+    query: Vec<(String, String)>,
+    vary: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    /// Starts from the request's host, path and full query string.
+    pub fn from_request(req: &Request) -> Result<Self> {
+        let host = req.host()?;
+        let path_and_query = req.path()?;
+        let (path, query) = path_and_query
+            .split_once('?')
+            .unwrap_or((&path_and_query, ""));
+
+        Ok(Self {
+            host,
+            path: path.to_owned(),
+            query: query
+                .split('&')
+                .filter(|p| !p.is_empty())
+                .map(|p| {
+                    let (k, v) = p.split_once('=').unwrap_or((p, ""));
+                    (k.to_owned(), v.to_owned())
+                })
+                .collect(),
+            vary: Vec::new(),
+        })
+    }
+
+    /// Drops the query string, so requests differing only by query share one object.
+    pub fn without_query(mut self) -> Self {
+        self.query.clear();
+        self
+    }
+
+    /// Keeps only the named query parameters, such as a quality level, and drops tracking parameters and the like.
+    pub fn keep_query_params(mut self, names: &[&str]) -> Self {
+        self.query.retain(|(k, _)| names.contains(&k.as_str()));
+        self
+    }
+
+    /// Replaces the path.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_owned();
+        self
+    }
+
+    /// Varies the key on a request header.
+    pub fn vary_on_header(mut self, req: &Request, name: &str) -> Result<Self> {
+        let value = req.header(name)?.unwrap_or_default();
+        self.vary.push((name.to_ascii_lowercase(), value));
+        Ok(self)
+    }
+
+    /// Varies the key on an arbitrary value, such as a device class the module derived itself.
+    pub fn vary_on(mut self, name: &str, value: &str) -> Self {
+        self.vary.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let mut key = String::with_capacity(7 + self.host.len() + self.path.len());
+        key.push_str("http://");
+        key.push_str(&self.host);
+        key.push_str(&self.path);
+
+        for (i, (k, v)) in self.query.iter().enumerate() {
+            key.push(if i == 0 { '?' } else { '&' });
+            key.push_str(k);
+            if !v.is_empty() {
+                key.push('=');
+                key.push_str(v);
+            }
+        }
+        for (k, v) in &self.vary {
+            key.push('#');
+            key.push_str(k);
+            key.push('=');
+            key.push_str(v);
+        }
+
+        key
+    }
+}
@@ -0,0 +1,72 @@
+//! Outbound HTTP requests from request hooks.
+//!
+//! Hooks can't wait on the network, so sending a request only queues it. Reading the response of a request that
+//! hasn't been sent yet fails with [Error::AwaitFetches]; returning that from the hook, usually with `?`, has stavka
+//! send every queued request and run the hook again from the top. Sending the same request again on the rerun
+//! returns the same [Fetch], now with its response:
+//!
+//! ```ignore
+//! fn on_request(req: Request) -> Result<()> {
+//!     let auth = http::get("https://auth.example.com/check")?;
+//!     if auth.status()? != 200 {
+//!         req.respond(403, b"forbidden")?;
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The module must be allowed to reach the host in its permissions; otherwise sending fails with [Error::Denied].
+//! Resident instances can't send requests.
+
+use crate::{check, read_string, read_value, sys, Error, Result};
+
+/// A queued request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fetch(i32);
+
+/// Queues a request.
+pub fn send(method: &str, url: &str, body: &[u8]) -> Result<Fetch> {
+    let id = check(unsafe {
+        sys::http_send(
+            method.as_ptr(),
+            method.len() as i32,
+            url.as_ptr(),
+            url.len() as i32,
+            body.as_ptr(),
+            body.len() as i32,
+        )
+    })?;
+    Ok(Fetch(id))
+}
+
+/// Queues a `GET` request.
+pub fn get(url: &str) -> Result<Fetch> {
+    send("GET", url, &[])
+}
+
+impl Fetch {
+    /// The response status.
+    /// Fails with [Error::AwaitFetches] if the request hasn't been sent yet and [Error::Failed] if it failed.
+    pub fn status(&self) -> Result<u16> {
+        match check(unsafe { sys::http_status(self.0) }) {
+            Ok(status) => Ok(status as u16),
+            Err(Error::Missing) => Err(Error::AwaitFetches),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A response header.
+    pub fn header(&self, name: &str) -> Result<Option<String>> {
+        // The host reports both a pending request and an absent header as missing.
+        self.status()?;
+        read_string(|ptr, cap| unsafe {
+            sys::http_header(self.0, name.as_ptr(), name.len() as i32, ptr, cap)
+        })
+    }
+
+    /// The response body.
+    pub fn body(&self) -> Result<Vec<u8>> {
+        self.status()?;
+        Ok(read_value(|ptr, cap| unsafe { sys::http_body(self.0, ptr, cap) })?.unwrap_or_default())
+    }
+}
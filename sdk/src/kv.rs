@@ -0,0 +1,38 @@
+//! The host's key/value store.
+//!
+//! Values are shared by every worker thread and survive module swaps, but not restarts. Keys are scoped to the site
+//! the current request or event is for.
+
+use crate::{check, read_value, sys, Error, Result};
+use std::time::Duration;
+
+/// Returns the value of a key, if it is set and has not expired.
+pub fn get(key: &[u8]) -> Result<Option<Vec<u8>>> {
+    read_value(|ptr, cap| unsafe { sys::kv_get(key.as_ptr(), key.len() as i32, ptr, cap) })
+}
+
+/// Sets a key. If `ttl` is [None], the value never expires.
+///
+/// Fails with [Error::Invalid] if the value is too large and [Error::Failed] if the store is full.
+pub fn set(key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<()> {
+    let ttl_secs = ttl.map_or(0, |ttl| ttl.as_secs().max(1) as i64);
+    check(unsafe {
+        sys::kv_set(
+            key.as_ptr(),
+            key.len() as i32,
+            value.as_ptr(),
+            value.len() as i32,
+            ttl_secs,
+        )
+    })?;
+    Ok(())
+}
+
+/// Removes a key, returning whether it was set.
+pub fn delete(key: &[u8]) -> Result<bool> {
+    match check(unsafe { sys::kv_delete(key.as_ptr(), key.len() as i32) }) {
+        Ok(_) => Ok(true),
+        Err(Error::Missing) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,386 @@
+//! Write stavka WebAssembly modules in Rust.
+//!
+//! A module is a `cdylib` built for `wasm32-unknown-unknown`. Hooks are plain functions registered with [hooks!],
+//! which also exports the ABI version stavka checks when loading the module:
+//!
+//! ```ignore
+//! use stavka_sdk::{Request, Result};
+//!
+//! fn on_request(req: Request) -> Result<()> {
+//!     if req.path()?.starts_with("/admin") {
+//!         req.respond(403, b"forbidden")?;
+//!     }
+//!     Ok(())
+//! }
+//!
+//! stavka_sdk::hooks! {
+//!     on_request => on_request,
+//! }
+//! ```
+//!
+//! Per-request state lives in the hook instance and is thrown away after the request. Longer-lived state belongs in
+//! the [kv] store or a [resident] instance.
+
+pub mod http;
+pub mod kv;
+pub mod resident;
+#[doc(hidden)]
+pub mod sys;
+
+mod cachekey;
+
+pub use cachekey::CacheKey;
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The host ABI version this SDK targets.
+pub const ABI_VERSION: i32 = 1;
+
+/// The size of the first buffer values are read into. Larger values are read again with an exact-size buffer.
+const INITIAL_READ_CAP: usize = 256;
+
+/// An error reported by the host.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The value doesn't exist, or the call isn't available in the current phase.
+    Missing,
+
+    /// An argument was rejected, such as a malformed header name or out-of-range status.
+    Invalid,
+
+    /// The operation failed, such as a resident call trapping or the key/value store being full.
+    Failed,
+
+    /// The module wasn't granted the capability, such as sending HTTP requests to a host.
+    Denied,
+
+    /// An outbound HTTP request hasn't been sent yet.
+    /// Returning this from a hook has stavka send the queued requests and run the hook again; see [http].
+    AwaitFetches,
+
+    /// An error code this SDK doesn't know about.
+    Unknown(i32),
+}
+
+impl Error {
+    fn from_code(code: i32) -> Self {
+        match code {
+            -1 => Error::Missing,
+            -2 => Error::Invalid,
+            -3 => Error::Failed,
+            -4 => Error::Denied,
+            code => Error::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Missing => f.write_str("missing value"),
+            Error::Invalid => f.write_str("invalid argument"),
+            Error::Failed => f.write_str("operation failed"),
+            Error::Denied => f.write_str("capability denied"),
+            Error::AwaitFetches => f.write_str("awaiting outbound HTTP requests"),
+            Error::Unknown(code) => write!(f, "unknown error code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Turns a host return code into a result.
+pub(crate) fn check(code: i32) -> Result<i32> {
+    if code < 0 {
+        Err(Error::from_code(code))
+    } else {
+        Ok(code)
+    }
+}
+
+/// Reads a value through a host function that fills an output buffer, retrying once if the buffer was too small.
+/// Returns [None] if the value doesn't exist.
+pub(crate) fn read_value(mut read: impl FnMut(*mut u8, i32) -> i32) -> Result<Option<Vec<u8>>> {
+    let mut buf = vec![0u8; INITIAL_READ_CAP];
+    loop {
+        let len = match check(read(buf.as_mut_ptr(), buf.len() as i32)) {
+            Ok(len) => len as usize,
+            Err(Error::Missing) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if len <= buf.len() {
+            buf.truncate(len);
+            return Ok(Some(buf));
+        }
+        buf.resize(len, 0);
+    }
+}
+
+/// Like [read_value], for values that are always valid UTF-8.
+pub(crate) fn read_string(read: impl FnMut(*mut u8, i32) -> i32) -> Result<Option<String>> {
+    Ok(read_value(read)?.map(|v| String::from_utf8_lossy(&v).into_owned()))
+}
+
+/// Writes a message to stavka's log, prefixed with the site it is for.
+pub fn log(msg: &str) {
+    unsafe { sys::log(msg.as_ptr(), msg.len() as i32) }
+}
+
+/// The point in request handling a hook runs at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// After the client request is received, before an origin is chosen.
+    OnRequest,
+
+    /// Right before the request is sent to the origin.
+    OnOriginRequest,
+
+    /// After the origin response head is received, before it is cached.
+    OnOriginResponse,
+
+    /// Right before the response is sent to the client.
+    OnResponse,
+}
+
+/// Returns the phase the current hook is running in.
+pub fn phase() -> Phase {
+    match unsafe { sys::get_phase() } {
+        0 => Phase::OnRequest,
+        1 => Phase::OnOriginRequest,
+        2 => Phase::OnOriginResponse,
+        _ => Phase::OnResponse,
+    }
+}
+
+/// Header access shared by [Request] and [Response].
+/// The host applies these to whichever message the current phase operates on.
+fn header(name: &str) -> Result<Option<String>> {
+    read_string(|ptr, cap| unsafe { sys::get_header(name.as_ptr(), name.len() as i32, ptr, cap) })
+}
+
+fn set_header(name: &str, value: &str) -> Result<()> {
+    check(unsafe {
+        sys::set_header(
+            name.as_ptr(),
+            name.len() as i32,
+            value.as_ptr(),
+            value.len() as i32,
+        )
+    })?;
+    Ok(())
+}
+
+fn remove_header(name: &str) -> Result<bool> {
+    match check(unsafe { sys::remove_header(name.as_ptr(), name.len() as i32) }) {
+        Ok(_) => Ok(true),
+        Err(Error::Missing) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The client request, as seen by the `on_request` and `on_origin_request` hooks.
+///
+/// Changes made in `on_request` decide which origin and cache object the request maps to; changes made in
+/// `on_origin_request` only affect what is sent to the origin.
+pub struct Request(());
+
+impl Request {
+    #[doc(hidden)]
+    pub fn __new() -> Self {
+        Self(())
+    }
+
+    pub fn method(&self) -> Result<String> {
+        Ok(read_string(|ptr, cap| unsafe { sys::get_method(ptr, cap) })?.unwrap_or_default())
+    }
+
+    /// The host the request is for, without a port.
+    pub fn host(&self) -> Result<String> {
+        Ok(read_string(|ptr, cap| unsafe { sys::get_host(ptr, cap) })?.unwrap_or_default())
+    }
+
+    /// The client's IP address, as given by trusted forwarding headers or a PROXY protocol header if there are any.
+    pub fn client_ip(&self) -> Result<Option<IpAddr>> {
+        let ip = read_string(|ptr, cap| unsafe { sys::get_client_ip(ptr, cap) })?;
+        Ok(ip.and_then(|ip| ip.parse().ok()))
+    }
+
+    /// The path and query string.
+    pub fn path(&self) -> Result<String> {
+        Ok(read_string(|ptr, cap| unsafe { sys::get_path(ptr, cap) })?.unwrap_or_default())
+    }
+
+    /// Rewrites the path and query string.
+    pub fn set_path(&self, path: &str) -> Result<()> {
+        check(unsafe { sys::set_path(path.as_ptr(), path.len() as i32) })?;
+        Ok(())
+    }
+
+    pub fn header(&self, name: &str) -> Result<Option<String>> {
+        header(name)
+    }
+
+    pub fn set_header(&self, name: &str, value: &str) -> Result<()> {
+        set_header(name, value)
+    }
+
+    /// Removes a header, returning whether it was present.
+    pub fn remove_header(&self, name: &str) -> Result<bool> {
+        remove_header(name)
+    }
+
+    /// Fetches the request from another origin, given as `scheme://authority`.
+    /// Only available in `on_request`.
+    pub fn set_origin(&self, origin: &str) -> Result<()> {
+        check(unsafe { sys::set_origin(origin.as_ptr(), origin.len() as i32) })?;
+        Ok(())
+    }
+
+    /// Stores the response under a different cache key.
+    pub fn set_cache_key(&self, key: &CacheKey) -> Result<()> {
+        let key = key.build();
+        check(unsafe { sys::set_cache_key(key.as_ptr(), key.len() as i32) })?;
+        Ok(())
+    }
+
+    /// Overrides how long the response stays fresh in the cache.
+    pub fn set_ttl(&self, ttl: Duration) -> Result<()> {
+        set_ttl(ttl)
+    }
+
+    /// Overrides how far past the requested range stavka keeps reading from the origin.
+    pub fn set_readahead(&self, bytes: u64) -> Result<()> {
+        check(unsafe { sys::set_readahead(bytes as i64) })?;
+        Ok(())
+    }
+
+    /// Answers the request without going to the origin.
+    pub fn respond(&self, status: u16, body: &[u8]) -> Result<()> {
+        respond(status, body)
+    }
+
+    /// Redirects the client. `status` must be a 3xx code.
+    pub fn redirect(&self, status: u16, location: &str) -> Result<()> {
+        check(unsafe { sys::redirect(status as i32, location.as_ptr(), location.len() as i32) })?;
+        Ok(())
+    }
+}
+
+/// The response, as seen by the `on_origin_response` and `on_response` hooks.
+///
+/// Changes made in `on_origin_response` are cached along with the object; changes made in `on_response` only affect
+/// the current client.
+pub struct Response(());
+
+impl Response {
+    #[doc(hidden)]
+    pub fn __new() -> Self {
+        Self(())
+    }
+
+    /// The path and query string of the request this responds to.
+    pub fn request_path(&self) -> Result<String> {
+        Ok(read_string(|ptr, cap| unsafe { sys::get_path(ptr, cap) })?.unwrap_or_default())
+    }
+
+    pub fn status(&self) -> Result<u16> {
+        Ok(check(unsafe { sys::get_status() })? as u16)
+    }
+
+    pub fn set_status(&self, status: u16) -> Result<()> {
+        check(unsafe { sys::set_status(status as i32) })?;
+        Ok(())
+    }
+
+    pub fn header(&self, name: &str) -> Result<Option<String>> {
+        header(name)
+    }
+
+    pub fn set_header(&self, name: &str, value: &str) -> Result<()> {
+        set_header(name, value)
+    }
+
+    /// Removes a header, returning whether it was present.
+    pub fn remove_header(&self, name: &str) -> Result<bool> {
+        remove_header(name)
+    }
+
+    /// Overrides how long the response stays fresh in the cache.
+    pub fn set_ttl(&self, ttl: Duration) -> Result<()> {
+        set_ttl(ttl)
+    }
+
+    /// Replaces the origin's response entirely.
+    pub fn respond(&self, status: u16, body: &[u8]) -> Result<()> {
+        respond(status, body)
+    }
+}
+
+fn set_ttl(ttl: Duration) -> Result<()> {
+    check(unsafe { sys::set_ttl(ttl.as_secs() as i64) })?;
+    Ok(())
+}
+
+fn respond(status: u16, body: &[u8]) -> Result<()> {
+    check(unsafe { sys::respond(status as i32, body.as_ptr(), body.len() as i32) })?;
+    Ok(())
+}
+
+/// Converts a hook's result into the status code returned to the host, logging any error.
+#[doc(hidden)]
+pub fn __hook_status(name: &str, res: Result<()>) -> i32 {
+    match res {
+        Ok(()) => 0,
+        // Has to differ from the 1 a failed hook returns, so the host can tell them apart.
+        Err(Error::AwaitFetches) => 2,
+        Err(e) => {
+            log(&format!("{} failed: {}", name, e));
+            1
+        }
+    }
+}
+
+/// Exports hook functions and the ABI version.
+///
+/// Request hooks (`on_request`, `on_origin_request`) take a [Request] and response hooks (`on_origin_response`,
+/// `on_response`) take a [Response]; all return [Result]. An error fails the request.
+#[macro_export]
+macro_rules! hooks {
+    ($($phase:ident => $func:path),* $(,)?) => {
+        #[no_mangle]
+        pub extern "C" fn stavka_abi_version() -> i32 {
+            $crate::ABI_VERSION
+        }
+
+        $($crate::hooks!(@export $phase, $func);)*
+    };
+
+    (@export on_request, $func:path) => {
+        #[no_mangle]
+        pub extern "C" fn on_request() -> i32 {
+            $crate::__hook_status("on_request", $func($crate::Request::__new()))
+        }
+    };
+    (@export on_origin_request, $func:path) => {
+        #[no_mangle]
+        pub extern "C" fn on_origin_request() -> i32 {
+            $crate::__hook_status("on_origin_request", $func($crate::Request::__new()))
+        }
+    };
+    (@export on_origin_response, $func:path) => {
+        #[no_mangle]
+        pub extern "C" fn on_origin_response() -> i32 {
+            $crate::__hook_status("on_origin_response", $func($crate::Response::__new()))
+        }
+    };
+    (@export on_response, $func:path) => {
+        #[no_mangle]
+        pub extern "C" fn on_response() -> i32 {
+            $crate::__hook_status("on_response", $func($crate::Response::__new()))
+        }
+    };
+}
@@ -0,0 +1,132 @@
+//! Long-lived per-thread instances.
+//!
+//! If a module registers an event handler with [crate::on_event!] or any function with [crate::resident_fn!], stavka keeps one
+//! long-lived instance of it on each worker thread. Its memory persists between requests until the module is
+//! swapped or the instance traps, so it is suited to caches of things like access-control lists. Request hooks
+//! reach it with [call].
+
+use crate::{check, read_string, read_value, sys, Result};
+
+/// What an event is about.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// The resident was just created, either at startup or after a module swap.
+    Start,
+
+    /// An operator pushed a named update through the admin API.
+    Config,
+
+    /// Cached objects were purged. The name is the selector kind and the data is its value.
+    Purge,
+
+    /// Sent periodically.
+    Timer,
+}
+
+/// An event delivered to the resident.
+pub struct Event {
+    pub kind: EventKind,
+}
+
+impl Event {
+    #[doc(hidden)]
+    pub fn __new(kind: i32) -> Self {
+        let kind = match kind {
+            0 => EventKind::Start,
+            1 => EventKind::Config,
+            2 => EventKind::Purge,
+            _ => EventKind::Timer,
+        };
+        Self { kind }
+    }
+
+    /// The site the event is for, or an empty string if it isn't for any one site.
+    pub fn site(&self) -> Result<String> {
+        site()
+    }
+
+    pub fn name(&self) -> Result<String> {
+        Ok(read_string(|ptr, cap| unsafe { sys::get_event_name(ptr, cap) })?.unwrap_or_default())
+    }
+
+    pub fn data(&self) -> Result<Vec<u8>> {
+        Ok(read_value(|ptr, cap| unsafe { sys::get_event_data(ptr, cap) })?.unwrap_or_default())
+    }
+}
+
+/// The site the current event or call is for.
+pub fn site() -> Result<String> {
+    Ok(read_string(|ptr, cap| unsafe { sys::get_site(ptr, cap) })?.unwrap_or_default())
+}
+
+/// Calls a function registered with [crate::resident_fn!] on this thread's resident instance.
+/// Returns [None] if the resident has no such function.
+///
+/// `name` is the export name without its `resident_` prefix.
+pub fn call(name: &str, arg: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut first = true;
+    read_value(|ptr, cap| unsafe {
+        // Only call once; if the result didn't fit, fetch it again without re-running the function.
+        if std::mem::take(&mut first) {
+            sys::call_resident(
+                name.as_ptr(),
+                name.len() as i32,
+                arg.as_ptr(),
+                arg.len() as i32,
+                ptr,
+                cap,
+            )
+        } else {
+            sys::get_resident_result(ptr, cap)
+        }
+    })
+}
+
+#[doc(hidden)]
+pub fn __call_arg() -> Vec<u8> {
+    read_value(|ptr, cap| unsafe { sys::get_call_arg(ptr, cap) })
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+#[doc(hidden)]
+pub fn __finish_call(res: Result<Vec<u8>>) -> i32 {
+    match res {
+        Ok(result) => {
+            match check(unsafe { sys::set_call_result(result.as_ptr(), result.len() as i32) }) {
+                Ok(_) => 0,
+                Err(e) => crate::__hook_status("resident call", Err(e)),
+            }
+        }
+        Err(e) => crate::__hook_status("resident call", Err(e)),
+    }
+}
+
+/// Exports a resident event handler taking an [Event] and returning [Result].
+#[macro_export]
+macro_rules! on_event {
+    ($func:path) => {
+        #[no_mangle]
+        pub extern "C" fn on_event(kind: i32) -> i32 {
+            $crate::__hook_status("on_event", $func($crate::resident::Event::__new(kind)))
+        }
+    };
+}
+
+/// Exports a resident function request hooks can [call].
+/// The function takes the argument bytes and returns the result bytes.
+/// The export name must start with `resident_`; callers leave the prefix off.
+///
+/// ```ignore
+/// stavka_sdk::resident_fn!(resident_is_private => is_private);
+/// ```
+#[macro_export]
+macro_rules! resident_fn {
+    ($export:ident => $func:path) => {
+        #[no_mangle]
+        pub extern "C" fn $export() -> i32 {
+            $crate::resident::__finish_call($func(&$crate::resident::__call_arg()))
+        }
+    };
+}
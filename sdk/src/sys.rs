@@ -0,0 +1,65 @@
+//! Raw imports from the host.
+//!
+//! Outside of WebAssembly these are stubs that panic, so code using the SDK can still be built and unit tested
+//! natively.
+
+macro_rules! imports {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*) => {
+        #[cfg(target_arch = "wasm32")]
+        #[link(wasm_import_module = "stavka_v1")]
+        extern "C" {
+            $(pub fn $name($($arg: $ty),*) $(-> $ret)?;)*
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        mod native {
+            $(
+                #[allow(unused_variables)]
+                pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                    panic!(concat!("host function ", stringify!($name), " is only available inside stavka"))
+                }
+            )*
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        pub use native::*;
+    };
+}
+
+imports! {
+    fn log(ptr: *const u8, len: i32);
+
+    fn kv_get(key_ptr: *const u8, key_len: i32, ptr: *mut u8, cap: i32) -> i32;
+    fn kv_set(key_ptr: *const u8, key_len: i32, value_ptr: *const u8, value_len: i32, ttl_secs: i64) -> i32;
+    fn kv_delete(key_ptr: *const u8, key_len: i32) -> i32;
+
+    fn get_phase() -> i32;
+    fn get_method(ptr: *mut u8, cap: i32) -> i32;
+    fn get_host(ptr: *mut u8, cap: i32) -> i32;
+    fn get_client_ip(ptr: *mut u8, cap: i32) -> i32;
+    fn get_path(ptr: *mut u8, cap: i32) -> i32;
+    fn set_path(ptr: *const u8, len: i32) -> i32;
+    fn get_header(name_ptr: *const u8, name_len: i32, ptr: *mut u8, cap: i32) -> i32;
+    fn set_header(name_ptr: *const u8, name_len: i32, value_ptr: *const u8, value_len: i32) -> i32;
+    fn remove_header(name_ptr: *const u8, name_len: i32) -> i32;
+    fn get_status() -> i32;
+    fn set_status(status: i32) -> i32;
+    fn set_origin(ptr: *const u8, len: i32) -> i32;
+    fn set_cache_key(ptr: *const u8, len: i32) -> i32;
+    fn set_ttl(secs: i64) -> i32;
+    fn set_readahead(bytes: i64) -> i32;
+    fn respond(status: i32, body_ptr: *const u8, body_len: i32) -> i32;
+    fn redirect(status: i32, loc_ptr: *const u8, loc_len: i32) -> i32;
+    fn call_resident(name_ptr: *const u8, name_len: i32, arg_ptr: *const u8, arg_len: i32, ptr: *mut u8, cap: i32) -> i32;
+    fn get_resident_result(ptr: *mut u8, cap: i32) -> i32;
+    fn http_send(method_ptr: *const u8, method_len: i32, url_ptr: *const u8, url_len: i32, body_ptr: *const u8, body_len: i32) -> i32;
+    fn http_status(id: i32) -> i32;
+    fn http_header(id: i32, name_ptr: *const u8, name_len: i32, ptr: *mut u8, cap: i32) -> i32;
+    fn http_body(id: i32, ptr: *mut u8, cap: i32) -> i32;
+
+    fn get_site(ptr: *mut u8, cap: i32) -> i32;
+    fn get_event_name(ptr: *mut u8, cap: i32) -> i32;
+    fn get_event_data(ptr: *mut u8, cap: i32) -> i32;
+    fn get_call_arg(ptr: *mut u8, cap: i32) -> i32;
+    fn set_call_result(ptr: *const u8, len: i32) -> i32;
+}
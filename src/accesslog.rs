@@ -0,0 +1,452 @@
+use crate::metrics::CacheResult;
+use http::{Method, Version};
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Incremented by the `SIGUSR1` handler.
+/// Each thread's logger reopens its file when it sees the generation change.
+static REOPEN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    REOPEN_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Installs the `SIGUSR1` handler that triggers log file reopening.
+/// Rotate logs by renaming the file and then sending `SIGUSR1`.
+pub fn install_reopen_handler() -> io::Result<()> {
+    let handler = on_sigusr1 as extern "C" fn(libc::c_int);
+    let ret = unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+    if ret == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A field that can appear in an access log line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    Time,
+    ClientIp,
+    Host,
+    Method,
+    Path,
+    Range,
+    Status,
+    Bytes,
+    CacheStatus,
+    OriginMs,
+    TotalMs,
+    UserAgent,
+    Referer,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "time" => Field::Time,
+            "client_ip" => Field::ClientIp,
+            "host" => Field::Host,
+            "method" => Field::Method,
+            "path" => Field::Path,
+            "range" => Field::Range,
+            "status" => Field::Status,
+            "bytes" => Field::Bytes,
+            "cache_status" => Field::CacheStatus,
+            "origin_ms" => Field::OriginMs,
+            "total_ms" => Field::TotalMs,
+            "user_agent" => Field::UserAgent,
+            "referer" => Field::Referer,
+            _ => return None,
+        })
+    }
+}
+
+/// A piece of a custom log template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplatePart {
+    Literal(String),
+    Field(Field),
+}
+
+/// The format access log lines are written in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The NCSA combined log format, with the cache status and total time appended.
+    Combined,
+
+    /// One JSON object per line.
+    Json,
+
+    /// A custom template where `$name` is replaced with the named field, e.g. `$client_ip $status $cache_status`.
+    Template(Vec<TemplatePart>),
+}
+
+impl LogFormat {
+    /// Parses a format name (`combined` or `json`) or a custom template.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "combined" => return Ok(LogFormat::Combined),
+            "json" => return Ok(LogFormat::Json),
+            _ => {}
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+
+            let mut end = i + 1;
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_ascii_lowercase() || c == '_') {
+                    break;
+                }
+                end = j + 1;
+                chars.next();
+            }
+
+            let name = &s[i + 1..end];
+            let field =
+                Field::from_name(name).ok_or_else(|| format!("unknown log field ${}", name))?;
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(TemplatePart::Field(field));
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Ok(LogFormat::Template(parts))
+    }
+}
+
+/// Everything recorded about a single request.
+pub struct AccessLogEntry {
+    pub time: SystemTime,
+    /// The client's address, as reported by trusted proxies if the request came through any.
+    pub client_ip: IpAddr,
+    pub host: String,
+    pub method: Method,
+    pub version: Version,
+    pub path: String,
+    pub range: Option<String>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub status: u16,
+    /// The response body bytes sent to the client.
+    pub bytes: u64,
+    pub cache_status: CacheResult,
+    /// How long the origin took to answer, summed over the range requests of a partial hit.
+    pub origin_time: Option<Duration>,
+    /// The time until the response body was sent, or the client went away.
+    pub total_time: Duration,
+}
+
+impl AccessLogEntry {
+    /// Writes a field's value.
+    /// Text that came from the client is escaped when `escape` is set, so it can't break up the line.
+    fn write_field(&self, out: &mut String, field: Field, escape: bool) {
+        let text = |out: &mut String, s: &str| {
+            if escape {
+                write_escaped(out, s);
+            } else {
+                out.push_str(s);
+            }
+            Ok(())
+        };
+        let _ = match field {
+            Field::Time => write_iso8601(out, self.time),
+            Field::ClientIp => write!(out, "{}", self.client_ip),
+            Field::Host => text(out, &self.host),
+            Field::Method => write!(out, "{}", self.method),
+            Field::Path => text(out, &self.path),
+            Field::Range => text(out, self.range.as_deref().unwrap_or("-")),
+            Field::Status => write!(out, "{}", self.status),
+            Field::Bytes => write!(out, "{}", self.bytes),
+            Field::CacheStatus => write!(out, "{}", cache_status_str(self.cache_status)),
+            Field::OriginMs => match self.origin_time {
+                Some(t) => write!(out, "{:.3}", t.as_secs_f64() * 1000.0),
+                None => write!(out, "-"),
+            },
+            Field::TotalMs => write!(out, "{:.3}", self.total_time.as_secs_f64() * 1000.0),
+            Field::UserAgent => text(out, self.user_agent.as_deref().unwrap_or("-")),
+            Field::Referer => text(out, self.referer.as_deref().unwrap_or("-")),
+        };
+    }
+
+    /// Formats the entry as a single line, including the trailing newline.
+    pub fn format(&self, format: &LogFormat, out: &mut String) {
+        match format {
+            LogFormat::Combined => {
+                let _ = write!(out, "{} - - [", self.client_ip);
+                write_clf_time(out, self.time);
+                let _ = write!(out, "] \"{} ", self.method);
+                self.write_field(out, Field::Path, true);
+                let _ = write!(
+                    out,
+                    " {:?}\" {} {} \"",
+                    self.version, self.status, self.bytes
+                );
+                self.write_field(out, Field::Referer, true);
+                out.push_str("\" \"");
+                self.write_field(out, Field::UserAgent, true);
+                let _ = write!(out, "\" {} ", cache_status_str(self.cache_status));
+                self.write_field(out, Field::TotalMs, true);
+            }
+            LogFormat::Json => {
+                out.push('{');
+                let fields = [
+                    ("time", Field::Time),
+                    ("client_ip", Field::ClientIp),
+                    ("host", Field::Host),
+                    ("method", Field::Method),
+                    ("path", Field::Path),
+                    ("range", Field::Range),
+                    ("status", Field::Status),
+                    ("bytes", Field::Bytes),
+                    ("cache_status", Field::CacheStatus),
+                    ("origin_ms", Field::OriginMs),
+                    ("total_ms", Field::TotalMs),
+                    ("user_agent", Field::UserAgent),
+                    ("referer", Field::Referer),
+                ];
+                for (i, (name, field)) in fields.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    let mut value = String::new();
+                    self.write_field(&mut value, field, false);
+                    let _ = write!(out, "\"{}\":", name);
+                    write_json_str(out, &value);
+                }
+                out.push('}');
+            }
+            LogFormat::Template(parts) => {
+                for part in parts {
+                    match part {
+                        TemplatePart::Literal(s) => out.push_str(s),
+                        TemplatePart::Field(f) => self.write_field(out, *f, true),
+                    }
+                }
+            }
+        }
+        out.push('\n');
+    }
+}
+
+fn cache_status_str(status: CacheResult) -> &'static str {
+    match status {
+        CacheResult::Hit => "HIT",
+        CacheResult::Miss => "MISS",
+        CacheResult::Partial => "PARTIAL",
+        CacheResult::Stale => "STALE",
+        CacheResult::Bypass => "BYPASS",
+    }
+}
+
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes text with quotes, backslashes and control characters escaped as `\xHH`, the way nginx logs them.
+fn write_escaped(out: &mut String, s: &str) {
+    for c in s.chars() {
+        if c == '"' || c == '\\' || c.is_ascii_control() {
+            let _ = write!(out, "\\x{:02X}", c as u32);
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+/// Splits a timestamp into UTC calendar fields: (year, month, day, hour, minute, second).
+pub fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+fn write_iso8601(out: &mut String, time: SystemTime) -> std::fmt::Result {
+    let (y, mo, d, h, mi, s) = civil_time(time);
+    write!(
+        out,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        y, mo, d, h, mi, s
+    )
+}
+
+fn write_clf_time(out: &mut String, time: SystemTime) {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (y, mo, d, h, mi, s) = civil_time(time);
+    let _ = write!(
+        out,
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        d,
+        MONTHS[mo as usize - 1],
+        y,
+        h,
+        mi,
+        s
+    );
+}
+
+/// A per-thread access logger.
+///
+/// Lines are appended to an in-memory buffer and written to the log file by a background task,
+/// so logging a request never waits on disk.
+pub struct AccessLogger {
+    path: PathBuf,
+    format: LogFormat,
+    flush_threshold: usize,
+    buf: RefCell<String>,
+    file: RefCell<Option<Rc<monoio::fs::File>>>,
+    generation: RefCell<u64>,
+
+    /// Whether a flush is writing, so another one leaves the buffer to it instead of writing lines out of order.
+    flushing: Cell<bool>,
+}
+
+impl AccessLogger {
+    /// Opens the log file for appending.
+    pub async fn open(
+        path: PathBuf,
+        format: LogFormat,
+        flush_threshold: usize,
+    ) -> io::Result<Rc<Self>> {
+        let generation = REOPEN_GENERATION.load(Ordering::Relaxed);
+        let file = open_log_file(&path).await?;
+
+        Ok(Rc::new(Self {
+            path,
+            format,
+            flush_threshold,
+            buf: RefCell::new(String::with_capacity(flush_threshold * 2)),
+            file: RefCell::new(Some(Rc::new(file))),
+            generation: RefCell::new(generation),
+            flushing: Cell::new(false),
+        }))
+    }
+
+    /// Queues an entry for writing.
+    pub fn log(self: &Rc<Self>, entry: &AccessLogEntry) {
+        let mut buf = self.buf.borrow_mut();
+        entry.format(&self.format, &mut buf);
+
+        if buf.len() >= self.flush_threshold {
+            drop(buf);
+            monoio::spawn(self.clone().flush());
+        }
+    }
+
+    /// Writes everything buffered so far to the log file, reopening it first if rotation was requested.
+    /// If another flush is already writing, it picks up what was buffered since instead.
+    pub async fn flush(self: Rc<Self>) {
+        if self.flushing.replace(true) {
+            return;
+        }
+        while self.write_buffered().await {}
+        self.flushing.set(false);
+    }
+
+    /// Writes out the buffer once, returning whether there was anything to write.
+    async fn write_buffered(&self) -> bool {
+        let generation = REOPEN_GENERATION.load(Ordering::Relaxed);
+        if generation != *self.generation.borrow() {
+            *self.generation.borrow_mut() = generation;
+            match open_log_file(&self.path).await {
+                Ok(file) => {
+                    *self.file.borrow_mut() = Some(Rc::new(file));
+                }
+                Err(e) => println!("failed to reopen access log {}: {}", self.path.display(), e),
+            }
+        }
+
+        let data = std::mem::take(&mut *self.buf.borrow_mut());
+        if data.is_empty() {
+            return false;
+        }
+
+        let Some(file) = self.file.borrow().clone() else {
+            return false;
+        };
+
+        // The file is opened with O_APPEND, so the kernel ignores the offset and appends.
+        let mut data = data.into_bytes();
+        while !data.is_empty() {
+            let (res, returned) = file.write_at(data, 0).await;
+            match res {
+                Ok(n) => {
+                    data = returned;
+                    data.drain(..n);
+                }
+                Err(e) => {
+                    println!("failed to write access log {}: {}", self.path.display(), e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Periodically flushes the buffer so lines show up even when traffic is light.
+    pub async fn run_flusher(self: Rc<Self>, interval: Duration) {
+        loop {
+            monoio::time::sleep(interval).await;
+            self.clone().flush().await;
+        }
+    }
+}
+
+async fn open_log_file(path: &PathBuf) -> io::Result<monoio::fs::File> {
+    monoio::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .await
+}
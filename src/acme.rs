@@ -0,0 +1,604 @@
+//! Automatic certificate issuance with ACME (RFC 8555).
+//!
+//! A dedicated thread keeps a certificate for every configured host, issuing one when there is none on disk and
+//! renewing it ahead of expiry. Certificates are stored under the storage directory, so a restart serves them right
+//! away instead of reissuing, and are handed to the TLS resolver as soon as they are issued.
+//!
+//! Domains are validated by a [ChallengeSolver]. HTTP-01 is answered by the plaintext listener and TLS-ALPN-01 by
+//! the TLS listener, so neither needs anything else running. Other challenge types, such as DNS-01 through a DNS
+//! provider's API, can be added by implementing the trait.
+//!
+//! To test against Pebble, point the directory URL at `https://localhost:14000/dir`, trust `pebble.minica.pem` with
+//! the CA bundle setting, and set Pebble's `httpPort` and `tlsPort` to stavka's listener ports.
+
+use crate::config::{AcmeChallenge, AcmeConfig};
+use crate::originreq::{OriginConnector, OriginRequestOptions, OriginTls};
+use crate::tls;
+use aws_lc_rs::digest::{digest, SHA256};
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use http::header::{CONTENT_TYPE, LOCATION};
+use http::{HeaderMap, Method, Request, StatusCode, Uri};
+use monoio::IoUringDriver;
+use monoio_http::common::body::{BodyExt, HttpBody};
+use monoio_http::h1::payload::{FixedPayload, Payload};
+use monoio_http_client::Client;
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The path prefix HTTP-01 challenges are requested under.
+const HTTP01_PREFIX: &str = "/.well-known/acme-challenge/";
+
+const REPLAY_NONCE: &str = "replay-nonce";
+
+/// How long to wait between polls of an authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The maximum number of times an authorization or order is polled before giving up on it.
+const MAX_POLLS: u32 = 30;
+
+/// The maximum number of times a request is sent again after the server rejects its nonce.
+const MAX_NONCE_RETRIES: u32 = 3;
+
+/// HTTP-01 challenges being validated, as token and key authorization pairs.
+static HTTP01_TOKENS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Returns the response body for an HTTP-01 challenge request path, if the challenge is being validated.
+pub fn http01_key_authorization(path: &str) -> Option<String> {
+    let token = path.strip_prefix(HTTP01_PREFIX)?;
+    HTTP01_TOKENS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(t, _)| t == token)
+        .map(|(_, key_authorization)| key_authorization.clone())
+}
+
+/// Proves control of a domain to the ACME server.
+pub trait ChallengeSolver: Send {
+    /// The ACME challenge type solved, such as `http-01`.
+    fn challenge_type(&self) -> &'static str;
+
+    /// Makes a challenge's key authorization available for the ACME server to check.
+    fn present(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Removes what [ChallengeSolver::present] set up, once validation is over.
+    fn clean_up(&self, domain: &str, token: &str);
+}
+
+/// Answers HTTP-01 challenges from the plaintext listener.
+pub struct Http01Solver;
+
+impl ChallengeSolver for Http01Solver {
+    fn challenge_type(&self) -> &'static str {
+        "http-01"
+    }
+
+    fn present(
+        &self,
+        _domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        HTTP01_TOKENS
+            .lock()
+            .unwrap()
+            .push((token.to_owned(), key_authorization.to_owned()));
+        Ok(())
+    }
+
+    fn clean_up(&self, _domain: &str, token: &str) {
+        HTTP01_TOKENS.lock().unwrap().retain(|(t, _)| t != token);
+    }
+}
+
+/// Answers TLS-ALPN-01 challenges (RFC 8737) from the TLS listener, with a self-signed certificate carrying the
+/// digest of the key authorization.
+pub struct TlsAlpn01Solver;
+
+impl ChallengeSolver for TlsAlpn01Solver {
+    fn challenge_type(&self) -> &'static str {
+        "tls-alpn-01"
+    }
+
+    fn present(
+        &self,
+        domain: &str,
+        _token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let key = rcgen::KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![domain.to_owned()])?;
+        params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+            digest(&SHA256, key_authorization.as_bytes()).as_ref(),
+        )];
+        let cert = params.self_signed(&key)?;
+
+        let certified =
+            tls::parse_certified_key(cert.pem().as_bytes(), key.serialize_pem().as_bytes())?;
+        tls::set_alpn_challenge(domain, Some(Arc::new(certified)));
+        Ok(())
+    }
+
+    fn clean_up(&self, domain: &str, _token: &str) {
+        tls::set_alpn_challenge(domain, None);
+    }
+}
+
+/// Returns the built-in solver for a challenge type.
+pub fn solver(challenge: AcmeChallenge) -> Box<dyn ChallengeSolver> {
+    match challenge {
+        AcmeChallenge::Http01 => Box::new(Http01Solver),
+        AcmeChallenge::TlsAlpn01 => Box::new(TlsAlpn01Solver),
+    }
+}
+
+/// Launches certificate management on its own thread.
+pub fn launch(config: AcmeConfig, solver: Box<dyn ChallengeSolver>) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        monoio::RuntimeBuilder::<IoUringDriver>::new()
+            .enable_timer()
+            .build()
+            .expect("failed to build ACME runtime")
+            .block_on(manage(config, solver))
+    })
+}
+
+/// Keeps every configured host's certificate issued and fresh.
+async fn manage(config: AcmeConfig, solver: Box<dyn ChallengeSolver>) {
+    let hosts: Vec<String> = config
+        .hosts
+        .iter()
+        .map(|host| host.to_ascii_lowercase())
+        .filter(|host| {
+            // Hosts name directories in the storage directory.
+            let valid = !host.is_empty() && !host.starts_with('.') && !host.contains('/');
+            if !valid {
+                println!("ignoring invalid ACME host {:?}", host);
+            }
+            valid
+        })
+        .collect();
+
+    // Serve what's already stored right away, so a restart doesn't wait on the ACME server.
+    for host in &hosts {
+        if let Err(e) = install_stored(&config, host) {
+            println!("no usable stored certificate for {}: {}", host, e);
+        }
+    }
+
+    let mut client: Option<AcmeClient> = None;
+    loop {
+        for host in &hosts {
+            let renew_at = SystemTime::now() + config.renew_before;
+            if stored_expiry(&cert_path(&config, host)).is_some_and(|expiry| expiry > renew_at) {
+                continue;
+            }
+
+            if client.is_none() {
+                match AcmeClient::connect(&config).await {
+                    Ok(connected) => client = Some(connected),
+                    Err(e) => {
+                        println!("failed to connect to ACME server: {}", e);
+                        break;
+                    }
+                }
+            }
+            let acme = client.as_mut().unwrap();
+
+            println!("issuing certificate for {}", host);
+            let res = match acme.issue(host, &*solver).await {
+                Ok((chain, key)) => store(&config, host, &chain, &key),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => println!("issued certificate for {}", host),
+                Err(e) => println!("failed to issue certificate for {}: {}", host, e),
+            }
+        }
+
+        monoio::time::sleep(config.check_interval).await;
+    }
+}
+
+fn cert_path(config: &AcmeConfig, host: &str) -> PathBuf {
+    config.storage_dir.join(host).join("cert.pem")
+}
+
+fn key_path(config: &AcmeConfig, host: &str) -> PathBuf {
+    config.storage_dir.join(host).join("key.pem")
+}
+
+/// Returns when a stored certificate expires, or [None] if it can't be read.
+fn stored_expiry(path: &Path) -> Option<SystemTime> {
+    let pem = std::fs::read(path).ok()?;
+    let der = rustls_pemfile::certs(&mut pem.as_slice()).next()?.ok()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(der.as_ref()).ok()?;
+    let not_after = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(not_after))
+}
+
+/// Serves a host's stored certificate.
+fn install_stored(config: &AcmeConfig, host: &str) -> Result<(), Box<dyn Error>> {
+    let cert = tls::ServedCert::parse(
+        std::fs::read(cert_path(config, host))?,
+        std::fs::read(key_path(config, host))?,
+    )?;
+    tls::set_managed_certificate(host, Arc::new(cert));
+    Ok(())
+}
+
+/// Stores a newly issued certificate and starts serving it.
+fn store(config: &AcmeConfig, host: &str, chain: &str, key: &str) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(config.storage_dir.join(host))?;
+    // The key goes first, since a stored certificate is taken to mean its key is there too.
+    write_atomic(&key_path(config, host), key.as_bytes(), 0o600)?;
+    write_atomic(&cert_path(config, host), chain.as_bytes(), 0o644)?;
+    install_stored(config, host)
+}
+
+/// Writes a file by renaming a temporary file over it, so it is never seen half-written.
+fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// The ACME account key, which signs every request.
+struct AccountKey {
+    pair: EcdsaKeyPair,
+    rng: SystemRandom,
+
+    /// The public key as a JWK, sent until the account's URL is known.
+    jwk: Value,
+
+    /// The JWK thumbprint (RFC 7638), which key authorizations end with.
+    thumbprint: String,
+}
+
+impl AccountKey {
+    /// Loads the account key, creating it if there isn't one yet.
+    fn load_or_create(path: &Path) -> Result<AccountKey, Box<dyn Error>> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)?;
+                write_atomic(path, pkcs8.as_ref(), 0o600)?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)?;
+
+        // The public key is an uncompressed point: 0x04, then the x and y coordinates.
+        let point = pair.public_key().as_ref();
+        let x = URL_SAFE_NO_PAD.encode(&point[1..33]);
+        let y = URL_SAFE_NO_PAD.encode(&point[33..65]);
+        // The thumbprint is taken over the members in lexicographic order, without whitespace.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint = URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()));
+
+        Ok(AccountKey {
+            pair,
+            rng,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+        })
+    }
+
+    /// Signs a payload, returning the JWS in flattened JSON serialization.
+    fn sign(&self, protected: &Value, payload: &str) -> Result<String, Box<dyn Error>> {
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let signature = self
+            .pair
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string())
+    }
+}
+
+/// A response from the ACME server.
+struct AcmeResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl AcmeResponse {
+    fn json(&self) -> Result<Value, Box<dyn Error>> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    /// The URL of the object the request created.
+    fn location(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self
+            .headers
+            .get(LOCATION)
+            .ok_or("ACME response has no Location")?
+            .to_str()?
+            .to_owned())
+    }
+
+    fn nonce(&self) -> Option<String> {
+        let nonce = self.headers.get(REPLAY_NONCE)?.to_str().ok()?;
+        Some(nonce.to_owned())
+    }
+}
+
+/// Sends a request to the ACME server.
+async fn send(
+    client: &Client,
+    connector: &OriginConnector,
+    method: Method,
+    url: &str,
+    jws: Option<String>,
+) -> Result<AcmeResponse, Box<dyn Error>> {
+    let mut req = Request::builder().method(method).uri(url.parse::<Uri>()?);
+    let body = match jws {
+        Some(jws) => {
+            req = req.header(CONTENT_TYPE, "application/jose+json");
+            HttpBody::from(Payload::Fixed(FixedPayload::new(Bytes::from(jws))))
+        }
+        None => HttpBody::from(Payload::None),
+    };
+
+    let res = connector.send(client, req.body(body)?).await?;
+    let (parts, body) = res.into_parts();
+    Ok(AcmeResponse {
+        status: parts.status,
+        headers: parts.headers,
+        body: body.bytes().await?,
+    })
+}
+
+/// An ACME account on a server.
+struct AcmeClient {
+    client: Client,
+    connector: OriginConnector,
+    key: AccountKey,
+
+    new_nonce: String,
+    new_order: String,
+
+    /// The account's URL, which signed requests identify it by.
+    account_url: String,
+
+    /// A nonce from the server's last response, to be used by the next request.
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetches the server's directory and registers the account, or finds it if it is already registered.
+    async fn connect(config: &AcmeConfig) -> Result<AcmeClient, Box<dyn Error>> {
+        std::fs::create_dir_all(&config.storage_dir)?;
+        let key = AccountKey::load_or_create(&config.storage_dir.join("account.key"))?;
+
+        let client = Client::default();
+        let connector = OriginConnector::new(OriginRequestOptions {
+            tls: OriginTls {
+                ca_bundle: config.ca_bundle.clone(),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let directory = send(
+            &client,
+            &connector,
+            Method::GET,
+            &config.directory_url,
+            None,
+        )
+        .await?
+        .json()?;
+        let url = |name: &str| -> Result<String, Box<dyn Error>> {
+            Ok(directory[name]
+                .as_str()
+                .ok_or_else(|| format!("ACME directory has no {}", name))?
+                .to_owned())
+        };
+
+        let mut acme = AcmeClient {
+            client,
+            connector,
+            key,
+            new_nonce: url("newNonce")?,
+            new_order: url("newOrder")?,
+            account_url: String::new(),
+            nonce: None,
+        };
+
+        let account = json!({
+            "termsOfServiceAgreed": config.accept_terms,
+            "contact": config.contact,
+        });
+        acme.account_url = acme
+            .post(&url("newAccount")?, &account.to_string())
+            .await?
+            .location()?;
+        Ok(acme)
+    }
+
+    /// Sends a signed request, which is a POST-as-GET if the payload is empty.
+    async fn post(&mut self, url: &str, payload: &str) -> Result<AcmeResponse, Box<dyn Error>> {
+        let mut retries = 0;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => send(
+                    &self.client,
+                    &self.connector,
+                    Method::HEAD,
+                    &self.new_nonce,
+                    None,
+                )
+                .await?
+                .nonce()
+                .ok_or("ACME server returned no nonce")?,
+            };
+
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            // Only the account registration carries the key itself.
+            if self.account_url.is_empty() {
+                protected["jwk"] = self.key.jwk.clone();
+            } else {
+                protected["kid"] = json!(self.account_url);
+            }
+
+            let jws = self.key.sign(&protected, payload)?;
+            let res = send(&self.client, &self.connector, Method::POST, url, Some(jws)).await?;
+            self.nonce = res.nonce();
+            if res.status.is_success() {
+                return Ok(res);
+            }
+
+            // Servers may reject any nonce, and send a fresh one along with the error.
+            let problem = res.json().unwrap_or(Value::Null);
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce"
+                && retries < MAX_NONCE_RETRIES
+            {
+                retries += 1;
+                continue;
+            }
+            return Err(format!(
+                "ACME request to {} failed with {}: {}",
+                url,
+                res.status,
+                problem["detail"].as_str().unwrap_or("no detail")
+            )
+            .into());
+        }
+    }
+
+    /// Polls an authorization or order until it is no longer pending or processing.
+    async fn poll(&mut self, url: &str) -> Result<Value, Box<dyn Error>> {
+        for _ in 0..MAX_POLLS {
+            let object = self.post(url, "").await?.json()?;
+            match object["status"].as_str() {
+                Some("pending") | Some("processing") => monoio::time::sleep(POLL_INTERVAL).await,
+                _ => return Ok(object),
+            }
+        }
+        Err(format!("{} was still pending after {} polls", url, MAX_POLLS).into())
+    }
+
+    /// Issues a certificate for a domain, returning its PEM chain and private key.
+    async fn issue(
+        &mut self,
+        domain: &str,
+        solver: &dyn ChallengeSolver,
+    ) -> Result<(String, String), Box<dyn Error>> {
+        let new_order = self.new_order.clone();
+        let order = json!({ "identifiers": [{ "type": "dns", "value": domain }] });
+        let res = self.post(&new_order, &order.to_string()).await?;
+        let order_url = res.location()?;
+        let order = res.json()?;
+
+        for authorization in order["authorizations"]
+            .as_array()
+            .ok_or("order has no authorizations")?
+        {
+            let url = authorization
+                .as_str()
+                .ok_or("order has an invalid authorization")?;
+            self.authorize(url, solver).await?;
+        }
+
+        let key = rcgen::KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![domain.to_owned()])?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key)?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or("order has no finalize URL")?;
+        let csr = json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) });
+        self.post(finalize, &csr.to_string()).await?;
+
+        let order = self.poll(&order_url).await?;
+        if order["status"] != "valid" {
+            return Err(format!("order ended up {}", order["status"]).into());
+        }
+        let certificate = order["certificate"]
+            .as_str()
+            .ok_or("order has no certificate URL")?;
+        let chain = self.post(certificate, "").await?.body;
+
+        Ok((String::from_utf8(chain.to_vec())?, key.serialize_pem()))
+    }
+
+    /// Completes an authorization with the solver's challenge, unless it is already valid.
+    async fn authorize(
+        &mut self,
+        url: &str,
+        solver: &dyn ChallengeSolver,
+    ) -> Result<(), Box<dyn Error>> {
+        let authorization = self.post(url, "").await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization["identifier"]["value"]
+            .as_str()
+            .ok_or("authorization has no identifier")?;
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|challenges| {
+                challenges
+                    .iter()
+                    .find(|c| c["type"] == solver.challenge_type())
+            })
+            .ok_or_else(|| format!("no {} challenge offered", solver.challenge_type()))?;
+        let token = challenge["token"]
+            .as_str()
+            .ok_or("challenge has no token")?;
+        let challenge_url = challenge["url"].as_str().ok_or("challenge has no URL")?;
+        let key_authorization = format!("{}.{}", token, self.key.thumbprint);
+
+        solver.present(domain, token, &key_authorization)?;
+        let res = match self.post(challenge_url, "{}").await {
+            Ok(_) => self.poll(url).await,
+            Err(e) => Err(e),
+        };
+        solver.clean_up(domain, token);
+
+        let authorization = res?;
+        if authorization["status"] != "valid" {
+            let detail = authorization["challenges"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find_map(|c| c["error"]["detail"].as_str())
+                .unwrap_or("no detail");
+            return Err(format!("validation of {} failed: {}", domain, detail).into());
+        }
+        Ok(())
+    }
+}
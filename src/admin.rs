@@ -0,0 +1,437 @@
+use crate::config::Config;
+use crate::metrics;
+use crate::purge::{Purge, PurgeMode, PurgeSelector, Purger};
+use crate::tagindex::{TagIndex, TagIndexUpdate};
+use crate::upgrade::{self, SocketKind};
+use crate::wasm::registry;
+use crate::wasm::resident::{self, ResidentEvent};
+use bytes::Bytes;
+use http::{response::Builder, Method, StatusCode};
+use monoio::io::{sink::SinkExt, stream::Stream, AsyncReadRent, Splitable};
+use monoio::net::{TcpListener, TcpStream};
+use monoio::IoUringDriver;
+use monoio_http::common::body::{BodyExt, HttpBody};
+use monoio_http::common::{request::Request, response::Response};
+use monoio_http::h1::codec::{decoder::RequestDecoder, encoder::GenericEncoder};
+use monoio_http::h1::payload::{FixedPayload, Payload};
+use serde_json::{json, Value};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// The largest hook module that can be uploaded.
+const MAX_MODULE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How often the purge thread applies tag index updates while it has no purges to run.
+const TAG_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Launches the admin API on its own thread.
+///
+/// Admin operations such as purges walk the cache directories with blocking filesystem calls,
+/// so they are kept off the worker threads entirely. Purges run on a thread of their own on top of that,
+/// so a long one doesn't hold up other admin requests.
+/// `updates` receives tag index changes from worker threads.
+pub fn launch(
+    config: Config,
+    updates: Option<Receiver<TagIndexUpdate>>,
+) -> std::thread::JoinHandle<()> {
+    // Taken before the worker threads report in, since inherited sockets still left then are closed.
+    let inherited = upgrade::take_inherited(SocketKind::Tcp, &config.admin.bind_addr);
+    std::thread::spawn(move || {
+        monoio::RuntimeBuilder::<IoUringDriver>::new()
+            .enable_timer()
+            .build()
+            .expect("failed to build admin runtime")
+            .block_on(serve(config, inherited, updates))
+    })
+}
+
+/// A purge for the purge thread, with where to send the number of objects it purged.
+/// The socket is closed once the result is sent, which wakes the admin request waiting for it.
+type PurgeJob = (Purge, Sender<io::Result<u64>>, UnixStream);
+
+/// State shared by admin connections.
+struct AdminState {
+    token: String,
+    purges: Sender<PurgeJob>,
+}
+
+async fn serve(
+    config: Config,
+    inherited: Option<OwnedFd>,
+    updates: Option<Receiver<TagIndexUpdate>>,
+) {
+    let bind_addr = &config.admin.bind_addr;
+    let listener = match inherited {
+        Some(fd) => TcpListener::from_std(std::net::TcpListener::from(fd)),
+        None => TcpListener::bind(bind_addr),
+    }
+    .expect(&*("failed to listen on admin addr ".to_owned() + bind_addr));
+    upgrade::register(SocketKind::Tcp, bind_addr, listener.as_raw_fd());
+
+    let (purges, jobs) = channel();
+    let roots = config.cache.roots;
+    std::thread::spawn(move || run_purges(roots, updates, jobs));
+
+    let state = Rc::new(AdminState {
+        token: config.admin.token,
+        purges,
+    });
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                monoio::spawn(handle_connection(stream, state.clone()));
+            }
+            Err(e) => {
+                println!("accepted admin connection failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Carries out purges one at a time, once the tag index is built.
+/// Between purges, tag index updates are applied as they come in, so they don't pile up in the channel.
+fn run_purges(
+    roots: Vec<PathBuf>,
+    updates: Option<Receiver<TagIndexUpdate>>,
+    jobs: Receiver<PurgeJob>,
+) {
+    let mut tag_index = TagIndex::new(updates);
+    if let Err(e) = tag_index.rebuild(&roots) {
+        println!("failed to build tag index: {}", e);
+    }
+
+    let purger = Purger::new(roots, tag_index);
+    loop {
+        match jobs.recv_timeout(TAG_UPDATE_INTERVAL) {
+            Ok((purge, done, wake)) => {
+                let _ = done.send(purger.purge(&purge));
+                drop(wake);
+            }
+            Err(RecvTimeoutError::Timeout) => purger.apply_tag_updates(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Rc<AdminState>) {
+    let (r, w) = stream.into_split();
+    let mut sender = GenericEncoder::new(w);
+    let mut receiver = RequestDecoder::new(r);
+
+    while let Some(Ok(req)) = receiver.next().await {
+        let resp = handle_request(req, &state).await;
+        if sender.send_and_flush(resp).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<HttpBody> {
+    Builder::new()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(HttpBody::from(Payload::Fixed(FixedPayload::new(
+            Bytes::from(body.to_string()),
+        ))))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<HttpBody> {
+    json_response(status, json!({ "error": message }))
+}
+
+/// Returns whether the request carries the admin bearer token.
+fn is_authorized(req: &Request, token: &str) -> bool {
+    let presented = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match presented {
+        Some(presented) => constant_time_eq(presented.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle_request(req: Request, state: &AdminState) -> Response<HttpBody> {
+    if !is_authorized(&req, &state.token) {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/purge") => handle_purge(&req, state).await,
+        (&Method::GET, "/metrics") => Builder::new()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(HttpBody::from(Payload::Fixed(FixedPayload::new(
+                Bytes::from(metrics::render()),
+            ))))
+            .unwrap(),
+        (&Method::GET, "/wasm") => handle_wasm_status(),
+        (&Method::PUT, "/wasm") => handle_wasm_upload(req).await,
+        (&Method::POST, "/wasm/rollback") => handle_wasm_rollback(),
+        (&Method::POST, "/wasm/event") => handle_wasm_event(req).await,
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Handles `POST /purge`.
+///
+/// Exactly one of the `url`, `prefix`, `host` or `tag` query parameters selects what to purge,
+/// and `mode` is either `soft` (the default) or `hard`. A `url` or `prefix` purges the site's objects for both `http`
+/// and `https`.
+async fn handle_purge(req: &Request, state: &AdminState) -> Response<HttpBody> {
+    let mut selector = None;
+    let mut mode = PurgeMode::Soft;
+
+    for (name, value) in query_pairs(req.uri().query().unwrap_or("")) {
+        let next = match name.as_str() {
+            "url" => PurgeSelector::Url(value),
+            "prefix" => PurgeSelector::Prefix(value),
+            "host" => PurgeSelector::Host(value),
+            "tag" => PurgeSelector::Tag(value),
+            "mode" => {
+                mode = match value.as_str() {
+                    "soft" => PurgeMode::Soft,
+                    "hard" => PurgeMode::Hard,
+                    _ => return error_response(StatusCode::BAD_REQUEST, "invalid mode"),
+                };
+                continue;
+            }
+            _ => return error_response(StatusCode::BAD_REQUEST, "unknown parameter"),
+        };
+
+        if selector.replace(next).is_some() {
+            return error_response(StatusCode::BAD_REQUEST, "multiple selectors");
+        }
+    }
+
+    let selector = match selector {
+        Some(s) => s,
+        None => return error_response(StatusCode::BAD_REQUEST, "missing selector"),
+    };
+
+    let event = purge_event(&selector);
+    let (done, result) = channel();
+    let result = match UnixStream::pair() {
+        Ok((wake, woken)) => {
+            if state
+                .purges
+                .send((Purge { selector, mode }, done, wake))
+                .is_err()
+            {
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, "purge failed");
+            }
+            wait_for_purge(woken, result).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(count) => {
+            if registry::get().is_some() {
+                resident::broadcast(event);
+            }
+            json_response(StatusCode::OK, json!({ "purged": count }))
+        }
+        Err(e) => {
+            println!("purge failed: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "purge failed")
+        }
+    }
+}
+
+/// Waits for the purge thread to send a purge's result, without blocking the admin thread.
+/// The purge thread closes its end of `woken` once the result is sent, or drops it if it exits.
+async fn wait_for_purge(woken: UnixStream, result: Receiver<io::Result<u64>>) -> io::Result<u64> {
+    woken.set_nonblocking(true)?;
+    let mut woken = monoio::net::UnixStream::from_std(woken)?;
+    let (res, _) = woken.read(vec![0u8; 1]).await;
+    res?;
+
+    result
+        .try_recv()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "purge thread exited")))
+}
+
+/// Describes a purge to resident instances.
+fn purge_event(selector: &PurgeSelector) -> ResidentEvent {
+    let (site, selector, value) = match selector {
+        PurgeSelector::Url(url) => {
+            let site = url
+                .parse::<http::Uri>()
+                .ok()
+                .and_then(|u| u.host().map(str::to_owned))
+                .unwrap_or_default();
+            (site, "url", url)
+        }
+        PurgeSelector::Prefix(prefix) => (String::new(), "prefix", prefix),
+        PurgeSelector::Host(host) => (host.clone(), "host", host),
+        PurgeSelector::Tag(tag) => (String::new(), "tag", tag),
+    };
+
+    ResidentEvent::Purge {
+        site,
+        selector,
+        value: value.clone(),
+    }
+}
+
+fn wasm_disabled() -> Response<HttpBody> {
+    error_response(StatusCode::CONFLICT, "wasm hooks are not enabled")
+}
+
+/// Handles `GET /wasm`, describing the live hook module version.
+fn handle_wasm_status() -> Response<HttpBody> {
+    let Some(registry) = registry::get() else {
+        return wasm_disabled();
+    };
+
+    let body = match registry.current() {
+        Some(version) => json!({
+            "generation": version.generation,
+            "name": version.name,
+            "source": version.source,
+            "requests": version.requests(),
+            "failures": version.failures(),
+        }),
+        None => json!({ "generation": null }),
+    };
+    json_response(StatusCode::OK, body)
+}
+
+/// Handles `PUT /wasm?name=...`, installing the module in the request body as the new live version.
+/// `name` selects the module's permissions.
+///
+/// The module is compiled and smoke-tested on the admin thread before any worker thread sees it.
+async fn handle_wasm_upload(req: Request) -> Response<HttpBody> {
+    let Some(registry) = registry::get() else {
+        return wasm_disabled();
+    };
+
+    let name = query_pairs(req.uri().query().unwrap_or(""))
+        .find(|(k, _)| k == "name")
+        .map(|(_, v)| v);
+    let Some(name) = name.filter(|n| !n.is_empty()) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing name");
+    };
+
+    let declared_len = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared_len.map_or(false, |len| len > MAX_MODULE_SIZE) {
+        return error_response(StatusCode::PAYLOAD_TOO_LARGE, "module too large");
+    }
+
+    let bytes = match HttpBody::from(req.into_body()).bytes().await {
+        Ok(bytes) => bytes,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "failed to read body"),
+    };
+    if bytes.len() > MAX_MODULE_SIZE {
+        return error_response(StatusCode::PAYLOAD_TOO_LARGE, "module too large");
+    }
+
+    match registry.install(&bytes, &name, "admin upload".to_owned()) {
+        Ok(generation) => json_response(StatusCode::OK, json!({ "generation": generation })),
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, &format!("{:#}", e)),
+    }
+}
+
+/// Handles `POST /wasm/rollback`, restoring the previous hook module version.
+fn handle_wasm_rollback() -> Response<HttpBody> {
+    let Some(registry) = registry::get() else {
+        return wasm_disabled();
+    };
+
+    match registry.rollback() {
+        Some(generation) => json_response(StatusCode::OK, json!({ "generation": generation })),
+        None => error_response(StatusCode::CONFLICT, "no previous version"),
+    }
+}
+
+/// Handles `POST /wasm/event?name=...&site=...`, sending the request body to every resident instance as a
+/// config event. `site` is optional.
+async fn handle_wasm_event(req: Request) -> Response<HttpBody> {
+    if registry::get().is_none() {
+        return wasm_disabled();
+    }
+
+    let mut name = None;
+    let mut site = String::new();
+    for (k, v) in query_pairs(req.uri().query().unwrap_or("")) {
+        match k.as_str() {
+            "name" => name = Some(v),
+            "site" => site = v,
+            _ => return error_response(StatusCode::BAD_REQUEST, "unknown parameter"),
+        }
+    }
+    let Some(name) = name else {
+        return error_response(StatusCode::BAD_REQUEST, "missing name");
+    };
+
+    let data = match HttpBody::from(req.into_body()).bytes().await {
+        Ok(data) => data,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "failed to read body"),
+    };
+
+    resident::broadcast(ResidentEvent::Config {
+        site,
+        name,
+        data: data.to_vec(),
+    });
+    json_response(StatusCode::OK, json!({}))
+}
+
+/// Splits a query string into percent-decoded name/value pairs.
+pub fn query_pairs(query: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    query.split('&').filter(|p| !p.is_empty()).map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (percent_decode(name), percent_decode(value))
+    })
+}
+
+/// Decodes `%XX` escapes and `+` in a query component.
+/// Invalid escapes are kept as-is.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,222 @@
+//! The response bodies the request pipeline hands to client connections.
+//!
+//! Every variant is pulled one chunk at a time, so nothing is read from the origin or the cache before the client
+//! connection asks for it.
+
+use crate::accesslog::{AccessLogEntry, AccessLogger};
+use crate::cache::{CachedBody, PartialBody, StoringBody};
+use crate::failover::FillBody;
+use crate::metrics::HostMetrics;
+use crate::zerocopy::{BlockDestination, BlockServeMode};
+use bytes::Bytes;
+use monoio_http::common::body::{Body, HttpBody, StreamHint};
+use monoio_http::common::error::HttpError;
+use monoio_http::h1::payload::{FixedPayload, Payload};
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub enum ResponseBody {
+    /// A body built in memory.
+    Http(HttpBody),
+
+    /// An origin body passed through as-is.
+    Origin(OriginBody),
+
+    /// Blocks read from the cache.
+    Cached(CachedBody),
+
+    /// An origin body being stored in the cache as it is sent, narrowed to the client's range if the origin was
+    /// asked for whole blocks.
+    Storing(StoringBody),
+
+    /// Cached blocks with the gaps between them fetched from the origin.
+    Partial(PartialBody),
+
+    /// A body whose request is written to the access log once it has been sent.
+    Logged(Box<LoggedBody>),
+}
+
+impl ResponseBody {
+    pub fn empty() -> Self {
+        ResponseBody::Http(HttpBody::from(Payload::None))
+    }
+
+    pub fn fixed(data: Bytes) -> Self {
+        ResponseBody::Http(HttpBody::from(Payload::Fixed(FixedPayload::new(data))))
+    }
+}
+
+impl From<HttpBody> for ResponseBody {
+    fn from(body: HttpBody) -> Self {
+        ResponseBody::Http(body)
+    }
+}
+
+impl Body for ResponseBody {
+    type Data = Bytes;
+    type Error = HttpError;
+
+    async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        match self {
+            ResponseBody::Http(body) => body.next_data().await,
+            ResponseBody::Origin(body) => body.next_data().await,
+            ResponseBody::Cached(body) => body.next_data().await,
+            ResponseBody::Storing(body) => body.next_data().await,
+            ResponseBody::Partial(body) => body.next_data().await,
+            ResponseBody::Logged(body) => body.next_data().await,
+        }
+    }
+
+    fn stream_hint(&self) -> StreamHint {
+        match self {
+            ResponseBody::Http(body) => body.stream_hint(),
+            ResponseBody::Origin(body) => body.inner.stream_hint(),
+            ResponseBody::Cached(_) | ResponseBody::Storing(_) | ResponseBody::Partial(_) => {
+                StreamHint::Stream
+            }
+            ResponseBody::Logged(body) => body.inner.stream_hint(),
+        }
+    }
+}
+
+/// An origin response body, whose bytes and total fetch time are recorded as it is read.
+pub struct OriginBody {
+    inner: FillBody,
+    metrics: Arc<HostMetrics>,
+
+    /// When the origin request was sent.
+    start: Instant,
+
+    /// How long the response head took to arrive.
+    ttfb: Duration,
+
+    recorded: bool,
+}
+
+impl OriginBody {
+    pub fn new(inner: FillBody, metrics: Arc<HostMetrics>, start: Instant, ttfb: Duration) -> Self {
+        Self {
+            inner,
+            metrics,
+            start,
+            ttfb,
+            recorded: false,
+        }
+    }
+
+    /// The counters of the host the body is fetched for.
+    pub fn metrics(&self) -> &HostMetrics {
+        &self.metrics
+    }
+
+    pub async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        let data = self.inner.next_data().await;
+        match &data {
+            Some(Ok(chunk)) => self.metrics.record_bytes_from_origin(chunk.len() as u64),
+            Some(Err(_)) => self.record(None),
+            None => self.record(Some(self.start.elapsed())),
+        }
+        data
+    }
+
+    /// Records the origin request once, with its total time only if the body was read to the end.
+    fn record(&mut self, total: Option<Duration>) {
+        if !self.recorded {
+            self.recorded = true;
+            self.metrics.record_origin_request(self.ttfb, total);
+        }
+    }
+}
+
+impl Drop for OriginBody {
+    fn drop(&mut self) {
+        // The client went away, or never asked for the body.
+        self.record(None);
+    }
+}
+
+/// A response body that counts the bytes sent and logs its request once the body ends, fails or is dropped.
+pub struct LoggedBody {
+    inner: ResponseBody,
+    logger: Rc<AccessLogger>,
+
+    /// The entry to log, with the bytes and total time still to fill in. [None] once logged.
+    entry: Option<AccessLogEntry>,
+
+    /// When the request came in.
+    start: Instant,
+}
+
+impl LoggedBody {
+    pub fn new(
+        inner: ResponseBody,
+        logger: Rc<AccessLogger>,
+        entry: AccessLogEntry,
+        start: Instant,
+    ) -> Self {
+        Self {
+            inner,
+            logger,
+            entry: Some(entry),
+            start,
+        }
+    }
+
+    pub async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        let data = self.inner.next_data().await;
+        match &data {
+            Some(Ok(chunk)) => {
+                if let Some(entry) = &mut self.entry {
+                    entry.bytes += chunk.len() as u64;
+                }
+            }
+            Some(Err(_)) | None => self.log(),
+        }
+        data
+    }
+
+    /// Whether the body is read from the cache, so it can be sent with [Self::send_cached_to].
+    pub fn is_cached(&self) -> bool {
+        matches!(self.inner, ResponseBody::Cached(_))
+    }
+
+    /// Sends a cached body straight to a client connection, like [CachedBody::send_to], and logs the request.
+    pub async fn send_cached_to<D: BlockDestination>(
+        &mut self,
+        dst: &mut D,
+        mode: &mut BlockServeMode,
+    ) -> io::Result<()> {
+        let ResponseBody::Cached(body) = std::mem::replace(&mut self.inner, ResponseBody::empty())
+        else {
+            unreachable!("BUG: only cached bodies can be sent directly");
+        };
+        let res = body.send_to(dst, mode).await;
+        if let (Ok(sent), Some(entry)) = (&res, &mut self.entry) {
+            entry.bytes += sent;
+        }
+        self.log();
+        res.map(|_| ())
+    }
+
+    fn log(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            // A partial hit only goes to the origin as its body is sent.
+            if let ResponseBody::Partial(body) = &self.inner {
+                if let Some(time) = body.origin_time() {
+                    entry.origin_time = Some(entry.origin_time.unwrap_or_default() + time);
+                }
+            }
+            entry.total_time = self.start.elapsed();
+            self.logger.log(&entry);
+        }
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // The client went away before the end of the body, or the connection never asked for it.
+        self.log();
+    }
+}
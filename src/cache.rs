@@ -0,0 +1,1323 @@
+//! Serving objects from the cache, and storing origin responses in it.
+//!
+//! A `GET` or `HEAD` for a fresh object is answered from the cache. Blocks the response needs that aren't cached yet
+//! are fetched from the origin with block-aligned `Range` requests, guarded by `If-Range` so they are never stitched
+//! onto a different version, and stored as they pass through. Expired objects are revalidated with the origin and
+//! served from the cache if it answers `304`.
+//!
+//! A cacheable `200`, or a `206` with a validator, is stored while it is sent to the client. A client's single range
+//! is widened to whole blocks on its way to the origin, so every block it touches can be stored, and the response is
+//! narrowed back down to what the client asked for. The meta file is written first with no blocks covered, and each
+//! block is marked covered once its file is written, so a fill that breaks off only leaves gaps behind. Only one fill
+//! at a time writes an object's blocks, and a fill stops as soon as its object is purged.
+
+use crate::body::{OriginBody, ResponseBody};
+use crate::cachestate::{
+    block_file_path, create_and_open_block_file, meta_file_path, open_block_file, root_for_object,
+    set_exp_ts, FileReadPlan, FileReadPlanStep, FileReadPlanStepKind, ObjectMeta,
+    ObjectMetaPreamble, OpenObjectMeta,
+};
+use crate::config::CacheConfig;
+use crate::directio::{read_block, AlignedBuf, AlignedBufPool};
+use crate::failover::{validator, RangeFetcher, CONDITIONAL_HEADERS};
+use crate::hash::{create_file_block_hash, create_object_hash, FileBlockHash, FileBlockInfo};
+use crate::metrics::{record_cache_usage, HostMetrics, MetricsRecorder};
+use crate::routing::CachePolicy;
+use crate::tagindex::{notify, tags_from_headers, TagIndexUpdate};
+use crate::zerocopy::{send_block_range, BlockDestination, BlockServeMode};
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    ACCEPT_RANGES, AGE, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, SET_COOKIE, VARY,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use monoio::buf::{IoBuf, IoBufMut};
+use monoio::io::AsyncWriteRentExt;
+use monoio_http::common::error::HttpError;
+use std::cmp::min;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The most bytes read from a block file per body chunk when the body is pulled rather than spliced.
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Returns whether a response header is left out of meta files, because it is recomputed for every response
+/// served from the cache.
+fn is_recomputed(name: &HeaderName) -> bool {
+    [CONTENT_LENGTH, CONTENT_RANGE, ACCEPT_RANGES, AGE].contains(name)
+}
+
+/// Returns the current time as seconds since the Unix epoch, as stored in meta files.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// An object found in the cache, fresh or not.
+pub struct CachedObject {
+    meta: ObjectMeta,
+    root: PathBuf,
+    path: PathBuf,
+}
+
+/// Looks up the object stored under the cache key.
+/// Returns [None] if there isn't one. Expired objects are returned too, so they can be revalidated.
+pub async fn lookup(config: &CacheConfig, key: &str) -> Option<CachedObject> {
+    let hash = create_object_hash(key);
+    let root = root_for_object(&hash, &config.roots);
+    let path = meta_file_path(&hash, root);
+    let meta = match ObjectMeta::from_file(&path).await {
+        Ok(meta) => meta,
+        Err(e) => {
+            let missing = e
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::NotFound);
+            if !missing {
+                println!("failed to read cache meta for {}: {}", key, e);
+            }
+            return None;
+        }
+    };
+
+    // Another key with the same hash isn't this object.
+    let preamble = &meta.preamble;
+    if preamble.key != key || preamble.size_bytes == 0 || preamble.block_size == 0 {
+        return None;
+    }
+
+    Some(CachedObject {
+        meta,
+        root: root.to_owned(),
+        path,
+    })
+}
+
+/// Returns the headers stored with an object, leaving out the ones recomputed for every response.
+fn stored_headers(preamble: &ObjectMetaPreamble) -> HeaderMap {
+    let mut headers = HeaderMap::with_capacity(preamble.headers.len() + 3);
+    for (name, value) in &preamble.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            if !is_recomputed(&name) {
+                headers.append(name, value);
+            }
+        }
+    }
+    headers
+}
+
+/// Returns whether a meta file holds the same version of an object as an origin response describes.
+/// Without a validator there is no telling, so the answer is no.
+fn is_same_object(
+    meta: &ObjectMeta,
+    key: &str,
+    size: u64,
+    block_size: u32,
+    validator: Option<&HeaderValue>,
+) -> bool {
+    let preamble = &meta.preamble;
+    validator.is_some()
+        && preamble.key == key
+        && preamble.size_bytes == size
+        && preamble.block_size == block_size
+        && crate::failover::validator(&stored_headers(preamble)).as_ref() == validator
+}
+
+/// What a `Range` header asks for of an object.
+enum RangeRequest {
+    /// The whole object, also used for ranges that can't be served as one.
+    Full,
+
+    /// The bytes from the start to the end (inclusive).
+    Range(u64, u64),
+
+    /// A range that lies entirely past the end of the object.
+    Unsatisfiable,
+}
+
+/// Parses a single byte range request for an object of `size` bytes.
+/// Multiple ranges and malformed headers are answered with the whole object, which clients have to accept.
+fn parse_range(value: Option<&HeaderValue>, size: u64) -> RangeRequest {
+    let Some(spec) = value
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last n bytes.
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(n) => (size.saturating_sub(n), size - 1),
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let end = match end {
+                "" => size - 1,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => min(end, size - 1),
+                    _ => return RangeRequest::Full,
+                },
+            };
+            (start, end)
+        }
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Range(start, end)
+}
+
+/// How the body of a response from the cache is read.
+pub enum CachedResponse {
+    /// Every byte the response needs is cached.
+    Hit(ResponseBody),
+
+    /// Some of the blocks the response needs have to be fetched from the origin.
+    Partial(PartialHit),
+}
+
+impl CachedObject {
+    pub fn preamble(&self) -> &ObjectMetaPreamble {
+        &self.meta.preamble
+    }
+
+    /// Returns whether the object hasn't expired.
+    pub fn is_fresh(&self) -> bool {
+        self.meta.preamble.exp_ts > unix_now()
+    }
+
+    /// Returns the object's strong validator, which ranges fetched from the origin are checked against.
+    fn validator(&self) -> Option<HeaderValue> {
+        validator(&stored_headers(&self.meta.preamble))
+    }
+
+    /// Returns whether the object can be revalidated with the origin once it has expired.
+    pub fn can_revalidate(&self) -> bool {
+        self.validator().is_some()
+    }
+
+    /// Replaces the conditions in an origin request with ones that ask whether the object is still current.
+    /// The client's own conditions are left out, as the response comes from the cache just like a fresh hit's.
+    pub fn set_conditions(&self, headers: &mut HeaderMap) {
+        for name in CONDITIONAL_HEADERS {
+            headers.remove(name);
+        }
+        let stored = stored_headers(&self.meta.preamble);
+        if let Some(etag) = stored.get(ETAG) {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = stored.get(LAST_MODIFIED) {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    /// Makes the object fresh again after the origin answered a revalidation with `304`.
+    /// The TTL comes from the route, or from the `Cache-Control` of the `304` or else the stored response. If neither
+    /// gives one, the object stays expired and is only served this once.
+    pub async fn refresh(&mut self, not_modified: &HeaderMap, policy: &CachePolicy) {
+        let ttl = if not_modified.contains_key(CACHE_CONTROL) {
+            freshness(not_modified, policy)
+        } else {
+            freshness(&stored_headers(&self.meta.preamble), policy)
+        };
+        let Some(ttl) = ttl else {
+            return;
+        };
+
+        let exp_ts = unix_now() + ttl.as_secs();
+        match set_exp_ts(&self.path, exp_ts).await {
+            Ok(()) => self.meta.preamble.exp_ts = exp_ts,
+            Err(e) => println!("failed to refresh {}: {}", self.meta.preamble.key, e),
+        }
+    }
+
+    /// Returns whether every block holding the bytes from the start to the end (inclusive) is cached.
+    fn covers(&self, start: u64, end: u64) -> bool {
+        let block_size = self.meta.preamble.block_size as u64;
+        (start / block_size..=end / block_size)
+            .all(|block_num| self.meta.coverage_map.0.get(block_num as usize) == Some(&true))
+    }
+
+    /// Builds the response to a request for the object from its stored headers and blocks.
+    /// Blocks the response needs that aren't cached make it a partial hit, which fetches them from the origin.
+    /// Returns [None] if that isn't possible because the object has no validator to check the origin's ranges
+    /// against, so the request has to go to the origin.
+    /// `dio_pool` is the pool to read blocks into if block files are read with `O_DIRECT`, and `metrics` counts the
+    /// bytes sent.
+    pub fn respond(
+        self,
+        method: &Method,
+        range: Option<&HeaderValue>,
+        dio_pool: Option<Rc<AlignedBufPool>>,
+        metrics: Arc<HostMetrics>,
+    ) -> Option<(StatusCode, HeaderMap, CachedResponse)> {
+        let size = self.meta.preamble.size_bytes;
+
+        let mut headers = stored_headers(&self.meta.preamble);
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+        let (status, start, end) = match parse_range(range, size) {
+            RangeRequest::Full => (StatusCode::OK, 0, size - 1),
+            RangeRequest::Range(start, end) => {
+                let content_range = format!("bytes {}-{}/{}", start, end, size);
+                headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).ok()?);
+                (StatusCode::PARTIAL_CONTENT, start, end)
+            }
+            RangeRequest::Unsatisfiable => {
+                let content_range = format!("bytes */{}", size);
+                headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).ok()?);
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+                return Some((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    headers,
+                    CachedResponse::Hit(ResponseBody::empty()),
+                ));
+            }
+        };
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+
+        if method == Method::HEAD {
+            return Some((status, headers, CachedResponse::Hit(ResponseBody::empty())));
+        }
+
+        if !self.covers(start, end) {
+            let validator = self.validator()?;
+            let block_size = self.meta.preamble.block_size as u64;
+            let steps = FileReadPlan::new(start, end, size, block_size, self.meta.coverage_map)
+                .collect::<Vec<_>>();
+            let hit = PartialHit {
+                key: self.meta.preamble.key,
+                root: self.root,
+                path: self.path,
+                block_size,
+                size,
+                validator,
+                steps,
+                dio_pool,
+                metrics,
+            };
+            return Some((status, headers, CachedResponse::Partial(hit)));
+        }
+
+        let body = CachedBody::new(
+            self.meta.preamble.key,
+            self.root,
+            self.meta.preamble.block_size as u64,
+            dio_pool,
+            metrics,
+            start,
+            end + 1,
+        );
+        Some((
+            status,
+            headers,
+            CachedResponse::Hit(ResponseBody::Cached(body)),
+        ))
+    }
+}
+
+/// A response to be served partly from the cache, along with the plan for which bytes come from where.
+pub struct PartialHit {
+    key: String,
+    root: PathBuf,
+    path: PathBuf,
+    block_size: u64,
+    size: u64,
+    validator: HeaderValue,
+    steps: Vec<FileReadPlanStep>,
+    dio_pool: Option<Rc<AlignedBufPool>>,
+    metrics: Arc<HostMetrics>,
+}
+
+impl PartialHit {
+    /// How many of the response's bytes come from the cache and how many from the origin.
+    pub fn bytes(&self) -> (u64, u64) {
+        let mut cache = 0;
+        let mut origin = 0;
+        for step in &self.steps {
+            match step.kind {
+                FileReadPlanStepKind::CACHE => cache += step.client_len(),
+                FileReadPlanStepKind::ORIGIN { .. } => origin += step.client_len(),
+            }
+        }
+        (cache, origin)
+    }
+
+    /// Starts the response body, which fetches the missing blocks with `fetcher` and stores them, unless another
+    /// fill is writing the object. `recorder` counts the blocks written.
+    pub fn into_body(
+        mut self,
+        fetcher: RangeFetcher,
+        recorder: Rc<MetricsRecorder>,
+    ) -> PartialBody {
+        let steps = std::mem::take(&mut self.steps).into_iter();
+        PartialBody {
+            hit: self,
+            steps,
+            fetcher,
+            recorder,
+            store: PartialStore::Unopened,
+            segment: None,
+            origin_time: None,
+        }
+    }
+}
+
+/// A response body read from an object's block files.
+pub struct CachedBody {
+    key: String,
+    root: PathBuf,
+    block_size: u64,
+
+    /// The pool blocks are read into if block files are read with `O_DIRECT`.
+    dio_pool: Option<Rc<AlignedBufPool>>,
+
+    metrics: Arc<HostMetrics>,
+
+    /// The next object byte to send.
+    pos: u64,
+
+    /// The object byte the body ends before.
+    end: u64,
+
+    /// The block being read from, and its block number.
+    block: Option<(u64, OpenBlock)>,
+}
+
+/// A block a cached body is being read from.
+enum OpenBlock {
+    File(monoio::fs::File),
+
+    /// The whole block, read with `O_DIRECT`.
+    Read(AlignedBuf),
+}
+
+impl CachedBody {
+    /// Creates a body sending the object bytes from `pos` up to `end` (exclusive).
+    fn new(
+        key: String,
+        root: PathBuf,
+        block_size: u64,
+        dio_pool: Option<Rc<AlignedBufPool>>,
+        metrics: Arc<HostMetrics>,
+        pos: u64,
+        end: u64,
+    ) -> Self {
+        Self {
+            key,
+            root,
+            block_size,
+            dio_pool,
+            metrics,
+            pos,
+            end,
+            block: None,
+        }
+    }
+
+    fn block_hash(&self, block_num: u64) -> FileBlockHash {
+        create_file_block_hash(
+            &self.key,
+            FileBlockInfo {
+                block_size: self.block_size as u32,
+                block_num,
+            },
+        )
+    }
+
+    /// Reads a whole block with `O_DIRECT`.
+    async fn read_aligned(&self, block_num: u64, pool: &AlignedBufPool) -> io::Result<AlignedBuf> {
+        let file = open_block_file(&self.block_hash(block_num), &self.root, true).await?;
+        let buf = read_block(&file, pool).await;
+        file.close().await?;
+        buf
+    }
+
+    /// Returns the specified block, opening it if it isn't the one already open.
+    async fn open(&mut self, block_num: u64) -> io::Result<&OpenBlock> {
+        if self.block.as_ref().map(|(n, _)| *n) != Some(block_num) {
+            let block = match &self.dio_pool {
+                Some(pool) => OpenBlock::Read(self.read_aligned(block_num, pool).await?),
+                None => OpenBlock::File(
+                    open_block_file(&self.block_hash(block_num), &self.root, false).await?,
+                ),
+            };
+            if let (Some((_, OpenBlock::Read(buf))), Some(pool)) =
+                (self.block.replace((block_num, block)), &self.dio_pool)
+            {
+                pool.give(buf);
+            }
+        }
+        Ok(&self.block.as_ref().unwrap().1)
+    }
+
+    /// The block the next byte is in, the offset of the byte within it, and how many of the block's bytes to send.
+    fn next_span(&self) -> (u64, u64, u64) {
+        let block_num = self.pos / self.block_size;
+        let offset = self.pos % self.block_size;
+        (
+            block_num,
+            offset,
+            min(self.end - self.pos, self.block_size - offset),
+        )
+    }
+
+    pub async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        if self.pos >= self.end {
+            return None;
+        }
+        Some(self.read_chunk().await.map_err(HttpError::from))
+    }
+
+    async fn read_chunk(&mut self) -> io::Result<Bytes> {
+        let (block_num, offset, len) = self.next_span();
+        let len = min(len, READ_CHUNK_SIZE) as usize;
+        let data = match self.open(block_num).await? {
+            OpenBlock::File(file) => {
+                let (res, buf) = file
+                    .read_exact_at(Vec::with_capacity(len).slice_mut(0..len), offset)
+                    .await;
+                res?;
+                Bytes::from(buf.into_inner())
+            }
+            OpenBlock::Read(buf) => {
+                let offset = offset as usize;
+                let data = buf
+                    .as_slice()
+                    .get(offset..offset + len)
+                    .ok_or_else(short_block)?;
+                Bytes::copy_from_slice(data)
+            }
+        };
+        self.pos += len as u64;
+        self.metrics.record_bytes_from_cache(len as u64);
+        Ok(data)
+    }
+
+    /// Sends the rest of the body straight to a client connection, splicing it from the block files if the mode
+    /// allows. Blocks read with `O_DIRECT` are written from their aligned buffers instead.
+    /// Returns the number of bytes sent.
+    pub async fn send_to<D: BlockDestination>(
+        mut self,
+        dst: &mut D,
+        mode: &mut BlockServeMode,
+    ) -> io::Result<u64> {
+        let start = self.pos;
+        while self.pos < self.end {
+            let (block_num, offset, len) = self.next_span();
+            match &self.dio_pool {
+                Some(pool) => {
+                    let buf = self.read_aligned(block_num, pool).await?;
+                    let range = offset as usize..(offset + len) as usize;
+                    if buf.as_slice().len() < range.end {
+                        pool.give(buf);
+                        return Err(short_block());
+                    }
+                    let (res, slice) = dst.write_all(buf.slice(range)).await;
+                    pool.give(slice.into_inner());
+                    res?;
+                }
+                None => {
+                    let file =
+                        open_block_file(&self.block_hash(block_num), &self.root, false).await?;
+                    send_block_range(mode, dst, &file, offset, len).await?;
+                }
+            }
+            self.pos += len;
+            self.metrics.record_bytes_from_cache(len);
+        }
+        Ok(self.pos - start)
+    }
+}
+
+fn short_block() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "block file ended before the requested range",
+    )
+}
+
+/// The response body of a partial hit: cached blocks read from their files, and the gaps between them fetched from
+/// the origin in the order the client needs them.
+/// Fetched blocks are stored as they pass through, provided no other fill holds the object's lock.
+pub struct PartialBody {
+    hit: PartialHit,
+
+    /// The steps still to send.
+    steps: std::vec::IntoIter<FileReadPlanStep>,
+
+    fetcher: RangeFetcher,
+    recorder: Rc<MetricsRecorder>,
+    store: PartialStore,
+
+    /// The step being sent.
+    segment: Option<Segment>,
+
+    /// How long the origin took to answer the range requests so far.
+    origin_time: Option<Duration>,
+}
+
+/// Whether a partial hit stores the blocks it fetches.
+enum PartialStore {
+    /// Nothing was fetched yet, so the object's lock wasn't taken.
+    Unopened,
+
+    Writing(BlockWriter),
+
+    /// Another fill holds the lock, the object changed, or storing failed.
+    Off,
+}
+
+/// A step of a partial hit being sent.
+enum Segment {
+    Cached(CachedBody),
+
+    /// Blocks fetched from the origin, of which the client gets the bytes in the window.
+    Origin(OriginBody, ClientWindow),
+}
+
+impl PartialBody {
+    pub async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        loop {
+            let Some(segment) = &mut self.segment else {
+                let Some(step) = self.steps.next() else {
+                    self.finish_store().await;
+                    return None;
+                };
+                match self.start(step).await {
+                    Ok(segment) => self.segment = Some(segment),
+                    Err(e) => return Some(Err(self.give_up(e.into()))),
+                }
+                continue;
+            };
+
+            match segment {
+                Segment::Cached(body) => match body.next_data().await {
+                    Some(Ok(data)) => return Some(Ok(data)),
+                    Some(Err(e)) => return Some(Err(self.give_up(e))),
+                    None => self.segment = None,
+                },
+                Segment::Origin(body, window) => match body.next_data().await {
+                    Some(Ok(data)) => {
+                        let data_for_client = window.clip(data.clone());
+                        let readahead = data.len() - data_for_client.len();
+                        self.hit.metrics.record_readahead_bytes(readahead as u64);
+                        if let PartialStore::Writing(writer) = &mut self.store {
+                            if let Err(e) = writer.push(&data).await {
+                                println!("failed to store {}: {}", self.hit.key, e);
+                                self.store = PartialStore::Off;
+                            }
+                        }
+                        if !data_for_client.is_empty() {
+                            return Some(Ok(data_for_client));
+                        }
+                    }
+                    Some(Err(e)) => return Some(Err(self.give_up(e))),
+                    None => self.segment = None,
+                },
+            }
+        }
+    }
+
+    /// Starts sending a step, fetching its blocks from the origin if they aren't cached.
+    async fn start(&mut self, step: FileReadPlanStep) -> io::Result<Segment> {
+        let hit = &self.hit;
+        let step_start = step.block_start_num * hit.block_size;
+        let FileReadPlanStepKind::ORIGIN {
+            byte_start,
+            byte_end,
+        } = step.kind
+        else {
+            return Ok(Segment::Cached(CachedBody::new(
+                hit.key.clone(),
+                hit.root.clone(),
+                hit.block_size,
+                hit.dio_pool.clone(),
+                hit.metrics.clone(),
+                step_start + step.client_start_offset,
+                step_start + step.client_end_offset,
+            )));
+        };
+
+        let origin_start = Instant::now();
+        let fill = self
+            .fetcher
+            .fetch(byte_start, byte_end, &hit.validator)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let Some(fill) = fill else {
+            // The origin has a different version now. Expiring the cached one gets it revalidated, and replaced, on
+            // the next request.
+            if let Err(e) = set_exp_ts(&hit.path, 0).await {
+                println!("failed to expire {}: {}", hit.key, e);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "object changed at the origin",
+            ));
+        };
+        let ttfb = origin_start.elapsed();
+        self.origin_time = Some(self.origin_time.unwrap_or_default() + ttfb);
+        let body = OriginBody::new(fill, hit.metrics.clone(), origin_start, ttfb);
+
+        if let PartialStore::Unopened = self.store {
+            self.store = match resume_store(hit, self.recorder.clone()).await {
+                Some(writer) => PartialStore::Writing(writer),
+                None => PartialStore::Off,
+            };
+        }
+        if let PartialStore::Writing(writer) = &mut self.store {
+            writer.seek(byte_start);
+        }
+
+        let window = ClientWindow {
+            skip: step.client_start_offset,
+            remaining: step.client_len(),
+        };
+        Ok(Segment::Origin(body, window))
+    }
+
+    /// How long the origin took to answer the range requests sent so far, or [None] if none were.
+    pub fn origin_time(&self) -> Option<Duration> {
+        self.origin_time
+    }
+
+    /// Stops sending the body after an error, keeping the blocks stored so far.
+    fn give_up(&mut self, err: HttpError) -> HttpError {
+        self.steps = Vec::new().into_iter();
+        self.segment = None;
+        self.store = PartialStore::Off;
+        err
+    }
+
+    async fn finish_store(&mut self) {
+        if let PartialStore::Writing(writer) = std::mem::replace(&mut self.store, PartialStore::Off)
+        {
+            if let Err(e) = writer.finish().await {
+                println!("failed to store {}: {}", self.hit.key, e);
+            }
+        }
+    }
+}
+
+/// Returns how long an origin response to a `GET` may be cached for, or [None] if it can't be stored.
+/// A `206` is only stored if it has a validator, which the rest of the object's blocks are checked against when they
+/// are fetched later, and says how big the whole object is.
+/// The route's (or a hook's) TTL takes precedence over the origin's `Cache-Control`.
+pub fn storable_ttl(
+    method: &Method,
+    status: StatusCode,
+    headers: &HeaderMap,
+    policy: &CachePolicy,
+) -> Option<Duration> {
+    if policy.bypass || method != Method::GET {
+        return None;
+    }
+    // Responses that differ per client can't be served to everyone under one key.
+    if headers.contains_key(SET_COOKIE) || headers.contains_key(VARY) {
+        return None;
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        validator(headers)?;
+    }
+    object_span(status, headers)?;
+
+    freshness(headers, policy)
+}
+
+/// Returns how long a response stays fresh, or [None] if it can't be cached at all.
+fn freshness(headers: &HeaderMap, policy: &CachePolicy) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+        let seconds = || value.and_then(|v| v.parse::<u64>().ok());
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = seconds(),
+            "s-maxage" => s_maxage = seconds(),
+            _ => {}
+        }
+    }
+
+    let ttl = policy
+        .ttl
+        .or_else(|| s_maxage.or(max_age).map(Duration::from_secs))?;
+    (ttl.as_secs() > 0).then_some(ttl)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Parses a `Content-Range` header into the first and last byte it covers, and the object's size if it is known.
+pub fn content_range(headers: &HeaderMap) -> Option<(u64, u64, Option<u64>)> {
+    // bytes <start>-<end>/<size>
+    let (range, size) = headers
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    if end < start {
+        return None;
+    }
+    let size = match size.trim() {
+        "*" => None,
+        size => Some(size.parse().ok()?),
+    };
+    Some((start, end, size))
+}
+
+/// Returns the object byte a response body starts at and the size of the whole object, for a `200` or a `206`.
+fn object_span(status: StatusCode, headers: &HeaderMap) -> Option<(u64, u64)> {
+    let (start, size) = match status {
+        StatusCode::OK => (0, content_length(headers)?),
+        StatusCode::PARTIAL_CONTENT => {
+            let (start, _, size) = content_range(headers)?;
+            (start, size?)
+        }
+        _ => return None,
+    };
+    (size > 0 && start < size).then_some((start, size))
+}
+
+/// The single byte range a client asked for, from the start to the end (inclusive) or to the end of the object.
+pub struct FillRange {
+    start: u64,
+    end: Option<u64>,
+}
+
+/// Returns the client's range and the block-aligned `Range` to send the origin in its place, so every block the
+/// client's range touches comes back whole and can be stored.
+/// Returns [None] if the request's `Range` should be sent on as is: there is none, it asks for several ranges, or it
+/// asks for the last bytes of an object whose size isn't known yet.
+pub fn fill_range(
+    range: Option<&HeaderValue>,
+    block_size: u64,
+) -> Option<(FillRange, HeaderValue)> {
+    let spec = range?.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse::<u64>().ok().filter(|&end| end >= start)?),
+    };
+
+    let aligned_start = start - start % block_size;
+    let aligned = match end {
+        Some(end) => format!(
+            "bytes={}-{}",
+            aligned_start,
+            (end / block_size + 1) * block_size - 1
+        ),
+        None => format!("bytes={}-", aligned_start),
+    };
+    Some((
+        FillRange { start, end },
+        HeaderValue::from_str(&aligned).ok()?,
+    ))
+}
+
+impl FillRange {
+    /// Narrows the origin's response to the widened range back down to what the client asked for, rewriting its
+    /// `Content-Range` and `Content-Length`.
+    /// Returns the part of the body to send, or [None] if the response isn't a `206` holding the client's range, in
+    /// which case it is passed on as is.
+    pub fn narrow(&self, status: StatusCode, headers: &mut HeaderMap) -> Option<ClientWindow> {
+        if status != StatusCode::PARTIAL_CONTENT {
+            return None;
+        }
+        let (start, end, size) = content_range(headers)?;
+        let client_end = min(self.end.unwrap_or(end), end);
+        if self.start < start || self.start > client_end {
+            return None;
+        }
+
+        let size = size.map_or_else(|| "*".to_owned(), |size| size.to_string());
+        let content_range = format!("bytes {}-{}/{}", self.start, client_end, size);
+        headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range).ok()?);
+        headers.insert(
+            CONTENT_LENGTH,
+            HeaderValue::from(client_end - self.start + 1),
+        );
+
+        Some(ClientWindow {
+            skip: self.start - start,
+            remaining: client_end - self.start + 1,
+        })
+    }
+}
+
+/// The part of an origin body that is sent to the client, when the origin sends more than the client asked for.
+pub struct ClientWindow {
+    /// Bytes still to drop before the client's range starts.
+    skip: u64,
+
+    /// Bytes of the client's range still to send.
+    remaining: u64,
+}
+
+impl ClientWindow {
+    /// Cuts a chunk of the origin body down to the part of it inside the window.
+    fn clip(&mut self, data: Bytes) -> Bytes {
+        let skip = min(self.skip, data.len() as u64) as usize;
+        self.skip -= skip as u64;
+        let data = data.slice(skip..);
+
+        let len = min(self.remaining, data.len() as u64) as usize;
+        self.remaining -= len as u64;
+        data.slice(..len)
+    }
+}
+
+/// Starts storing an origin response under the cache key, writing its meta file.
+/// `status` and `headers` are the response's, which has to be a `200` with its `Content-Length` or a `206` with its
+/// `Content-Range`. A `206` for the version of the object that is already stored fills in more of its blocks rather
+/// than starting over.
+/// The object's tags come from the configured tag header, and the tag index learns of them once the meta file is
+/// written.
+/// `dio_pool` is the pool to fill blocks in if block files are written with `O_DIRECT`.
+/// Returns [None] if the response can't be stored, including when another fill is writing the object.
+pub async fn start_store(
+    config: &CacheConfig,
+    key: &str,
+    status: StatusCode,
+    headers: &HeaderMap,
+    ttl: Duration,
+    dio_pool: Option<&Rc<AlignedBufPool>>,
+    metrics: Rc<MetricsRecorder>,
+) -> Option<BlockWriter> {
+    let (start, size) = object_span(status, headers)?;
+    let hash = create_object_hash(key);
+    let root = root_for_object(&hash, &config.roots);
+    let exp_ts = unix_now() + ttl.as_secs();
+
+    let locked = match OpenObjectMeta::lock(&meta_file_path(&hash, root)).await {
+        Ok(Some(locked)) => locked,
+        Ok(None) => return None,
+        Err(e) => {
+            println!("failed to store {}: {}", key, e);
+            return None;
+        }
+    };
+
+    let validator = validator(headers);
+    let reuse = locked.existing.as_ref().is_some_and(|existing| {
+        is_same_object(existing, key, size, config.block_size, validator.as_ref())
+    });
+    let meta = if reuse {
+        locked.reuse(exp_ts).await
+    } else {
+        let tags = tags_from_headers(headers, &config.tag_header);
+        let stored_headers = headers
+            .iter()
+            .filter(|(name, _)| !is_recomputed(name))
+            .filter_map(|(name, value)| {
+                Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned()))
+            })
+            .collect();
+        let preamble = ObjectMetaPreamble {
+            exp_ts,
+            size_bytes: size,
+            block_size: config.block_size,
+            key: key.to_owned(),
+            tags: tags.clone(),
+            headers: stored_headers,
+        };
+        let meta = locked.replace(preamble).await;
+        // Whatever was stored before is gone either way.
+        match &meta {
+            Ok(_) => notify(TagIndexUpdate::Stored { hash, tags }),
+            Err(_) => notify(TagIndexUpdate::Removed { hash }),
+        }
+        meta
+    };
+    let meta = match meta {
+        Ok(meta) => meta,
+        Err(e) => {
+            println!("failed to store {}: {}", key, e);
+            return None;
+        }
+    };
+
+    let mut writer = BlockWriter::new(
+        key.to_owned(),
+        root.to_owned(),
+        config.block_size as u64,
+        size,
+        meta,
+        dio_pool,
+        metrics,
+    );
+    writer.seek(start);
+    Some(writer)
+}
+
+/// Takes over filling in a partially cached object, for the blocks a partial hit fetches.
+/// Returns [None] if another fill is writing the object, or it was replaced since the partial hit looked it up.
+async fn resume_store(hit: &PartialHit, metrics: Rc<MetricsRecorder>) -> Option<BlockWriter> {
+    let locked = match OpenObjectMeta::lock(&hit.path).await {
+        Ok(Some(locked)) => locked,
+        Ok(None) => return None,
+        Err(e) => {
+            println!("failed to store {}: {}", hit.key, e);
+            return None;
+        }
+    };
+    let existing = locked.existing.as_ref().filter(|existing| {
+        is_same_object(
+            existing,
+            &hit.key,
+            hit.size,
+            hit.block_size as u32,
+            Some(&hit.validator),
+        )
+    })?;
+
+    let exp_ts = existing.preamble.exp_ts;
+    match locked.reuse(exp_ts).await {
+        Ok(meta) => Some(BlockWriter::new(
+            hit.key.clone(),
+            hit.root.clone(),
+            hit.block_size,
+            hit.size,
+            meta,
+            hit.dio_pool.as_ref(),
+            metrics,
+        )),
+        Err(e) => {
+            println!("failed to store {}: {}", hit.key, e);
+            None
+        }
+    }
+}
+
+/// The buffer a block is filled in before it is written.
+enum BlockBuf {
+    Buffered(BytesMut),
+
+    /// For block files written with `O_DIRECT`, along with the pool it goes back to.
+    Aligned(AlignedBuf, Rc<AlignedBufPool>),
+}
+
+impl BlockBuf {
+    fn len(&self) -> usize {
+        match self {
+            BlockBuf::Buffered(buf) => buf.len(),
+            BlockBuf::Aligned(buf, _) => buf.as_slice().len(),
+        }
+    }
+
+    fn extend_from_slice(&mut self, data: &[u8]) {
+        match self {
+            BlockBuf::Buffered(buf) => buf.extend_from_slice(data),
+            BlockBuf::Aligned(buf, _) => buf.extend_from_slice(data),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            BlockBuf::Buffered(buf) => buf.clear(),
+            BlockBuf::Aligned(buf, _) => buf.clear(),
+        }
+    }
+}
+
+/// Writes an object's blocks as its body comes in.
+/// Holds the object's fill lock until it is finished or dropped.
+pub struct BlockWriter {
+    key: String,
+    root: PathBuf,
+    block_size: u64,
+    size: u64,
+    meta: OpenObjectMeta,
+
+    /// The object byte the next body byte is.
+    pos: u64,
+
+    /// The block being filled, from its first byte. It stays empty for the rest of a block the body started partway
+    /// into, since that block can't be written whole.
+    buf: BlockBuf,
+
+    metrics: Rc<MetricsRecorder>,
+}
+
+impl BlockWriter {
+    fn new(
+        key: String,
+        root: PathBuf,
+        block_size: u64,
+        size: u64,
+        meta: OpenObjectMeta,
+        dio_pool: Option<&Rc<AlignedBufPool>>,
+        metrics: Rc<MetricsRecorder>,
+    ) -> Self {
+        Self {
+            key,
+            root,
+            block_size,
+            size,
+            meta,
+            pos: 0,
+            buf: match dio_pool {
+                Some(pool) => BlockBuf::Aligned(pool.take(), pool.clone()),
+                None => BlockBuf::Buffered(BytesMut::with_capacity(block_size as usize)),
+            },
+            metrics,
+        }
+    }
+
+    /// Moves the writer to the object byte the next body starts at, dropping any block left partly filled.
+    fn seek(&mut self, pos: u64) {
+        self.pos = pos;
+        self.buf.clear();
+    }
+
+    async fn push(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if self.pos + data.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "origin body runs past the end of the object",
+            ));
+        }
+
+        while !data.is_empty() {
+            let block_num = self.pos / self.block_size;
+            let offset = self.pos % self.block_size;
+            // The last block is short.
+            let block_len = min(self.block_size, self.size - block_num * self.block_size);
+
+            let n = min(block_len - offset, data.len() as u64) as usize;
+            if self.buf.len() as u64 == offset {
+                self.buf.extend_from_slice(&data[..n]);
+            }
+            data = &data[n..];
+            self.pos += n as u64;
+
+            if offset + n as u64 == block_len {
+                if self.buf.len() as u64 == block_len {
+                    self.write_block(block_num).await?;
+                }
+                self.buf.clear();
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_block(&mut self, block_num: u64) -> io::Result<()> {
+        // A partial hit refetches short runs of cached blocks along with the gaps around them.
+        if self.meta.is_covered(block_num) {
+            return Ok(());
+        }
+        if self.meta.is_unlinked()? {
+            return Err(purged());
+        }
+
+        let hash = create_file_block_hash(
+            &self.key,
+            FileBlockInfo {
+                block_size: self.block_size as u32,
+                block_num,
+            },
+        );
+        let path = block_file_path(&hash, &self.root);
+        let len = self.buf.len();
+        let buf = std::mem::replace(&mut self.buf, BlockBuf::Buffered(BytesMut::new()));
+        let (file, buf, replaced) = match buf {
+            BlockBuf::Buffered(buf) => {
+                let (file, replaced) = create_block_file(hash, &self.root, false).await?;
+                let (res, mut buf) = file.write_all_at(buf, 0).await;
+                res?;
+                buf.clear();
+                (file, BlockBuf::Buffered(buf), replaced)
+            }
+            BlockBuf::Aligned(mut buf, pool) => {
+                let (file, replaced) = create_block_file(hash, &self.root, true).await?;
+                // Only whole sectors can be written, so a short last block is padded out and then cut back.
+                let padded = buf.pad_to_alignment();
+                let (res, mut buf) = file.write_all_at(buf, 0).await;
+                res?;
+                if padded != len {
+                    truncate(&file, len as u64)?;
+                }
+                buf.clear();
+                (file, BlockBuf::Aligned(buf, pool), replaced)
+            }
+        };
+        self.buf = buf;
+
+        // A purge that deleted the meta file while the block was written may have missed it.
+        if self.meta.is_unlinked()? {
+            if is_same_file(&file, &path) {
+                monoio::fs::remove_file(&path).await?;
+            }
+            file.close().await?;
+            return Err(purged());
+        }
+        file.close().await?;
+
+        self.metrics.record_block_write();
+        // A replaced block file was already counted, and held the same part of the same object.
+        if !replaced {
+            record_cache_usage(&self.root, len as i64);
+        }
+
+        self.meta.mark_block_covered(block_num).await
+    }
+
+    /// Finishes filling the object once its body has ended, releasing its fill lock.
+    async fn finish(self) -> io::Result<()> {
+        if let BlockBuf::Aligned(buf, pool) = self.buf {
+            pool.give(buf);
+        }
+        self.meta.close().await
+    }
+}
+
+fn purged() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "object was purged")
+}
+
+fn truncate(file: &monoio::fs::File, len: u64) -> io::Result<()> {
+    if unsafe { libc::ftruncate(file.as_raw_fd(), len as libc::off_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns whether the path still names the open file, rather than one created in its place.
+fn is_same_file(file: &monoio::fs::File, path: &Path) -> bool {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(file.as_raw_fd(), &mut stat) } != 0 {
+        return false;
+    }
+    std::fs::metadata(path)
+        .is_ok_and(|m| m.dev() == stat.st_dev as u64 && m.ino() == stat.st_ino as u64)
+}
+
+/// Creates a block file, replacing one left from an earlier copy of the object or a fill that broke off before
+/// marking it covered. Only the fill holding the object's lock writes its blocks, so nothing else is writing the
+/// file being replaced. Returns the file and whether one was replaced.
+async fn create_block_file(
+    hash: FileBlockHash,
+    root: &Path,
+    direct_io: bool,
+) -> io::Result<(monoio::fs::File, bool)> {
+    match create_and_open_block_file(hash.clone(), root, direct_io).await {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            monoio::fs::remove_file(block_file_path(&hash, root)).await?;
+            let file = create_and_open_block_file(hash, root, direct_io).await?;
+            Ok((file, true))
+        }
+        res => res.map(|file| (file, false)),
+    }
+}
+
+/// An origin response body that is stored in the cache as the client reads it, and narrowed down to the client's
+/// range if the origin was asked for more.
+/// Blocks are written before the chunk that completes them is passed on, so the disk sets the pace along with the
+/// client. If storing fails, the body keeps flowing and the object is left with gaps.
+pub struct StoringBody {
+    inner: OriginBody,
+    writer: Option<BlockWriter>,
+    window: Option<ClientWindow>,
+}
+
+impl StoringBody {
+    pub fn new(
+        inner: OriginBody,
+        writer: Option<BlockWriter>,
+        window: Option<ClientWindow>,
+    ) -> Self {
+        Self {
+            inner,
+            writer,
+            window,
+        }
+    }
+
+    pub async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        loop {
+            // Once the client has its range, the rest is only read to finish the last block.
+            let sent = self.window.as_ref().is_some_and(|w| w.remaining == 0);
+            if sent && self.writer.is_none() {
+                return None;
+            }
+
+            let data = self.inner.next_data().await;
+            if let Some(writer) = &mut self.writer {
+                match &data {
+                    Some(Ok(chunk)) => {
+                        if let Err(e) = writer.push(chunk).await {
+                            println!("failed to store {}: {}", writer.key, e);
+                            self.writer = None;
+                        }
+                    }
+                    // The fill broke off, so the rest of the object can't be stored.
+                    Some(Err(_)) => self.writer = None,
+                    None => {
+                        let writer = self.writer.take().unwrap();
+                        let key = writer.key.clone();
+                        if let Err(e) = writer.finish().await {
+                            println!("failed to store {}: {}", key, e);
+                        }
+                    }
+                }
+            }
+
+            let Some(window) = &mut self.window else {
+                return data;
+            };
+            match data {
+                Some(Ok(chunk)) => {
+                    let len = chunk.len();
+                    let chunk = window.clip(chunk);
+                    self.inner
+                        .metrics()
+                        .record_readahead_bytes((len - chunk.len()) as u64);
+                    if !chunk.is_empty() {
+                        return Some(Ok(chunk));
+                    }
+                }
+                // The client already has everything it asked for.
+                Some(Err(_)) | None if sent => return None,
+                data => return data,
+            }
+        }
+    }
+}
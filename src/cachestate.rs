@@ -1,14 +1,21 @@
-use crate::cachestate::ObjectMetaVersion::V0;
+use crate::cachestate::ObjectMetaVersion::{V0, V1, V2};
 use crate::constant::MAX_COVERAGE_BLOCK_SKIP_SIZE;
-use crate::hash::FileBlockHash;
+use crate::hash::{FileBlockHash, ObjectHash};
 use monoio::buf::IoBufMut;
-use std::cmp::{max, min};
+use std::cmp::min;
 use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
-use std::path::Path;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
 #[repr(u8)]
-enum ObjectMetaVersion {
+pub enum ObjectMetaVersion {
     V0,
+
+    /// Adds the object's cache key.
+    V1,
+
+    /// Adds the object's surrogate keys (cache tags).
+    V2,
 }
 
 impl TryFrom<u8> for ObjectMetaVersion {
@@ -16,17 +23,32 @@ impl TryFrom<u8> for ObjectMetaVersion {
     fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
             0 => Ok(V0),
+            1 => Ok(V1),
+            2 => Ok(V2),
             _ => Err(()),
         }
     }
 }
 
-const OBJECT_META_SERIAL_VER: ObjectMetaVersion = V0;
+const OBJECT_META_SERIAL_VER: ObjectMetaVersion = V2;
+
+/// The byte offset of `exp_ts` within a serialized meta file.
+/// It directly follows the version byte in every version, so it can be rewritten in place.
+pub const OBJECT_META_EXP_TS_OFFSET: u64 = 1;
 
 pub struct ObjectMetaPreamble {
     pub exp_ts: u64,
     pub size_bytes: u64,
     pub block_size: u32,
+
+    /// The cache key the object was stored under (the full request URL).
+    /// Empty for objects stored with [ObjectMetaVersion::V0].
+    pub key: String,
+
+    /// The surrogate keys (cache tags) the origin assigned to the object.
+    /// Empty for objects stored before [ObjectMetaVersion::V2].
+    pub tags: Vec<String>,
+
     pub headers: Vec<(String, String)>,
     // TODO Store ETag as its own Option field?
     // We have to invalidate the cache if the ETag changes.
@@ -35,7 +57,7 @@ pub struct ObjectMetaPreamble {
 }
 
 /// A loaded cache block coverage map.
-struct LoadedCoverageMap(pub Vec<bool>);
+pub(crate) struct LoadedCoverageMap(pub Vec<bool>);
 
 impl LoadedCoverageMap {
     /// Returns whether the block at the following index is covered.
@@ -58,6 +80,33 @@ pub struct ObjectMeta {
     pub coverage_map: LoadedCoverageMap,
 }
 
+/// Reads a u16 length-prefixed UTF-8 string at the offset, advancing the offset past it.
+fn read_prefixed_str(buf: &[u8], offset: &mut usize, what: &str) -> Result<String, String> {
+    if buf.len() < *offset + 2 {
+        return Err(format!("buffer ended unexpectedly in {} length", what));
+    }
+    let len = u16::from_le_bytes(buf[*offset..*offset + 2].try_into().unwrap()) as usize;
+    *offset += 2;
+
+    if buf.len() < *offset + len {
+        return Err(format!("buffer ended unexpectedly in {}", what));
+    }
+    let value = String::from_utf8(buf[*offset..*offset + len].to_vec())
+        .map_err(|_| format!("invalid utf8 in {}", what))?;
+    *offset += len;
+
+    Ok(value)
+}
+
+/// Writes a u16 length-prefixed string.
+fn write_prefixed_str(vec: &mut Vec<u8>, value: &str) {
+    let bytes = value.as_bytes();
+
+    // u16 length prefix (little endian)
+    vec.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    vec.extend_from_slice(bytes);
+}
+
 impl ObjectMeta {
     pub fn from_bytes(buf: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
         let (preamble, _, offset) = Self::deserialize_preamble(buf)?;
@@ -76,11 +125,17 @@ impl ObjectMeta {
         Self::from_bytes(&file).map_err(|e| e.into())
     }
 
-    fn deserialize_preamble_v1(buf: &[u8]) -> Result<(ObjectMetaPreamble, usize), String> {
+    /// Deserializes the preamble fields following the version byte.
+    /// `has_key` and `has_tags` are whether the version includes the cache key and tags.
+    fn deserialize_preamble_fields(
+        buf: &[u8],
+        has_key: bool,
+        has_tags: bool,
+    ) -> Result<(ObjectMetaPreamble, usize), String> {
         let mut offset: usize = 0;
 
         // exp_ts
-        if buf.len() < (offset + 8) as usize {
+        if buf.len() < offset + 8 {
             return Err("buffer too small for exp_ts".into());
         }
         let exp_ts = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
@@ -100,6 +155,29 @@ impl ObjectMeta {
         let block_size = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
         offset += 4;
 
+        // key
+        let key = if has_key {
+            read_prefixed_str(buf, &mut offset, "key")?
+        } else {
+            String::new()
+        };
+
+        // tags
+        let mut tags = Vec::new();
+        if has_tags {
+            if buf.len() < offset + 2 {
+                return Err("buffer too small for tags count".into());
+            }
+            let tags_count =
+                u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
+            offset += 2;
+
+            tags.reserve(tags_count);
+            for _ in 0..tags_count {
+                tags.push(read_prefixed_str(buf, &mut offset, "tag")?);
+            }
+        }
+
         // headers count
         if buf.len() < offset + 2 {
             return Err("buffer too small for headers count".into());
@@ -109,37 +187,11 @@ impl ObjectMeta {
         offset += 2;
 
         // headers
+        // The coverage map directly follows the headers, so only read as many as were written.
         let mut headers = Vec::with_capacity(headers_count);
-
-        while offset < buf.len() {
-            // Check if there's enough for a u16 length
-            if buf.len() < offset + 2 {
-                break; // stop before coverage_map
-            }
-            let name_len = u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
-            offset += 2;
-
-            if buf.len() < offset + name_len {
-                break;
-            }
-            let name = String::from_utf8(buf[offset..offset + name_len].to_vec())
-                .map_err(|_| "invalid utf8 in header name")?;
-            offset += name_len;
-
-            if buf.len() < offset + 2 {
-                return Err("buffer ended unexpectedly in header value length".into());
-            }
-            let value_len =
-                u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap()) as usize;
-            offset += 2;
-
-            if buf.len() < offset + value_len {
-                return Err("buffer ended unexpectedly in header value".into());
-            }
-            let value = String::from_utf8(buf[offset..offset + value_len].to_vec())
-                .map_err(|_| "invalid utf8 in header value")?;
-            offset += value_len;
-
+        for _ in 0..headers_count {
+            let name = read_prefixed_str(buf, &mut offset, "header name")?;
+            let value = read_prefixed_str(buf, &mut offset, "header value")?;
             headers.push((name, value));
         }
 
@@ -148,12 +200,16 @@ impl ObjectMeta {
                 exp_ts,
                 size_bytes,
                 block_size,
+                key,
+                tags,
                 headers,
             },
             offset, // where coverage_map begins
         ))
     }
 
+    /// Deserializes the preamble of a meta file.
+    /// Returns the preamble, its version, and the offset within the buffer where the coverage map begins.
     pub fn deserialize_preamble(
         buf: &[u8],
     ) -> Result<(ObjectMetaPreamble, ObjectMetaVersion, usize), String> {
@@ -169,27 +225,39 @@ impl ObjectMeta {
 
         offset += 1;
 
-        match version {
-            V0 => {
-                let (preamble, offset) = Self::deserialize_preamble_v1(&buf[offset..])?;
+        let (has_key, has_tags) = match version {
+            V0 => (false, false),
+            V1 => (true, false),
+            V2 => (true, true),
+        };
+        let (preamble, fields_len) =
+            Self::deserialize_preamble_fields(&buf[offset..], has_key, has_tags)?;
 
-                Ok((preamble, version, offset))
-            }
-        }
+        Ok((preamble, version, offset + fields_len))
     }
 
-    pub fn serialize_preamble(self) -> Vec<u8> {
+    pub fn serialize_preamble(&self) -> Vec<u8> {
         const VER_LEN: usize = size_of::<ObjectMetaVersion>();
         const EXP_TS_LEN: usize = size_of::<u64>();
         const SIZE_BYTES_LEN: usize = size_of::<u64>();
         const BLOCK_SIZE_LEN: usize = size_of::<u32>();
+        const TAGS_COUNT_LEN: usize = size_of::<u16>();
         const HEADERS_COUNT_LEN: usize = size_of::<u16>();
-
-        let mut serial_len =
-            VER_LEN + EXP_TS_LEN + SIZE_BYTES_LEN + BLOCK_SIZE_LEN + HEADERS_COUNT_LEN;
-
         const STR_PREFIX_LEN: usize = size_of::<u16>();
 
+        let mut serial_len = VER_LEN
+            + EXP_TS_LEN
+            + SIZE_BYTES_LEN
+            + BLOCK_SIZE_LEN
+            + STR_PREFIX_LEN
+            + self.preamble.key.len()
+            + TAGS_COUNT_LEN
+            + HEADERS_COUNT_LEN;
+
+        for tag in &self.preamble.tags {
+            serial_len += STR_PREFIX_LEN + tag.len();
+        }
+
         let mut headers_count: u16 = 0;
         for (name, value) in &self.preamble.headers {
             serial_len += STR_PREFIX_LEN + name.len() + STR_PREFIX_LEN + value.len();
@@ -210,20 +278,22 @@ impl ObjectMeta {
         // block_size
         vec.extend_from_slice(&self.preamble.block_size.to_le_bytes());
 
+        // key
+        write_prefixed_str(&mut vec, &self.preamble.key);
+
+        // tags
+        vec.extend_from_slice(&(self.preamble.tags.len() as u16).to_le_bytes());
+        for tag in &self.preamble.tags {
+            write_prefixed_str(&mut vec, tag);
+        }
+
         // headers count
         vec.extend_from_slice(&headers_count.to_le_bytes());
 
         // headers
         for (name, value) in &self.preamble.headers {
-            let name_bytes = name.as_bytes();
-            let value_bytes = value.as_bytes();
-
-            // u16 length prefix (little endian)
-            vec.extend_from_slice(&(name_bytes.len() as u16).to_le_bytes());
-            vec.extend_from_slice(name_bytes);
-
-            vec.extend_from_slice(&(value_bytes.len() as u16).to_le_bytes());
-            vec.extend_from_slice(value_bytes);
+            write_prefixed_str(&mut vec, name);
+            write_prefixed_str(&mut vec, value);
         }
 
         debug_assert_eq!(vec.len(), serial_len);
@@ -231,79 +301,245 @@ impl ObjectMeta {
     }
 }
 
-struct OpenObjectMeta {
+/// An object's meta file, opened to fill its blocks in.
+/// Holds the object's fill lock, so no other fill writes the object's blocks until it is closed.
+pub(crate) struct OpenObjectMeta {
     meta: ObjectMeta,
     file: monoio::fs::File,
     coverage_map_offset: u64,
 }
 
+/// An object's meta file, locked for a fill that hasn't decided yet whether to keep the object in it.
+pub(crate) struct LockedObjectMeta {
+    /// The object already in the meta file, if there is a readable one.
+    pub existing: Option<ObjectMeta>,
+
+    file: monoio::fs::File,
+}
+
 impl OpenObjectMeta {
-    async fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let file = monoio::fs::File::open(path).await?;
+    /// Opens an object's meta file to fill blocks in, creating it if there isn't one, and takes the object's fill lock.
+    /// Returns [None] if another fill (on any thread or process) holds the lock.
+    /// The lock is an `flock` on the meta file, so it goes away with the file descriptor, even if the process dies.
+    pub(crate) async fn lock(path: &Path) -> Result<Option<LockedObjectMeta>, std::io::Error> {
+        let containing_dir = path.parent().expect("BUG: meta file path has no parent");
+        monoio::fs::DirBuilder::new()
+            .recursive(true)
+            .create(containing_dir)
+            .await?;
+
+        let file = monoio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .await?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(e);
+        }
 
         let sys_file = unsafe { std::fs::File::from_raw_fd(file.as_raw_fd()) };
-        let size = sys_file.metadata()?.len() as usize;
+        let size = sys_file.metadata().map(|m| m.len() as usize);
         let _ = sys_file.into_raw_fd();
+        let size = size?;
+
+        let existing = if size > 0 {
+            let (res, buf) = file
+                .read_exact_at(Vec::with_capacity(size).slice_mut(0..size), 0)
+                .await;
+            res?;
+            ObjectMeta::from_bytes(&buf.into_inner()).ok()
+        } else {
+            None
+        };
 
-        let (res, buf) = file
-            .read_exact_at(Vec::with_capacity(size).slice_mut(0..size), 0)
-            .await;
-        res?;
+        Ok(Some(LockedObjectMeta { existing, file }))
+    }
 
-        let meta = ObjectMeta::from_bytes(&buf)?;
-        let offset = meta.coverage_map_offset;
+    /// Returns whether the block is covered.
+    pub(crate) fn is_covered(&self, block_num: u64) -> bool {
+        self.meta.coverage_map.0.get(block_num as usize) == Some(&true)
+    }
 
-        Ok(Self {
-            meta,
-            file,
-            coverage_map_offset: offset,
-        })
+    /// Returns whether the meta file was deleted since it was opened, as a hard purge does.
+    /// Blocks written after that would be left behind with nothing pointing at them.
+    pub(crate) fn is_unlinked(&self) -> Result<bool, std::io::Error> {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(self.file.as_raw_fd(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(stat.st_nlink == 0)
     }
 
-    async fn mark_block_covered(mut self, block_num: u64) -> Result<(), std::io::Error> {
+    pub(crate) async fn mark_block_covered(
+        &mut self,
+        block_num: u64,
+    ) -> Result<(), std::io::Error> {
         let byte_idx = self.coverage_map_offset + block_num;
-        self.file.write_at(&[1], byte_idx).await.0.map(|_| ())?;
+        self.file.write_all_at(vec![1u8], byte_idx).await.0?;
         self.meta.coverage_map.mark_covered(block_num as u64);
         Ok(())
     }
 
-    async fn close(self) -> Result<(), std::io::Error> {
+    /// Closes the meta file, releasing the fill lock.
+    pub(crate) async fn close(self) -> Result<(), std::io::Error> {
         self.file.close().await
     }
 }
 
+impl LockedObjectMeta {
+    /// Keeps filling the object already in the meta file, with its expiry moved to `exp_ts`.
+    /// Panics if there is no readable object in it.
+    pub(crate) async fn reuse(self, exp_ts: u64) -> Result<OpenObjectMeta, std::io::Error> {
+        let mut meta = self
+            .existing
+            .expect("BUG: reusing a meta file with no object in it");
+        self.file
+            .write_all_at(exp_ts.to_le_bytes().to_vec(), OBJECT_META_EXP_TS_OFFSET)
+            .await
+            .0?;
+        meta.preamble.exp_ts = exp_ts;
+
+        let offset = meta.coverage_map_offset;
+        Ok(OpenObjectMeta {
+            meta,
+            file: self.file,
+            coverage_map_offset: offset,
+        })
+    }
+
+    /// Replaces whatever is in the meta file with a new object, with none of its blocks covered yet.
+    pub(crate) async fn replace(
+        self,
+        preamble: ObjectMetaPreamble,
+    ) -> Result<OpenObjectMeta, std::io::Error> {
+        let block_count = preamble.size_bytes.div_ceil(preamble.block_size as u64) as usize;
+        let mut meta = ObjectMeta {
+            preamble,
+            coverage_map_offset: 0,
+            coverage_map: LoadedCoverageMap(vec![false; block_count]),
+        };
+        let mut buf = meta.serialize_preamble();
+        meta.coverage_map_offset = buf.len() as u64;
+        buf.resize(buf.len() + block_count, 0);
+
+        if unsafe { libc::ftruncate(self.file.as_raw_fd(), 0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.file.write_all_at(buf, 0).await.0?;
+
+        let offset = meta.coverage_map_offset;
+        Ok(OpenObjectMeta {
+            meta,
+            file: self.file,
+            coverage_map_offset: offset,
+        })
+    }
+}
+
+/// Rewrites the expiry of the object in a meta file, in place.
+pub async fn set_exp_ts(path: &Path, exp_ts: u64) -> Result<(), std::io::Error> {
+    let file = monoio::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await?;
+    file.write_all_at(exp_ts.to_le_bytes().to_vec(), OBJECT_META_EXP_TS_OFFSET)
+        .await
+        .0?;
+    file.close().await
+}
+
+/// Returns the directory under the cache root that files for the specified hash are stored in.
+/// The first two pairs of hex digits are used as nested directory names to keep directories small.
+fn hash_dir(hash: &str, cache_root: &Path) -> PathBuf {
+    let seg1 = hash.get(..2).expect("BUG: hash is too short");
+    let seg2 = hash.get(2..4).expect("BUG: hash is too short");
+
+    cache_root.join(seg1).join(seg2)
+}
+
+/// Returns the path of the block file for the specified hash under the specified cache root.
+/// The file may or may not exist.
+pub fn block_file_path(hash: &FileBlockHash, cache_root: &Path) -> PathBuf {
+    hash_dir(hash, cache_root).join(hash)
+}
+
+/// Returns the path of the meta file for the specified object under the specified cache root.
+/// The file may or may not exist.
+pub fn meta_file_path(hash: &ObjectHash, cache_root: &Path) -> PathBuf {
+    let mut filename = String::with_capacity(hash.len() + META_FILE_SUFFIX.len());
+    filename.push_str(hash);
+    filename.push_str(META_FILE_SUFFIX);
+
+    hash_dir(hash, cache_root).join(filename)
+}
+
+/// The filename suffix of meta files.
+pub const META_FILE_SUFFIX: &str = ".meta";
+
+/// Returns the cache root the specified object is stored under.
+/// Objects are spread across roots by hash so each object always lives on exactly one root.
+pub fn root_for_object<'a>(hash: &ObjectHash, cache_roots: &'a [PathBuf]) -> &'a Path {
+    let idx = u64::from_str_radix(hash.get(..8).expect("BUG: hash is too short"), 16)
+        .expect("BUG: hash is not hex");
+
+    &cache_roots[(idx % cache_roots.len() as u64) as usize]
+}
+
+/// Opens an existing block file for reading.
+/// If `direct_io` is true, the file is opened with `O_DIRECT` and must be read with aligned buffers.
+pub async fn open_block_file(
+    hash: &FileBlockHash,
+    cache_root: &Path,
+    direct_io: bool,
+) -> Result<monoio::fs::File, std::io::Error> {
+    let mut opts = monoio::fs::OpenOptions::new();
+    opts.read(true);
+    if direct_io {
+        opts.custom_flags(libc::O_DIRECT);
+    }
+
+    opts.open(block_file_path(hash, cache_root)).await
+}
+
 /// Creates and opens a block file for writing.
 /// The file will be atomically created. An error will be returned if the file already exists.
-async fn create_and_open_block_file(
+/// If `direct_io` is true, the file is opened with `O_DIRECT` and must be written with aligned buffers.
+pub(crate) async fn create_and_open_block_file(
     hash: FileBlockHash,
     cache_root: &Path,
+    direct_io: bool,
 ) -> Result<monoio::fs::File, std::io::Error> {
-    let seg1 = hash.get(..2).expect("BUG: hash is too short");
-    let seg2 = hash.get(2..4).expect("BUG: hash is too short");
-    let filename = hash.get(4..6).expect("BUG: hash is too short");
+    let file_path = block_file_path(&hash, cache_root);
 
     // Try to create directories.
-    let containing_dir = cache_root.join(seg1).join(seg2);
+    let containing_dir = file_path
+        .parent()
+        .expect("BUG: block file path has no parent");
     monoio::fs::DirBuilder::new()
         .recursive(true)
-        .create(&containing_dir)
+        .create(containing_dir)
         .await?;
 
-    let file_path = containing_dir.join(filename);
+    let mut opts = monoio::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    if direct_io {
+        opts.custom_flags(libc::O_DIRECT);
+    }
 
-    let file = monoio::fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(file_path)
-        .await?;
+    let file = opts.open(file_path).await?;
 
     Ok(file)
 }
 
 /// The kind of file read step.
-enum FileReadPlanStepKind {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FileReadPlanStepKind {
     /// Read cached blocks.
-    /// The starting and ending block numbers are to be read from.
     CACHE,
 
     /// Read from origin.
@@ -319,31 +555,38 @@ enum FileReadPlanStepKind {
 /// A file read plan step.
 /// Steps instruct the caller on how to read data from the file.
 /// For example, a step may be a cache read, then the next step might be an origin read.
-struct FileReadPlanStep {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileReadPlanStep {
     /// The step kind.
     pub kind: FileReadPlanStepKind,
 
-    /// The relevant starting block number (inclusive).
+    /// The first block number the step reads (inclusive).
     pub block_start_num: u64,
 
-    /// The relevant ending block number (inclusive).
+    /// The block number the step ends before (exclusive).
     pub block_end_num: u64,
 
-    /// The offset within the returned data to start returning data to the client.
+    /// The offset within the step's data, which starts at its first block, to start returning data to the client.
     /// This only applies to the response to return to the client, not the origin request or cache block writes.
-    client_start_offset: u64,
+    pub client_start_offset: u64,
 
-    /// The offset within the returned data to stop returning data to the client.
+    /// The offset within the step's data to stop returning data to the client (exclusive).
     /// This only applies to the response to return to the client, not the origin request or cache block writes.
-    client_end_offset: u64,
+    pub client_end_offset: u64,
+}
+
+impl FileReadPlanStep {
+    /// The number of bytes the step returns to the client.
+    pub fn client_len(&self) -> u64 {
+        self.client_end_offset - self.client_start_offset
+    }
 }
 
 /// The struct that manages the read plan for a file.
 /// Each iteration instructs the caller on what type of read to make.
 /// For example, if it can read from the cache for the first 10 blocks, then the first iteration will return a step that reads 10 blocks,
 /// then if the next 5 blocks need to be read from origin, the second iteration will return a step that reads 5 blocks from origin.
-struct FileReadPlan {
-    start_byte: u64,
+pub(crate) struct FileReadPlan {
     end_byte: u64,
     file_size: u64,
     block_size: u64,
@@ -353,7 +596,7 @@ struct FileReadPlan {
 }
 
 impl FileReadPlan {
-    /// Creates a new FileReadPlan for the specified range and parameters.
+    /// Creates a new FileReadPlan for the bytes from the start to the end (inclusive) of a file.
     pub fn new(
         start_byte: u64,
         end_byte: u64,
@@ -362,105 +605,74 @@ impl FileReadPlan {
         coverage_map: LoadedCoverageMap,
     ) -> Self {
         Self {
-            start_byte,
-            end_byte: min(end_byte, file_size),
+            end_byte: min(end_byte, file_size.saturating_sub(1)),
             file_size,
             block_size,
             coverage_map,
             cur_byte: start_byte,
         }
     }
+
+    fn is_covered(&self, block_num: u64) -> bool {
+        self.coverage_map.0.get(block_num as usize) == Some(&true)
+    }
 }
 
 impl Iterator for FileReadPlan {
     type Item = FileReadPlanStep;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur_byte >= self.end_byte - 1 {
+        if self.cur_byte > self.end_byte || self.file_size == 0 {
             return None;
         }
 
         let start_block = self.cur_byte / self.block_size;
-        let max_block = max(
-            self.end_byte / self.block_size,
-            (self.coverage_map.0.len() - 1) as u64,
-        );
+        let last_block = self.end_byte / self.block_size;
 
-        // Bytes pulled from disk and origin operate on fixed-sized blocks.
-        // However, client requests may not align with block boundaries.
-        // These values store the offsets to send to the client based on the original range request.
-        let client_start_offset: u64;
-        let client_end_offset: u64;
-        if self.cur_byte == self.start_byte {
-            client_start_offset = self.start_byte % self.block_size;
-        } else {
-            client_start_offset = 0;
-        }
-        if self.end_byte - self.cur_byte < self.block_size {
-            client_end_offset = self.end_byte % self.block_size;
-        } else {
-            client_end_offset = self.block_size - 1;
-        }
-
-        let res: FileReadPlanStep;
-        let first_block_covered = self.coverage_map.0[start_block as usize];
-
-        if first_block_covered {
+        let mut end_block = start_block + 1;
+        let kind = if self.is_covered(start_block) {
             // Figure out how many cached blocks we can read consecutively.
-
-            let mut last_covered = start_block;
-            while last_covered <= max_block {
-                let is_covered = self.coverage_map.0[last_covered as usize];
-                if !is_covered {
-                    break;
-                }
-
-                last_covered += 1;
+            while end_block <= last_block && self.is_covered(end_block) {
+                end_block += 1;
             }
-
-            res = FileReadPlanStep {
-                kind: FileReadPlanStepKind::CACHE,
-                block_start_num: start_block,
-                block_end_num: last_covered,
-                client_start_offset: client_start_offset,
-                client_end_offset: client_end_offset,
-            };
+            FileReadPlanStepKind::CACHE
         } else {
-            // Figure out how many blocks we need to fetch from origin.
-
-            let mut last_uncovered = start_block;
-            let mut skipped: u64 = 0;
-            while last_uncovered <= max_block {
-                let is_covered = self.coverage_map.0[last_uncovered as usize];
-                if is_covered {
-                    skipped += 1;
-
-                    if skipped * self.block_size > MAX_COVERAGE_BLOCK_SKIP_SIZE {
-                        // Break if it's over the acceptable coverage skip size.
-                        last_uncovered -= skipped;
-                        break;
-                    }
-
-                    last_uncovered += 1;
-                } else {
-                    skipped = 0;
-                    last_uncovered += 1;
+            // Figure out how many blocks we need to fetch from origin, fetching short runs of cached blocks along with
+            // the gaps around them.
+            loop {
+                while end_block <= last_block && !self.is_covered(end_block) {
+                    end_block += 1;
+                }
+                let mut next_gap = end_block;
+                while next_gap <= last_block && self.is_covered(next_gap) {
+                    next_gap += 1;
                 }
+                if next_gap > last_block
+                    || (next_gap - end_block) * self.block_size > MAX_COVERAGE_BLOCK_SKIP_SIZE
+                {
+                    break;
+                }
+                end_block = next_gap;
             }
+            FileReadPlanStepKind::ORIGIN {
+                byte_start: start_block * self.block_size,
+                byte_end: min(end_block * self.block_size, self.file_size) - 1,
+            }
+        };
 
-            res = FileReadPlanStep {
-                kind: FileReadPlanStepKind::ORIGIN {
-                    byte_start: start_block * self.block_size,
-                    byte_end: max(last_uncovered * self.block_size, self.file_size),
-                },
-                block_start_num: start_block,
-                block_end_num: last_uncovered,
-                client_start_offset: client_start_offset,
-                client_end_offset: client_end_offset,
-            };
-        }
-
-        self.cur_byte = res.block_end_num * self.block_size;
+        // Bytes pulled from disk and origin operate on fixed-sized blocks.
+        // However, client requests may not align with block boundaries.
+        // These values store the offsets to send to the client based on the original range request.
+        let step_start = start_block * self.block_size;
+        let res = FileReadPlanStep {
+            kind,
+            block_start_num: start_block,
+            block_end_num: end_block,
+            client_start_offset: self.cur_byte - step_start,
+            client_end_offset: min(end_block * self.block_size, self.end_byte + 1) - step_start,
+        };
+
+        self.cur_byte = end_block * self.block_size;
 
         Some(res)
     }
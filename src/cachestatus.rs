@@ -0,0 +1,249 @@
+use crate::cachestate::ObjectMetaPreamble;
+use crate::metrics::CacheResult;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The cache name used in `Cache-Status` headers.
+pub const CACHE_STATUS_NAME: &str = "stavka";
+
+/// Parses an IMF-fixdate HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+/// The obsolete RFC 850 and asctime formats are not accepted.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let s = s.trim();
+    let (_, rest) = s.split_once(", ")?;
+    let mut parts = rest.split(' ');
+
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = MONTHS.iter().position(|&m| m == parts.next()?)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+
+    let mut hms = parts.next()?.split(':');
+    let hour: u64 = hms.next()?.parse().ok()?;
+    let minute: u64 = hms.next()?.parse().ok()?;
+    let second: u64 = hms.next()?.parse().ok()?;
+
+    if parts.next()? != "GMT" || day == 0 || day > 31 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Howard Hinnant's days-from-civil algorithm.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    if days < 0 {
+        return None;
+    }
+
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Returns the value of the first stored header with the specified name.
+fn stored_header<'a>(preamble: &'a ObjectMetaPreamble, name: &str) -> Option<&'a str> {
+    preamble
+        .headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Computes the `Age` of a stored object in seconds, per RFC 9111 section 4.2.3.
+/// The origin's `Date` header marks when the response was generated, and any `Age` it sent is added on top.
+pub fn object_age(preamble: &ObjectMetaPreamble, now: SystemTime) -> u64 {
+    response_age(
+        stored_header(preamble, "date"),
+        stored_header(preamble, "age"),
+        now,
+    )
+}
+
+/// Computes the age in seconds of a response with the specified `Date` and `Age` headers.
+/// A stored object that was just revalidated is as old as the `304` that confirmed it (RFC 9111 section 4.3.4).
+pub fn response_age(date: Option<&str>, age: Option<&str>, now: SystemTime) -> u64 {
+    let apparent_age = date
+        .and_then(parse_http_date)
+        .and_then(|date| now.duration_since(date).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let origin_age = age.and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(0);
+
+    apparent_age + origin_age
+}
+
+/// Returns the remaining freshness lifetime of a stored object in seconds.
+/// Negative if the object has expired.
+pub fn object_ttl(preamble: &ObjectMetaPreamble, now: SystemTime) -> i64 {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    preamble.exp_ts as i64 - now as i64
+}
+
+/// The information reported in a `Cache-Status` header (RFC 9211).
+pub struct CacheStatus<'a> {
+    pub result: CacheResult,
+
+    /// The status code the origin returned, if a request was forwarded.
+    pub fwd_status: Option<u16>,
+
+    /// The remaining freshness lifetime of the cached object, if there is one.
+    pub ttl: Option<i64>,
+
+    /// The cache key the request mapped to.
+    pub key: &'a str,
+
+    /// For partial hits, how many of the response's bytes come from the cache and how many from the origin.
+    pub partial_bytes: Option<(u64, u64)>,
+}
+
+impl CacheStatus<'_> {
+    /// Formats the header value.
+    pub fn to_header_value(&self) -> String {
+        let mut out = String::with_capacity(64 + self.key.len());
+        out.push_str(CACHE_STATUS_NAME);
+
+        match self.result {
+            CacheResult::Hit => out.push_str("; hit"),
+            CacheResult::Miss => out.push_str("; fwd=miss"),
+            CacheResult::Stale => out.push_str("; fwd=stale"),
+            CacheResult::Partial => out.push_str("; fwd=partial"),
+            CacheResult::Bypass => out.push_str("; fwd=bypass"),
+        }
+
+        if let Some(status) = self.fwd_status {
+            let _ = write!(out, "; fwd-status={}", status);
+        }
+        if let Some(ttl) = self.ttl {
+            let _ = write!(out, "; ttl={}", ttl);
+        }
+
+        out.push_str("; key=");
+        write_sf_string(&mut out, self.key);
+
+        if let Some((cache, origin)) = self.partial_bytes {
+            out.push_str("; detail=");
+            write_sf_string(&mut out, &format!("cache={} origin={}", cache, origin));
+        }
+
+        out
+    }
+}
+
+/// Writes a structured field string (RFC 8941 section 3.3.3).
+/// Characters that can't appear in one are dropped.
+fn write_sf_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => {}
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parses_imf_fixdate() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(at(784111777))
+        );
+        assert_eq!(
+            parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(at(0))
+        );
+        assert_eq!(
+            parse_http_date("Fri, 31 Dec 1999 23:59:59 GMT"),
+            Some(at(946684799))
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"),
+            Some(at(1709208000))
+        );
+        assert_eq!(
+            parse_http_date("Wed, 01 Mar 2100 00:00:00 GMT"),
+            Some(at(4107542400))
+        );
+        assert_eq!(
+            parse_http_date("  Sun, 06 Nov 1994 08:49:37 GMT\t"),
+            Some(at(784111777))
+        );
+    }
+
+    #[test]
+    fn accepts_leap_seconds() {
+        assert_eq!(
+            parse_http_date("Sat, 31 Dec 2016 23:59:60 GMT"),
+            Some(at(1483228800))
+        );
+    }
+
+    #[test]
+    fn rejects_obsolete_formats() {
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 08:49:37 +0000",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1994 08:49:61 GMT",
+            "Sun, 6  Nov 1994 08:49:37 GMT",
+            "Sun, xx Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{:?}", date);
+        }
+    }
+
+    #[test]
+    fn rejects_dates_before_the_epoch() {
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+    }
+
+    #[test]
+    fn response_ages() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let now = at(784111777 + 30);
+        assert_eq!(response_age(Some(date), None, now), 30);
+        assert_eq!(response_age(Some(date), Some("100"), now), 130);
+        assert_eq!(response_age(Some(date), Some(" 5 "), now), 35);
+        assert_eq!(response_age(None, Some("100"), now), 100);
+        // A date in the future or that doesn't parse counts as no apparent age.
+        assert_eq!(response_age(Some(date), None, at(784111777 - 30)), 0);
+        assert_eq!(response_age(Some("yesterday"), Some("junk"), now), 0);
+    }
+}
@@ -0,0 +1,647 @@
+use crate::origin::HostOptions;
+use crate::originpool::{OriginServer, PoolOptions};
+use crate::routing::{CachePolicy, PathPattern, PathRewrite};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Cache storage settings.
+#[derive(Clone)]
+pub struct CacheConfig {
+    /// The directories block and meta files are stored under.
+    /// Objects are spread across roots by hash, so each root can be a different disk.
+    pub roots: Vec<PathBuf>,
+
+    /// The size of cache blocks, in bytes.
+    pub block_size: u32,
+
+    /// Whether to open block files with `O_DIRECT`, bypassing the page cache.
+    /// When enabled, the block size must be a multiple of the logical block size of the devices backing the cache roots.
+    pub direct_io: bool,
+
+    /// The maximum number of aligned buffers each thread keeps around for direct I/O.
+    /// Buffers beyond this are freed when returned instead of being kept for reuse.
+    pub direct_io_pool_size: usize,
+
+    /// The origin response header surrogate keys (cache tags) are read from.
+    /// Tags are separated by whitespace. The header isn't passed on to clients.
+    pub tag_header: String,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            roots: vec![PathBuf::from("/var/cache/stavka")],
+            block_size: 1024 * 1024,
+            direct_io: false,
+            direct_io_pool_size: 64,
+            tag_header: "surrogate-key".to_owned(),
+        }
+    }
+}
+
+/// Origin fetch settings.
+#[derive(Clone)]
+pub struct OriginConfig {
+    /// The maximum number of times a `GET` or `HEAD` fill is attempted, including the first try.
+    /// Retries go to another server in the host's pool when there is one.
+    pub max_attempts: u32,
+
+    /// Origin response statuses that are retried.
+    pub retry_statuses: Vec<u16>,
+
+    /// How long to wait for an origin response head, or for more of its body, before giving up on the server.
+    pub response_timeout: Duration,
+
+    /// The maximum number of times a fill whose body broke off is resumed with a `Range` request.
+    pub max_resumes: u32,
+}
+
+impl Default for OriginConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            retry_statuses: vec![502, 503, 504],
+            response_timeout: Duration::from_secs(30),
+            max_resumes: 3,
+        }
+    }
+}
+
+/// The headers that tell origins who the client is.
+#[derive(Clone)]
+pub struct ForwardingConfig {
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+
+    /// Whether to add the standard `Forwarded` header (RFC 7239).
+    pub forwarded: bool,
+
+    /// Whether to add `Via` to requests and responses.
+    pub via: bool,
+
+    /// The name stavka goes by in `Via`.
+    pub via_name: String,
+
+    /// Addresses or CIDR ranges of proxies in front of stavka, such as `10.0.0.0/8`.
+    /// Forwarding headers from them are kept and extended; from anyone else, they are replaced.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        Self {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            forwarded: false,
+            via: true,
+            via_name: "stavka".to_owned(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// A certificate served to TLS clients.
+#[derive(Clone)]
+pub struct CertificateConfig {
+    /// The hostnames the certificate is served for, such as `cdn.example.com` or `*.example.com`.
+    pub hostnames: Vec<String>,
+
+    /// A PEM file with the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+
+    /// A PEM file with the certificate's private key.
+    pub key_path: PathBuf,
+
+    /// A DER-encoded OCSP response to staple, such as one fetched periodically with `openssl ocsp`.
+    /// Reloaded along with the certificate when it changes.
+    pub ocsp_path: Option<PathBuf>,
+}
+
+/// TLS listener settings.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// The address the TLS listener binds to.
+    pub bind_addr: String,
+
+    /// The certificates to serve.
+    /// If empty, the TLS listener is not started.
+    pub certificates: Vec<CertificateConfig>,
+
+    /// The index in [TlsConfig::certificates] of the certificate served to clients that send no server name, or one
+    /// no certificate covers.
+    /// If [None], such handshakes fail.
+    pub default_certificate: Option<usize>,
+
+    /// The protocols offered with ALPN, in order of preference.
+    pub alpn_protocols: Vec<String>,
+
+    /// Whether to issue session tickets, so clients can resume sessions on any worker thread.
+    /// Ticket keys rotate every 6 hours.
+    pub session_tickets: bool,
+
+    /// How often certificate, key and OCSP response files are checked for changes.
+    pub reload_interval: Duration,
+
+    /// How long a client has to complete the handshake before the connection is closed.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:50443".to_owned(),
+            certificates: Vec::new(),
+            default_certificate: None,
+            alpn_protocols: vec!["h2".to_owned(), "http/1.1".to_owned()],
+            session_tickets: true,
+            reload_interval: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How domains are validated for ACME.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    /// Answered by the plaintext listener, which must be reachable on port 80.
+    Http01,
+
+    /// Answered by the TLS listener, which must be reachable on port 443.
+    TlsAlpn01,
+}
+
+/// Automatic certificate issuance settings.
+#[derive(Clone)]
+pub struct AcmeConfig {
+    /// The ACME server's directory URL.
+    pub directory_url: String,
+
+    /// Contact URLs for the account, such as `mailto:ops@example.com`.
+    pub contact: Vec<String>,
+
+    /// Whether the operator agrees to the ACME server's terms of service, which most servers require.
+    pub accept_terms: bool,
+
+    /// The hosts to issue certificates for, each getting its own certificate.
+    /// If empty, ACME is disabled.
+    pub hosts: Vec<String>,
+
+    pub challenge: AcmeChallenge,
+
+    /// Where the account key and issued certificates are stored.
+    pub storage_dir: PathBuf,
+
+    /// A PEM file of CA certificates to trust for the ACME server instead of the bundled public roots,
+    /// such as Pebble's `pebble.minica.pem` when testing.
+    pub ca_bundle: Option<PathBuf>,
+
+    /// How long before expiry certificates are renewed.
+    pub renew_before: Duration,
+
+    /// How often certificates are checked for renewal, and failed issuances retried.
+    pub check_interval: Duration,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            directory_url: "https://acme-v02.api.letsencrypt.org/directory".to_owned(),
+            contact: Vec::new(),
+            accept_terms: false,
+            hosts: Vec::new(),
+            challenge: AcmeChallenge::Http01,
+            storage_dir: PathBuf::from("/var/lib/stavka/acme"),
+            ca_bundle: None,
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            check_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The plain HTTP listener, client connection limits and HTTP/1.1 timeouts.
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    /// The address the plain HTTP listener binds to.
+    pub bind_addr: String,
+
+    /// How long a client has to send a request head once it starts sending it, or the first one after connecting.
+    /// Clients that take longer get a `408`.
+    pub header_read_timeout: Duration,
+
+    /// How long a keep-alive connection may wait for its next request before it is closed.
+    pub keepalive_timeout: Duration,
+
+    /// How long a request body may go without any data arriving before the connection is closed.
+    pub body_read_timeout: Duration,
+
+    /// The number of requests served on an HTTP/1.1 connection before it is closed.
+    pub max_requests_per_connection: usize,
+
+    /// The number of pipelined requests read ahead of the one being responded to.
+    pub max_pipelined_requests: usize,
+
+    /// The largest HTTP/1.1 request head a client may send, in bytes.
+    /// Larger ones get a `431`.
+    pub max_header_size: usize,
+
+    /// The maximum number of client connections each worker thread has open at once.
+    pub max_connections_per_thread: usize,
+
+    /// The maximum number of connections a client IP may have open at once, across all worker threads.
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:50002".to_owned(),
+            header_read_timeout: Duration::from_secs(10),
+            keepalive_timeout: Duration::from_secs(60),
+            body_read_timeout: Duration::from_secs(30),
+            max_requests_per_connection: 1000,
+            max_pipelined_requests: 16,
+            max_header_size: 32 * 1024,
+            max_connections_per_thread: 10_000,
+            max_connections_per_ip: 256,
+        }
+    }
+}
+
+/// Whether a listener expects a PROXY protocol header at the start of each connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocolMode {
+    Off,
+
+    /// Connections may start with a header or not.
+    /// Any client that can reach the listener directly can then claim any address, so this is only for migrating
+    /// to [ProxyProtocolMode::Required].
+    Optional,
+
+    /// Connections without a header are closed.
+    Required,
+}
+
+/// PROXY protocol (v1 and v2) settings, for running behind an L4 load balancer that can't otherwise pass on client
+/// addresses.
+/// The address in the header is used as the client's for logging, `X-Forwarded-For`, connection limits and WASM
+/// hooks.
+#[derive(Clone)]
+pub struct ProxyProtocolConfig {
+    /// For the plain HTTP listener.
+    pub plain: ProxyProtocolMode,
+
+    /// For the TLS listener. The header comes before the TLS handshake.
+    pub tls: ProxyProtocolMode,
+
+    /// For the h2c listener.
+    pub h2c: ProxyProtocolMode,
+
+    /// How long a connection has to send its header.
+    pub header_timeout: Duration,
+}
+
+impl Default for ProxyProtocolConfig {
+    fn default() -> Self {
+        Self {
+            plain: ProxyProtocolMode::Off,
+            tls: ProxyProtocolMode::Off,
+            h2c: ProxyProtocolMode::Off,
+            header_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Graceful shutdown settings.
+#[derive(Clone)]
+pub struct ShutdownConfig {
+    /// How long connections may take to finish after a shutdown signal before they are cut off.
+    pub drain_timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Binary upgrade settings.
+#[derive(Clone)]
+pub struct UpgradeConfig {
+    /// How long a new process has to get its listeners up before the upgrade is rolled back.
+    pub ready_timeout: Duration,
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        Self {
+            ready_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// HTTP/2 settings.
+/// HTTP/2 is negotiated with ALPN on the TLS listener when `h2` is in [TlsConfig::alpn_protocols].
+#[derive(Clone)]
+pub struct Http2Config {
+    /// The maximum number of streams a client may have open at once on a connection.
+    pub max_concurrent_streams: u32,
+
+    /// The flow control window for each stream's request body, in bytes.
+    pub initial_stream_window: u32,
+
+    /// The flow control window for all request bodies on a connection, in bytes.
+    pub initial_connection_window: u32,
+
+    /// The largest frame a client may send, in bytes.
+    pub max_frame_size: u32,
+
+    /// The largest request header block a client may send, in bytes.
+    pub max_header_list_size: u32,
+
+    /// The address of a listener that speaks cleartext HTTP/2 with prior knowledge, for testing.
+    /// If [None], it is not started.
+    pub h2c_bind_addr: Option<String>,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: 128,
+            initial_stream_window: 256 * 1024,
+            initial_connection_window: 1024 * 1024,
+            max_frame_size: 16 * 1024,
+            max_header_list_size: 64 * 1024,
+            h2c_bind_addr: None,
+        }
+    }
+}
+
+/// HTTP/3 settings.
+/// HTTP/3 serves the same certificates as the TLS listener, so it also needs [TlsConfig] or [AcmeConfig] set up.
+#[derive(Clone)]
+pub struct Http3Config {
+    /// The UDP address the QUIC listener binds to.
+    /// If [None], it is not started.
+    pub bind_addr: Option<String>,
+
+    /// The port advertised to TLS clients in `Alt-Svc`, if it differs from the bound one, such as behind NAT.
+    pub advertised_port: Option<u16>,
+
+    /// How long clients may remember that HTTP/3 is available.
+    pub alt_svc_max_age: Duration,
+
+    /// How long a connection may go without any packets before it is closed.
+    pub max_idle_timeout: Duration,
+
+    /// The maximum number of request streams a client may open on a connection at first.
+    pub max_concurrent_streams: u64,
+
+    /// The flow control window for each stream's request body, in bytes.
+    pub initial_stream_window: u64,
+
+    /// The flow control window for all request bodies on a connection, in bytes.
+    pub initial_connection_window: u64,
+
+    /// The largest request header section a client may send, in bytes.
+    pub max_field_section_size: u64,
+
+    /// Whether to batch outgoing packets with UDP generic segmentation offload, when the kernel supports it.
+    pub gso: bool,
+
+    /// Whether new clients are sent a Retry to prove they own their address before any connection state is kept.
+    /// This costs a round trip on every new connection, but keeps spoofed packets from using up connection slots.
+    pub retry: bool,
+}
+
+impl Default for Http3Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            advertised_port: None,
+            alt_svc_max_age: Duration::from_secs(24 * 60 * 60),
+            max_idle_timeout: Duration::from_secs(30),
+            max_concurrent_streams: 128,
+            initial_stream_window: 256 * 1024,
+            initial_connection_window: 1024 * 1024,
+            max_field_section_size: 64 * 1024,
+            gso: true,
+            retry: true,
+        }
+    }
+}
+
+/// Admin API settings.
+#[derive(Clone)]
+pub struct AdminConfig {
+    /// The address the admin listener binds to.
+    /// This should not be reachable from the Internet.
+    pub bind_addr: String,
+
+    /// The bearer token admin requests must present in the `Authorization` header.
+    /// If empty, the admin listener is not started.
+    pub token: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "127.0.0.1:50003".to_owned(),
+            token: String::new(),
+        }
+    }
+}
+
+/// Access log settings.
+#[derive(Clone)]
+pub struct LogConfig {
+    /// The file access log lines are appended to.
+    /// If [None], access logging is disabled.
+    pub access_log_path: Option<PathBuf>,
+
+    /// The access log format: `combined`, `json`, or a custom template such as `$client_ip $status $cache_status`.
+    pub access_log_format: String,
+
+    /// The number of buffered bytes that triggers a write.
+    pub flush_threshold: usize,
+
+    /// The maximum time lines sit in the buffer before being written.
+    pub flush_interval: Duration,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            access_log_path: None,
+            access_log_format: "combined".to_owned(),
+            flush_threshold: 64 * 1024,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A host directory exposed to a module through WASI.
+#[derive(Clone)]
+pub struct PreopenedDir {
+    pub host_path: PathBuf,
+
+    /// The path the module sees the directory at.
+    pub guest_path: String,
+
+    pub read_only: bool,
+}
+
+/// The host capabilities granted to a module.
+/// Everything is denied by default, and attempts to use a denied capability are logged.
+#[derive(Clone, Default)]
+pub struct ModulePermissions {
+    /// Whether the module may read the WASI clocks and sleep.
+    pub clock: bool,
+
+    /// Whether the module may read WASI random bytes.
+    pub random: bool,
+
+    /// Host directories exposed to the module through WASI.
+    pub preopened_dirs: Vec<PreopenedDir>,
+
+    /// Origins the module may send HTTP requests to through the `http_send` host function, as `scheme://host:port`.
+    pub http_hosts: Vec<String>,
+}
+
+/// WebAssembly hook settings.
+#[derive(Clone)]
+pub struct WasmConfig {
+    /// The module to run request hooks from at startup.
+    /// If this and [WasmConfig::module_dir] are both [None], no hooks run.
+    pub module_path: Option<PathBuf>,
+
+    /// A directory to watch for new module versions.
+    /// The most recently modified `.wasm` file in it is loaded whenever it changes.
+    pub module_dir: Option<PathBuf>,
+
+    /// How often [WasmConfig::module_dir] is checked for changes.
+    pub watch_interval: Duration,
+
+    /// Paths of synthetic `GET` requests every phase of a new module is run against before it goes live.
+    /// A module that fails any of them is rejected.
+    pub smoke_test_paths: Vec<String>,
+
+    /// The number of requests a new module must handle before its failure ratio is checked.
+    pub rollback_min_requests: u64,
+
+    /// The fraction of requests a module may fail (trap, run out of fuel or time, or return an error)
+    /// before it is automatically rolled back to the previous version.
+    pub rollback_failure_ratio: f64,
+
+    /// How often resident instances receive timer events and pending broadcast events.
+    pub resident_tick: Duration,
+
+    /// The maximum number of entries in the module key/value store.
+    pub kv_max_entries: usize,
+
+    /// The maximum size of a value in the module key/value store.
+    pub kv_max_value_bytes: usize,
+
+    /// Capabilities granted to modules, keyed by module name.
+    /// A module's name is its file name without the extension, or the name given when it is uploaded.
+    /// Modules without an entry get no capabilities.
+    pub permissions: HashMap<String, ModulePermissions>,
+
+    /// The fuel each hook phase gets, roughly the number of WebAssembly instructions it may execute.
+    pub fuel_per_phase: u64,
+
+    /// The wall-clock time each hook phase may run for before it is interrupted.
+    /// Hooks run on the worker thread, so this bounds how long a bad module can stall it.
+    pub time_limit_per_phase: Duration,
+
+    /// Whether to preallocate instance slots with the pooling allocator, which makes per-request instantiation cheaper.
+    pub pooled: bool,
+
+    /// The maximum number of concurrent instances when pooled.
+    pub max_instances: u32,
+
+    /// The maximum linear memory size of an instance when pooled.
+    pub max_memory_bytes: usize,
+}
+
+impl Default for WasmConfig {
+    fn default() -> Self {
+        Self {
+            module_path: None,
+            module_dir: None,
+            watch_interval: Duration::from_secs(1),
+            smoke_test_paths: vec!["/".to_owned()],
+            rollback_min_requests: 100,
+            rollback_failure_ratio: 0.05,
+            resident_tick: Duration::from_millis(250),
+            kv_max_entries: 100_000,
+            kv_max_value_bytes: 64 * 1024,
+            permissions: HashMap::new(),
+            fuel_per_phase: 10_000_000,
+            time_limit_per_phase: Duration::from_millis(10),
+            pooled: true,
+            max_instances: 1000,
+            max_memory_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// An origin pool, which routes refer to by name.
+#[derive(Clone)]
+pub struct PoolConfig {
+    pub servers: Vec<OriginServer>,
+    pub options: PoolOptions,
+
+    /// Hosts, such as `cdn.example.com` or `*.cdn.example.com`, whose requests go to the pool when none of their
+    /// routes match.
+    pub hosts: Vec<String>,
+}
+
+/// A routing rule, sending matching requests to a pool.
+#[derive(Clone)]
+pub struct RouteConfig {
+    /// The hosts the route applies to, such as `cdn.example.com` or `*.cdn.example.com`.
+    pub host: String,
+
+    pub path: PathPattern,
+
+    /// The name of the pool in [Config::pools].
+    pub pool: String,
+
+    pub rewrite: PathRewrite,
+    pub cache: CachePolicy,
+}
+
+/// The server configuration, loaded from a TOML file by [crate::configfile].
+/// Each worker thread holds its own copy.
+#[derive(Clone, Default)]
+pub struct Config {
+    pub cache: CacheConfig,
+    pub origin: OriginConfig,
+
+    /// Origin pools, keyed by name.
+    pub pools: HashMap<String, PoolConfig>,
+
+    /// Routing rules. Rules for a host are tried in order, before the catch-all of a pool the host is sent to.
+    pub routes: Vec<RouteConfig>,
+
+    /// Per-host behavior, keyed by hostname. Hosts without an entry use the defaults.
+    pub hosts: HashMap<String, HostOptions>,
+
+    pub forwarding: ForwardingConfig,
+    pub connection: ConnectionConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub shutdown: ShutdownConfig,
+    pub upgrade: UpgradeConfig,
+    pub tls: TlsConfig,
+    pub acme: AcmeConfig,
+    pub http2: Http2Config,
+    pub http3: Http3Config,
+    pub admin: AdminConfig,
+    pub log: LogConfig,
+    pub wasm: WasmConfig,
+}
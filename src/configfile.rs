@@ -0,0 +1,851 @@
+//! Loading the server configuration from a TOML file.
+//!
+//! Each [Config] section is a table of the same name, such as `[cache]` or `[wasm.permissions.my_module]`, whose
+//! keys are the section's field names. Anything left out keeps its default. Durations are given as a number of
+//! seconds or a string such as `"500ms"`, `"30s"`, `"5m"`, `"6h"` or `"30d"`.
+//!
+//! Unknown keys, values of the wrong type and values that make no sense are all errors, so a typo doesn't silently
+//! leave a setting at its default.
+
+use crate::accesslog::LogFormat;
+use crate::config::{
+    AcmeChallenge, CertificateConfig, Config, ModulePermissions, PoolConfig, PreopenedDir,
+    ProxyProtocolMode, RouteConfig,
+};
+use crate::forwarded::Forwarding;
+use crate::origin::{HostOptions, OriginManager};
+use crate::originpool::{BalanceStrategy, HealthCheck, OriginServer, PoolOptions};
+use crate::originreq::{OriginRequestOptions, TlsVerify};
+use crate::routing::{CachePolicy, PathPattern, PathRewrite};
+use crate::wasm::fetch::http_origin;
+use http::uri::Scheme;
+use http::{HeaderName, HeaderValue, Uri};
+use regex::Regex;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::{Table, Value};
+
+/// The environment variable the config file path is read from when it isn't given with `--config`.
+const CONFIG_ENV: &str = "STAVKA_CONFIG";
+
+/// Loads the configuration from the file named by `--config <path>` or `$STAVKA_CONFIG`, and validates it.
+/// Without either, the defaults are used.
+pub fn load_from_args() -> Result<Config, String> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = Some(args.next().ok_or("--config needs a path")?);
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(value.to_owned());
+        } else {
+            return Err(format!("unknown argument {}", arg));
+        }
+    }
+    let path = path.or_else(|| std::env::var(CONFIG_ENV).ok());
+
+    let config = match path {
+        Some(path) => load(Path::new(&path))?,
+        None => {
+            println!("no config file given, using the defaults");
+            Config::default()
+        }
+    };
+    validate(&config)?;
+    if config.pools.is_empty() {
+        println!("no origin pools are configured, so every request will get a 404");
+    }
+    Ok(config)
+}
+
+/// Loads the configuration from a TOML file, without validating it.
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let table: Table = text
+        .parse()
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+    parse(&table).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Checks for settings that parse but can't work, so they fail at startup instead of on some later request.
+pub fn validate(config: &Config) -> Result<(), String> {
+    fn bind_addr(name: &str, addr: &str) -> Result<(), String> {
+        addr.parse::<SocketAddr>()
+            .map(|_| ())
+            .map_err(|e| format!("{} {:?} isn't a valid address: {}", name, addr, e))
+    }
+
+    if config.cache.roots.is_empty() {
+        return Err("cache.roots must name at least one directory".to_owned());
+    }
+    if config.cache.block_size == 0 {
+        return Err("cache.block_size must be greater than 0".to_owned());
+    }
+
+    bind_addr("connection.bind_addr", &config.connection.bind_addr)?;
+    bind_addr("tls.bind_addr", &config.tls.bind_addr)?;
+    bind_addr("admin.bind_addr", &config.admin.bind_addr)?;
+    if let Some(addr) = &config.http2.h2c_bind_addr {
+        bind_addr("http2.h2c_bind_addr", addr)?;
+    }
+    if let Some(addr) = &config.http3.bind_addr {
+        bind_addr("http3.bind_addr", addr)?;
+    }
+
+    if config.connection.max_pipelined_requests == 0 {
+        return Err("connection.max_pipelined_requests must be greater than 0".to_owned());
+    }
+    if config.connection.max_requests_per_connection == 0 {
+        return Err("connection.max_requests_per_connection must be greater than 0".to_owned());
+    }
+
+    if let Some(index) = config.tls.default_certificate {
+        if index >= config.tls.certificates.len() {
+            return Err(format!(
+                "tls.default_certificate is {}, but there are only {} certificates",
+                index,
+                config.tls.certificates.len()
+            ));
+        }
+    }
+
+    OriginManager::from_config(config)?;
+    for (name, pool) in &config.pools {
+        pool.options
+            .request
+            .tls
+            .check()
+            .map_err(|e| format!("pools.{}.request: {}", name, e))?;
+    }
+
+    Forwarding::new(&config.forwarding).map_err(|e| format!("forwarding: {}", e))?;
+    LogFormat::parse(&config.log.access_log_format)
+        .map_err(|e| format!("log.access_log_format: {}", e))?;
+
+    if !(0.0..=1.0).contains(&config.wasm.rollback_failure_ratio) {
+        return Err("wasm.rollback_failure_ratio must be between 0 and 1".to_owned());
+    }
+
+    Ok(())
+}
+
+fn parse(table: &Table) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut root = Section::new(String::new(), table);
+
+    if let Some(mut s) = root.section("cache")? {
+        let c = &mut config.cache;
+        s.set("roots", &mut c.roots, paths)?;
+        s.set("block_size", &mut c.block_size, integer)?;
+        s.set("direct_io", &mut c.direct_io, boolean)?;
+        s.set("direct_io_pool_size", &mut c.direct_io_pool_size, integer)?;
+        s.set("tag_header", &mut c.tag_header, string)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("origin")? {
+        let c = &mut config.origin;
+        s.set("max_attempts", &mut c.max_attempts, integer)?;
+        s.set("retry_statuses", &mut c.retry_statuses, |v| {
+            list(v, integer)
+        })?;
+        s.set("response_timeout", &mut c.response_timeout, duration)?;
+        s.set("max_resumes", &mut c.max_resumes, integer)?;
+        s.finish()?;
+    }
+
+    if let Some(mut pools) = root.section("pools")? {
+        for name in pools.keys() {
+            let s = pools.section(&name)?.expect("key should exist");
+            config.pools.insert(name, pool(s)?);
+        }
+        pools.finish()?;
+    }
+
+    for s in root.sections("routes")? {
+        config.routes.push(route(s)?);
+    }
+
+    if let Some(mut hosts) = root.section("hosts")? {
+        for name in hosts.keys() {
+            let mut s = hosts.section(&name)?.expect("key should exist");
+            let mut options = HostOptions::default();
+            s.set(
+                "cache_status_headers",
+                &mut options.cache_status_headers,
+                boolean,
+            )?;
+            s.finish()?;
+            config.hosts.insert(name, options);
+        }
+        hosts.finish()?;
+    }
+
+    if let Some(mut s) = root.section("forwarding")? {
+        let c = &mut config.forwarding;
+        s.set("x_forwarded_for", &mut c.x_forwarded_for, boolean)?;
+        s.set("x_forwarded_proto", &mut c.x_forwarded_proto, boolean)?;
+        s.set("x_forwarded_host", &mut c.x_forwarded_host, boolean)?;
+        s.set("forwarded", &mut c.forwarded, boolean)?;
+        s.set("via", &mut c.via, boolean)?;
+        s.set("via_name", &mut c.via_name, string)?;
+        s.set("trusted_proxies", &mut c.trusted_proxies, strings)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("connection")? {
+        let c = &mut config.connection;
+        s.set("bind_addr", &mut c.bind_addr, string)?;
+        s.set("header_read_timeout", &mut c.header_read_timeout, duration)?;
+        s.set("keepalive_timeout", &mut c.keepalive_timeout, duration)?;
+        s.set("body_read_timeout", &mut c.body_read_timeout, duration)?;
+        s.set(
+            "max_requests_per_connection",
+            &mut c.max_requests_per_connection,
+            integer,
+        )?;
+        s.set(
+            "max_pipelined_requests",
+            &mut c.max_pipelined_requests,
+            integer,
+        )?;
+        s.set("max_header_size", &mut c.max_header_size, integer)?;
+        s.set(
+            "max_connections_per_thread",
+            &mut c.max_connections_per_thread,
+            integer,
+        )?;
+        s.set(
+            "max_connections_per_ip",
+            &mut c.max_connections_per_ip,
+            integer,
+        )?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("proxy_protocol")? {
+        let c = &mut config.proxy_protocol;
+        s.set("plain", &mut c.plain, proxy_protocol_mode)?;
+        s.set("tls", &mut c.tls, proxy_protocol_mode)?;
+        s.set("h2c", &mut c.h2c, proxy_protocol_mode)?;
+        s.set("header_timeout", &mut c.header_timeout, duration)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("shutdown")? {
+        s.set(
+            "drain_timeout",
+            &mut config.shutdown.drain_timeout,
+            duration,
+        )?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("upgrade")? {
+        s.set("ready_timeout", &mut config.upgrade.ready_timeout, duration)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("tls")? {
+        let c = &mut config.tls;
+        s.set("bind_addr", &mut c.bind_addr, string)?;
+        for mut cert in s.sections("certificates")? {
+            let mut certificate = CertificateConfig {
+                hostnames: Vec::new(),
+                cert_path: PathBuf::new(),
+                key_path: PathBuf::new(),
+                ocsp_path: None,
+            };
+            cert.require("hostnames", &mut certificate.hostnames, strings)?;
+            cert.require("cert_path", &mut certificate.cert_path, path)?;
+            cert.require("key_path", &mut certificate.key_path, path)?;
+            cert.set("ocsp_path", &mut certificate.ocsp_path, |v| {
+                path(v).map(Some)
+            })?;
+            cert.finish()?;
+            c.certificates.push(certificate);
+        }
+        s.set("default_certificate", &mut c.default_certificate, |v| {
+            integer(v).map(Some)
+        })?;
+        s.set("alpn_protocols", &mut c.alpn_protocols, strings)?;
+        s.set("session_tickets", &mut c.session_tickets, boolean)?;
+        s.set("reload_interval", &mut c.reload_interval, duration)?;
+        s.set("handshake_timeout", &mut c.handshake_timeout, duration)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("acme")? {
+        let c = &mut config.acme;
+        s.set("directory_url", &mut c.directory_url, string)?;
+        s.set("contact", &mut c.contact, strings)?;
+        s.set("accept_terms", &mut c.accept_terms, boolean)?;
+        s.set("hosts", &mut c.hosts, strings)?;
+        s.set("challenge", &mut c.challenge, |v| {
+            match string(v)?.as_str() {
+                "http-01" => Ok(AcmeChallenge::Http01),
+                "tls-alpn-01" => Ok(AcmeChallenge::TlsAlpn01),
+                other => Err(format!(
+                    "unknown challenge {:?}, expected \"http-01\" or \"tls-alpn-01\"",
+                    other
+                )),
+            }
+        })?;
+        s.set("storage_dir", &mut c.storage_dir, path)?;
+        s.set("ca_bundle", &mut c.ca_bundle, |v| path(v).map(Some))?;
+        s.set("renew_before", &mut c.renew_before, duration)?;
+        s.set("check_interval", &mut c.check_interval, duration)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("http2")? {
+        let c = &mut config.http2;
+        s.set(
+            "max_concurrent_streams",
+            &mut c.max_concurrent_streams,
+            integer,
+        )?;
+        s.set(
+            "initial_stream_window",
+            &mut c.initial_stream_window,
+            integer,
+        )?;
+        s.set(
+            "initial_connection_window",
+            &mut c.initial_connection_window,
+            integer,
+        )?;
+        s.set("max_frame_size", &mut c.max_frame_size, integer)?;
+        s.set("max_header_list_size", &mut c.max_header_list_size, integer)?;
+        s.set("h2c_bind_addr", &mut c.h2c_bind_addr, |v| {
+            string(v).map(Some)
+        })?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("http3")? {
+        let c = &mut config.http3;
+        s.set("bind_addr", &mut c.bind_addr, |v| string(v).map(Some))?;
+        s.set("advertised_port", &mut c.advertised_port, |v| {
+            integer(v).map(Some)
+        })?;
+        s.set("alt_svc_max_age", &mut c.alt_svc_max_age, duration)?;
+        s.set("max_idle_timeout", &mut c.max_idle_timeout, duration)?;
+        s.set(
+            "max_concurrent_streams",
+            &mut c.max_concurrent_streams,
+            integer,
+        )?;
+        s.set(
+            "initial_stream_window",
+            &mut c.initial_stream_window,
+            integer,
+        )?;
+        s.set(
+            "initial_connection_window",
+            &mut c.initial_connection_window,
+            integer,
+        )?;
+        s.set(
+            "max_field_section_size",
+            &mut c.max_field_section_size,
+            integer,
+        )?;
+        s.set("gso", &mut c.gso, boolean)?;
+        s.set("retry", &mut c.retry, boolean)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("admin")? {
+        let c = &mut config.admin;
+        s.set("bind_addr", &mut c.bind_addr, string)?;
+        s.set("token", &mut c.token, string)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("log")? {
+        let c = &mut config.log;
+        s.set("access_log_path", &mut c.access_log_path, |v| {
+            path(v).map(Some)
+        })?;
+        s.set("access_log_format", &mut c.access_log_format, string)?;
+        s.set("flush_threshold", &mut c.flush_threshold, integer)?;
+        s.set("flush_interval", &mut c.flush_interval, duration)?;
+        s.finish()?;
+    }
+
+    if let Some(mut s) = root.section("wasm")? {
+        let c = &mut config.wasm;
+        s.set("module_path", &mut c.module_path, |v| path(v).map(Some))?;
+        s.set("module_dir", &mut c.module_dir, |v| path(v).map(Some))?;
+        s.set("watch_interval", &mut c.watch_interval, duration)?;
+        s.set("smoke_test_paths", &mut c.smoke_test_paths, strings)?;
+        s.set(
+            "rollback_min_requests",
+            &mut c.rollback_min_requests,
+            integer,
+        )?;
+        s.set(
+            "rollback_failure_ratio",
+            &mut c.rollback_failure_ratio,
+            float,
+        )?;
+        s.set("resident_tick", &mut c.resident_tick, duration)?;
+        s.set("kv_max_entries", &mut c.kv_max_entries, integer)?;
+        s.set("kv_max_value_bytes", &mut c.kv_max_value_bytes, integer)?;
+        s.set("fuel_per_phase", &mut c.fuel_per_phase, integer)?;
+        s.set(
+            "time_limit_per_phase",
+            &mut c.time_limit_per_phase,
+            duration,
+        )?;
+        s.set("pooled", &mut c.pooled, boolean)?;
+        s.set("max_instances", &mut c.max_instances, integer)?;
+        s.set("max_memory_bytes", &mut c.max_memory_bytes, integer)?;
+
+        if let Some(mut modules) = s.section("permissions")? {
+            for name in modules.keys() {
+                let mut m = modules.section(&name)?.expect("key should exist");
+                let mut p = ModulePermissions::default();
+                m.set("clock", &mut p.clock, boolean)?;
+                m.set("random", &mut p.random, boolean)?;
+                m.set("http_hosts", &mut p.http_hosts, |v| list(v, http_host))?;
+                for mut dir in m.sections("preopened_dirs")? {
+                    let mut d = PreopenedDir {
+                        host_path: PathBuf::new(),
+                        guest_path: String::new(),
+                        read_only: true,
+                    };
+                    dir.require("host_path", &mut d.host_path, path)?;
+                    dir.require("guest_path", &mut d.guest_path, string)?;
+                    dir.set("read_only", &mut d.read_only, boolean)?;
+                    dir.finish()?;
+                    p.preopened_dirs.push(d);
+                }
+                m.finish()?;
+                c.permissions.insert(name, p);
+            }
+            modules.finish()?;
+        }
+        s.finish()?;
+    }
+
+    root.finish()?;
+    Ok(config)
+}
+
+/// Reads a `[pools.<name>]` table, such as:
+///
+/// ```toml
+/// [pools.media]
+/// servers = [{ address = "https://10.0.0.1:8443", weight = 2 }, { address = "https://10.0.0.2" }]
+/// strategy = "url-hash"
+/// hosts = ["media.example.com"]
+///
+/// [pools.media.health_check]
+/// path = "/healthz"
+///
+/// [pools.media.request]
+/// host_header = "media-origin.example.com"
+/// add_headers = { "x-origin-secret" = "..." }
+/// ```
+fn pool(mut s: Section) -> Result<PoolConfig, String> {
+    let mut pool = PoolConfig {
+        servers: Vec::new(),
+        options: PoolOptions::default(),
+        hosts: Vec::new(),
+    };
+
+    if !s.table.contains_key("servers") {
+        return Err(format!("{} is required", s.key_path("servers")));
+    }
+    for mut server in s.sections("servers")? {
+        let mut address = String::new();
+        let mut weight = 1;
+        server.require("address", &mut address, string)?;
+        server.set("weight", &mut weight, integer)?;
+        let (scheme, authority) = origin_address(&address)
+            .map_err(|e| format!("{}: {}", server.key_path("address"), e))?;
+        server.finish()?;
+        pool.servers.push(OriginServer {
+            scheme,
+            authority,
+            weight,
+        });
+    }
+
+    let o = &mut pool.options;
+    s.set("strategy", &mut o.strategy, |v| match string(v)?.as_str() {
+        "round-robin" => Ok(BalanceStrategy::RoundRobin),
+        "least-connections" => Ok(BalanceStrategy::LeastConnections),
+        "url-hash" => Ok(BalanceStrategy::UrlHash),
+        other => Err(format!(
+            "unknown strategy {:?}, expected \"round-robin\", \"least-connections\" or \"url-hash\"",
+            other
+        )),
+    })?;
+    s.set("max_failures", &mut o.max_failures, integer)?;
+    s.set("ejection_time", &mut o.ejection_time, duration)?;
+    if let Some(mut h) = s.section("health_check")? {
+        let mut check = HealthCheck::default();
+        h.set("path", &mut check.path, string)?;
+        h.set("host", &mut check.host, string)?;
+        h.set("interval", &mut check.interval, duration)?;
+        h.set("timeout", &mut check.timeout, duration)?;
+        h.set(
+            "unhealthy_threshold",
+            &mut check.unhealthy_threshold,
+            integer,
+        )?;
+        h.set("healthy_threshold", &mut check.healthy_threshold, integer)?;
+        h.finish()?;
+        o.health_check = Some(check);
+    }
+    if let Some(r) = s.section("request")? {
+        o.request = request_options(r)?;
+    }
+    s.set("hosts", &mut pool.hosts, strings)?;
+
+    s.finish()?;
+    Ok(pool)
+}
+
+/// Reads a `[[routes]]` table, such as:
+///
+/// ```toml
+/// [[routes]]
+/// host = "*.cdn.example.com"
+/// path_prefix = "/videos/"
+/// pool = "media"
+/// strip_prefix = "/videos"
+/// cache = { ttl = "1d" }
+/// ```
+///
+/// Paths are matched with `path_prefix` or `path_regex`, or every path if neither is given.
+fn route(mut s: Section) -> Result<RouteConfig, String> {
+    let mut route = RouteConfig {
+        host: String::new(),
+        path: PathPattern::Any,
+        pool: String::new(),
+        rewrite: PathRewrite::default(),
+        cache: CachePolicy::default(),
+    };
+    s.require("host", &mut route.host, string)?;
+    s.require("pool", &mut route.pool, string)?;
+
+    s.set("path_prefix", &mut route.path, |v| {
+        string(v).map(PathPattern::Prefix)
+    })?;
+    if s.table.contains_key("path_regex") {
+        if !matches!(route.path, PathPattern::Any) {
+            return Err(format!(
+                "{}: only one of path_prefix and path_regex can be given",
+                s.path
+            ));
+        }
+        s.set("path_regex", &mut route.path, |v| {
+            Regex::new(&string(v)?)
+                .map(PathPattern::Regex)
+                .map_err(|e| e.to_string())
+        })?;
+    }
+
+    s.set("strip_prefix", &mut route.rewrite.strip_prefix, |v| {
+        string(v).map(Some)
+    })?;
+    s.set("add_prefix", &mut route.rewrite.add_prefix, |v| {
+        string(v).map(Some)
+    })?;
+
+    if let Some(mut c) = s.section("cache")? {
+        c.set("bypass", &mut route.cache.bypass, boolean)?;
+        c.set("ttl", &mut route.cache.ttl, |v| duration(v).map(Some))?;
+        c.finish()?;
+    }
+
+    s.finish()?;
+    Ok(route)
+}
+
+/// Reads a pool's `request` table, which sets how requests are presented to its servers.
+fn request_options(mut s: Section) -> Result<OriginRequestOptions, String> {
+    let mut o = OriginRequestOptions::default();
+    s.set("host_header", &mut o.host_header, |v| {
+        header_value(v).map(Some)
+    })?;
+    s.set("sni", &mut o.tls.sni, |v| string(v).map(Some))?;
+    s.set("verify", &mut o.tls.verify, |v| match string(v)?.as_str() {
+        "full" => Ok(TlsVerify::Full),
+        "ignore-hostname" => Ok(TlsVerify::IgnoreHostname),
+        "insecure" => Ok(TlsVerify::Insecure),
+        other => Err(format!(
+            "unknown mode {:?}, expected \"full\", \"ignore-hostname\" or \"insecure\"",
+            other
+        )),
+    })?;
+    s.set("ca_bundle", &mut o.tls.ca_bundle, |v| path(v).map(Some))?;
+    if let Some(mut headers) = s.section("add_headers")? {
+        for name in headers.keys() {
+            let header =
+                header_name(&name).map_err(|e| format!("{}: {}", headers.key_path(&name), e))?;
+            let mut value = HeaderValue::from_static("");
+            headers.set(&name, &mut value, header_value)?;
+            o.add_headers.push((header, value));
+        }
+        headers.finish()?;
+    }
+    s.set("remove_headers", &mut o.remove_headers, |v| {
+        list(v, |v| header_name(&string(v)?))
+    })?;
+    s.finish()?;
+    Ok(o)
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("{:?} isn't a valid header name", name))
+}
+
+fn header_value(value: &Value) -> Result<HeaderValue, String> {
+    let s = string(value)?;
+    HeaderValue::from_str(&s).map_err(|_| format!("{:?} isn't a valid header value", s))
+}
+
+/// Parses an origin server address such as `https://10.0.0.1:8443`.
+fn origin_address(address: &str) -> Result<(Scheme, http::uri::Authority), String> {
+    let uri: Uri = address
+        .parse()
+        .map_err(|e| format!("{:?} isn't a valid address: {}", address, e))?;
+    let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) else {
+        return Err(format!(
+            "{:?} needs a scheme and host, such as \"https://{}\"",
+            address, address
+        ));
+    };
+    if *scheme != Scheme::HTTP && *scheme != Scheme::HTTPS {
+        return Err(format!("{:?} must be http or https", address));
+    }
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return Err(format!("{:?} can't have a path", address));
+    }
+    Ok((scheme.clone(), authority.clone()))
+}
+
+/// Parses an origin a module may fetch from, such as `api.example.com`, `api.example.com:8443` or
+/// `http://10.0.0.1`, into the `scheme://host:port` form requests are checked against.
+/// Without a scheme only HTTPS is allowed, and without a port only the scheme's default one.
+fn http_host(value: &Value) -> Result<String, String> {
+    let s = string(value)?;
+    let address = if s.contains("://") {
+        s
+    } else {
+        format!("https://{}", s)
+    };
+    let (scheme, authority) = origin_address(&address)?;
+    let uri = Uri::builder()
+        .scheme(scheme)
+        .authority(authority)
+        .path_and_query("/")
+        .build()
+        .map_err(|e| format!("{:?} isn't a valid address: {}", address, e))?;
+    Ok(http_origin(&uri).expect("origin address should have an http or https scheme and a host"))
+}
+
+/// A table being read into a config section, which keeps track of the keys that were used so unknown ones can be
+/// reported.
+struct Section<'a> {
+    /// The dotted path to the table, for error messages.
+    path: String,
+    table: &'a Table,
+    used: HashSet<&'a str>,
+}
+
+impl<'a> Section<'a> {
+    fn new(path: String, table: &'a Table) -> Section<'a> {
+        Section {
+            path,
+            table,
+            used: HashSet::new(),
+        }
+    }
+
+    fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&'a Value> {
+        let (key, value) = self.table.get_key_value(key)?;
+        self.used.insert(key.as_str());
+        Some(value)
+    }
+
+    /// Returns the keys of the table, such as for a map of named sections.
+    fn keys(&self) -> Vec<String> {
+        self.table.keys().cloned().collect()
+    }
+
+    /// Sets `field` to the key's value if it's there.
+    fn set<T>(
+        &mut self,
+        key: &str,
+        field: &mut T,
+        parse: impl FnOnce(&Value) -> Result<T, String>,
+    ) -> Result<(), String> {
+        if let Some(value) = self.get(key) {
+            *field = parse(value).map_err(|e| format!("{}: {}", self.key_path(key), e))?;
+        }
+        Ok(())
+    }
+
+    /// Sets `field` to the key's value, which must be there.
+    fn require<T>(
+        &mut self,
+        key: &str,
+        field: &mut T,
+        parse: impl FnOnce(&Value) -> Result<T, String>,
+    ) -> Result<(), String> {
+        if !self.table.contains_key(key) {
+            return Err(format!("{} is required", self.key_path(key)));
+        }
+        self.set(key, field, parse)
+    }
+
+    /// Returns the nested table under the key, if it's there.
+    fn section(&mut self, key: &str) -> Result<Option<Section<'a>>, String> {
+        let path = self.key_path(key);
+        match self.get(key) {
+            Some(Value::Table(table)) => Ok(Some(Section::new(path, table))),
+            Some(_) => Err(format!("{} must be a table", path)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the array of tables under the key, which is empty if it isn't there.
+    fn sections(&mut self, key: &str) -> Result<Vec<Section<'a>>, String> {
+        let path = self.key_path(key);
+        match self.get(key) {
+            Some(Value::Array(items)) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| match item {
+                    Value::Table(table) => Ok(Section::new(format!("{}[{}]", path, i), table)),
+                    _ => Err(format!("{}[{}] must be a table", path, i)),
+                })
+                .collect(),
+            Some(_) => Err(format!("{} must be an array of tables", path)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Fails if the table has any keys that weren't read.
+    fn finish(self) -> Result<(), String> {
+        match self.table.keys().find(|k| !self.used.contains(k.as_str())) {
+            Some(key) => Err(format!("unknown setting {}", self.key_path(key))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn type_error(expected: &str, value: &Value) -> String {
+    format!("expected {}, found {}", expected, value.type_str())
+}
+
+fn string(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(type_error("a string", value)),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        _ => Err(type_error("a boolean", value)),
+    }
+}
+
+fn integer<T: TryFrom<i64>>(value: &Value) -> Result<T, String> {
+    match value {
+        Value::Integer(i) => T::try_from(*i).map_err(|_| format!("{} is out of range", i)),
+        _ => Err(type_error("an integer", value)),
+    }
+}
+
+fn float(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Float(f) => Ok(*f),
+        Value::Integer(i) => Ok(*i as f64),
+        _ => Err(type_error("a number", value)),
+    }
+}
+
+fn path(value: &Value) -> Result<PathBuf, String> {
+    string(value).map(PathBuf::from)
+}
+
+fn list<T>(value: &Value, parse: impl Fn(&Value) -> Result<T, String>) -> Result<Vec<T>, String> {
+    match value {
+        Value::Array(items) => items.iter().map(parse).collect(),
+        _ => Err(type_error("an array", value)),
+    }
+}
+
+fn strings(value: &Value) -> Result<Vec<String>, String> {
+    list(value, string)
+}
+
+fn paths(value: &Value) -> Result<Vec<PathBuf>, String> {
+    list(value, path)
+}
+
+/// Parses a duration given as a number of seconds, or as a string with a unit such as `"500ms"` or `"30s"`.
+fn duration(value: &Value) -> Result<Duration, String> {
+    let s = match value {
+        Value::Integer(secs) => {
+            return u64::try_from(*secs)
+                .map(Duration::from_secs)
+                .map_err(|_| "durations can't be negative".to_owned())
+        }
+        Value::String(s) => s.trim(),
+        _ => return Err(type_error("a duration", value)),
+    };
+
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("{:?} needs a unit, such as \"{}s\"", s, s))?;
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("{:?} isn't a duration", s))?;
+    let millis = match unit.trim() {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("{:?} has an unknown unit", s)),
+    };
+    number
+        .checked_mul(millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("{:?} is too long", s))
+}
+
+fn proxy_protocol_mode(value: &Value) -> Result<ProxyProtocolMode, String> {
+    match string(value)?.as_str() {
+        "off" => Ok(ProxyProtocolMode::Off),
+        "optional" => Ok(ProxyProtocolMode::Optional),
+        "required" => Ok(ProxyProtocolMode::Required),
+        other => Err(format!(
+            "unknown mode {:?}, expected \"off\", \"optional\" or \"required\"",
+            other
+        )),
+    }
+}
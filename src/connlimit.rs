@@ -0,0 +1,77 @@
+//! Limits on how many client connections are open at once.
+//!
+//! Each worker thread counts its own connections, while the count per client IP is shared by every thread, since
+//! `SO_REUSEPORT` spreads one client's connections across them. Connections over either limit are closed as soon as
+//! they're accepted, or once their PROXY protocol header names the client.
+
+use crate::config::ConnectionConfig;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Mutex, OnceLock};
+
+/// Key: client IP
+static PER_IP: OnceLock<Mutex<HashMap<IpAddr, usize>>> = OnceLock::new();
+
+/// Hands out permits for a worker thread's connections.
+pub struct ConnectionLimiter {
+    open: Rc<Cell<usize>>,
+    max_per_thread: usize,
+    max_per_ip: usize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &ConnectionConfig) -> ConnectionLimiter {
+        ConnectionLimiter {
+            open: Rc::new(Cell::new(0)),
+            max_per_thread: config.max_connections_per_thread,
+            max_per_ip: config.max_connections_per_ip,
+        }
+    }
+
+    /// The number of connections open on this thread.
+    pub fn open(&self) -> usize {
+        self.open.get()
+    }
+
+    /// Returns a permit for a new connection from a client, or [None] if that would go over a limit.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        if self.open.get() >= self.max_per_thread {
+            return None;
+        }
+
+        let mut per_ip = PER_IP.get_or_init(Default::default).lock().unwrap();
+        let count = per_ip.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+
+        self.open.set(self.open.get() + 1);
+        Some(ConnectionPermit {
+            open: self.open.clone(),
+            ip,
+        })
+    }
+}
+
+/// Counts a connection against the limits until dropped.
+pub struct ConnectionPermit {
+    open: Rc<Cell<usize>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.open.set(self.open.get() - 1);
+
+        let mut per_ip = PER_IP.get().unwrap().lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use monoio::buf::{IoBuf, IoBufMut};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The `statx` mask bit requesting direct I/O alignment information.
+/// Not exported by every libc release, so it is defined here.
+const STATX_DIOALIGN: u32 = 0x2000;
+
+/// The alignment used when the filesystem does not report its direct I/O requirements.
+const FALLBACK_DIO_ALIGN: u32 = 4096;
+
+/// A heap buffer whose start address and capacity are aligned for `O_DIRECT` reads and writes.
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+    init: usize,
+}
+
+impl AlignedBuf {
+    /// Allocates a zeroed buffer with the specified capacity and alignment.
+    /// The capacity must be a multiple of the alignment.
+    pub fn new(capacity: usize, align: usize) -> Self {
+        assert!(capacity > 0, "BUG: aligned buffer capacity must not be zero");
+        assert_eq!(
+            capacity % align,
+            0,
+            "BUG: aligned buffer capacity must be a multiple of the alignment"
+        );
+
+        let layout = Layout::from_size_align(capacity, align).expect("invalid buffer layout");
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+
+        Self {
+            ptr,
+            layout,
+            init: 0,
+        }
+    }
+
+    /// The total capacity of the buffer.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// The initialized portion of the buffer.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.init) }
+    }
+
+    /// Marks the buffer as empty without touching its contents.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.init = 0;
+    }
+
+    /// Appends bytes to the initialized portion.
+    /// Panics if they don't fit.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        assert!(
+            self.init + data.len() <= self.capacity(),
+            "BUG: aligned buffer overflow"
+        );
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.init), data.len())
+        };
+        self.init += data.len();
+    }
+
+    /// Extends the initialized portion to the next multiple of the alignment so it can be written with `O_DIRECT`,
+    /// and returns its new length. The padding keeps whatever the buffer last held there.
+    pub fn pad_to_alignment(&mut self) -> usize {
+        self.init = self.init.next_multiple_of(self.layout.align());
+        self.init
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+unsafe impl IoBuf for AlignedBuf {
+    #[inline]
+    fn read_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    #[inline]
+    fn bytes_init(&self) -> usize {
+        self.init
+    }
+}
+
+unsafe impl IoBufMut for AlignedBuf {
+    #[inline]
+    fn write_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    #[inline]
+    fn bytes_total(&mut self) -> usize {
+        self.layout.size()
+    }
+
+    #[inline]
+    unsafe fn set_init(&mut self, pos: usize) {
+        self.init = pos;
+    }
+}
+
+/// A per-thread pool of reusable aligned buffers, each one block in size.
+///
+/// Buffers are handed out with [AlignedBufPool::take] and given back with [AlignedBufPool::give].
+/// Monoio does not expose io_uring buffer registration, so buffers are not registered as fixed buffers;
+/// reusing the same allocations still avoids allocator churn and keeps their pages resident.
+pub struct AlignedBufPool {
+    buf_size: usize,
+    align: usize,
+    max_idle: usize,
+    idle: RefCell<Vec<AlignedBuf>>,
+}
+
+impl AlignedBufPool {
+    /// Creates a pool and preallocates `max_idle` buffers.
+    pub fn new(buf_size: usize, align: usize, max_idle: usize) -> Self {
+        let idle = (0..max_idle)
+            .map(|_| AlignedBuf::new(buf_size, align))
+            .collect();
+
+        Self {
+            buf_size,
+            align,
+            max_idle,
+            idle: RefCell::new(idle),
+        }
+    }
+
+    /// Takes a buffer from the pool, allocating a new one if the pool is empty.
+    pub fn take(&self) -> AlignedBuf {
+        match self.idle.borrow_mut().pop() {
+            Some(buf) => buf,
+            None => AlignedBuf::new(self.buf_size, self.align),
+        }
+    }
+
+    /// Returns a buffer to the pool.
+    /// The buffer is freed if the pool is already full.
+    pub fn give(&self, mut buf: AlignedBuf) {
+        let mut idle = self.idle.borrow_mut();
+        if idle.len() < self.max_idle && buf.capacity() == self.buf_size {
+            buf.clear();
+            idle.push(buf);
+        }
+    }
+}
+
+/// Returns the offset alignment required for direct I/O on files under the specified path.
+/// Falls back to 4096 if the kernel or filesystem does not report it.
+pub fn dio_alignment(path: &Path) -> io::Result<u32> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            0,
+            STATX_DIOALIGN,
+            &mut stx,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if stx.stx_mask & STATX_DIOALIGN == 0 || stx.stx_dio_offset_align == 0 {
+        return Ok(FALLBACK_DIO_ALIGN);
+    }
+
+    Ok(stx.stx_dio_offset_align)
+}
+
+/// Checks that the specified block size can be used for direct I/O under the specified cache root.
+/// Returns the required alignment if it can.
+pub fn validate_block_size(cache_root: &Path, block_size: u32) -> io::Result<u32> {
+    let align = dio_alignment(cache_root)?;
+
+    if block_size == 0 || block_size % align != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "block size {} is not a multiple of the logical block size {} of {}",
+                block_size,
+                align,
+                cache_root.display()
+            ),
+        ));
+    }
+
+    Ok(align)
+}
+
+/// Reads a whole block file into a pooled buffer.
+/// The last block of an object may be short, in which case the returned buffer holds fewer than `block_size` bytes.
+pub async fn read_block(file: &monoio::fs::File, pool: &AlignedBufPool) -> io::Result<AlignedBuf> {
+    let buf = pool.take();
+    // Every block is its own file, so the block starts at the beginning of it.
+    let (res, buf) = file.read_at(buf, 0).await;
+    match res {
+        Ok(_) => Ok(buf),
+        Err(e) => {
+            pool.give(buf);
+            Err(e)
+        }
+    }
+}
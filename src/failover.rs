@@ -0,0 +1,490 @@
+//! Retrying and resuming origin fills.
+//!
+//! `GET` and `HEAD` fills that fail to connect, time out or get a retryable status are tried again, on another
+//! server of the host's pool when there is one. A fill whose body breaks off partway is resumed with a `Range`
+//! request from the start of the block being filled, guarded by `If-Range` so a changed object is never stitched
+//! onto the old one. The client keeps receiving one continuous body. The blocks a partial hit is missing are fetched
+//! with `Range` requests guarded the same way.
+
+use crate::cache::content_range;
+use crate::config::OriginConfig;
+use crate::originpool::{OriginLease, OriginPool};
+use bytes::Bytes;
+use http::header::{
+    CONTENT_LENGTH, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri};
+use monoio_http::common::body::{Body, HttpBody, StreamHint};
+use monoio_http::common::error::HttpError;
+use monoio_http::common::response::Response;
+use monoio_http::h1::payload::Payload;
+use monoio_http_client::Client;
+use std::cmp::{max, min};
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Where a fill is fetched from.
+#[derive(Clone)]
+pub enum OriginTarget {
+    /// A server picked from the host's pool.
+    Pool {
+        pool: Rc<OriginPool>,
+        cache_key: String,
+    },
+
+    /// A fixed origin URI, such as one set by a hook.
+    Fixed(Uri),
+}
+
+impl OriginTarget {
+    /// Picks the server to fetch the client URI from, avoiding the servers in `tried` if the pool has others.
+    fn select(&self, uri: &Uri, tried: &[usize]) -> Option<(Uri, Option<OriginLease>)> {
+        match self {
+            OriginTarget::Pool { pool, cache_key } => {
+                let lease = pool.select(cache_key, tried)?;
+                Some((lease.origin_uri(uri), Some(lease)))
+            }
+            OriginTarget::Fixed(origin) => Some((origin.clone(), None)),
+        }
+    }
+}
+
+/// A fill's origin response.
+pub struct Fill {
+    pub response: Response<HttpBody>,
+
+    /// The pool server the response came from, if the target was a pool.
+    pub lease: Option<OriginLease>,
+}
+
+/// Whether requests with the method can be retried and resumed.
+fn is_idempotent(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+fn timed_out(uri: &Uri) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("origin {} timed out", uri))
+}
+
+/// Sends a request to a pool server with the pool's request settings, or as-is to a fixed origin.
+async fn send(
+    client: &Client,
+    lease: &Option<OriginLease>,
+    mut req: Request<HttpBody>,
+) -> Result<Response<HttpBody>, Box<dyn std::error::Error>> {
+    match lease {
+        Some(lease) => {
+            let connector = lease.pool().connector();
+            connector.prepare(req.headers_mut());
+            connector.send(client, req).await
+        }
+        None => Ok(client.send_request(req).await?),
+    }
+}
+
+/// Sends a request to the origin, retrying `GET` and `HEAD` requests as configured.
+/// `uri` is the client's request URI; `headers` are sent with every attempt.
+pub async fn fetch(
+    client: &Client,
+    config: &OriginConfig,
+    target: &OriginTarget,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: HttpBody,
+) -> Result<Fill, Box<dyn std::error::Error>> {
+    let attempts = if is_idempotent(method) {
+        max(config.max_attempts, 1)
+    } else {
+        1
+    };
+    let mut body = Some(body);
+    let mut tried = Vec::new();
+
+    for attempt in 1..=attempts {
+        let last = attempt == attempts;
+        let (origin_uri, lease) = target
+            .select(uri, &tried)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "origin pool is empty"))?;
+
+        let mut req = Request::builder()
+            .method(method.clone())
+            .uri(origin_uri.clone());
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        // Only the first attempt has the client's body; retried methods don't carry one.
+        let req = req.body(body.take().unwrap_or_else(|| HttpBody::from(Payload::None)))?;
+
+        match monoio::time::timeout(config.response_timeout, send(client, &lease, req)).await {
+            Ok(Ok(res)) => {
+                let status = res.status();
+                if let Some(lease) = &lease {
+                    lease.record_response(status);
+                }
+                if last || !config.retry_statuses.contains(&status.as_u16()) {
+                    return Ok(Fill {
+                        response: res,
+                        lease,
+                    });
+                }
+                println!("origin {} returned {}, retrying", origin_uri, status);
+            }
+            Ok(Err(e)) => {
+                if let Some(lease) = &lease {
+                    lease.record_error();
+                }
+                if last {
+                    return Err(e.into());
+                }
+                println!("origin {} failed: {}, retrying", origin_uri, e);
+            }
+            Err(_) => {
+                if let Some(lease) = &lease {
+                    lease.record_error();
+                }
+                if last {
+                    return Err(timed_out(&origin_uri).into());
+                }
+                println!("origin {} timed out, retrying", origin_uri);
+            }
+        }
+
+        if let Some(lease) = &lease {
+            tried.push(lease.index());
+        }
+    }
+
+    unreachable!("the last attempt should have returned")
+}
+
+/// Returns the object byte range a response body covers, if it is known.
+fn body_range(status: StatusCode, headers: &HeaderMap) -> Option<(u64, u64)> {
+    match status {
+        StatusCode::OK => {
+            let len: u64 = headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()?;
+            (len > 0).then(|| (0, len - 1))
+        }
+        StatusCode::PARTIAL_CONTENT => {
+            let (start, end, _) = content_range(headers)?;
+            Some((start, end))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the value to send in `If-Range` so a resume only succeeds if the object hasn't changed.
+/// Weak entity tags can't be used for ranges, so they fall back to the modification time.
+pub fn validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    if let Some(etag) = headers.get(ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            return Some(etag.clone());
+        }
+    }
+    headers.get(LAST_MODIFIED).cloned()
+}
+
+/// Request headers that make the origin's answer depend on what the client already has.
+pub const CONDITIONAL_HEADERS: [HeaderName; 4] = [
+    IF_MATCH,
+    IF_NONE_MATCH,
+    IF_MODIFIED_SINCE,
+    IF_UNMODIFIED_SINCE,
+];
+
+/// Copies the headers of a request for the whole object into one for a range of it, leaving out the client's range
+/// and conditions.
+fn range_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in CONDITIONAL_HEADERS.into_iter().chain([RANGE, IF_RANGE]) {
+        headers.remove(name);
+    }
+    headers
+}
+
+/// What's needed to fetch the blocks of a partial hit that aren't cached.
+pub struct RangeFetcher {
+    pub client: Rc<Client>,
+    pub config: OriginConfig,
+    pub target: OriginTarget,
+
+    /// The client's request URI.
+    pub uri: Uri,
+
+    /// The headers sent to the origin with each range request, apart from the range and its condition.
+    pub headers: HeaderMap,
+
+    /// The cache block size resumes are aligned to.
+    pub block_size: u64,
+}
+
+impl RangeFetcher {
+    /// Fetches the object bytes from the start to the end (inclusive), provided the origin's copy still matches
+    /// `validator`. Returns [None] if it doesn't.
+    pub async fn fetch(
+        &self,
+        start: u64,
+        end: u64,
+        validator: &HeaderValue,
+    ) -> Result<Option<FillBody>, Box<dyn std::error::Error>> {
+        let mut headers = range_headers(&self.headers);
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&format!("bytes={}-{}", start, end))?,
+        );
+        headers.insert(IF_RANGE, validator.clone());
+
+        let fill = fetch(
+            &self.client,
+            &self.config,
+            &self.target,
+            &Method::GET,
+            &self.uri,
+            &headers,
+            HttpBody::from(Payload::None),
+        )
+        .await?;
+        let (parts, body) = fill.response.into_parts();
+        // A 200 means the object changed, or the server ignored the range.
+        if parts.status != StatusCode::PARTIAL_CONTENT
+            || body_range(parts.status, &parts.headers) != Some((start, end))
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(resumable(
+            &Method::GET,
+            parts.status,
+            &parts.headers,
+            body,
+            fill.lease,
+            Resume {
+                client: self.client.clone(),
+                target: self.target.clone(),
+                uri: self.uri.clone(),
+                headers,
+                block_size: self.block_size,
+                max_resumes: self.config.max_resumes,
+                timeout: self.config.response_timeout,
+            },
+        )))
+    }
+}
+
+/// What's needed to resume a broken fill.
+pub struct Resume {
+    pub client: Rc<Client>,
+    pub target: OriginTarget,
+
+    /// The client's request URI.
+    pub uri: Uri,
+
+    /// The headers sent to the origin with the original request.
+    pub headers: HeaderMap,
+
+    /// The cache block size resumes are aligned to.
+    pub block_size: u64,
+
+    pub max_resumes: u32,
+    pub timeout: Duration,
+}
+
+/// Wraps a fill's response body so it resumes from the origin if it breaks off.
+/// The body is passed through untouched if the fill can't be resumed, such as when the origin gives no validator or
+/// the method isn't idempotent.
+pub fn resumable(
+    method: &Method,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: HttpBody,
+    lease: Option<OriginLease>,
+    resume: Resume,
+) -> FillBody {
+    if method != Method::GET || resume.max_resumes == 0 {
+        return FillBody { body, state: None };
+    }
+    let (Some((start, end)), Some(validator)) = (body_range(status, headers), validator(headers))
+    else {
+        return FillBody { body, state: None };
+    };
+
+    FillBody {
+        body,
+        state: Some(ResumeState {
+            resume,
+            validator,
+            start,
+            end,
+            lease,
+            tried: Vec::new(),
+            position: start,
+            skip: 0,
+            resumes: 0,
+        }),
+    }
+}
+
+/// A fill's response body.
+/// Nothing is read from the origin until the body is asked for its next chunk, and a broken-off body is only
+/// reopened then too, so the client sets the pace all the way back to the origin connection.
+pub struct FillBody {
+    body: HttpBody,
+
+    /// How to resume the body, if it can be.
+    state: Option<ResumeState>,
+}
+
+struct ResumeState {
+    resume: Resume,
+    validator: HeaderValue,
+
+    /// The object byte range the client is being sent (inclusive).
+    start: u64,
+    end: u64,
+
+    /// The server currently being read from.
+    lease: Option<OriginLease>,
+    tried: Vec<usize>,
+
+    /// The next object byte to send to the client.
+    position: u64,
+
+    /// Bytes at the start of a resumed body the client already has.
+    skip: u64,
+
+    resumes: u32,
+}
+
+impl ResumeState {
+    /// Requests the rest of the object from `from` on, from another server if there is one.
+    async fn reopen(&mut self, from: u64) -> Option<HttpBody> {
+        if let Some(lease) = self.lease.take() {
+            lease.record_error();
+            self.tried.push(lease.index());
+        }
+
+        let (origin_uri, lease) = self.resume.target.select(&self.resume.uri, &self.tried)?;
+        let mut req = Request::builder()
+            .method(Method::GET)
+            .uri(origin_uri.clone());
+        // Conditions from the client could turn the resume into a 304 or 412.
+        for (k, v) in &range_headers(&self.resume.headers) {
+            req = req.header(k, v);
+        }
+        let req = req
+            .header(RANGE, format!("bytes={}-{}", from, self.end))
+            .header(IF_RANGE, self.validator.clone())
+            .body(HttpBody::from(Payload::None))
+            .ok()?;
+
+        let res = match monoio::time::timeout(
+            self.resume.timeout,
+            send(&self.resume.client, &lease, req),
+        )
+        .await
+        {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                println!("resuming from origin {} failed: {}", origin_uri, e);
+                self.lease = lease;
+                return None;
+            }
+            Err(_) => {
+                println!("resuming from origin {} timed out", origin_uri);
+                self.lease = lease;
+                return None;
+            }
+        };
+
+        if let Some(lease) = &lease {
+            lease.record_response(res.status());
+        }
+        self.lease = lease;
+
+        // A 200 means the object changed, or the server ignored the range.
+        let (parts, body) = res.into_parts();
+        if body_range(parts.status, &parts.headers) != Some((from, self.end))
+            || parts.status != StatusCode::PARTIAL_CONTENT
+        {
+            println!(
+                "origin {} answered resume from byte {} with {}, giving up",
+                origin_uri, from, parts.status
+            );
+            return None;
+        }
+
+        Some(body)
+    }
+}
+
+impl FillBody {
+    pub async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        let Some(state) = &mut self.state else {
+            return self.body.next_data().await;
+        };
+
+        loop {
+            let err: HttpError =
+                match monoio::time::timeout(state.resume.timeout, self.body.next_data()).await {
+                    Ok(Some(Ok(mut data))) => {
+                        if state.skip > 0 {
+                            let n = min(state.skip, data.len() as u64);
+                            data = data.slice(n as usize..);
+                            state.skip -= n;
+                        }
+                        if data.is_empty() {
+                            continue;
+                        }
+                        state.position += data.len() as u64;
+                        return Some(Ok(data));
+                    }
+                    Ok(None) if state.position > state.end => return None,
+                    Ok(None) => {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "origin body ended early")
+                            .into()
+                    }
+                    Ok(Some(Err(e))) => e,
+                    Err(_) => io::Error::new(io::ErrorKind::TimedOut, "origin body stalled").into(),
+                };
+
+            if state.resumes >= state.resume.max_resumes {
+                return Some(Err(self.give_up(err)));
+            }
+            state.resumes += 1;
+
+            // Restart the block being filled so it can be written whole.
+            let from = max(
+                state.start,
+                state.position - state.position % state.resume.block_size,
+            );
+            println!(
+                "fill of {} broke off at byte {} ({}), resuming from byte {}",
+                state.resume.uri, state.position, err, from
+            );
+            match state.reopen(from).await {
+                Some(next) => {
+                    self.body = next;
+                    state.skip = state.position - from;
+                }
+                None => return Some(Err(self.give_up(err))),
+            }
+        }
+    }
+
+    /// Stops reading from a fill that can't be resumed, so the broken body isn't read again.
+    fn give_up(&mut self, err: HttpError) -> HttpError {
+        self.body = HttpBody::from(Payload::None);
+        self.state = None;
+        err
+    }
+
+    pub fn stream_hint(&self) -> StreamHint {
+        match self.state {
+            // A resumed body arrives in pieces, however the first response was framed.
+            Some(_) => StreamHint::Stream,
+            None => self.body.stream_hint(),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Hop-by-hop header stripping and the headers that tell origins about the client.
+//!
+//! Hop-by-hop headers (RFC 9110 section 7.6.1) describe a single connection, so they are removed from messages in
+//! both directions, along with any header the `Connection` header names.
+//!
+//! Incoming `X-Forwarded-*` and `Forwarded` headers are only trusted when the connection comes from a trusted proxy,
+//! such as a load balancer in front of stavka. Otherwise a client could claim any address, so they are replaced.
+
+use crate::config::ForwardingConfig;
+use http::header::{
+    HeaderName, CONNECTION, FORWARDED, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE, VIA,
+};
+use http::{HeaderMap, HeaderValue, Version};
+use std::net::IpAddr;
+
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Removes hop-by-hop headers from a message.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Headers named in `Connection` go first, since they are only known from it.
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in [
+        CONNECTION,
+        KEEP_ALIVE,
+        PROXY_CONNECTION,
+        TRANSFER_ENCODING,
+        TE,
+        TRAILER,
+        UPGRADE,
+        PROXY_AUTHORIZATION,
+        PROXY_AUTHENTICATE,
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// An IP address range, such as `10.0.0.0/8`.
+struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Parses a range in CIDR notation, or a single address.
+    fn parse(s: &str) -> Option<IpRange> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr.parse::<IpAddr>().ok()?, Some(len.parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        (prefix_len <= max_len).then_some(IpRange { addr, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on dual-stack sockets show up as mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Formats a node for `Forwarded`, which needs IPv6 addresses bracketed and quoted.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Appends a value to a comma-separated header, or sets it if it isn't there.
+fn append_list(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let joined = match headers.get(&name).and_then(|v| v.to_str().ok()) {
+        Some(existing) if !existing.is_empty() => format!("{}, {}", existing, value),
+        _ => value.to_owned(),
+    };
+    if let Ok(joined) = HeaderValue::from_str(&joined) {
+        headers.insert(name, joined);
+    }
+}
+
+fn version_str(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Adds the forwarding headers to requests and `Via` to responses.
+pub struct Forwarding {
+    config: ForwardingConfig,
+    trusted: Vec<IpRange>,
+}
+
+impl Forwarding {
+    /// Fails if a trusted proxy range can't be parsed.
+    pub fn new(config: &ForwardingConfig) -> Result<Forwarding, String> {
+        let trusted = config
+            .trusted_proxies
+            .iter()
+            .map(|s| IpRange::parse(s).ok_or_else(|| format!("invalid trusted proxy range {}", s)))
+            .collect::<Result<_, _>>()?;
+        Ok(Forwarding {
+            config: config.clone(),
+            trusted,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|range| range.contains(ip))
+    }
+
+    /// Returns the address of the client that made the request.
+    /// When the request came through trusted proxies, this is the nearest address in `X-Forwarded-For` that isn't
+    /// one of them.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+        let chain: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        for hop in chain.iter().rev() {
+            let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Adds the forwarding headers to a request about to be sent to an origin.
+    /// `peer` is the address the connection came from and `proto` the scheme the client used.
+    pub fn apply_to_request(
+        &self,
+        headers: &mut HeaderMap,
+        version: Version,
+        peer: IpAddr,
+        proto: &str,
+    ) {
+        let trusted = self.is_trusted(peer);
+        let host = headers.get(HOST).cloned();
+
+        if self.config.x_forwarded_for {
+            if !trusted {
+                headers.remove(X_FORWARDED_FOR);
+            }
+            append_list(headers, X_FORWARDED_FOR, &peer.to_string());
+        }
+
+        // The scheme and host are the ones the client used, which a trusted proxy in front already recorded.
+        if self.config.x_forwarded_proto && !(trusted && headers.contains_key(X_FORWARDED_PROTO)) {
+            headers.insert(X_FORWARDED_PROTO, HeaderValue::from_str(proto).unwrap());
+        }
+        if self.config.x_forwarded_host && !(trusted && headers.contains_key(X_FORWARDED_HOST)) {
+            match &host {
+                Some(host) => headers.insert(X_FORWARDED_HOST, host.clone()),
+                None => headers.remove(X_FORWARDED_HOST),
+            };
+        }
+
+        if self.config.forwarded {
+            if !trusted {
+                headers.remove(FORWARDED);
+            }
+            let mut element = format!("for={};proto={}", forwarded_node(peer), proto);
+            if let Some(host) = host.as_ref().and_then(|h| h.to_str().ok()) {
+                element.push_str(";host=\"");
+                element.push_str(host);
+                element.push('"');
+            }
+            append_list(headers, FORWARDED, &element);
+        }
+
+        self.add_via(headers, version);
+    }
+
+    /// Adds `Via` to a response about to be sent to the client.
+    pub fn apply_to_response(&self, headers: &mut HeaderMap, version: Version) {
+        self.add_via(headers, version);
+    }
+
+    fn add_via(&self, headers: &mut HeaderMap, version: Version) {
+        if self.config.via {
+            append_list(
+                headers,
+                VIA,
+                &format!("{} {}", version_str(version), self.config.via_name),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(list: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in list {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn forwarding(trusted_proxies: &[&str]) -> Forwarding {
+        Forwarding::new(&ForwardingConfig {
+            forwarded: true,
+            trusted_proxies: trusted_proxies.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn strips_hop_by_hop() {
+        let mut h = headers(&[
+            ("connection", "keep-alive, X-Custom"),
+            ("connection", "close"),
+            ("keep-alive", "timeout=5"),
+            ("x-custom", "1"),
+            ("transfer-encoding", "chunked"),
+            ("te", "trailers"),
+            ("trailer", "expires"),
+            ("upgrade", "h2c"),
+            ("proxy-connection", "keep-alive"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("proxy-authenticate", "Basic"),
+            ("content-type", "text/plain"),
+            ("cache-control", "max-age=60"),
+        ]);
+        strip_hop_by_hop(&mut h);
+        let mut names: Vec<&str> = h.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["cache-control", "content-type"]);
+    }
+
+    #[test]
+    fn ignores_invalid_connection_names() {
+        let mut h = headers(&[("connection", "bad name, ,x-custom"), ("x-custom", "1")]);
+        strip_hop_by_hop(&mut h);
+        assert!(h.is_empty());
+    }
+
+    #[test]
+    fn ip_ranges() {
+        let range = IpRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("11.0.0.1")));
+        assert!(!range.contains(ip("::1")));
+
+        let range = IpRange::parse("2001:db8::/32").unwrap();
+        assert!(range.contains(ip("2001:db8:1::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+
+        assert!(IpRange::parse("192.0.2.1")
+            .unwrap()
+            .contains(ip("192.0.2.1")));
+        assert!(!IpRange::parse("192.0.2.1")
+            .unwrap()
+            .contains(ip("192.0.2.2")));
+        assert!(IpRange::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("203.0.113.9")));
+
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+        assert!(IpRange::parse("::/129").is_none());
+        assert!(IpRange::parse("example.com").is_none());
+        assert!(Forwarding::new(&ForwardingConfig {
+            trusted_proxies: vec!["10.0.0.0/x".to_owned()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn client_ip_from_untrusted_peer() {
+        let f = forwarding(&["10.0.0.0/8"]);
+        let h = headers(&[("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(f.client_ip(&h, ip("203.0.113.9")), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn client_ip_through_trusted_proxies() {
+        let f = forwarding(&["10.0.0.0/8"]);
+        let h = headers(&[
+            ("x-forwarded-for", "198.51.100.7, 203.0.113.9"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(f.client_ip(&h, ip("10.0.0.1")), ip("203.0.113.9"));
+
+        // A chain of nothing but trusted proxies ends at the farthest one.
+        let h = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(f.client_ip(&h, ip("10.0.0.1")), ip("10.0.0.3"));
+
+        // Garbage stops the walk at the last address that parsed.
+        let h = headers(&[("x-forwarded-for", "198.51.100.7, unknown, 10.0.0.2")]);
+        assert_eq!(f.client_ip(&h, ip("10.0.0.1")), ip("10.0.0.2"));
+
+        assert_eq!(
+            f.client_ip(&HeaderMap::new(), ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn replaces_headers_from_untrusted_peer() {
+        let f = forwarding(&["10.0.0.0/8"]);
+        let mut h = headers(&[
+            ("host", "example.com"),
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "evil.example"),
+            ("forwarded", "for=192.0.2.1"),
+        ]);
+        f.apply_to_request(&mut h, Version::HTTP_11, ip("203.0.113.9"), "http");
+        assert_eq!(h["x-forwarded-for"], "203.0.113.9");
+        assert_eq!(h["x-forwarded-proto"], "http");
+        assert_eq!(h["x-forwarded-host"], "example.com");
+        assert_eq!(
+            h["forwarded"],
+            "for=203.0.113.9;proto=http;host=\"example.com\""
+        );
+        assert_eq!(h["via"], "1.1 stavka");
+    }
+
+    #[test]
+    fn extends_headers_from_trusted_peer() {
+        let f = forwarding(&["10.0.0.0/8"]);
+        let mut h = headers(&[
+            ("host", "origin.internal"),
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+            ("forwarded", "for=192.0.2.1;proto=https"),
+            ("via", "1.1 lb"),
+        ]);
+        f.apply_to_request(&mut h, Version::HTTP_2, ip("10.0.0.1"), "http");
+        assert_eq!(h["x-forwarded-for"], "192.0.2.1, 10.0.0.1");
+        assert_eq!(h["x-forwarded-proto"], "https");
+        assert_eq!(h["x-forwarded-host"], "example.com");
+        assert_eq!(
+            h["forwarded"],
+            "for=192.0.2.1;proto=https, for=10.0.0.1;proto=http;host=\"origin.internal\""
+        );
+        assert_eq!(h["via"], "1.1 lb, 2 stavka");
+    }
+
+    #[test]
+    fn brackets_ipv6_in_forwarded() {
+        let f = forwarding(&[]);
+        let mut h = HeaderMap::new();
+        f.apply_to_request(&mut h, Version::HTTP_3, ip("2001:db8::1"), "https");
+        assert_eq!(h["forwarded"], "for=\"[2001:db8::1]\";proto=https");
+        assert_eq!(h["x-forwarded-for"], "2001:db8::1");
+        assert!(!h.contains_key("x-forwarded-host"));
+    }
+
+    #[test]
+    fn respects_disabled_headers() {
+        let f = Forwarding::new(&ForwardingConfig {
+            x_forwarded_for: false,
+            x_forwarded_proto: false,
+            x_forwarded_host: false,
+            forwarded: false,
+            via: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut h = headers(&[("host", "example.com"), ("x-forwarded-for", "192.0.2.1")]);
+        f.apply_to_request(&mut h, Version::HTTP_11, ip("203.0.113.9"), "http");
+        f.apply_to_response(&mut h, Version::HTTP_11);
+        assert_eq!(h.len(), 2);
+        assert_eq!(h["x-forwarded-for"], "192.0.2.1");
+    }
+
+    #[test]
+    fn adds_via_to_responses() {
+        let f = forwarding(&[]);
+        let mut h = headers(&[("via", "1.1 origin")]);
+        f.apply_to_response(&mut h, Version::HTTP_10);
+        assert_eq!(h["via"], "1.1 origin, 1.0 stavka");
+    }
+}
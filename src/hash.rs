@@ -15,34 +15,64 @@ pub struct FileBlockInfo {
 
     /// The block number.
     /// Block numbers start at 0.
-    pub(crate) block_num: u16,
+    /// They are written in decimal, so names don't depend on the width of this field.
+    pub(crate) block_num: u64,
 }
 
 /// The hash of a file block.
 /// Can be used as a key to identify a file block.
 pub type FileBlockHash = String;
 
-/// Creates a hash for a file block.
-pub fn create_file_block_hash(path: &str, block: FileBlockInfo) -> FileBlockHash {
+/// The hash of an object (a cached file as a whole).
+/// Used as the name of the object's meta file, and as the prefix of its block hashes.
+pub type ObjectHash = String;
+
+/// Builds the cache key for a request.
+/// The key is the full request URL, which is also what purges match against. A site's `http` and `https` objects
+/// get separate keys, which purges select together.
+pub fn cache_key(scheme: &str, host: &str, path_and_query: &str) -> String {
+    let mut key = String::with_capacity(scheme.len() + 3 + host.len() + path_and_query.len());
+    key.push_str(scheme);
+    key.push_str("://");
+    key.push_str(host);
+    key.push_str(path_and_query);
+    key
+}
+
+/// Creates a hash for an object from its cache key.
+pub fn create_object_hash(key: &str) -> ObjectHash {
+    create_object_hash_with_capacity(key, 0)
+}
+
+/// Creates a hash for an object, reserving room for the specified number of extra bytes.
+fn create_object_hash_with_capacity(key: &str, extra: usize) -> ObjectHash {
     // Hash with xxh3.
     let mut hasher = xxhash_rust::xxh3::Xxh3::with_seed(0);
 
-    hasher.write(path.as_bytes());
+    hasher.write(key.as_bytes());
 
     let hash = hasher.finish();
     let hash = hash.to_le_bytes();
 
+    let mut hash_str = String::with_capacity((hash.len() * 2) + extra);
+
+    for byte in hash {
+        let hex = format!("{:02x}", byte);
+        hash_str.push_str(&hex);
+    }
+
+    hash_str
+}
+
+/// Creates a hash for a file block.
+pub fn create_file_block_hash(path: &str, block: FileBlockInfo) -> FileBlockHash {
     // The average length of the filename trailer.
     // The trailer includes the block size and the block number.
     const FILENAME_PREFIX: &str = ".fb";
     const FILENAME_TRAILER_AVG_LEN: usize = FILENAME_PREFIX.len() + 8 + 1 + 4; // .fb99999999-9999;
-    let mut hash_str = String::with_capacity((hash.len() * 2) + FILENAME_TRAILER_AVG_LEN);
 
     // Start with hex.
-    for byte in hash {
-        let hex = format!("{:02x}", byte);
-        hash_str.push_str(&hex);
-    }
+    let mut hash_str = create_object_hash_with_capacity(path, FILENAME_TRAILER_AVG_LEN);
 
     // Push trailer.
     hash_str.push_str(FILENAME_PREFIX);
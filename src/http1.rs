@@ -0,0 +1,520 @@
+//! HTTP/1.1 client connections.
+//!
+//! Requests are decoded on the connection's task and handed to a second task, which handles them one at a time and
+//! writes the responses in order. A client can pipeline requests while earlier responses are still being sent, up to
+//! a limit; beyond that, the connection isn't read until a response goes out.
+//!
+//! A request head has to arrive within the header timeout of its first byte and fit in the header size limit, or it
+//! gets a `408` or `431` and the connection is closed. Requests that can't be decoded get a `400`. Keep-alive
+//! connections that stay idle, and request bodies that stall, are closed without a response.
+//!
+//! Once the thread starts draining, idle connections are closed and the next response on each busy one carries
+//! `Connection: close`.
+//!
+//! Cached bodies skip the encoder and go straight from the block files to the socket, spliced on plain connections.
+
+use crate::body::ResponseBody;
+use crate::shutdown::Shutdown;
+use crate::zerocopy::{BlockDestination, BlockServeMode};
+use crate::{handle_request, ConnectionGuard, ConnectionInfo, ThreadContext};
+use http::header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::response::Parts;
+use http::{response::Builder, HeaderValue, StatusCode, Version};
+use monoio::buf::{IoBufMut, IoVecBufMut};
+use monoio::io::sink::SinkExt;
+use monoio::io::stream::Stream;
+use monoio::io::{
+    AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, OwnedWriteHalf, Split, Splitable,
+};
+use monoio::time::{sleep, Instant};
+use monoio::BufResult;
+use monoio_http::common::error::HttpError;
+use monoio_http::common::request::Request;
+use monoio_http::common::response::Response;
+use monoio_http::h1::codec::{decoder::RequestDecoder, encoder::GenericEncoder};
+use monoio_http::util::spsc::{spsc_pair, SPSCReceiver};
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::future::{poll_fn, Future};
+use std::io::Write;
+use std::pin::pin;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Duration;
+
+/// Serves an HTTP/1.1 connection until the client closes it or a limit is reached.
+pub async fn serve<S>(stream: S, conn: ConnectionInfo, ctx: Rc<ThreadContext>)
+where
+    S: Split + AsyncReadRent + AsyncWriteRent + 'static,
+    OwnedWriteHalf<S>: BlockDestination,
+{
+    ctx.metrics.connection_opened();
+    let _guard = ConnectionGuard(ctx.clone());
+    let config = &ctx.config.connection;
+
+    let (r, w) = stream.into_split();
+    let head = Rc::new(HeadTracker::default());
+    let mut receiver = RequestDecoder::new(TrackedReader {
+        inner: r,
+        head: head.clone(),
+    });
+    let pipeline = Rc::new(Pipeline::default());
+    let (mut tx, rx) = spsc_pair();
+    monoio::spawn(respond(rx, w, pipeline.clone(), conn, ctx.clone()));
+
+    // A client is expected to send its first request right after connecting.
+    let mut idle_timeout = config.header_read_timeout;
+    let mut body_pending = false;
+    let mut served = 0;
+    loop {
+        if !pipeline.wait_below(config.max_pipelined_requests).await {
+            return;
+        }
+
+        // While an earlier request's body is still coming in, its bytes can't be told apart from the next head's.
+        head.start(if body_pending {
+            None
+        } else {
+            Some(config.max_header_size)
+        });
+        let next = next_request(
+            &mut receiver,
+            &head,
+            idle_timeout,
+            config.header_read_timeout,
+            config.body_read_timeout,
+            &ctx.shutdown,
+        );
+        let incoming = match next.await {
+            Next::Request(req) => {
+                served += 1;
+                body_pending = has_body(&req);
+                let close = wants_close(&req) || served >= config.max_requests_per_connection;
+                Incoming::Request { req, close }
+            }
+            Next::Closed => return,
+            Next::Idle => return,
+            Next::Stalled => {
+                println!("request body from {} stalled", conn.client_addr);
+                return;
+            }
+            Next::TimedOut => Incoming::Reject(StatusCode::REQUEST_TIMEOUT),
+            Next::TooLarge => Incoming::Reject(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            Next::Invalid => Incoming::Reject(StatusCode::BAD_REQUEST),
+        };
+
+        let last = incoming.closes();
+        pipeline.push();
+        if tx.send(incoming).await.is_err() || last {
+            return;
+        }
+        idle_timeout = config.keepalive_timeout;
+    }
+}
+
+/// What the connection's task hands to the responding task.
+enum Incoming {
+    /// A request to handle, and whether the connection closes after it.
+    Request { req: Request, close: bool },
+
+    /// A request that couldn't be read, answered with an error status before closing the connection.
+    Reject(StatusCode),
+}
+
+impl Incoming {
+    fn closes(&self) -> bool {
+        match self {
+            Incoming::Request { close, .. } => *close,
+            Incoming::Reject(_) => true,
+        }
+    }
+}
+
+/// What came of waiting for the next request.
+enum Next {
+    Request(Request),
+
+    /// The client closed the connection.
+    Closed,
+
+    /// The client didn't start another request in time.
+    Idle,
+
+    /// The client started a request head but didn't finish it in time.
+    TimedOut,
+
+    /// The request body stopped arriving.
+    Stalled,
+
+    /// The request head went over the size limit.
+    TooLarge,
+
+    /// The request couldn't be decoded.
+    Invalid,
+}
+
+/// Which timeout applies while waiting for the next request.
+#[derive(Clone, Copy)]
+enum Phase {
+    Idle,
+    Head,
+
+    /// The number of bytes read when the body last made progress.
+    Body(u64),
+}
+
+/// Waits for the next request, applying the timeout for whatever is being read at the time.
+async fn next_request<D, E>(
+    decoder: &mut D,
+    head: &HeadTracker,
+    idle_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    shutdown: &Shutdown,
+) -> Next
+where
+    D: Stream<Item = Result<Request, E>>,
+{
+    let mut next = pin!(decoder.next());
+    let mut phase = if head.limit.get().is_some() {
+        Phase::Idle
+    } else {
+        Phase::Body(head.total.get())
+    };
+    let mut timer = pin!(sleep(match phase {
+        Phase::Body(_) => body_timeout,
+        _ => idle_timeout,
+    }));
+
+    poll_fn(|cx| {
+        // Reads only complete while the decoder is polled, so whatever it read is seen right after.
+        if let Poll::Ready(item) = next.as_mut().poll(cx) {
+            return Poll::Ready(match item {
+                Some(Ok(req)) => Next::Request(req),
+                None => Next::Closed,
+                Some(Err(_)) if head.too_large.get() => Next::TooLarge,
+                Some(Err(_)) => Next::Invalid,
+            });
+        }
+        if head.too_large.get() {
+            return Poll::Ready(Next::TooLarge);
+        }
+
+        if let (Phase::Idle, Some(started)) = (phase, head.started.get()) {
+            phase = Phase::Head;
+            timer.as_mut().reset(started + header_timeout);
+        }
+        if matches!(phase, Phase::Head) && head.complete.get() {
+            // No count matches, so the body timer starts below.
+            phase = Phase::Body(u64::MAX);
+        }
+        if let Phase::Body(seen) = phase {
+            if head.total.get() != seen {
+                phase = Phase::Body(head.total.get());
+                timer.as_mut().reset(Instant::now() + body_timeout);
+            }
+        }
+
+        // A draining thread doesn't wait for requests that haven't started.
+        if matches!(phase, Phase::Idle) && shutdown.poll_draining(cx).is_ready() {
+            return Poll::Ready(Next::Idle);
+        }
+
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(match phase {
+                Phase::Idle => Next::Idle,
+                Phase::Head => Next::TimedOut,
+                Phase::Body(_) => Next::Stalled,
+            }),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Whether a request has a body following its head.
+fn has_body(req: &Request) -> bool {
+    let headers = req.headers();
+    headers.contains_key(TRANSFER_ENCODING)
+        || headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim() != "0")
+}
+
+/// Whether the client asked for the connection to be closed after a request.
+/// HTTP/1.0 connections are only kept alive if the client asks for it.
+fn wants_close(req: &Request) -> bool {
+    let has_token = |token: &str| {
+        req.headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+
+    match req.version() {
+        Version::HTTP_10 => !has_token("keep-alive"),
+        _ => has_token("close"),
+    }
+}
+
+/// Handles requests in the order they arrived and writes their responses.
+async fn respond<W: BlockDestination>(
+    mut receiver: SPSCReceiver<Incoming>,
+    mut writer: W,
+    pipeline: Rc<Pipeline>,
+    conn: ConnectionInfo,
+    ctx: Rc<ThreadContext>,
+) {
+    // Whatever ends this task, the connection's task shouldn't keep waiting on it.
+    let _closed = PipelineClosed(pipeline.clone());
+    let mut mode = BlockServeMode::select(conn.tls);
+
+    while let Some(incoming) = receiver.recv().await {
+        let close = incoming.closes() || ctx.shutdown.is_draining();
+        let mut resp = match incoming {
+            Incoming::Request { req, .. } => match handle_request(req, conn, &ctx).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    println!("request from {} failed: {}", conn.client_addr, e);
+                    return_error(&mut writer, StatusCode::BAD_GATEWAY).await;
+                    return;
+                }
+            },
+            Incoming::Reject(status) => error_response(status),
+        };
+
+        if close {
+            resp.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+        if let Err(e) = send_response(&mut writer, resp, &mut mode).await {
+            println!("response to {} failed: {}", conn.client_addr, e);
+            return;
+        }
+        if close {
+            return;
+        }
+        pipeline.pop();
+    }
+}
+
+/// Writes a response, sending a cached body straight from its block files rather than through the encoder.
+async fn send_response<W: BlockDestination>(
+    writer: &mut W,
+    resp: Response<ResponseBody>,
+    mode: &mut BlockServeMode,
+) -> Result<(), Box<dyn Error>> {
+    let (parts, body) = resp.into_parts();
+    match body {
+        ResponseBody::Cached(body) => {
+            write_head(writer, &parts).await?;
+            body.send_to(writer, mode).await?;
+            writer.flush().await?;
+        }
+        ResponseBody::Logged(mut body) if body.is_cached() => {
+            write_head(writer, &parts).await?;
+            body.send_cached_to(writer, mode).await?;
+            writer.flush().await?;
+        }
+        body => {
+            GenericEncoder::new(&mut *writer)
+                .send_and_flush(Response::from_parts(parts, body))
+                .await
+                .map_err(HttpError::from)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a response head, for a body that is sent without the encoder.
+async fn write_head<W: AsyncWriteRent>(writer: &mut W, parts: &Parts) -> std::io::Result<()> {
+    let mut head = Vec::with_capacity(256);
+    write!(
+        head,
+        "HTTP/1.1 {} {}\r\n",
+        parts.status.as_str(),
+        parts.status.canonical_reason().unwrap_or("")
+    )?;
+    for (name, value) in &parts.headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    writer.write_all(head).await.0?;
+    Ok(())
+}
+
+/// Sends an error response that closes the connection, for a request that failed before its response started.
+async fn return_error<W: BlockDestination>(writer: &mut W, status: StatusCode) {
+    let mut resp = error_response(status);
+    resp.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+    let _ = send_response(writer, resp, &mut BlockServeMode::Buffered).await;
+}
+
+fn error_response(status: StatusCode) -> Response<ResponseBody> {
+    Builder::new()
+        .status(status)
+        .header(CONTENT_LENGTH, 0)
+        .body(ResponseBody::empty())
+        .unwrap()
+}
+
+/// Counts the requests read but not yet responded to.
+#[derive(Default)]
+struct Pipeline {
+    queued: Cell<usize>,
+
+    /// Whether the responding task has finished, so no more requests will be responded to.
+    closed: Cell<bool>,
+
+    /// The connection's task, waiting for the queue to shrink.
+    waker: RefCell<Option<Waker>>,
+}
+
+impl Pipeline {
+    fn push(&self) {
+        self.queued.set(self.queued.get() + 1);
+    }
+
+    fn pop(&self) {
+        self.queued.set(self.queued.get() - 1);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+
+    /// Waits until fewer than `max` requests are queued.
+    /// Returns false if the responding task finished instead.
+    async fn wait_below(&self, max: usize) -> bool {
+        poll_fn(|cx| {
+            if self.closed.get() {
+                Poll::Ready(false)
+            } else if self.queued.get() < max {
+                Poll::Ready(true)
+            } else {
+                *self.waker.borrow_mut() = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Marks the pipeline closed when the responding task ends.
+struct PipelineClosed(Rc<Pipeline>);
+
+impl Drop for PipelineClosed {
+    fn drop(&mut self) {
+        self.0.closed.set(true);
+        self.0.wake();
+    }
+}
+
+/// Tracks the bytes of the request head being read.
+#[derive(Default)]
+struct HeadTracker {
+    /// The most bytes the head may have, or [None] if it isn't being tracked.
+    limit: Cell<Option<usize>>,
+
+    /// When the head's first byte arrived.
+    started: Cell<Option<Instant>>,
+
+    /// The bytes of the head read so far.
+    len: Cell<usize>,
+
+    /// How far into the blank line ending the head the last byte read was.
+    terminator: Cell<u8>,
+
+    /// Whether the blank line ending the head has been read.
+    complete: Cell<bool>,
+
+    too_large: Cell<bool>,
+
+    /// All bytes read on the connection, to tell whether a body is still arriving.
+    total: Cell<u64>,
+}
+
+impl HeadTracker {
+    /// Starts tracking the next head, limited to `limit` bytes.
+    /// If `limit` is [None], the head isn't tracked.
+    fn start(&self, limit: Option<usize>) {
+        self.limit.set(limit);
+        self.started.set(None);
+        self.len.set(0);
+        self.terminator.set(0);
+        self.complete.set(false);
+        self.too_large.set(false);
+    }
+
+    fn record(&self, data: &[u8]) {
+        self.total.set(self.total.get() + data.len() as u64);
+        let Some(limit) = self.limit.get() else {
+            return;
+        };
+        if self.complete.get() || data.is_empty() {
+            return;
+        }
+        if self.started.get().is_none() {
+            self.started.set(Some(Instant::now()));
+        }
+
+        let mut terminator = self.terminator.get();
+        for (i, &b) in data.iter().enumerate() {
+            terminator = match (terminator, b) {
+                (0 | 2, b'\r') => terminator + 1,
+                (1 | 3, b'\n') => terminator + 1,
+                (_, b'\r') => 1,
+                _ => 0,
+            };
+            if terminator == 4 {
+                self.len.set(self.len.get() + i + 1);
+                self.complete.set(true);
+                break;
+            }
+        }
+        self.terminator.set(terminator);
+        if !self.complete.get() {
+            self.len.set(self.len.get() + data.len());
+        }
+        if self.len.get() > limit {
+            self.too_large.set(true);
+        }
+    }
+}
+
+/// Reports what the decoder reads to a [HeadTracker].
+struct TrackedReader<R> {
+    inner: R,
+    head: Rc<HeadTracker>,
+}
+
+impl<R: AsyncReadRent> AsyncReadRent for TrackedReader<R> {
+    async fn read<T: IoBufMut>(&mut self, buf: T) -> BufResult<usize, T> {
+        let (res, mut buf) = self.inner.read(buf).await;
+        if let Ok(n) = res {
+            // The read filled the buffer from its write pointer.
+            let data = unsafe { std::slice::from_raw_parts(buf.write_ptr() as *const u8, n) };
+            self.head.record(data);
+        }
+        (res, buf)
+    }
+
+    async fn readv<T: IoVecBufMut>(&mut self, buf: T) -> BufResult<usize, T> {
+        // Vectored reads aren't scanned for the end of the head, which only costs the header limits precision.
+        let (res, buf) = self.inner.readv(buf).await;
+        if let Ok(n) = res {
+            self.head.total.set(self.head.total.get() + n as u64);
+        }
+        (res, buf)
+    }
+}
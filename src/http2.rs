@@ -0,0 +1,160 @@
+//! HTTP/2 client connections.
+//!
+//! Each stream is handled on its own task by the same request pipeline as HTTP/1.1, so a client can have many
+//! segment requests in flight on one connection. Response bodies are pulled from the origin connection or the cache
+//! one chunk at a time, and the next chunk is only pulled once the previous one fit in the stream's flow control
+//! window, so a slow stream holds back its own reads rather than buffering them.
+//!
+//! When the thread starts draining, clients are sent `GOAWAY`, so they open no more streams and the connection closes
+//! once the open ones finish.
+
+use crate::body::ResponseBody;
+use crate::{forwarded, handle_request, ConnectionGuard, ConnectionInfo, ThreadContext};
+use bytes::Bytes;
+use http::header::HOST;
+use http::HeaderValue;
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
+use monoio_http::common::body::{Body, HttpBody};
+use monoio_http::common::request::Request;
+use monoio_http::h1::payload::Payload;
+use monoio_http::h2::server::{self, SendResponse};
+use monoio_http::h2::{Reason, RecvStream, SendStream};
+use std::cmp::min;
+use std::error::Error;
+use std::future::poll_fn;
+use std::io;
+use std::rc::Rc;
+
+/// Serves an HTTP/2 connection until the client closes it.
+pub async fn serve<S>(stream: S, conn: ConnectionInfo, ctx: Rc<ThreadContext>)
+where
+    S: AsyncReadRent + AsyncWriteRent + Unpin + 'static,
+{
+    ctx.metrics.connection_opened();
+    let _guard = ConnectionGuard(ctx.clone());
+
+    let config = &ctx.config.http2;
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .initial_window_size(config.initial_stream_window)
+        .initial_connection_window_size(config.initial_connection_window)
+        .max_frame_size(config.max_frame_size)
+        .max_header_list_size(config.max_header_list_size)
+        .handshake::<_, Bytes>(stream);
+    let mut connection = match handshake.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("HTTP/2 handshake with {} failed: {}", conn.client_addr, e);
+            return;
+        }
+    };
+
+    // Accepting also drives the connection, so it continues while streams are being handled.
+    let mut draining = false;
+    loop {
+        let accepted = if draining {
+            connection.accept().await
+        } else {
+            monoio::select! {
+                accepted = connection.accept() => accepted,
+                _ = ctx.shutdown.draining() => {
+                    // Streams already open are finished, and the connection closes after them.
+                    connection.graceful_shutdown();
+                    draining = true;
+                    continue;
+                }
+            }
+        };
+        let Some(accepted) = accepted else {
+            return;
+        };
+        match accepted {
+            Ok((req, respond)) => {
+                monoio::spawn(handle_stream(req, respond, conn, ctx.clone()));
+            }
+            Err(e) => {
+                println!("HTTP/2 connection from {} failed: {}", conn.client_addr, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Handles a single stream's request.
+async fn handle_stream(
+    req: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    conn: ConnectionInfo,
+    ctx: Rc<ThreadContext>,
+) {
+    let (mut parts, body) = req.into_parts();
+
+    // HTTP/2 carries the host in the `:authority` pseudo-header, which the pipeline expects in `Host`.
+    if !parts.headers.contains_key(HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                parts.headers.insert(HOST, host);
+            }
+        }
+    }
+    let req: Request = http::Request::from_parts(parts, request_body(body));
+
+    let res = match handle_request(req, conn, &ctx).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("HTTP/2 request from {} failed: {}", conn.client_addr, e);
+            respond.send_reset(Reason::INTERNAL_ERROR);
+            return;
+        }
+    };
+
+    let (mut parts, body) = res.into_parts();
+    // Connection-specific headers are not allowed in HTTP/2.
+    forwarded::strip_hop_by_hop(&mut parts.headers);
+    let stream = match respond.send_response(http::Response::from_parts(parts, ()), false) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("HTTP/2 response to {} failed: {}", conn.client_addr, e);
+            return;
+        }
+    };
+
+    if let Err(e) = send_body(body, stream).await {
+        println!("HTTP/2 response body to {} failed: {}", conn.client_addr, e);
+    }
+}
+
+/// Turns a stream's request body into the pipeline's body.
+/// The stream itself is handed over, so each chunk is only taken off it, and its flow control capacity given back to
+/// the client, once the origin request reads it. A slow origin holds back the client instead of the body piling up.
+fn request_body(recv: RecvStream) -> HttpBody {
+    if recv.is_end_stream() {
+        return HttpBody::from(Payload::None);
+    }
+    HttpBody::from(recv)
+}
+
+/// Sends a response body, reading each chunk only once the previous one fits in the flow control window.
+async fn send_body(
+    mut body: ResponseBody,
+    mut stream: SendStream<Bytes>,
+) -> Result<(), Box<dyn Error>> {
+    while let Some(data) = body.next_data().await {
+        let mut data = data?;
+        while !data.is_empty() {
+            stream.reserve_capacity(data.len());
+            let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed").into())
+                }
+            };
+            if capacity == 0 {
+                continue;
+            }
+            stream.send_data(data.split_to(min(capacity, data.len())), false)?;
+        }
+    }
+    stream.send_data(Bytes::new(), true)?;
+    Ok(())
+}
@@ -0,0 +1,1074 @@
+//! HTTP/3 client connections over QUIC.
+//!
+//! Every worker thread binds its own UDP socket with `SO_REUSEPORT`, so the kernel keeps each client's packets on
+//! one thread, the same way TCP connections stay on the thread that accepted them. QUIC runs in quiche, which does
+//! no I/O itself: a receive task queues incoming datagrams, and a driver task feeds them in, handles HTTP/3 events
+//! and sends what quiche has to send, batched with GSO where the kernel supports it. Only connections with something
+//! to do are driven: ones that received datagrams, had a timer fire or had a stream's handler make progress. Unless
+//! Retry is turned off, new clients are sent a Retry first, and no connection state is kept until they echo its
+//! token from the address it was issued to.
+//!
+//! Each request stream is handled on its own task by the same request pipeline as the TCP listeners. Response bodies
+//! are pulled from the origin connection or the cache one chunk at a time, and the next chunk is only pulled once the
+//! previous one fit in the stream's flow control window. Request bodies are read from quiche a chunk at a time too,
+//! once the pipeline has consumed the previous chunk, so a slow origin holds back the client's flow control credit.
+//!
+//! Certificates are the ones the TLS listener serves, picked by SNI through [tls::find_certificate], so reloads and
+//! ACME certificates apply here too. OCSP responses are not stapled over QUIC.
+//!
+//! When the thread starts draining, new connections are turned away and each connection is closed once it has no
+//! streams left. If it is draining because an upgrade handed the socket to a new process, it stops receiving on the
+//! socket so the new process gets every datagram, and closes its connections straight away.
+
+use crate::config::Http3Config;
+use crate::connlimit::ConnectionPermit;
+use crate::upgrade::{self, SocketKind};
+use crate::{forwarded, handle_request, tls, ConnectionGuard, ConnectionInfo, ThreadContext};
+use aws_lc_rs::hmac;
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use boring::error::ErrorStack;
+use boring::pkey::PKey;
+use boring::ssl::{
+    select_next_proto, AlpnError, NameType, SniError, SslContext, SslContextBuilder, SslMethod,
+};
+use boring::x509::X509;
+use bytes::Bytes;
+use http::header::HOST;
+use http::{HeaderName, HeaderValue, Method, Version};
+use monoio::net::udp::UdpSocket;
+use monoio_http::common::body::Body;
+use monoio_http::common::error::HttpError;
+use monoio_http::common::request::Request;
+use monoio_http::h1::payload::{stream_payload_pair, Payload, PayloadSender};
+use quiche::h3::{self, NameValue};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The largest UDP payload sent or received, which fits the typical path MTU with room for tunnels.
+const MAX_DATAGRAM_SIZE: usize = 1350;
+
+/// The most datagrams sent in one GSO batch.
+const MAX_GSO_SEGMENTS: usize = 32;
+
+/// The most request body read from a stream at once.
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a Retry token stays valid, which only has to cover the client's next round trip.
+const RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The length of the HMAC-SHA256 tag that ends a Retry token.
+const RETRY_TOKEN_TAG_LEN: usize = 32;
+
+/// HTTP/3 error codes (RFC 9114 section 8.1).
+const H3_NO_ERROR: u64 = 0x100;
+const H3_GENERAL_PROTOCOL_ERROR: u64 = 0x101;
+const H3_INTERNAL_ERROR: u64 = 0x102;
+const H3_MESSAGE_ERROR: u64 = 0x10e;
+
+/// TLS contexts built for served certificates, reused until the certificate is replaced.
+static SSL_CONTEXTS: Mutex<Vec<(Arc<tls::ServedCert>, SslContext)>> = Mutex::new(Vec::new());
+
+/// Starts the HTTP/3 listener on this worker thread.
+pub fn launch(ctx: Rc<ThreadContext>) -> Result<(), Box<dyn Error>> {
+    let config = &ctx.config.http3;
+    let bind_str = config.bind_addr.as_deref().ok_or("HTTP/3 is not enabled")?;
+    let bind_addr: SocketAddr = bind_str.parse()?;
+
+    let socket = match upgrade::take_inherited(SocketKind::Udp, bind_str) {
+        Some(fd) => Socket::from(fd),
+        None => bind(bind_addr)?,
+    };
+    upgrade::register(SocketKind::Udp, bind_str, socket.as_raw_fd());
+    let gso = config.gso && enable_gso(&socket);
+    let retry = config.retry;
+    let quic_config = quic_config(config)?;
+    let mut h3_config = h3::Config::new()?;
+    // Clients may not use the dynamic table, which spares keeping one per connection.
+    h3_config.set_qpack_max_table_capacity(0);
+    h3_config.set_max_field_section_size(ctx.config.http3.max_field_section_size);
+
+    let mut seed = [0; 32];
+    SystemRandom::new()
+        .fill(&mut seed)
+        .map_err(|_| "failed to generate connection ID key")?;
+    let mut token_seed = [0; 32];
+    SystemRandom::new()
+        .fill(&mut token_seed)
+        .map_err(|_| "failed to generate Retry token key")?;
+
+    let server = Rc::new(Server {
+        socket: UdpSocket::from_std(socket.into())?,
+        local_addr: bind_addr,
+        gso,
+        retry,
+        conn_id_key: hmac::Key::new(hmac::HMAC_SHA256, &seed),
+        token_key: hmac::Key::new(hmac::HMAC_SHA256, &token_seed),
+        notify: Arc::new(Notify::default()),
+        ctx,
+        state: RefCell::new(State {
+            quic_config,
+            h3_config,
+            clients: HashMap::new(),
+            inbox: VecDeque::new(),
+            outbox: Vec::new(),
+            timers: BTreeSet::new(),
+            touched: HashSet::new(),
+            drain_seen: false,
+        }),
+    });
+    let receiving = server.clone();
+    monoio::spawn(async move {
+        monoio::select! {
+            _ = receive(receiving.clone()) => {}
+            _ = handover(&receiving.ctx) => {}
+        }
+    });
+    let draining = server.clone();
+    monoio::spawn(async move {
+        draining.ctx.shutdown.draining().await;
+        draining.notify.notify();
+    });
+    monoio::spawn(drive(server));
+    Ok(())
+}
+
+/// Binds a UDP socket that every worker thread shares with `SO_REUSEPORT`.
+fn bind(addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Has the kernel split sends into datagrams of [MAX_DATAGRAM_SIZE], returning whether it is supported.
+fn enable_gso(socket: &Socket) -> bool {
+    let size = MAX_DATAGRAM_SIZE as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &size as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&size) as libc::socklen_t,
+        )
+    };
+    ret == 0
+}
+
+/// Builds the QUIC config, with certificates picked per handshake by SNI.
+fn quic_config(config: &Http3Config) -> Result<quiche::Config, Box<dyn Error>> {
+    let mut ssl = SslContextBuilder::new(SslMethod::tls())?;
+    ssl.set_servername_callback(|ssl, _alert| {
+        let cert = tls::find_certificate(ssl.servername(NameType::HOST_NAME))
+            .ok_or(SniError::ALERT_FATAL)?;
+        let context = ssl_context(cert).map_err(|_| SniError::ALERT_FATAL)?;
+        ssl.set_ssl_context(&context)
+            .map_err(|_| SniError::ALERT_FATAL)
+    });
+
+    let mut quic = quiche::Config::with_boring_ssl_ctx_builder(quiche::PROTOCOL_VERSION, ssl)?;
+    quic.set_application_protos(h3::APPLICATION_PROTOCOL)?;
+    quic.set_max_idle_timeout(config.max_idle_timeout.as_millis() as u64);
+    quic.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+    quic.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+    quic.set_initial_max_data(config.initial_connection_window);
+    quic.set_initial_max_stream_data_bidi_remote(config.initial_stream_window);
+    quic.set_initial_max_stream_data_bidi_local(config.initial_stream_window);
+    quic.set_initial_max_stream_data_uni(config.initial_stream_window);
+    quic.set_initial_max_streams_bidi(config.max_concurrent_streams);
+    // The control and QPACK streams, with room for extensions.
+    quic.set_initial_max_streams_uni(16);
+    // Packets from a new address could land on another thread's socket.
+    quic.set_disable_active_migration(true);
+    Ok(quic)
+}
+
+/// Returns the TLS context for a served certificate, building it the first time the certificate is seen.
+fn ssl_context(cert: Arc<tls::ServedCert>) -> Result<SslContext, ErrorStack> {
+    let mut contexts = SSL_CONTEXTS.lock().unwrap();
+    if let Some((_, context)) = contexts.iter().find(|(c, _)| Arc::ptr_eq(c, &cert)) {
+        return Ok(context.clone());
+    }
+    // Certificates only held here have been replaced.
+    contexts.retain(|(c, _)| Arc::strong_count(c) > 1);
+
+    let mut builder = SslContextBuilder::new(SslMethod::tls())?;
+    let mut chain = X509::stack_from_pem(&cert.chain_pem)?.into_iter();
+    if let Some(leaf) = chain.next() {
+        builder.set_certificate(&leaf)?;
+    }
+    for intermediate in chain {
+        builder.add_extra_chain_cert(intermediate)?;
+    }
+    builder.set_private_key(&PKey::private_key_from_pem(&cert.key_pem)?)?;
+    // Switching to this context also switches ALPN selection to it, so it has to agree on HTTP/3 itself.
+    builder.set_alpn_select_callback(|_, client| {
+        select_next_proto(&h3_alpn_wire(), client).ok_or(AlpnError::ALERT_FATAL)
+    });
+    let context = builder.build();
+
+    contexts.push((cert, context.clone()));
+    Ok(context)
+}
+
+/// The HTTP/3 ALPN protocols in wire format, each prefixed by its length.
+fn h3_alpn_wire() -> Vec<u8> {
+    let mut wire = Vec::new();
+    for protocol in h3::APPLICATION_PROTOCOL {
+        wire.push(protocol.len() as u8);
+        wire.extend_from_slice(protocol);
+    }
+    wire
+}
+
+/// What a Retry token's tag signs: the token's own contents and the address it was issued to.
+fn token_message(token: &[u8], addr: SocketAddr) -> Vec<u8> {
+    let mut message = token.to_vec();
+    message.extend_from_slice(addr.to_string().as_bytes());
+    message
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Wakes the driver task when there is something for it to do.
+/// Request body chunks wake it from wherever the pipeline drops them, so it is shareable across threads.
+#[derive(Default)]
+struct Notify {
+    notified: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+
+    /// The connections whose streams' handlers made progress since the driver last looked.
+    ready: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl Notify {
+    fn notify(&self) {
+        self.notified.store(true, Ordering::Release);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    async fn notified(&self) {
+        poll_fn(|cx| {
+            if self.notified.swap(false, Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            // A notification between the check and storing the waker would otherwise be missed.
+            if self.notified.swap(false, Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Wakes the driver task for a connection's streams.
+struct ConnectionWaker {
+    /// The connection ID given to the client
+    id: Vec<u8>,
+    driver: Arc<Notify>,
+}
+
+impl ConnectionWaker {
+    fn wake(&self) {
+        self.driver.ready.lock().unwrap().insert(self.id.clone());
+        self.driver.notify();
+    }
+}
+
+/// A request body chunk handed to the pipeline.
+/// Dropping it, once the origin request has sent it on, lets the driver read the stream's next chunk from quiche,
+/// which is what gives the client its flow control credit back.
+struct BodyChunk {
+    data: Vec<u8>,
+    consumed: Arc<AtomicBool>,
+    driver: Arc<ConnectionWaker>,
+}
+
+impl AsRef<[u8]> for BodyChunk {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for BodyChunk {
+    fn drop(&mut self) {
+        self.consumed.store(true, Ordering::Release);
+        self.driver.wake();
+    }
+}
+
+/// A worker thread's HTTP/3 listener.
+struct Server {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+
+    /// Whether sends are split into datagrams by the kernel.
+    gso: bool,
+
+    /// Whether new clients must echo a Retry token before a connection is set up.
+    retry: bool,
+
+    /// Derives the connection IDs given to clients from the ones they picked, so retransmitted initial packets
+    /// find the connection they started.
+    conn_id_key: hmac::Key,
+
+    /// Signs the Retry tokens that prove a client owns its address.
+    token_key: hmac::Key,
+
+    notify: Arc<Notify>,
+    ctx: Rc<ThreadContext>,
+    state: RefCell<State>,
+}
+
+struct State {
+    quic_config: quiche::Config,
+    h3_config: h3::Config,
+
+    /// Key: the connection ID given to the client
+    clients: HashMap<Vec<u8>, Client>,
+
+    /// Datagrams received but not yet processed.
+    inbox: VecDeque<(Vec<u8>, SocketAddr)>,
+
+    /// Datagrams to send that don't belong to a connection, such as version negotiation.
+    outbox: Vec<(Vec<u8>, SocketAddr)>,
+
+    /// When each connection's next timer fires, and its ID.
+    timers: BTreeSet<(Instant, Vec<u8>)>,
+
+    /// The connections driven since their output was last sent.
+    touched: HashSet<Vec<u8>>,
+
+    /// Whether every connection has been driven since the thread started draining, to close the idle ones.
+    drain_seen: bool,
+}
+
+/// A QUIC connection from a client.
+struct Client {
+    conn: quiche::Connection,
+
+    /// Set up once the handshake completes.
+    h3: Option<h3::Connection>,
+
+    /// Key: stream ID
+    streams: HashMap<u64, Rc<RefCell<StreamIo>>>,
+
+    info: ConnectionInfo,
+    waker: Arc<ConnectionWaker>,
+
+    /// When the connection's timer entry in [State::timers] fires.
+    timer: Option<Instant>,
+
+    _guard: ConnectionGuard,
+    _permit: ConnectionPermit,
+}
+
+/// A request stream's state, shared between the driver task and the stream's handler task.
+#[derive(Default)]
+struct StreamIo {
+    /// Feeds the request body to the handler, until it ends.
+    body: Option<PayloadSender<Bytes, HttpError>>,
+
+    /// Whether quiche has request body for the stream that hasn't been read yet.
+    readable: bool,
+
+    /// Set once the last chunk handed to the handler has been consumed. [None] before the first chunk.
+    consumed: Option<Arc<AtomicBool>>,
+
+    /// The response head, until it is sent.
+    head: Option<Vec<h3::Header>>,
+
+    /// A response body chunk waiting for flow control capacity.
+    pending: Option<Bytes>,
+
+    /// Whether the handler has given the whole response body.
+    finished: bool,
+
+    /// Whether the handler gave up, so the stream should be reset.
+    aborted: bool,
+
+    /// Whether the stream is gone, such as when the client reset it.
+    closed: bool,
+
+    /// The handler, waiting for the pending chunk to be sent.
+    waker: Option<Waker>,
+}
+
+impl StreamIo {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(mut body) = self.body.take() {
+            body.feed_error(HttpError::from(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "stream closed",
+            )));
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receives datagrams into the inbox.
+async fn receive(server: Rc<Server>) {
+    let mut buf = Vec::with_capacity(u16::MAX as usize);
+    loop {
+        buf.clear();
+        let (res, returned) = server.socket.recv_from(buf).await;
+        buf = returned;
+        match res {
+            Ok((len, from)) => {
+                let datagram = buf[..len].to_vec();
+                server.state.borrow_mut().inbox.push_back((datagram, from));
+                server.notify.notify();
+            }
+            Err(e) => println!("HTTP/3 receive failed: {}", e),
+        }
+    }
+}
+
+/// Waits until an upgrade has handed the listeners to a new process, which is never if the thread drains for any
+/// other reason.
+async fn handover(ctx: &ThreadContext) {
+    ctx.shutdown.draining().await;
+    if !upgrade::handed_over() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Processes datagrams, timers and stream output, and sends whatever that produces.
+async fn drive(server: Rc<Server>) {
+    loop {
+        server.process();
+        server.flush().await;
+
+        let timeout = server
+            .state
+            .borrow()
+            .timers
+            .first()
+            .map(|(at, _)| at.saturating_duration_since(Instant::now()));
+        match timeout {
+            Some(timeout) => {
+                let _ = monoio::time::timeout(timeout, server.notify.notified()).await;
+            }
+            None => server.notify.notified().await,
+        }
+    }
+}
+
+impl Server {
+    /// Drives the connections that received datagrams, had a timer fire or had a stream's handler make progress.
+    fn process(&self) {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let mut ready = std::mem::take(&mut *self.notify.ready.lock().unwrap());
+
+        while let Some((mut datagram, from)) = state.inbox.pop_front() {
+            if let Some(id) = self.receive_datagram(state, &mut datagram, from) {
+                ready.insert(id);
+            }
+        }
+
+        let now = Instant::now();
+        while state.timers.first().is_some_and(|(at, _)| *at <= now) {
+            let (_, id) = state.timers.pop_first().unwrap();
+            if let Some(client) = state.clients.get_mut(&id) {
+                client.timer = None;
+                client.conn.on_timeout();
+                ready.insert(id);
+            }
+        }
+
+        let draining = self.ctx.shutdown.is_draining();
+        // Idle connections have nothing to wake them, but have to be closed once the thread drains.
+        if draining && !state.drain_seen {
+            state.drain_seen = true;
+            ready.extend(state.clients.keys().cloned());
+        }
+
+        for id in ready {
+            let Some(client) = state.clients.get_mut(&id) else {
+                continue;
+            };
+            self.poll_client(client, &state.h3_config);
+
+            // Once handed over, the connection's packets go to the new process, so it can't finish its streams.
+            if draining && (client.streams.is_empty() || upgrade::handed_over()) {
+                let _ = client.conn.close(true, H3_NO_ERROR, b"");
+            }
+            state.touched.insert(id);
+        }
+    }
+
+    /// Derives the connection ID for a client's chosen destination connection ID.
+    fn connection_id(&self, dcid: &[u8]) -> Vec<u8> {
+        let tag = hmac::sign(&self.conn_id_key, dcid);
+        tag.as_ref()[..quiche::MAX_CONN_ID_LEN].to_vec()
+    }
+
+    /// Builds a Retry token, which binds the client's address to the connection ID it first picked.
+    fn retry_token(&self, odcid: &[u8], from: SocketAddr) -> Vec<u8> {
+        let mut token = unix_now().to_be_bytes().to_vec();
+        token.extend_from_slice(odcid);
+        let tag = hmac::sign(&self.token_key, &token_message(&token, from));
+        token.extend_from_slice(tag.as_ref());
+        token
+    }
+
+    /// Checks a Retry token from a client's initial packet.
+    /// Returns the connection ID the client first picked, or [None] if the token wasn't issued to its address or has
+    /// expired.
+    fn validate_retry_token(&self, token: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let (signed, tag) = token.split_at(token.len().checked_sub(RETRY_TOKEN_TAG_LEN)?);
+        let (issued, odcid) = signed.split_first_chunk::<8>()?;
+        hmac::verify(&self.token_key, &token_message(signed, from), tag).ok()?;
+        if unix_now() > u64::from_be_bytes(*issued) + RETRY_TOKEN_LIFETIME.as_secs() {
+            return None;
+        }
+        Some(odcid.to_vec())
+    }
+
+    /// Feeds a datagram to its connection, setting one up for a new client.
+    /// Returns the ID of the connection it went to, if any.
+    fn receive_datagram(
+        &self,
+        state: &mut State,
+        datagram: &mut [u8],
+        from: SocketAddr,
+    ) -> Option<Vec<u8>> {
+        let Ok(header) = quiche::Header::from_slice(datagram, quiche::MAX_CONN_ID_LEN) else {
+            return None;
+        };
+        let (ty, version) = (header.ty, header.version);
+        let dcid = header.dcid.to_vec();
+        let scid = header.scid.to_vec();
+        let token = header.token.unwrap_or_default();
+
+        let key = if state.clients.contains_key(&dcid) {
+            dcid
+        } else {
+            let derived = self.connection_id(&dcid);
+            if state.clients.contains_key(&derived) {
+                derived
+            } else {
+                // Only an initial packet can start a connection, and not once the thread is draining.
+                if ty != quiche::Type::Initial || self.ctx.shutdown.is_draining() {
+                    return None;
+                }
+                if !quiche::version_is_supported(version) {
+                    let mut out = vec![0; MAX_DATAGRAM_SIZE];
+                    let scid = quiche::ConnectionId::from_ref(&scid);
+                    let dcid = quiche::ConnectionId::from_ref(&dcid);
+                    if let Ok(len) = quiche::negotiate_version(&scid, &dcid, &mut out) {
+                        out.truncate(len);
+                        state.outbox.push((out, from));
+                    }
+                    return None;
+                }
+
+                let (id, odcid) = if !self.retry {
+                    (derived, None)
+                } else if token.is_empty() {
+                    // Nothing is kept for a client until it proves it owns its address by echoing a Retry token,
+                    // so spoofed initial packets can't use up connection slots or amplify traffic.
+                    let mut out = vec![0; MAX_DATAGRAM_SIZE];
+                    let token = self.retry_token(&dcid, from);
+                    if let Ok(len) = quiche::retry(
+                        &quiche::ConnectionId::from_ref(&scid),
+                        &quiche::ConnectionId::from_ref(&dcid),
+                        &quiche::ConnectionId::from_ref(&derived),
+                        &token,
+                        version,
+                        &mut out,
+                    ) {
+                        out.truncate(len);
+                        state.outbox.push((out, from));
+                    }
+                    return None;
+                } else {
+                    // The client now addresses the connection ID the Retry gave it, which is derived from the one it
+                    // first picked, so later packets are looked up the same way as before.
+                    let odcid = self.validate_retry_token(&token, from)?;
+                    if self.connection_id(&odcid) != dcid {
+                        return None;
+                    }
+                    (dcid, Some(odcid))
+                };
+
+                let permit = self.ctx.connection_limiter.acquire(from.ip())?;
+                let odcid = odcid.as_deref().map(quiche::ConnectionId::from_ref);
+                let conn = match quiche::accept(
+                    &quiche::ConnectionId::from_ref(&id),
+                    odcid.as_ref(),
+                    self.local_addr,
+                    from,
+                    &mut state.quic_config,
+                ) {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("HTTP/3 connection from {} failed: {}", from, e);
+                        return None;
+                    }
+                };
+
+                self.ctx.metrics.connection_opened();
+                state.clients.insert(
+                    id.clone(),
+                    Client {
+                        conn,
+                        h3: None,
+                        streams: HashMap::new(),
+                        info: ConnectionInfo {
+                            client_addr: from,
+                            tls: true,
+                        },
+                        waker: Arc::new(ConnectionWaker {
+                            id: id.clone(),
+                            driver: self.notify.clone(),
+                        }),
+                        timer: None,
+                        _guard: ConnectionGuard(self.ctx.clone()),
+                        _permit: permit,
+                    },
+                );
+                id
+            }
+        };
+
+        let client = state.clients.get_mut(&key).unwrap();
+        let info = quiche::RecvInfo {
+            from,
+            to: self.local_addr,
+        };
+        // Bad packets are dropped by quiche, and fatal errors close the connection.
+        let _ = client.conn.recv(datagram, info);
+        Some(key)
+    }
+
+    /// Handles a connection's HTTP/3 events and writes out its streams' responses.
+    fn poll_client(&self, client: &mut Client, h3_config: &h3::Config) {
+        let Client {
+            conn,
+            h3,
+            streams,
+            info,
+            waker,
+            ..
+        } = client;
+
+        if h3.is_none() && (conn.is_established() || conn.is_in_early_data()) {
+            match h3::Connection::with_transport(conn, h3_config) {
+                Ok(connection) => *h3 = Some(connection),
+                Err(e) => {
+                    println!("HTTP/3 setup with {} failed: {}", info.client_addr, e);
+                    let _ = conn.close(true, H3_GENERAL_PROTOCOL_ERROR, b"");
+                    return;
+                }
+            }
+        }
+        let Some(h3) = h3 else {
+            return;
+        };
+
+        // Reading a stream's last chunk of request body is what lets quiche report it finished, so polling goes on
+        // until no more body was read.
+        loop {
+            if !self.poll_events(conn, h3, streams, info, waker) {
+                return;
+            }
+            let mut read = false;
+            for (&id, io) in streams.iter() {
+                read |= read_body(conn, h3, id, &mut io.borrow_mut(), waker);
+            }
+            if !read {
+                break;
+            }
+        }
+
+        streams.retain(|&id, io| {
+            let mut io = io.borrow_mut();
+            let done = write_stream(conn, h3, id, &mut io);
+            // The response was sent before the handler read the whole request body, which nobody wants anymore.
+            if done && io.body.is_some() {
+                let _ = conn.stream_shutdown(id, quiche::Shutdown::Read, H3_NO_ERROR);
+            }
+            !done
+        });
+    }
+
+    /// Handles a connection's pending HTTP/3 events.
+    /// Returns false if the connection failed.
+    fn poll_events(
+        &self,
+        conn: &mut quiche::Connection,
+        h3: &mut h3::Connection,
+        streams: &mut HashMap<u64, Rc<RefCell<StreamIo>>>,
+        info: &ConnectionInfo,
+        waker: &Arc<ConnectionWaker>,
+    ) -> bool {
+        loop {
+            match h3.poll(conn) {
+                Ok((id, h3::Event::Headers { list, more_frames })) => {
+                    match self.start_stream(list, more_frames, *info, waker) {
+                        Some(io) => {
+                            streams.insert(id, io);
+                        }
+                        None => {
+                            let _ =
+                                conn.stream_shutdown(id, quiche::Shutdown::Read, H3_MESSAGE_ERROR);
+                            let _ =
+                                conn.stream_shutdown(id, quiche::Shutdown::Write, H3_MESSAGE_ERROR);
+                        }
+                    }
+                }
+                Ok((id, h3::Event::Data)) => match streams.get(&id) {
+                    Some(io) => io.borrow_mut().readable = true,
+                    None => {
+                        // Nothing handles the stream, so its body is thrown away.
+                        let mut buf = [0; 16 * 1024];
+                        while h3.recv_body(conn, id, &mut buf).is_ok() {}
+                    }
+                },
+                Ok((id, h3::Event::Finished)) => {
+                    if let Some(io) = streams.get(&id) {
+                        if let Some(mut body) = io.borrow_mut().body.take() {
+                            body.feed_data(None);
+                        }
+                    }
+                }
+                Ok((id, h3::Event::Reset(_))) => {
+                    if let Some(io) = streams.remove(&id) {
+                        io.borrow_mut().close();
+                    }
+                }
+                Ok(_) => {}
+                Err(h3::Error::Done) => return true,
+                Err(e) => {
+                    println!("HTTP/3 connection from {} failed: {}", info.client_addr, e);
+                    let _ = conn.close(true, H3_GENERAL_PROTOCOL_ERROR, b"");
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Builds the request from a stream's headers and starts handling it.
+    /// Returns [None] if the headers don't make a valid request.
+    fn start_stream(
+        &self,
+        list: Vec<h3::Header>,
+        more_frames: bool,
+        info: ConnectionInfo,
+        waker: &Arc<ConnectionWaker>,
+    ) -> Option<Rc<RefCell<StreamIo>>> {
+        let mut req = http::Request::builder().version(Version::HTTP_3);
+        let mut authority = None;
+        for header in &list {
+            match header.name() {
+                b":method" => req = req.method(Method::from_bytes(header.value()).ok()?),
+                b":path" => req = req.uri(header.value()),
+                b":authority" => authority = Some(HeaderValue::from_bytes(header.value()).ok()?),
+                b":scheme" | b":protocol" => {}
+                name => {
+                    req = req.header(
+                        HeaderName::from_bytes(name).ok()?,
+                        HeaderValue::from_bytes(header.value()).ok()?,
+                    )
+                }
+            }
+        }
+        // HTTP/3 carries the host in `:authority`, which the pipeline expects in `Host`.
+        if let Some(authority) = authority {
+            if !req.headers_ref()?.contains_key(HOST) {
+                req = req.header(HOST, authority);
+            }
+        }
+
+        let io = Rc::new(RefCell::new(StreamIo::default()));
+        let body = if more_frames {
+            let (payload, sender) = stream_payload_pair();
+            io.borrow_mut().body = Some(sender);
+            Payload::Stream(payload)
+        } else {
+            Payload::None
+        };
+        let req: Request = req.body(body.into()).ok()?;
+
+        monoio::spawn(handle_stream(
+            req,
+            io.clone(),
+            info,
+            self.ctx.clone(),
+            waker.clone(),
+        ));
+        Some(io)
+    }
+
+    /// Sends every datagram waiting to go out.
+    async fn flush(&self) {
+        loop {
+            let datagrams = self.collect_datagrams();
+            if datagrams.is_empty() {
+                return;
+            }
+            for (datagram, to) in datagrams {
+                let (res, _) = self.socket.send_to(datagram, to).await;
+                if let Err(e) = res {
+                    println!("HTTP/3 send to {} failed: {}", to, e);
+                }
+            }
+        }
+    }
+
+    /// Takes what the driven connections have to send, packing runs of full-size packets into GSO batches.
+    /// Connections that have closed are dropped, and the others' timers are rescheduled.
+    fn collect_datagrams(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let mut datagrams = std::mem::take(&mut state.outbox);
+        let segments = if self.gso { MAX_GSO_SEGMENTS } else { 1 };
+
+        for id in std::mem::take(&mut state.touched) {
+            let Some(client) = state.clients.get_mut(&id) else {
+                continue;
+            };
+            loop {
+                let mut batch = Vec::with_capacity(MAX_DATAGRAM_SIZE * segments);
+                let mut to = None;
+                for _ in 0..segments {
+                    let start = batch.len();
+                    batch.resize(start + MAX_DATAGRAM_SIZE, 0);
+                    match client.conn.send(&mut batch[start..]) {
+                        Ok((len, send_info)) => {
+                            batch.truncate(start + len);
+                            to = Some(send_info.to);
+                            // Only the last datagram of a batch may be short.
+                            if len < MAX_DATAGRAM_SIZE {
+                                break;
+                            }
+                        }
+                        Err(quiche::Error::Done) => {
+                            batch.truncate(start);
+                            break;
+                        }
+                        Err(e) => {
+                            batch.truncate(start);
+                            println!("HTTP/3 send to {} failed: {}", client.info.client_addr, e);
+                            let _ = client.conn.close(false, 0x1, b"");
+                            break;
+                        }
+                    }
+                }
+
+                match to {
+                    Some(to) if !batch.is_empty() => datagrams.push((batch, to)),
+                    _ => break,
+                }
+            }
+
+            // Receiving and sending both move the connection's timers.
+            if let Some(at) = client.timer.take() {
+                state.timers.remove(&(at, id.clone()));
+            }
+            if client.conn.is_closed() {
+                for io in client.streams.values() {
+                    io.borrow_mut().close();
+                }
+                state.clients.remove(&id);
+                continue;
+            }
+            client.timer = client.conn.timeout_instant();
+            if let Some(at) = client.timer {
+                state.timers.insert((at, id));
+            }
+        }
+
+        datagrams
+    }
+}
+
+/// Writes out what a stream's handler has produced, as far as flow control allows.
+/// Returns whether the stream is done with.
+fn write_stream(
+    conn: &mut quiche::Connection,
+    h3: &mut h3::Connection,
+    id: u64,
+    io: &mut StreamIo,
+) -> bool {
+    if io.closed {
+        return true;
+    }
+    if io.aborted {
+        let _ = conn.stream_shutdown(id, quiche::Shutdown::Read, H3_INTERNAL_ERROR);
+        let _ = conn.stream_shutdown(id, quiche::Shutdown::Write, H3_INTERNAL_ERROR);
+        io.close();
+        return true;
+    }
+
+    if let Some(head) = io.head.take() {
+        match h3.send_response(conn, id, &head, false) {
+            Ok(()) => {}
+            Err(h3::Error::StreamBlocked) => {
+                io.head = Some(head);
+                return false;
+            }
+            Err(_) => {
+                io.close();
+                return true;
+            }
+        }
+    }
+
+    if let Some(data) = io.pending.take() {
+        match h3.send_body(conn, id, &data, false) {
+            Ok(written) if written < data.len() => io.pending = Some(data.slice(written..)),
+            Ok(_) => {
+                if let Some(waker) = io.waker.take() {
+                    waker.wake();
+                }
+            }
+            Err(h3::Error::Done) => io.pending = Some(data),
+            Err(_) => {
+                io.close();
+                return true;
+            }
+        }
+    }
+
+    if io.finished && io.pending.is_none() {
+        return match h3.send_body(conn, id, &[], true) {
+            Ok(_) => true,
+            Err(h3::Error::Done) => false,
+            Err(_) => {
+                io.close();
+                true
+            }
+        };
+    }
+    false
+}
+
+/// Reads the next chunk of a stream's request body from quiche, once the handler has consumed the previous one.
+/// Returns whether anything was read.
+fn read_body(
+    conn: &mut quiche::Connection,
+    h3: &mut h3::Connection,
+    id: u64,
+    io: &mut StreamIo,
+    driver: &Arc<ConnectionWaker>,
+) -> bool {
+    if !io.readable
+        || io
+            .consumed
+            .as_ref()
+            .is_some_and(|c| !c.load(Ordering::Acquire))
+    {
+        return false;
+    }
+    let mut data = vec![0; BODY_CHUNK_SIZE];
+    let Ok(len) = h3.recv_body(conn, id, &mut data) else {
+        io.readable = false;
+        return false;
+    };
+    data.truncate(len);
+    // Once the handler has stopped reading, the rest of the body is thrown away.
+    if let Some(body) = &mut io.body {
+        let consumed = Arc::new(AtomicBool::new(false));
+        io.consumed = Some(consumed.clone());
+        body.feed_data(Some(Bytes::from_owner(BodyChunk {
+            data,
+            consumed,
+            driver: driver.clone(),
+        })));
+    }
+    true
+}
+
+/// Handles a stream's request and hands its response to the driver task.
+async fn handle_stream(
+    req: Request,
+    io: Rc<RefCell<StreamIo>>,
+    info: ConnectionInfo,
+    ctx: Rc<ThreadContext>,
+    driver: Arc<ConnectionWaker>,
+) {
+    let notify = || driver.wake();
+
+    let res = match handle_request(req, info, &ctx).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("HTTP/3 request from {} failed: {}", info.client_addr, e);
+            io.borrow_mut().aborted = true;
+            notify();
+            return;
+        }
+    };
+
+    let (mut parts, mut body) = res.into_parts();
+    // Connection-specific headers are not allowed in HTTP/3.
+    forwarded::strip_hop_by_hop(&mut parts.headers);
+    let mut head = Vec::with_capacity(parts.headers.len() + 1);
+    head.push(h3::Header::new(
+        b":status",
+        parts.status.as_str().as_bytes(),
+    ));
+    for (name, value) in &parts.headers {
+        head.push(h3::Header::new(name.as_str().as_bytes(), value.as_bytes()));
+    }
+    io.borrow_mut().head = Some(head);
+    notify();
+
+    while let Some(data) = body.next_data().await {
+        let Ok(data) = data else {
+            io.borrow_mut().aborted = true;
+            notify();
+            return;
+        };
+        if data.is_empty() {
+            continue;
+        }
+
+        io.borrow_mut().pending = Some(data);
+        notify();
+        let sent = poll_fn(|cx| {
+            let mut io = io.borrow_mut();
+            if io.closed {
+                Poll::Ready(false)
+            } else if io.pending.is_none() {
+                Poll::Ready(true)
+            } else {
+                io.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+        if !sent {
+            return;
+        }
+    }
+
+    io.borrow_mut().finished = true;
+    notify();
+}
@@ -1,54 +1,303 @@
+mod accesslog;
+mod acme;
+mod admin;
+mod body;
+mod cache;
 mod cachestate;
+mod cachestatus;
+mod config;
+mod configfile;
+mod connlimit;
 mod constant;
+mod directio;
+mod failover;
+mod forwarded;
 mod hash;
+mod http1;
+mod http2;
+mod http3;
+mod metrics;
 mod origin;
+mod originpool;
+mod originreq;
 mod proxy;
+mod proxyproto;
+mod purge;
+mod routing;
+mod shutdown;
+mod tagindex;
+mod tls;
+mod upgrade;
+mod wasm;
+mod zerocopy;
 
+use body::ResponseBody;
 use bytes::Bytes;
-use http::uri::Scheme;
-use http::{response::Builder, StatusCode};
+use http::{response::Builder, HeaderMap, HeaderValue, Method, StatusCode, Version};
+use monoio::net::{TcpListener, TcpStream};
 use monoio::utils::bind_to_cpu_set;
-use monoio::{
-    io::{
-        sink::{Sink, SinkExt},
-        stream::Stream,
-        Splitable,
-    },
-    net::{TcpListener, TcpStream},
-    IoUringDriver,
-};
+use monoio::IoUringDriver;
 use monoio_http::common::body::{Body, HttpBody};
-use monoio_http::{
-    common::{error::HttpError, request::Request, response::Response},
-    h1::{
-        codec::{decoder::RequestDecoder, encoder::GenericEncoder},
-        payload::{FixedPayload, Payload},
-    },
-    util::spsc::{spsc_pair, SPSCReceiver},
-};
+use monoio_http::common::{request::Request, response::Response};
 use monoio_http_client::Client;
+use monoio_rustls::TlsAcceptor;
 use std::cell::RefCell;
+use std::cmp::max;
 use std::io;
 use std::mem::size_of_val;
+use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
 use std::rc::Rc;
+use std::sync::{Once, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::constant::NOT_FOUND_HTML;
 
+/// The host label used for requests that did not carry a usable `Host` header.
+const UNKNOWN_HOST_LABEL: &str = "-";
+
+/// Per-thread state shared by every connection on a worker thread.
+struct ThreadContext {
+    config: Rc<config::Config>,
+    http_client: Rc<Client>,
+    origin_manager: Rc<RefCell<origin::OriginManager>>,
+    forwarding: forwarded::Forwarding,
+    metrics: Rc<metrics::MetricsRecorder>,
+    access_log: Option<Rc<accesslog::AccessLogger>>,
+    hooks: Option<wasm::ThreadHooks>,
+
+    connection_limiter: connlimit::ConnectionLimiter,
+    shutdown: shutdown::Shutdown,
+
+    /// The `Alt-Svc` value advertising HTTP/3 to TLS clients, if it is enabled.
+    alt_svc: Option<HeaderValue>,
+
+    /// The buffers cache blocks are read and written through when they bypass the page cache.
+    dio_pool: Option<Rc<directio::AlignedBufPool>>,
+}
+
+/// Returns the configuration, loading it the first time any thread asks.
+/// The process exits if it can't be loaded, before any thread starts serving.
+fn loaded_config() -> &'static config::Config {
+    static CONFIG: OnceLock<config::Config> = OnceLock::new();
+    CONFIG.get_or_init(|| match configfile::load_from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    })
+}
+
 async fn thread_main() -> Result<(), io::Error> {
+    let config = Rc::new(loaded_config().clone());
+
+    // Block buffers are only needed when bypassing the page cache.
+    let dio_pool = if config.cache.direct_io {
+        let mut align = 0;
+        for root in &config.cache.roots {
+            align = max(
+                align,
+                directio::validate_block_size(root, config.cache.block_size)?,
+            );
+        }
+        Some(Rc::new(directio::AlignedBufPool::new(
+            config.cache.block_size as usize,
+            align as usize,
+            config.cache.direct_io_pool_size,
+        )))
+    } else {
+        None
+    };
+
+    // Process-wide setup, such as the admin listener and hook module, only happens once.
+    static PROCESS_INIT: Once = Once::new();
+    PROCESS_INIT.call_once(|| {
+        metrics::set_cache_roots(config.cache.roots.clone());
+        if let Err(e) = accesslog::install_reopen_handler() {
+            println!("failed to install access log reopen handler: {}", e);
+        }
+        if let Err(e) = shutdown::install_handler() {
+            println!("failed to install shutdown handler: {}", e);
+        }
+        if let Err(e) = upgrade::launch(config.upgrade.clone()) {
+            println!("failed to install upgrade handler: {}", e);
+        }
+        if !config.admin.token.is_empty() {
+            admin::launch((*config).clone(), tagindex::init_updates());
+        }
+        wasm::init(&config.wasm).expect("failed to load wasm module");
+        tls::init(&config.tls, &config.acme).expect("failed to set up TLS");
+        if !config.acme.hosts.is_empty() {
+            acme::launch(config.acme.clone(), acme::solver(config.acme.challenge));
+        }
+    });
+
+    let hooks = wasm::ThreadHooks::new()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
     let http_client = Rc::new(Client::default());
-    let origin_manager = Rc::new(RefCell::new(origin::OriginManager::new()));
-
-    origin_manager.borrow_mut().set_origin_host(
-        "stavka.localhost".to_owned(),
-        Scheme::HTTPS,
-        "1.1.1.1"
-            .to_owned()
-            .try_into()
-            .expect("should have been valid authority"),
-    );
+    let origin_manager = origin::OriginManager::from_config(&config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let origin_manager = Rc::new(RefCell::new(origin_manager));
+
+    let access_log = match &config.log.access_log_path {
+        Some(path) => {
+            let format = accesslog::LogFormat::parse(&config.log.access_log_format)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let logger =
+                accesslog::AccessLogger::open(path.clone(), format, config.log.flush_threshold)
+                    .await?;
+            monoio::spawn(logger.clone().run_flusher(config.log.flush_interval));
+            Some(logger)
+        }
+        None => None,
+    };
+
+    let forwarding = forwarded::Forwarding::new(&config.forwarding)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let connection_limiter = connlimit::ConnectionLimiter::new(&config.connection);
+    let alt_svc =
+        alt_svc(&config.http3).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let ctx = Rc::new(ThreadContext {
+        config,
+        http_client,
+        origin_manager,
+        forwarding,
+        metrics: Rc::new(metrics::MetricsRecorder::register()),
+        access_log,
+        hooks,
+        connection_limiter,
+        shutdown: shutdown::Shutdown::register(),
+        alt_svc,
+        dio_pool,
+    });
+
+    for pool in ctx.origin_manager.borrow().pools() {
+        monoio::spawn(pool.run_health_checks(ctx.http_client.clone()));
+    }
+
+    if ctx.hooks.is_some() {
+        let ctx = ctx.clone();
+        monoio::spawn(async move {
+            if let Some(hooks) = &ctx.hooks {
+                hooks.run_resident_events().await;
+            }
+        });
+    }
+
+    let listener = listen(&ctx.config.connection.bind_addr)?;
+
+    if let Some(acceptor) = tls::acceptor() {
+        let tls_listener = listen(&ctx.config.tls.bind_addr)?;
+        monoio::spawn(accept_tls(tls_listener, Rc::new(acceptor), ctx.clone()));
+    }
+
+    if let Some(bind_addr) = &ctx.config.http2.h2c_bind_addr {
+        let h2c_listener = listen(bind_addr)?;
+        monoio::spawn(accept_h2c(h2c_listener, ctx.clone()));
+    }
+
+    if ctx.config.http3.bind_addr.is_some() {
+        http3::launch(ctx.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+
+    monoio::spawn(accept_plain(listener, ctx.clone()));
+
+    upgrade::thread_ready(WORKER_THREADS);
+    println!("Listening");
+    let drained = ctx
+        .shutdown
+        .run(&ctx.connection_limiter, ctx.config.shutdown.drain_timeout)
+        .await;
+    if let Some(logger) = &ctx.access_log {
+        logger.clone().flush().await;
+    }
+    shutdown::finish(drained).await;
+    Ok(())
+}
+
+/// Accepts plain HTTP/1.1 connections until the thread starts draining.
+async fn accept_plain(listener: TcpListener, ctx: Rc<ThreadContext>) {
+    loop {
+        let incoming = monoio::select! {
+            incoming = listener.accept() => incoming,
+            _ = ctx.shutdown.draining() => return,
+        };
+        match incoming {
+            Ok((stream, addr)) => {
+                //println!("accepted a connection from {}", addr);
+                let ctx = ctx.clone();
+                monoio::spawn(async move {
+                    let mode = ctx.config.proxy_protocol.plain;
+                    let Some((client_addr, _permit)) = admit(&stream, addr, mode, &ctx).await
+                    else {
+                        return;
+                    };
+                    let conn = ConnectionInfo {
+                        client_addr,
+                        tls: false,
+                    };
+                    http1::serve(stream, conn, ctx).await;
+                });
+            }
+            Err(e) => {
+                println!("accepted connection failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Reads a new connection's PROXY header if its listener expects one, then takes a permit for the client it names.
+/// Returns the client's address, or [None] if the connection should be closed.
+async fn admit(
+    stream: &TcpStream,
+    peer: SocketAddr,
+    mode: config::ProxyProtocolMode,
+    ctx: &ThreadContext,
+) -> Option<(SocketAddr, connlimit::ConnectionPermit)> {
+    let header_timeout = ctx.config.proxy_protocol.header_timeout;
+    let client_addr = match proxyproto::accept(stream, peer, mode, header_timeout).await {
+        Ok(client_addr) => client_addr,
+        Err(e) => {
+            println!("closing connection from {}: {}", peer, e);
+            return None;
+        }
+    };
+    let permit = ctx.connection_limiter.acquire(client_addr.ip())?;
+    Some((client_addr, permit))
+}
+
+/// Builds the `Alt-Svc` value advertising the HTTP/3 listener, or [None] if it isn't enabled.
+fn alt_svc(config: &config::Http3Config) -> Result<Option<HeaderValue>, String> {
+    let Some(bind_addr) = &config.bind_addr else {
+        return Ok(None);
+    };
+    let port = match config.advertised_port {
+        Some(port) => port,
+        None => bind_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("invalid HTTP/3 bind address {}: {}", bind_addr, e))?
+            .port(),
+    };
+    let value = format!("h3=\":{}\"; ma={}", port, config.alt_svc_max_age.as_secs());
+    HeaderValue::from_str(&value)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// Binds a listener that every worker thread shares with `SO_REUSEPORT`, or takes over one handed down by the
+/// process this one upgraded.
+fn listen(bind_addr: &str) -> Result<TcpListener, io::Error> {
+    if let Some(fd) = upgrade::take_inherited(upgrade::SocketKind::Tcp, bind_addr) {
+        let listener = TcpListener::from_std(std::net::TcpListener::from(fd))?;
+        upgrade::register(upgrade::SocketKind::Tcp, bind_addr, listener.as_raw_fd());
+        return Ok(listener);
+    }
 
-    let bind_addr = "0.0.0.0:50002";
     let listener = TcpListener::bind(bind_addr)
         .expect(&*("failed to listen on port addr ".to_owned() + bind_addr));
 
@@ -66,25 +315,57 @@ async fn thread_main() -> Result<(), io::Error> {
         }
     }
 
-    println!("Listening");
+    upgrade::register(upgrade::SocketKind::Tcp, bind_addr, listener.as_raw_fd());
+    Ok(listener)
+}
+
+/// Accepts TLS connections until the thread starts draining, handing each to [http1::serve] or [http2::serve] once
+/// its handshake completes.
+async fn accept_tls(listener: TcpListener, acceptor: Rc<TlsAcceptor>, ctx: Rc<ThreadContext>) {
     loop {
-        let incoming = listener.accept().await;
-        match incoming {
-            Ok((stream, addr)) => {
-                //println!("accepted a connection from {}", addr);
-                monoio::spawn(handle_connection(
-                    stream,
-                    http_client.clone(),
-                    origin_manager.clone(),
-                ));
-            }
+        let incoming = monoio::select! {
+            incoming = listener.accept() => incoming,
+            _ = ctx.shutdown.draining() => return,
+        };
+        let (stream, addr) = match incoming {
+            Ok(incoming) => incoming,
             Err(e) => {
-                println!("accepted connection failed: {}", e);
+                println!("accepted TLS connection failed: {}", e);
+                continue;
             }
-        }
+        };
+        let acceptor = acceptor.clone();
+        let ctx = ctx.clone();
+        monoio::spawn(async move {
+            let mode = ctx.config.proxy_protocol.tls;
+            let Some((client_addr, _permit)) = admit(&stream, addr, mode, &ctx).await else {
+                return;
+            };
+            let handshake =
+                monoio::time::timeout(ctx.config.tls.handshake_timeout, acceptor.accept(stream));
+            match handshake.await {
+                Ok(Ok(stream)) => {
+                    let conn = ConnectionInfo {
+                        client_addr,
+                        tls: true,
+                    };
+                    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                        http2::serve(stream, conn, ctx).await;
+                    } else {
+                        http1::serve(stream, conn, ctx).await;
+                    }
+                }
+                Ok(Err(e)) => println!("TLS handshake with {} failed: {}", client_addr, e),
+                Err(_) => println!("TLS handshake with {} timed out", client_addr),
+            }
+        });
     }
 }
 
+/// The number of worker threads, each running its own runtime pinned to a CPU.
+const WORKER_THREADS: usize = 12;
+
+/// Starts a worker thread pinned to `core`.
 fn thread_launcher(core: usize) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
         bind_to_cpu_set([core]).expect("failed to set thread CPU bind");
@@ -98,79 +379,234 @@ fn thread_launcher(core: usize) -> std::thread::JoinHandle<()> {
     })
 }
 
-#[monoio::main(timer_enabled = true, worker_threads = 12)]
-async fn main() {
-    thread_main().await.expect("main failed")
-}
+fn main() {
+    // Load the configuration before any thread starts, so a bad one stops the process here.
+    loaded_config();
 
-async fn handle_connection(
-    stream: TcpStream,
-    http_client: Rc<Client>,
-    origin_manager: Rc<RefCell<origin::OriginManager>>,
-) {
-    let (r, w) = stream.into_split();
-    let sender = GenericEncoder::new(w);
-    let mut receiver = RequestDecoder::new(r);
-    let (mut tx, rx) = spsc_pair();
-    monoio::spawn(handle_task(rx, sender, http_client, origin_manager));
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads: Vec<_> = (0..WORKER_THREADS)
+        .map(|i| thread_launcher(i % cores))
+        .collect();
+    for thread in threads {
+        thread.join().expect("worker thread panicked");
+    }
+}
 
+/// Accepts cleartext HTTP/2 connections from clients with prior knowledge, until the thread starts draining.
+async fn accept_h2c(listener: TcpListener, ctx: Rc<ThreadContext>) {
     loop {
-        match receiver.next().await {
-            None => {
-                //println!("connection closed, connection handler exit");
-                return;
+        let incoming = monoio::select! {
+            incoming = listener.accept() => incoming,
+            _ = ctx.shutdown.draining() => return,
+        };
+        match incoming {
+            Ok((stream, addr)) => {
+                let ctx = ctx.clone();
+                monoio::spawn(async move {
+                    let mode = ctx.config.proxy_protocol.h2c;
+                    let Some((client_addr, _permit)) = admit(&stream, addr, mode, &ctx).await
+                    else {
+                        return;
+                    };
+                    let conn = ConnectionInfo {
+                        client_addr,
+                        tls: false,
+                    };
+                    http2::serve(stream, conn, ctx).await;
+                });
             }
-            Some(Err(_)) => {
-                println!("receive request failed, connection handler exit");
-                return;
+            Err(e) => {
+                println!("accepted h2c connection failed: {}", e);
             }
-            Some(Ok(item)) => match tx.send(item).await {
-                Err(_) => {
-                    println!("request handler dropped, connection handler exit");
-                    return;
-                }
-                Ok(_) => {
-                    //println!("request handled success");
-                }
-            },
         }
     }
 }
 
-async fn handle_task(
-    mut receiver: SPSCReceiver<Request>,
-    mut sender: impl Sink<Response<HttpBody>, Error = impl Into<HttpError>>,
-    http_client: Rc<Client>,
-    origin_manager: Rc<RefCell<origin::OriginManager>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        let request = match receiver.recv().await {
-            Some(r) => r,
-            None => {
-                return Ok(());
-            }
-        };
-        let resp = handle_request(request, http_client.clone(), origin_manager.clone()).await?;
-        sender.send_and_flush(resp).await.map_err(|e| e.into())?;
+/// What's known about a client connection.
+#[derive(Clone, Copy)]
+struct ConnectionInfo {
+    client_addr: SocketAddr,
+
+    /// Whether the connection came in over TLS.
+    tls: bool,
+}
+
+impl ConnectionInfo {
+    /// The scheme the client used.
+    fn scheme(&self) -> &'static str {
+        if self.tls {
+            "https"
+        } else {
+            "http"
+        }
     }
 }
 
+/// Marks a connection as closed in the metrics when dropped.
+struct ConnectionGuard(Rc<ThreadContext>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.metrics.connection_closed();
+    }
+}
+
+/// What happened while handling a request, filled in as it is handled.
+struct RequestOutcome {
+    host: String,
+    cache_status: metrics::CacheResult,
+    origin_time: Option<Duration>,
+}
+
 async fn handle_request(
     req: Request,
-    http_client: Rc<Client>,
-    origin_manager: Rc<RefCell<origin::OriginManager>>,
-) -> Result<Response<HttpBody>, Box<dyn std::error::Error>> {
-    let uri = req.uri();
+    conn: ConnectionInfo,
+    ctx: &ThreadContext,
+) -> Result<Response<ResponseBody>, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let time = SystemTime::now();
+
+    fn header_string(req: &Request, name: http::header::HeaderName) -> Option<String> {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    }
+
+    // Only capture what the access log needs if it's enabled.
+    let log_fields = ctx.access_log.as_ref().map(|_| {
+        (
+            ctx.forwarding
+                .client_ip(req.headers(), conn.client_addr.ip()),
+            req.method().clone(),
+            req.version(),
+            req.uri()
+                .path_and_query()
+                .map(|p| p.as_str().to_owned())
+                .unwrap_or_else(|| "/".to_owned()),
+            header_string(&req, http::header::RANGE),
+            header_string(&req, http::header::USER_AGENT),
+            header_string(&req, http::header::REFERER),
+        )
+    });
+
+    let mut outcome = RequestOutcome {
+        host: UNKNOWN_HOST_LABEL.to_owned(),
+        cache_status: metrics::CacheResult::Miss,
+        origin_time: None,
+    };
+    let http3 = req.version() == Version::HTTP_3;
+    let mut resp = proxy_request(req, conn, ctx, &mut outcome).await?;
+    if let (true, false, Some(alt_svc)) = (conn.tls, http3, &ctx.alt_svc) {
+        resp.headers_mut()
+            .insert(http::header::ALT_SVC, alt_svc.clone());
+    }
+
+    let status = resp.status().as_u16();
+    let host_metrics = ctx.metrics.host(&outcome.host);
+    host_metrics.record_status(status);
+    host_metrics.record_cache_result(outcome.cache_status);
+
+    // The request is logged once its body has been sent, so the bytes and time cover all of it.
+    if let (Some(logger), Some((client_ip, method, version, path, range, user_agent, referer))) =
+        (&ctx.access_log, log_fields)
+    {
+        let entry = accesslog::AccessLogEntry {
+            time,
+            client_ip,
+            host: outcome.host,
+            method,
+            version,
+            path,
+            range,
+            user_agent,
+            referer,
+            status,
+            bytes: 0,
+            cache_status: outcome.cache_status,
+            origin_time: outcome.origin_time,
+            total_time: Duration::ZERO,
+        };
+        let logger = logger.clone();
+        resp = resp.map(|body| {
+            ResponseBody::Logged(Box::new(body::LoggedBody::new(body, logger, entry, start)))
+        });
+    }
+
+    Ok(resp)
+}
+
+/// Builds the response for a request a hook failed on.
+/// Hooks may be enforcing access control, so failures are closed rather than falling through to the origin.
+fn hook_failed(host: &str, err: wasmtime::Error) -> Response<ResponseBody> {
+    println!("wasm hook failed for {}: {:#}", host, err);
+    Builder::new()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(ResponseBody::empty())
+        .unwrap()
+}
+
+/// Builds the response for a request a hook answered itself.
+fn early_response(early: wasm::host::EarlyResponse) -> Response<ResponseBody> {
+    let mut res = Builder::new().status(early.status);
+    for (k, v) in &early.headers {
+        res = res.header(k, v);
+    }
+    res.body(ResponseBody::fixed(early.body)).unwrap()
+}
 
-    fn not_found() -> Response<HttpBody> {
+/// Runs the hook phases that see a response before it is sent.
+/// Returns the status and headers to send, or the response a hook replaced it with.
+async fn run_response_hooks(
+    hook: &mut Option<wasm::HookInstance>,
+    phases: &[wasm::host::Phase],
+    host: &str,
+    ctx: &ThreadContext,
+    status: StatusCode,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap), Response<ResponseBody>> {
+    let Some(instance) = hook else {
+        return Ok((status, headers));
+    };
+
+    let state = instance.state_mut();
+    state.status = status;
+    state.response_headers = headers;
+
+    for &phase in phases {
+        if let Err(e) = instance.run_with_fetches(phase, &ctx.http_client).await {
+            return Err(hook_failed(host, e));
+        }
+        if let Some(early) = instance.state_mut().early_response.take() {
+            return Err(early_response(early));
+        }
+    }
+
+    let state = instance.state_mut();
+    Ok((state.status, std::mem::take(&mut state.response_headers)))
+}
+
+async fn proxy_request(
+    req: Request,
+    conn: ConnectionInfo,
+    ctx: &ThreadContext,
+    outcome: &mut RequestOutcome,
+) -> Result<Response<ResponseBody>, Box<dyn std::error::Error>> {
+    fn not_found() -> Response<ResponseBody> {
         Builder::new()
             .status(StatusCode::NOT_FOUND)
-            .body(HttpBody::from(Payload::Fixed(FixedPayload::new(
-                Bytes::from_static(NOT_FOUND_HTML),
-            ))))
+            .body(ResponseBody::fixed(Bytes::from_static(NOT_FOUND_HTML)))
             .unwrap()
     }
 
+    if let Some(key_authorization) = acme::http01_key_authorization(req.uri().path()) {
+        return Ok(Builder::new()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+            .body(ResponseBody::fixed(Bytes::from(key_authorization)))
+            .unwrap());
+    }
+
     let host = req.headers().get(http::header::HOST);
     if host == None {
         return Ok(not_found());
@@ -187,62 +623,430 @@ async fn handle_request(
         }
         None => {}
     }
+    let host = host.to_owned();
 
-    let origin_manager = origin_manager.borrow();
-    let origin = origin_manager.uri_to_origin_uri(uri.clone(), host);
-    if origin.is_none() {
-        return Ok(not_found());
+    let (mut parts, body) = req.into_parts();
+    forwarded::strip_hop_by_hop(&mut parts.headers);
+
+    // Let the hook module inspect and rewrite the request before anything else happens.
+    let mut hook = match &ctx.hooks {
+        Some(hooks) => {
+            let mut state = wasm::host::HookState::new(
+                parts.method.clone(),
+                parts.uri.clone(),
+                parts.headers.clone(),
+                host.clone(),
+            );
+            state.client_ip = Some(
+                ctx.forwarding
+                    .client_ip(&parts.headers, conn.client_addr.ip()),
+            );
+            match hooks.start_request(state) {
+                Ok(instance) => instance,
+                Err(e) => return Ok(hook_failed(&host, e)),
+            }
+        }
+        None => None,
+    };
+    if let Some(instance) = &mut hook {
+        if let Err(e) = instance
+            .run_with_fetches(wasm::host::Phase::OnRequest, &ctx.http_client)
+            .await
+        {
+            return Ok(hook_failed(&host, e));
+        }
+        if let Some(early) = instance.state_mut().early_response.take() {
+            return Ok(early_response(early));
+        }
+
+        let state = instance.state();
+        parts.uri = state.uri.clone();
+        parts.headers = state.request_headers.clone();
+    }
+
+    let overrides = hook.as_ref().map(|h| &h.state().overrides);
+
+    let cache_key = match overrides.and_then(|o| o.cache_key.clone()) {
+        Some(key) => key,
+        None => hash::cache_key(
+            conn.scheme(),
+            &host,
+            parts
+                .uri
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/"),
+        ),
+    };
+
+    let origin_manager = ctx.origin_manager.borrow();
+    let route = origin_manager.route(&parts.uri, &host);
+
+    // The path to request from the origin, after the route's rewrites.
+    let mut origin_path = parts.uri.clone();
+    let target = match overrides.and_then(|o| o.origin.as_ref()) {
+        Some(origin) => failover::OriginTarget::Fixed(origin::join_origin_uri(origin, &parts.uri)),
+        None => match &route {
+            Some(route) => {
+                origin_path = route.path_and_query.parse()?;
+                failover::OriginTarget::Pool {
+                    pool: route.pool.clone(),
+                    cache_key: cache_key.clone(),
+                }
+            }
+            None => return Ok(not_found()),
+        },
+    };
+
+    // Only hosts that are served get their own metrics, so clients can't add labels by sending any Host header.
+    outcome.host = host.clone();
+    let host_metrics = ctx.metrics.host(&host);
+
+    let mut cache_policy = route.map(|r| r.cache.clone()).unwrap_or_default();
+    if let Some(ttl) = overrides.and_then(|o| o.ttl) {
+        cache_policy.ttl = Some(Duration::from_secs(ttl));
+    }
+    if cache_policy.bypass {
+        outcome.cache_status = metrics::CacheResult::Bypass;
+    }
+
+    let cache_status_headers = origin_manager.host_options(&host).cache_status_headers;
+    drop(origin_manager);
+
+    // The range the client asked for, as the origin may be asked for more.
+    let client_range = parts.headers.get(http::header::RANGE).cloned();
+    let object =
+        if !cache_policy.bypass && (parts.method == Method::GET || parts.method == Method::HEAD) {
+            cache::lookup(&ctx.config.cache, &cache_key).await
+        } else {
+            None
+        };
+    let mut partial = None;
+    let mut stale = None;
+    if let Some(object) = object {
+        let now = SystemTime::now();
+        let age = cachestatus::object_age(object.preamble(), now);
+        let ttl = cachestatus::object_ttl(object.preamble(), now);
+        if object.is_fresh() {
+            match object.respond(
+                &parts.method,
+                client_range.as_ref(),
+                ctx.dio_pool.clone(),
+                host_metrics.clone(),
+            ) {
+                Some((status, headers, cache::CachedResponse::Hit(body))) => {
+                    outcome.cache_status = metrics::CacheResult::Hit;
+                    let reply = CachedReply {
+                        status,
+                        headers,
+                        body,
+                        age,
+                        cache_status: cachestatus::CacheStatus {
+                            result: outcome.cache_status,
+                            fwd_status: None,
+                            ttl: Some(ttl),
+                            key: &cache_key,
+                            partial_bytes: None,
+                        },
+                    };
+                    let res = cached_response(
+                        reply,
+                        &mut hook,
+                        &host,
+                        ctx,
+                        parts.version,
+                        cache_status_headers,
+                    );
+                    return Ok(res.await);
+                }
+                Some((status, headers, cache::CachedResponse::Partial(hit))) => {
+                    partial = Some((status, headers, hit, age, ttl));
+                }
+                // The missing blocks can't be fetched on their own, so the whole response comes from the origin.
+                None => {}
+            }
+        } else if object.can_revalidate() {
+            stale = Some(object);
+        }
+    }
+
+    ctx.forwarding.apply_to_request(
+        &mut parts.headers,
+        parts.version,
+        conn.client_addr.ip(),
+        conn.scheme(),
+    );
+
+    if let Some(instance) = &mut hook {
+        instance.state_mut().request_headers = parts.headers.clone();
+        if let Err(e) = instance
+            .run_with_fetches(wasm::host::Phase::OnOriginRequest, &ctx.http_client)
+            .await
+        {
+            return Ok(hook_failed(&host, e));
+        }
+        if let Some(early) = instance.state_mut().early_response.take() {
+            return Ok(early_response(early));
+        }
+        parts.headers = instance.state().request_headers.clone();
     }
-    let origin = origin.unwrap();
+
+    if let Some((status, headers, hit, age, ttl)) = partial {
+        outcome.cache_status = metrics::CacheResult::Partial;
+        let partial_bytes = hit.bytes();
+        let fetcher = range_fetcher(ctx, target, origin_path, &parts.headers);
+        let reply = CachedReply {
+            status,
+            headers,
+            body: ResponseBody::Partial(hit.into_body(fetcher, ctx.metrics.clone())),
+            age,
+            cache_status: cachestatus::CacheStatus {
+                result: outcome.cache_status,
+                fwd_status: None,
+                ttl: Some(ttl),
+                key: &cache_key,
+                partial_bytes: Some(partial_bytes),
+            },
+        };
+        let res = cached_response(
+            reply,
+            &mut hook,
+            &host,
+            ctx,
+            parts.version,
+            cache_status_headers,
+        );
+        return Ok(res.await);
+    }
+
+    if let Some(object) = &stale {
+        object.set_conditions(&mut parts.headers);
+    }
+    // Whole blocks are fetched so every block the client's range touches can be stored.
+    let fill_range = if parts.method == Method::GET && !cache_policy.bypass {
+        cache::fill_range(
+            parts.headers.get(http::header::RANGE),
+            ctx.config.cache.block_size as u64,
+        )
+        .map(|(range, aligned)| {
+            parts.headers.insert(http::header::RANGE, aligned);
+            range
+        })
+    } else {
+        None
+    };
 
     // Make origin HTTP request.
-    let mut origin_req = Request::builder().method(req.method()).uri(origin);
+    let origin_start = Instant::now();
+    let fill = failover::fetch(
+        &ctx.http_client,
+        &ctx.config.origin,
+        &target,
+        &parts.method,
+        &origin_path,
+        &parts.headers,
+        HttpBody::from(body),
+    )
+    .await?;
+    let origin_res = fill.response;
+    let origin_time = origin_start.elapsed();
+    outcome.origin_time = Some(origin_time);
+
+    let (res_parts, body) = origin_res.into_parts();
+    let fwd_status = res_parts.status.as_u16();
 
-    for (k, v) in req.headers() {
-        origin_req = origin_req.header(k, v);
+    // The expired object is still current, so it is served from the cache.
+    if let (StatusCode::NOT_MODIFIED, Some(mut object)) = (res_parts.status, stale) {
+        object.refresh(&res_parts.headers, &cache_policy).await;
+        outcome.cache_status = metrics::CacheResult::Stale;
+        let now = SystemTime::now();
+        let header = |name: http::header::HeaderName| {
+            res_parts.headers.get(name).and_then(|v| v.to_str().ok())
+        };
+        let age =
+            cachestatus::response_age(header(http::header::DATE), header(http::header::AGE), now);
+        let ttl = cachestatus::object_ttl(object.preamble(), now);
+        let Some((status, headers, response)) = object.respond(
+            &parts.method,
+            client_range.as_ref(),
+            ctx.dio_pool.clone(),
+            host_metrics.clone(),
+        ) else {
+            return Err("revalidated object can't be served from the cache".into());
+        };
+        let (body, partial_bytes) = match response {
+            cache::CachedResponse::Hit(body) => (body, None),
+            cache::CachedResponse::Partial(hit) => {
+                let partial_bytes = hit.bytes();
+                let fetcher = range_fetcher(ctx, target, origin_path, &parts.headers);
+                let body = ResponseBody::Partial(hit.into_body(fetcher, ctx.metrics.clone()));
+                (body, Some(partial_bytes))
+            }
+        };
+        let reply = CachedReply {
+            status,
+            headers,
+            body,
+            age,
+            cache_status: cachestatus::CacheStatus {
+                result: outcome.cache_status,
+                fwd_status: Some(fwd_status),
+                ttl: Some(ttl),
+                key: &cache_key,
+                partial_bytes,
+            },
+        };
+        let res = cached_response(
+            reply,
+            &mut hook,
+            &host,
+            ctx,
+            parts.version,
+            cache_status_headers,
+        );
+        return Ok(res.await);
     }
 
-    let body = req.into_body();
-    let origin_req = origin_req.body(body)?;
-    let origin_res = http_client.send_request(origin_req).await?;
+    let body = failover::resumable(
+        &parts.method,
+        res_parts.status,
+        &res_parts.headers,
+        body,
+        fill.lease,
+        failover::Resume {
+            client: ctx.http_client.clone(),
+            target,
+            uri: origin_path,
+            headers: parts.headers.clone(),
+            block_size: ctx.config.cache.block_size as u64,
+            max_resumes: ctx.config.origin.max_resumes,
+            timeout: ctx.config.origin.response_timeout,
+        },
+    );
+    let mut headers = res_parts.headers;
+    forwarded::strip_hop_by_hop(&mut headers);
 
-    let mut res = Builder::new().status(origin_res.status());
-    for (k, v) in origin_res.headers() {
+    // What's stored is the origin's response, before anything is added for this client.
+    let store = match cache::storable_ttl(&parts.method, res_parts.status, &headers, &cache_policy)
+    {
+        Some(ttl) => {
+            cache::start_store(
+                &ctx.config.cache,
+                &cache_key,
+                res_parts.status,
+                &headers,
+                ttl,
+                ctx.dio_pool.as_ref(),
+                ctx.metrics.clone(),
+            )
+            .await
+        }
+        None => None,
+    };
+    let window = fill_range.and_then(|range| range.narrow(res_parts.status, &mut headers));
+    // Surrogate keys are for purging, not for clients.
+    headers.remove(ctx.config.cache.tag_header.as_str());
+    let body = body::OriginBody::new(body, host_metrics, origin_start, origin_time);
+    let body = if store.is_some() || window.is_some() {
+        ResponseBody::Storing(cache::StoringBody::new(body, store, window))
+    } else {
+        ResponseBody::Origin(body)
+    };
+
+    ctx.forwarding
+        .apply_to_response(&mut headers, res_parts.version);
+    let phases = [
+        wasm::host::Phase::OnOriginResponse,
+        wasm::host::Phase::OnResponse,
+    ];
+    let (status, headers) =
+        match run_response_hooks(&mut hook, &phases, &host, ctx, res_parts.status, headers).await {
+            Ok(response) => response,
+            Err(res) => return Ok(res),
+        };
+
+    let mut res = Builder::new().status(status);
+    for (k, v) in &headers {
         res = res.header(k, v);
     }
 
-    let body = origin_res.into_body();
+    if cache_status_headers {
+        let status = cachestatus::CacheStatus {
+            result: outcome.cache_status,
+            fwd_status: Some(fwd_status),
+            // A fresh fill stays fresh for the whole TTL.
+            ttl: match outcome.cache_status {
+                metrics::CacheResult::Bypass => None,
+                _ => cache_policy.ttl.map(|ttl| ttl.as_secs() as i64),
+            },
+            key: &cache_key,
+            partial_bytes: None,
+        };
+        res = res.header("cache-status", status.to_header_value());
+    }
 
     Ok(res.body(body).unwrap())
+}
+
+/// A response built from the cache, along with what its `Cache-Status` reports.
+struct CachedReply<'a> {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: ResponseBody,
+    age: u64,
+    cache_status: cachestatus::CacheStatus<'a>,
+}
+
+/// Finishes a response from the cache, running the response hook on it.
+async fn cached_response(
+    reply: CachedReply<'_>,
+    hook: &mut Option<wasm::HookInstance>,
+    host: &str,
+    ctx: &ThreadContext,
+    version: Version,
+    cache_status_headers: bool,
+) -> Response<ResponseBody> {
+    let CachedReply {
+        status,
+        mut headers,
+        body,
+        age,
+        cache_status,
+    } = reply;
+    headers.insert(http::header::AGE, HeaderValue::from(age));
+    // Surrogate keys are for purging, not for clients.
+    headers.remove(ctx.config.cache.tag_header.as_str());
+    ctx.forwarding.apply_to_response(&mut headers, version);
+    let phases = [wasm::host::Phase::OnResponse];
+    let (status, headers) =
+        match run_response_hooks(hook, &phases, host, ctx, status, headers).await {
+            Ok(response) => response,
+            Err(res) => return res,
+        };
 
-    // let mut has_error = false;
-    // let mut has_payload = false;
-    // let payload = match req.into_body() {
-    //     Payload::None => Payload::None,
-    //     Payload::Fixed(mut p) => match p.next().await.unwrap() {
-    //         Ok(data) => {
-    //             has_payload = true;
-    //             Payload::Fixed(FixedPayload::new(data))
-    //         }
-    //         Err(_) => {
-    //             has_error = true;
-    //             Payload::None
-    //         }
-    //     },
-    //     Payload::Stream(_) => unimplemented!(),
-    // };
-    //
-    // let status = if has_error {
-    //     StatusCode::INTERNAL_SERVER_ERROR
-    // } else if has_payload {
-    //     StatusCode::OK
-    // } else {
-    //     StatusCode::NO_CONTENT
-    // };
-    // Ok(Builder::new()
-    //     .status(status)
-    //     .header("Server", "monoio-http-demo")
-    //     .body(payload)
-    //     //.body(Payload::Fixed(FixedPayload::new(Bytes::from_static(b"Hello, World!"))))
-    //     .unwrap())
+    let mut res = Builder::new().status(status);
+    for (k, v) in &headers {
+        res = res.header(k, v);
+    }
+    if cache_status_headers {
+        res = res.header("cache-status", cache_status.to_header_value());
+    }
+    res.body(body).unwrap()
+}
+
+/// Builds what a partial hit fetches its missing blocks from the origin with.
+fn range_fetcher(
+    ctx: &ThreadContext,
+    target: failover::OriginTarget,
+    uri: http::Uri,
+    headers: &HeaderMap,
+) -> failover::RangeFetcher {
+    failover::RangeFetcher {
+        client: ctx.http_client.clone(),
+        config: ctx.config.origin.clone(),
+        target,
+        uri,
+        headers: headers.clone(),
+        block_size: ctx.config.cache.block_size as u64,
+    }
 }
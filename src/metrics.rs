@@ -0,0 +1,441 @@
+//! Per-thread counters and histograms, rendered for Prometheus at scrape time.
+//!
+//! Origin bytes are counted as they are read, including the read-ahead bytes fetched past a client's range to fill in
+//! whole blocks, which are also counted on their own. Evictions aren't counted, as the cache doesn't evict objects;
+//! they only leave it through purges.
+
+use crate::cachestate::META_FILE_SUFFIX;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Histogram bucket upper bounds, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// A latency histogram.
+/// Each bucket counts observations less than or equal to its bound; they are made cumulative at scrape time.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|&b| secs <= b) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Whether a request was answered from cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheResult {
+    Hit,
+    Miss,
+
+    /// Some of the requested range was cached and the rest came from origin.
+    Partial,
+
+    /// The cached copy had expired, and was served once the origin confirmed it was still current.
+    Stale,
+
+    /// The route doesn't cache, so the request went straight to the origin.
+    Bypass,
+}
+
+/// Counters for a single host on a single thread.
+#[derive(Default)]
+pub struct HostMetrics {
+    /// Requests by status class (index 0 is 1xx, index 4 is 5xx).
+    requests_by_status: [AtomicU64; 5],
+
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_partial_hits: AtomicU64,
+    cache_stale_hits: AtomicU64,
+    cache_bypasses: AtomicU64,
+    bytes_from_cache: AtomicU64,
+    bytes_from_origin: AtomicU64,
+
+    /// Bytes read from the origin past the client's range, to fill in whole cache blocks.
+    readahead_bytes: AtomicU64,
+
+    origin_requests: AtomicU64,
+    origin_latency: Histogram,
+    origin_ttfb: Histogram,
+}
+
+impl HostMetrics {
+    pub fn record_status(&self, status: u16) {
+        let class = (status / 100).clamp(1, 5) as usize - 1;
+        self.requests_by_status[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_result(&self, result: CacheResult) {
+        let counter = match result {
+            CacheResult::Hit => &self.cache_hits,
+            CacheResult::Miss => &self.cache_misses,
+            CacheResult::Partial => &self.cache_partial_hits,
+            CacheResult::Stale => &self.cache_stale_hits,
+            CacheResult::Bypass => &self.cache_bypasses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes_from_cache(&self, bytes: u64) {
+        self.bytes_from_cache.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes_from_origin(&self, bytes: u64) {
+        self.bytes_from_origin.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_readahead_bytes(&self, bytes: u64) {
+        self.readahead_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records an origin request.
+    /// `ttfb` is the time until the response head arrived, and `total` the time until the body finished.
+    pub fn record_origin_request(&self, ttfb: Duration, total: Option<Duration>) {
+        self.origin_requests.fetch_add(1, Ordering::Relaxed);
+        self.origin_ttfb.observe(ttfb);
+        if let Some(total) = total {
+            self.origin_latency.observe(total);
+        }
+    }
+}
+
+/// Counters for a single worker thread.
+///
+/// Only the owning thread writes to these, and the scraper only reads them, so plain relaxed atomics are enough.
+/// The host map is only locked when a thread sees a host for the first time and at scrape time.
+pub struct ThreadMetrics {
+    thread: usize,
+    hosts: Mutex<HashMap<String, Arc<HostMetrics>>>,
+
+    open_connections: AtomicI64,
+    block_writes: AtomicU64,
+}
+
+/// Every thread's metrics, registered once at thread startup.
+static REGISTRY: Mutex<Vec<Arc<ThreadMetrics>>> = Mutex::new(Vec::new());
+
+/// The cache roots reported on by [render], and how many bytes of blocks each holds.
+static CACHE_USAGE: OnceLock<Vec<(PathBuf, AtomicU64)>> = OnceLock::new();
+
+/// Sets the cache roots to report disk usage for.
+/// The blocks already stored in them are counted on a background thread, so usage climbs to its real value shortly
+/// after startup.
+pub fn set_cache_roots(roots: Vec<PathBuf>) {
+    let usage = roots.into_iter().map(|r| (r, AtomicU64::new(0))).collect();
+    if CACHE_USAGE.set(usage).is_err() {
+        return;
+    }
+
+    let res = std::thread::Builder::new()
+        .name("cache-usage-scan".to_owned())
+        .spawn(|| {
+            for (root, used) in CACHE_USAGE.get().unwrap() {
+                match block_bytes(root) {
+                    Ok(bytes) => {
+                        used.fetch_add(bytes, Ordering::Relaxed);
+                    }
+                    Err(e) => println!("failed to measure cache root {}: {}", root.display(), e),
+                }
+            }
+        });
+    if let Err(e) = res {
+        println!("failed to start cache usage scan: {}", e);
+    }
+}
+
+/// Records blocks being added to (positive) or removed from (negative) a cache root.
+pub fn record_cache_usage(root: &Path, bytes: i64) {
+    let Some((_, used)) = CACHE_USAGE
+        .get()
+        .and_then(|usage| usage.iter().find(|(r, _)| r == root))
+    else {
+        return;
+    };
+    if bytes >= 0 {
+        used.fetch_add(bytes as u64, Ordering::Relaxed);
+    } else {
+        // Blocks removed before the startup scan reached them would otherwise wrap below zero.
+        let _ = used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| {
+            Some(u.saturating_sub(bytes.unsigned_abs()))
+        });
+    }
+}
+
+/// Adds up the sizes of the block files under a directory.
+/// Uses blocking filesystem calls.
+fn block_bytes(dir: &Path) -> io::Result<u64> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut total = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            total += block_bytes(&entry.path())?;
+        } else if file_type.is_file()
+            && !entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(META_FILE_SUFFIX)
+        {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// The per-thread handle used to record metrics.
+/// Host counters are cached locally so recording never takes a lock after a host's first request.
+pub struct MetricsRecorder {
+    shared: Arc<ThreadMetrics>,
+    hosts: RefCell<HashMap<String, Arc<HostMetrics>>>,
+}
+
+impl MetricsRecorder {
+    /// Creates a recorder for the current thread and registers it for scraping.
+    pub fn register() -> Self {
+        let mut registry = REGISTRY.lock().unwrap();
+        let shared = Arc::new(ThreadMetrics {
+            thread: registry.len(),
+            hosts: Mutex::new(HashMap::new()),
+            open_connections: AtomicI64::new(0),
+            block_writes: AtomicU64::new(0),
+        });
+        registry.push(shared.clone());
+
+        Self {
+            shared,
+            hosts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the counters for the specified host.
+    pub fn host(&self, host: &str) -> Arc<HostMetrics> {
+        if let Some(metrics) = self.hosts.borrow().get(host) {
+            return metrics.clone();
+        }
+
+        let metrics = self
+            .shared
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_default()
+            .clone();
+        self.hosts
+            .borrow_mut()
+            .insert(host.to_owned(), metrics.clone());
+        metrics
+    }
+
+    pub fn connection_opened(&self) {
+        self.shared.open_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.shared.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_block_write(&self) {
+        self.shared.block_writes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, h: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&h.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+    }
+    let count = h.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+    let _ = writeln!(
+        out,
+        "{}_sum{{{}}} {}",
+        name,
+        labels,
+        h.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    );
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+}
+
+/// Renders every registered thread's metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let threads: Vec<Arc<ThreadMetrics>> = REGISTRY.lock().unwrap().clone();
+    let mut out = String::with_capacity(16 * 1024);
+
+    let _ = writeln!(out, "# TYPE stavka_open_connections gauge");
+    for t in &threads {
+        let _ = writeln!(
+            out,
+            "stavka_open_connections{{thread=\"{}\"}} {}",
+            t.thread,
+            t.open_connections.load(Ordering::Relaxed)
+        );
+    }
+
+    let _ = writeln!(out, "# TYPE stavka_block_writes_total counter");
+    for t in &threads {
+        let _ = writeln!(
+            out,
+            "stavka_block_writes_total{{thread=\"{}\"}} {}",
+            t.thread,
+            t.block_writes.load(Ordering::Relaxed)
+        );
+    }
+
+    // Snapshot the host maps so the per-thread locks are held as briefly as possible.
+    let hosts: Vec<(usize, Vec<(String, Arc<HostMetrics>)>)> = threads
+        .iter()
+        .map(|t| {
+            let hosts = t.hosts.lock().unwrap();
+            (
+                t.thread,
+                hosts.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            )
+        })
+        .collect();
+
+    let _ = writeln!(out, "# TYPE stavka_requests_total counter");
+    for (thread, host_metrics) in &hosts {
+        for (host, m) in host_metrics {
+            for (i, counter) in m.requests_by_status.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "stavka_requests_total{{thread=\"{}\",host=\"{}\",status=\"{}xx\"}} {}",
+                    thread,
+                    escape_label(host),
+                    i + 1,
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# TYPE stavka_cache_requests_total counter");
+    for (thread, host_metrics) in &hosts {
+        for (host, m) in host_metrics {
+            for (result, counter) in [
+                ("hit", &m.cache_hits),
+                ("miss", &m.cache_misses),
+                ("partial", &m.cache_partial_hits),
+                ("stale", &m.cache_stale_hits),
+                ("bypass", &m.cache_bypasses),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "stavka_cache_requests_total{{thread=\"{}\",host=\"{}\",result=\"{}\"}} {}",
+                    thread,
+                    escape_label(host),
+                    result,
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# TYPE stavka_served_bytes_total counter");
+    for (thread, host_metrics) in &hosts {
+        for (host, m) in host_metrics {
+            for (source, counter) in [
+                ("cache", &m.bytes_from_cache),
+                ("origin", &m.bytes_from_origin),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "stavka_served_bytes_total{{thread=\"{}\",host=\"{}\",source=\"{}\"}} {}",
+                    thread,
+                    escape_label(host),
+                    source,
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# TYPE stavka_readahead_bytes_total counter");
+    for (thread, host_metrics) in &hosts {
+        for (host, m) in host_metrics {
+            let _ = writeln!(
+                out,
+                "stavka_readahead_bytes_total{{thread=\"{}\",host=\"{}\"}} {}",
+                thread,
+                escape_label(host),
+                m.readahead_bytes.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let _ = writeln!(out, "# TYPE stavka_origin_requests_total counter");
+    for (thread, host_metrics) in &hosts {
+        for (host, m) in host_metrics {
+            let _ = writeln!(
+                out,
+                "stavka_origin_requests_total{{thread=\"{}\",host=\"{}\"}} {}",
+                thread,
+                escape_label(host),
+                m.origin_requests.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    for (name, get) in [
+        (
+            "stavka_origin_ttfb_seconds",
+            (|m| &m.origin_ttfb) as fn(&HostMetrics) -> &Histogram,
+        ),
+        ("stavka_origin_latency_seconds", |m| &m.origin_latency),
+    ] {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (thread, host_metrics) in &hosts {
+            for (host, m) in host_metrics {
+                let labels = format!("thread=\"{}\",host=\"{}\"", thread, escape_label(host));
+                write_histogram(&mut out, name, &labels, get(m));
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# TYPE stavka_disk_used_bytes gauge");
+    for (root, used) in CACHE_USAGE.get().map(|u| u.as_slice()).unwrap_or(&[]) {
+        let _ = writeln!(
+            out,
+            "stavka_disk_used_bytes{{root=\"{}\"}} {}",
+            escape_label(&root.display().to_string()),
+            used.load(Ordering::Relaxed)
+        );
+    }
+
+    out
+}
@@ -1,52 +1,154 @@
-use http::uri::{Authority, Scheme};
+use crate::config::Config;
+use crate::originpool::{OriginPool, PoolHealth};
+use crate::routing::{
+    CachePolicy, HostPattern, PathPattern, PathRewrite, Route, RouteMatch, RouteTable,
+};
 use http::Uri;
 use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Builds the URI for fetching the specified request URI from an origin given as `scheme://authority`.
+pub fn join_origin_uri(origin: &Uri, uri: &Uri) -> Uri {
+    let mut builder = Uri::builder();
+    if let Some(scheme) = origin.scheme() {
+        builder = builder.scheme(scheme.clone());
+    }
+    if let Some(authority) = origin.authority() {
+        builder = builder.authority(authority.clone());
+    }
+
+    builder
+        .path_and_query(
+            uri.path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/")
+                .to_owned(),
+        )
+        .build()
+        .expect("URI built from inputted URI should have been valid")
+}
+
+/// Per-host behavior settings.
+#[derive(Clone, Default)]
+pub struct HostOptions {
+    /// Whether to add the `Cache-Status` debugging header to responses.
+    /// Useful while debugging, but usually hidden in production.
+    pub cache_status_headers: bool,
+}
 
 pub struct OriginManager {
+    routes: RouteTable,
+
     /// Key: normalized DNS hostname
-    /// Value: URL-formatted IP (e.g. `192.168.1.1`, `[::1]`)
-    host_to_origin_host: HashMap<String, (Scheme, Authority)>,
+    /// Hosts without an entry use the default options.
+    host_options: HashMap<String, HostOptions>,
+
+    default_host_options: HostOptions,
 }
 
 impl OriginManager {
     pub fn new() -> OriginManager {
         OriginManager {
-            host_to_origin_host: HashMap::new(),
+            routes: RouteTable::new(),
+            host_options: HashMap::new(),
+            default_host_options: HostOptions::default(),
         }
     }
 
-    /// Resolves the appropriate origin URI for the specified URI.
-    /// If there is no origin for the URI, returns [None].
-    /// The hostname must not contain a port number.
-    pub fn uri_to_origin_uri<T: Into<Uri>>(&self, uri: T, hostname: &str) -> Option<Uri> {
-        let uri = uri.into();
-        let host = uri.host().unwrap_or(hostname);
+    /// Builds the pools and routes described by the configuration.
+    /// Each worker thread builds its own, so pools balance per thread, but share the results of their health checks.
+    pub fn from_config(config: &Config) -> Result<OriginManager, String> {
+        let mut manager = OriginManager::new();
 
-        let (origin_scheme, origin_authority) = self.host_to_origin_host.get(host)?;
+        let mut pools = HashMap::new();
+        for (name, pool) in &config.pools {
+            if pool.servers.is_empty() {
+                return Err(format!("pool {} has no servers", name));
+            }
+            let health = PoolHealth::shared(name, pool.servers.len());
+            let origin_pool = OriginPool::new(pool.servers.clone(), pool.options.clone(), health);
+            pools.insert(name.as_str(), Rc::new(origin_pool));
+        }
 
-        let mut builder = Uri::builder()
-            .scheme(origin_scheme.clone())
-            .authority(origin_authority.clone());
+        for route in &config.routes {
+            let pool = pools.get(route.pool.as_str()).ok_or_else(|| {
+                format!("route for {} uses unknown pool {}", route.host, route.pool)
+            })?;
+            let rule = Route {
+                path: route.path.clone(),
+                pool: pool.clone(),
+                rewrite: route.rewrite.clone(),
+                cache: route.cache.clone(),
+            };
+            manager.add_route(&route.host, rule).map_err(|e| {
+                format!("route for {} has an invalid path regex: {}", route.host, e)
+            })?;
+        }
 
-        if let Some(path_and_query) = uri.path_and_query() {
-            builder = builder.path_and_query(path_and_query.to_owned());
+        // Each host's catch-all goes after its routes, since routes for a host are tried in order.
+        let mut hosts = HashMap::new();
+        for (name, pool) in &config.pools {
+            for host in &pool.hosts {
+                if let Some(other) = hosts.insert(host.to_ascii_lowercase(), name) {
+                    return Err(format!(
+                        "host {} is sent to both pool {} and pool {}",
+                        host, other, name
+                    ));
+                }
+                manager.set_origin_pool(host, pools[name.as_str()].clone());
+            }
         }
 
-        Some(
-            builder
-                .build()
-                .expect("URI built from inputted URI should have been valid"),
-        )
+        for (host, options) in &config.hosts {
+            manager.set_host_options(host.to_ascii_lowercase(), options.clone());
+        }
+
+        Ok(manager)
+    }
+
+    /// Returns the options for the specified host.
+    /// The hostname must not contain a port number.
+    pub fn host_options(&self, hostname: &str) -> &HostOptions {
+        self.host_options
+            .get(hostname)
+            .unwrap_or(&self.default_host_options)
+    }
+
+    /// Sets the options for the specified host.
+    pub fn set_host_options(&mut self, host: String, options: HostOptions) {
+        self.host_options.insert(host, options);
+    }
+
+    /// Finds the route for the specified URI.
+    /// If there is no origin for the URI, returns [None].
+    /// The hostname must not contain a port number.
+    pub fn route(&self, uri: &Uri, hostname: &str) -> Option<RouteMatch<'_>> {
+        let host = uri.host().unwrap_or(hostname).to_ascii_lowercase();
+        let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        self.routes.route(&host, path_and_query)
+    }
+
+    /// Returns every route's origin pool.
+    pub fn pools(&self) -> Vec<Rc<OriginPool>> {
+        self.routes.pools()
+    }
+
+    /// Sends every request for the specified host that no earlier route matched to a pool of origin servers.
+    /// Health checks for the pool are started by [OriginPool::run_health_checks].
+    pub fn set_origin_pool(&mut self, host: &str, pool: Rc<OriginPool>) {
+        let route = Route {
+            path: PathPattern::Any,
+            pool,
+            rewrite: PathRewrite::default(),
+            cache: CachePolicy::default(),
+        };
+        self.add_route(host, route)
+            .expect("catch-all route should have compiled");
     }
 
-    /// Sets the origin host for the specified host.
-    pub fn set_origin_host(
-        &mut self,
-        host: String,
-        origin_scheme: Scheme,
-        origin_authority: Authority,
-    ) {
-        self.host_to_origin_host
-            .insert(host, (origin_scheme, origin_authority));
+    /// Adds a routing rule for the hosts matching a pattern such as `cdn.example.com` or `*.cdn.example.com`.
+    /// Rules for a host are tried in the order they were added.
+    pub fn add_route(&mut self, host: &str, route: Route) -> Result<(), regex::Error> {
+        self.routes.add(HostPattern::parse(host), route)
     }
 }
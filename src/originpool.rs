@@ -0,0 +1,533 @@
+//! Pools of origin servers behind a single host.
+//!
+//! Each request picks a server with the pool's [BalanceStrategy], skipping servers that are down. A server goes
+//! down when active health checks against it fail, or when enough requests to it fail in a row (passive ejection).
+//! Passively ejected servers are tried again after [PoolOptions::ejection_time]; servers failing active checks come
+//! back once the checks pass again.
+//!
+//! Pools live on a worker thread, so each thread balances, counts connections and ejects servers on its own. Active
+//! health checks run on one thread only, and every thread's copy of a pool reads their results from the
+//! [PoolHealth] it shares with the others.
+
+use crate::originreq::{OriginConnector, OriginRequestOptions};
+use http::uri::{Authority, Scheme};
+use http::{Request, StatusCode, Uri};
+use monoio_http::common::body::HttpBody;
+use monoio_http::h1::payload::Payload;
+use monoio_http_client::Client;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Every pool's health, by pool name.
+static POOL_HEALTH: OnceLock<Mutex<HashMap<String, Arc<PoolHealth>>>> = OnceLock::new();
+
+/// How a pool spreads requests across its servers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Take turns, in proportion to the servers' weights.
+    #[default]
+    RoundRobin,
+
+    /// Pick the server with the fewest in-flight requests relative to its weight.
+    LeastConnections,
+
+    /// Pick a server by hashing the request URL (its cache key), so each object is always filled from the same server.
+    /// Only the objects of a server that goes down move elsewhere.
+    UrlHash,
+}
+
+/// A server in a pool.
+#[derive(Clone)]
+pub struct OriginServer {
+    pub scheme: Scheme,
+    pub authority: Authority,
+
+    /// The server's share of requests relative to the others in the pool.
+    /// A weight of 0 makes it a backup, only used while every other server is down.
+    pub weight: u32,
+}
+
+/// Active health check settings.
+#[derive(Clone)]
+pub struct HealthCheck {
+    /// The path requested from each server.
+    /// Any 2xx or 3xx response counts as a pass.
+    pub path: String,
+
+    /// The `Host` header sent with checks.
+    /// If empty, the pool's `Host` header setting is used, or else the server's address.
+    pub host: String,
+
+    pub interval: Duration,
+
+    /// How long a check may take before it counts as a fail.
+    pub timeout: Duration,
+
+    /// The number of consecutive fails that take a server down.
+    pub unhealthy_threshold: u32,
+
+    /// The number of consecutive passes that bring a server back up.
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/".to_owned(),
+            host: String::new(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            unhealthy_threshold: 2,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+/// Pool behavior settings.
+#[derive(Clone)]
+pub struct PoolOptions {
+    pub strategy: BalanceStrategy,
+
+    /// If [None], servers are only taken down by passive ejection.
+    pub health_check: Option<HealthCheck>,
+
+    /// The number of consecutive failed requests that eject a server.
+    /// Connection errors and 502, 503 and 504 responses count as failures.
+    pub max_failures: u32,
+
+    /// How long a passively ejected server is skipped before being tried again.
+    pub ejection_time: Duration,
+
+    /// How requests are presented to the servers.
+    pub request: OriginRequestOptions,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            strategy: BalanceStrategy::RoundRobin,
+            health_check: None,
+            max_failures: 5,
+            ejection_time: Duration::from_secs(30),
+            request: OriginRequestOptions::default(),
+        }
+    }
+}
+
+/// The results of a pool's active health checks, shared by every worker thread's copy of the pool.
+pub struct PoolHealth {
+    /// Set while a thread is running the pool's health checks.
+    checking: AtomicBool,
+
+    /// In the same order as the pool's servers.
+    servers: Vec<ServerHealth>,
+}
+
+impl PoolHealth {
+    /// Creates the health of a pool that isn't shared with other threads.
+    pub fn new(servers: usize) -> PoolHealth {
+        PoolHealth {
+            checking: AtomicBool::new(false),
+            servers: (0..servers)
+                .map(|_| ServerHealth {
+                    healthy: AtomicBool::new(true),
+                    check_passes: AtomicU32::new(0),
+                    check_fails: AtomicU32::new(0),
+                    recoveries: AtomicU64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the health of the named pool, which every worker thread building the pool gets the same copy of.
+    pub fn shared(pool: &str, servers: usize) -> Arc<PoolHealth> {
+        POOL_HEALTH
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap()
+            .entry(pool.to_owned())
+            .or_insert_with(|| Arc::new(PoolHealth::new(servers)))
+            .clone()
+    }
+}
+
+/// A server's active health check results.
+struct ServerHealth {
+    /// Cleared while active health checks are failing.
+    healthy: AtomicBool,
+
+    /// Consecutive active check results, counting towards the thresholds.
+    /// Only the thread running the checks updates these.
+    check_passes: AtomicU32,
+    check_fails: AtomicU32,
+
+    /// How many times the server has come back up, which ends passive ejections on every thread.
+    recoveries: AtomicU64,
+}
+
+/// A server and its health.
+struct ServerState {
+    server: OriginServer,
+
+    /// The number of [ServerHealth::recoveries] this thread has seen.
+    recoveries_seen: Cell<u64>,
+
+    /// Consecutive failed requests.
+    failures: Cell<u32>,
+
+    /// Set while the server is passively ejected.
+    ejected_until: Cell<Option<Instant>>,
+
+    /// In-flight requests.
+    active: Cell<u32>,
+
+    /// The server's running total for smooth weighted round-robin.
+    current_weight: Cell<i64>,
+}
+
+impl ServerState {
+    fn is_up(&self, health: &ServerHealth, now: Instant) -> bool {
+        if !health.healthy.load(Ordering::Relaxed) {
+            return false;
+        }
+        // A passing check also ends a passive ejection early.
+        let recoveries = health.recoveries.load(Ordering::Relaxed);
+        if recoveries != self.recoveries_seen.get() {
+            self.recoveries_seen.set(recoveries);
+            self.ejected_until.set(None);
+            self.failures.set(0);
+        }
+        match self.ejected_until.get() {
+            Some(until) if until > now => false,
+            Some(_) => {
+                println!("origin {} reinstated after ejection", self.server.authority);
+                self.ejected_until.set(None);
+                self.failures.set(0);
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+/// A set of servers requests for a host are spread across.
+pub struct OriginPool {
+    servers: Vec<ServerState>,
+    health: Arc<PoolHealth>,
+    options: PoolOptions,
+    connector: OriginConnector,
+}
+
+impl OriginPool {
+    /// `health` must list as many servers as `servers`.
+    pub fn new(
+        servers: Vec<OriginServer>,
+        options: PoolOptions,
+        health: Arc<PoolHealth>,
+    ) -> OriginPool {
+        assert_eq!(
+            servers.len(),
+            health.servers.len(),
+            "BUG: pool health doesn't match its servers"
+        );
+        OriginPool {
+            servers: servers
+                .into_iter()
+                .zip(&health.servers)
+                .map(|(server, server_health)| ServerState {
+                    server,
+                    recoveries_seen: Cell::new(server_health.recoveries.load(Ordering::Relaxed)),
+                    failures: Cell::new(0),
+                    ejected_until: Cell::new(None),
+                    active: Cell::new(0),
+                    current_weight: Cell::new(0),
+                })
+                .collect(),
+            health,
+            connector: OriginConnector::new(options.request.clone()),
+            options,
+        }
+    }
+
+    pub fn options(&self) -> &PoolOptions {
+        &self.options
+    }
+
+    /// Sends requests to the pool's servers.
+    pub fn connector(&self) -> &OriginConnector {
+        &self.connector
+    }
+
+    /// Picks a server for a request.
+    /// `cache_key` identifies the object being fetched, for [BalanceStrategy::UrlHash].
+    /// Servers in `exclude`, such as ones a retried request already failed on, are skipped unless they are all
+    /// that's left.
+    ///
+    /// Servers with a weight of 0 are only used if every weighted server is down. If every server is down, all of
+    /// them are considered anyway, since a server that might be down beats certainly failing the request.
+    /// Returns [None] only if the pool is empty.
+    pub fn select(self: &Rc<Self>, cache_key: &str, exclude: &[usize]) -> Option<OriginLease> {
+        let mut allowed: Vec<usize> = (0..self.servers.len())
+            .filter(|i| !exclude.contains(i))
+            .collect();
+        if allowed.is_empty() {
+            allowed = (0..self.servers.len()).collect();
+        }
+
+        let now = Instant::now();
+        let up: Vec<usize> = allowed
+            .iter()
+            .copied()
+            .filter(|&i| self.servers[i].is_up(&self.health.servers[i], now))
+            .collect();
+
+        let mut candidates: Vec<usize> = up
+            .iter()
+            .copied()
+            .filter(|&i| self.servers[i].server.weight > 0)
+            .collect();
+        if candidates.is_empty() {
+            candidates = up;
+        }
+        if candidates.is_empty() {
+            candidates = allowed;
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self.options.strategy {
+            BalanceStrategy::RoundRobin => self.select_round_robin(&candidates),
+            BalanceStrategy::LeastConnections => self.select_least_connections(&candidates),
+            BalanceStrategy::UrlHash => self.select_url_hash(&candidates, cache_key),
+        };
+
+        let state = &self.servers[index];
+        state.active.set(state.active.get() + 1);
+        Some(OriginLease {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// Weights of 0 are treated as 1, since backups only get here when they are all that's left.
+    fn weight(&self, index: usize) -> i64 {
+        self.servers[index].server.weight.max(1) as i64
+    }
+
+    /// Smooth weighted round-robin, which interleaves servers instead of sending each its whole share in a row.
+    fn select_round_robin(&self, candidates: &[usize]) -> usize {
+        let mut total = 0;
+        let mut best = candidates[0];
+        for &i in candidates {
+            let weight = self.weight(i);
+            total += weight;
+            let state = &self.servers[i];
+            state
+                .current_weight
+                .set(state.current_weight.get() + weight);
+            if state.current_weight.get() > self.servers[best].current_weight.get() {
+                best = i;
+            }
+        }
+
+        let state = &self.servers[best];
+        state.current_weight.set(state.current_weight.get() - total);
+        best
+    }
+
+    fn select_least_connections(&self, candidates: &[usize]) -> usize {
+        // Compare active / weight without dividing.
+        *candidates
+            .iter()
+            .min_by(|&&a, &&b| {
+                let a_load = self.servers[a].active.get() as i64 * self.weight(b);
+                let b_load = self.servers[b].active.get() as i64 * self.weight(a);
+                a_load.cmp(&b_load)
+            })
+            .expect("candidates should not have been empty")
+    }
+
+    /// Weighted rendezvous hashing: every server scores the URL and the best score wins.
+    /// Removing a server only moves the URLs it was winning.
+    fn select_url_hash(&self, candidates: &[usize], cache_key: &str) -> usize {
+        let score = |i: usize| {
+            let mut hasher = xxhash_rust::xxh3::Xxh3::with_seed(0);
+            hasher.write(self.servers[i].server.authority.as_str().as_bytes());
+            hasher.write(cache_key.as_bytes());
+            // Map the hash into (0, 1), then weight it so higher weights win proportionally more often.
+            let unit = ((hasher.finish() >> 11) as f64 + 1.0) / ((1u64 << 53) as f64 + 1.0);
+            -(self.weight(i) as f64) / unit.ln()
+        };
+
+        *candidates
+            .iter()
+            .max_by(|&&a, &&b| score(a).total_cmp(&score(b)))
+            .expect("candidates should not have been empty")
+    }
+
+    /// Records the result of a request to a server, ejecting it after too many failures in a row.
+    fn record_result(&self, index: usize, success: bool) {
+        let state = &self.servers[index];
+        if success {
+            state.failures.set(0);
+            return;
+        }
+
+        let failures = state.failures.get() + 1;
+        state.failures.set(failures);
+        if failures >= self.options.max_failures && state.ejected_until.get().is_none() {
+            println!(
+                "origin {} ejected after {} consecutive failures",
+                state.server.authority, failures
+            );
+            state
+                .ejected_until
+                .set(Some(Instant::now() + self.options.ejection_time));
+        }
+    }
+
+    /// Records the result of an active health check, for every thread.
+    fn record_check(&self, index: usize, passed: bool, check: &HealthCheck) {
+        let authority = &self.servers[index].server.authority;
+        let health = &self.health.servers[index];
+        let healthy = health.healthy.load(Ordering::Relaxed);
+        if passed {
+            health.check_fails.store(0, Ordering::Relaxed);
+            let passes = health.check_passes.fetch_add(1, Ordering::Relaxed) + 1;
+            if !healthy && passes >= check.healthy_threshold {
+                println!("origin {} is healthy again", authority);
+                health.recoveries.fetch_add(1, Ordering::Relaxed);
+                health.healthy.store(true, Ordering::Relaxed);
+            }
+        } else {
+            health.check_passes.store(0, Ordering::Relaxed);
+            let fails = health.check_fails.fetch_add(1, Ordering::Relaxed) + 1;
+            if healthy && fails >= check.unhealthy_threshold {
+                println!("origin {} failed health checks", authority);
+                health.healthy.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Runs active health checks until the pool is dropped by everything else.
+    /// Does nothing if the pool has no health check configured, or if another thread is already checking it.
+    pub async fn run_health_checks(self: Rc<Self>, client: Rc<Client>) {
+        let Some(check) = self.options.health_check.clone() else {
+            return;
+        };
+        if self.health.checking.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        // Only this task is left holding the pool once it's been replaced.
+        while Rc::strong_count(&self) > 1 {
+            for index in 0..self.servers.len() {
+                let passed = self.check_server(index, &check, &client).await;
+                self.record_check(index, passed, &check);
+            }
+            monoio::time::sleep(check.interval).await;
+        }
+        self.health.checking.store(false, Ordering::Release);
+    }
+
+    async fn check_server(&self, index: usize, check: &HealthCheck, client: &Client) -> bool {
+        let server = &self.servers[index].server;
+        let uri = Uri::builder()
+            .scheme(server.scheme.clone())
+            .authority(server.authority.clone())
+            .path_and_query(check.path.as_str())
+            .build();
+        let Ok(uri) = uri else {
+            return false;
+        };
+
+        let host = if check.host.is_empty() {
+            server.authority.as_str()
+        } else {
+            check.host.as_str()
+        };
+        let Ok(mut req) = Request::builder()
+            .uri(uri)
+            .header(http::header::HOST, host)
+            .body(HttpBody::from(Payload::None))
+        else {
+            return false;
+        };
+        // A host set for the check wins over the pool's.
+        let check_host = req.headers().get(http::header::HOST).cloned();
+        self.connector.prepare(req.headers_mut());
+        if let (false, Some(check_host)) = (check.host.is_empty(), check_host) {
+            req.headers_mut().insert(http::header::HOST, check_host);
+        }
+
+        match monoio::time::timeout(check.timeout, self.connector.send(client, req)).await {
+            Ok(Ok(res)) => res.status().is_success() || res.status().is_redirection(),
+            Ok(Err(_)) | Err(_) => false,
+        }
+    }
+}
+
+/// A server picked for a request.
+/// Counts as an in-flight request for [BalanceStrategy::LeastConnections] until dropped.
+pub struct OriginLease {
+    pool: Rc<OriginPool>,
+    index: usize,
+}
+
+impl OriginLease {
+    pub fn pool(&self) -> &Rc<OriginPool> {
+        &self.pool
+    }
+
+    /// The server's position in the pool, for excluding it from later picks.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn server(&self) -> &OriginServer {
+        &self.pool.servers[self.index].server
+    }
+
+    /// Builds the URI for fetching the specified request URI from the server.
+    pub fn origin_uri(&self, uri: &Uri) -> Uri {
+        let server = self.server();
+        let mut builder = Uri::builder()
+            .scheme(server.scheme.clone())
+            .authority(server.authority.clone());
+
+        if let Some(path_and_query) = uri.path_and_query() {
+            builder = builder.path_and_query(path_and_query.to_owned());
+        }
+
+        builder
+            .build()
+            .expect("URI built from inputted URI should have been valid")
+    }
+
+    /// Records a request that got a response with the specified status.
+    pub fn record_response(&self, status: StatusCode) {
+        let failed = matches!(
+            status,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        );
+        self.pool.record_result(self.index, !failed);
+    }
+
+    /// Records a request that failed without a response.
+    pub fn record_error(&self) {
+        self.pool.record_result(self.index, false);
+    }
+}
+
+impl Drop for OriginLease {
+    fn drop(&mut self) {
+        let state = &self.pool.servers[self.index];
+        state.active.set(state.active.get() - 1);
+    }
+}
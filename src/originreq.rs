@@ -0,0 +1,315 @@
+//! How requests are presented to an origin pool's servers: the `Host` header, extra headers, and the TLS server
+//! name and certificate checks.
+//!
+//! Pools with default TLS settings share the worker thread's client and its connection reuse. Pools that change
+//! the server name or how certificates are checked connect on their own, one connection per request, since the
+//! shared client always presents the URI's host.
+
+use http::header::HOST;
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Uri};
+use monoio::net::TcpStream;
+use monoio_http::common::body::HttpBody;
+use monoio_http::common::response::Response;
+use monoio_http::h1::codec::ClientCodec;
+use monoio_http_client::Client;
+use monoio_rustls::TlsConnector;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::cell::OnceCell;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// How an origin's TLS certificate is checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TlsVerify {
+    /// The certificate must chain to a trusted root and be valid for the server name.
+    #[default]
+    Full,
+
+    /// The certificate must chain to a trusted root, but may be for any name.
+    /// For origins addressed by IP that present a certificate for their public name.
+    IgnoreHostname,
+
+    /// Any certificate is accepted. Only for testing, since anyone on the path can impersonate the origin.
+    Insecure,
+}
+
+/// TLS settings for an origin.
+#[derive(Clone, Default)]
+pub struct OriginTls {
+    /// The server name sent in SNI and checked against the certificate.
+    /// If [None], the host of the server's address is used.
+    pub sni: Option<String>,
+
+    pub verify: TlsVerify,
+
+    /// A PEM file of CA certificates to trust instead of the bundled public roots.
+    pub ca_bundle: Option<PathBuf>,
+}
+
+impl OriginTls {
+    /// Whether the settings differ from what the shared client does.
+    fn is_custom(&self) -> bool {
+        self.sni.is_some() || self.verify != TlsVerify::Full || self.ca_bundle.is_some()
+    }
+
+    /// Checks custom settings can be used, such as that the CA bundle can be read.
+    pub fn check(&self) -> Result<(), String> {
+        if self.is_custom() {
+            build_tls_config(self).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-origin request settings.
+#[derive(Clone, Default)]
+pub struct OriginRequestOptions {
+    /// The `Host` header sent to the origin.
+    /// If [None], the client's `Host` header is passed through.
+    pub host_header: Option<HeaderValue>,
+
+    pub tls: OriginTls,
+
+    /// Headers added to every origin request, replacing any the client sent, such as a secret the origin uses to
+    /// check requests came through the proxy.
+    pub add_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Client headers that aren't passed on to the origin.
+    pub remove_headers: Vec<HeaderName>,
+}
+
+/// Sends requests to an origin pool's servers with its [OriginRequestOptions].
+pub struct OriginConnector {
+    options: OriginRequestOptions,
+
+    /// Built on first use, if the TLS settings are custom.
+    tls_config: OnceCell<Result<Arc<ClientConfig>, String>>,
+}
+
+impl OriginConnector {
+    pub fn new(options: OriginRequestOptions) -> OriginConnector {
+        OriginConnector {
+            options,
+            tls_config: OnceCell::new(),
+        }
+    }
+
+    /// Applies the header settings to an origin request's headers.
+    pub fn prepare(&self, headers: &mut HeaderMap) {
+        for name in &self.options.remove_headers {
+            headers.remove(name);
+        }
+        if let Some(host) = &self.options.host_header {
+            headers.insert(HOST, host.clone());
+        }
+        for (name, value) in &self.options.add_headers {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+
+    /// Sends a prepared request.
+    pub async fn send(
+        &self,
+        client: &Client,
+        req: Request<HttpBody>,
+    ) -> Result<Response<HttpBody>, Box<dyn Error>> {
+        if req.uri().scheme() != Some(&Scheme::HTTPS) || !self.options.tls.is_custom() {
+            return Ok(client.send_request(req).await?);
+        }
+
+        let config = self
+            .tls_config
+            .get_or_init(|| build_tls_config(&self.options.tls).map_err(|e| e.to_string()))
+            .clone()?;
+
+        let (mut parts, body) = req.into_parts();
+        let authority = parts
+            .uri
+            .authority()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "origin URI has no host"))?
+            .clone();
+        let server_name = match &self.options.tls.sni {
+            Some(sni) => sni.clone(),
+            None => authority.host().trim_matches(['[', ']']).to_owned(),
+        };
+        let server_name = ServerName::try_from(server_name)?;
+
+        let tcp = TcpStream::connect((
+            authority.host().trim_matches(['[', ']']),
+            authority.port_u16().unwrap_or(443),
+        ))
+        .await?;
+        let tls = TlsConnector::from(config).connect(server_name, tcp).await?;
+
+        // The request goes straight to the server, so it only carries the path.
+        parts.uri = Uri::try_from(
+            parts
+                .uri
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/"),
+        )?;
+        parts
+            .headers
+            .entry(HOST)
+            .or_insert(HeaderValue::from_str(authority.as_str())?);
+
+        let mut codec = ClientCodec::new(tls);
+        codec
+            .send_and_flush(Request::from_parts(parts, body))
+            .await?;
+        let res = codec.next().await.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "origin closed the connection")
+        })??;
+
+        // The codec reads the body as the response is consumed, so it has to outlive this call.
+        let (parts, payload) = res.into_parts();
+        monoio::spawn(async move {
+            let _ = codec.fill_payload().await;
+        });
+        Ok(Response::from_parts(parts, HttpBody::from(payload)))
+    }
+}
+
+/// Builds the TLS client config for custom origin TLS settings.
+fn build_tls_config(tls: &OriginTls) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    match &tls.ca_bundle {
+        Some(path) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            for cert in rustls_pemfile::certs(&mut reader) {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let roots = Arc::new(roots);
+
+    let config = match tls.verify {
+        TlsVerify::Full => ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+        TlsVerify::IgnoreHostname => ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(IgnoreHostname(
+                WebPkiServerVerifier::builder(roots).build()?,
+            )))
+            .with_no_client_auth(),
+        TlsVerify::Insecure => ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny(
+                CryptoProvider::get_default()
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider())),
+            )))
+            .with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Checks the certificate chain but not the name it is for.
+#[derive(Debug)]
+struct IgnoreHostname(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for IgnoreHostname {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            res => res,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Accepts any certificate, only checking the handshake is signed by it.
+#[derive(Debug)]
+struct AcceptAny(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
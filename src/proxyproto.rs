@@ -0,0 +1,545 @@
+//! PROXY protocol headers (v1 and v2), which L4 load balancers send ahead of a connection's data to pass on the
+//! client's address.
+//!
+//! The header is peeked at first, so when it's optional and missing, nothing the client sent is consumed. Only the
+//! header's own bytes are then read off the socket, leaving the rest for the TLS handshake or the HTTP decoder.
+//! Headers almost always arrive in the connection's first segment; one that arrives in pieces is polled for.
+
+use crate::config::ProxyProtocolMode;
+use monoio::net::TcpStream;
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::Duration;
+
+/// The signature every v2 header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The length of the fixed part of a v2 header, before the addresses.
+const V2_HEADER_LEN: usize = 16;
+
+/// The longest a v1 header may be, including its CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// How much is peeked at first, which fits any v1 header and v2 headers with a few TLVs.
+const INITIAL_PEEK_LEN: usize = 536;
+
+/// How often a header that arrived in pieces is checked on.
+const PARTIAL_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Reads a connection's PROXY header according to its listener's mode.
+/// Returns the client address it names, or `peer` if there's no header or it names no address, such as for the
+/// load balancer's own health checks.
+pub async fn accept(
+    stream: &TcpStream,
+    peer: SocketAddr,
+    mode: ProxyProtocolMode,
+    timeout: Duration,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    if mode == ProxyProtocolMode::Off {
+        return Ok(peer);
+    }
+
+    match monoio::time::timeout(timeout, read_header(stream, mode)).await {
+        Ok(Ok(addr)) => Ok(addr.unwrap_or(peer)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err("timed out waiting for the PROXY header".into()),
+    }
+}
+
+async fn read_header(
+    stream: &TcpStream,
+    mode: ProxyProtocolMode,
+) -> Result<Option<SocketAddr>, Box<dyn Error>> {
+    let fd = stream.as_raw_fd();
+    let mut buf = vec![0; INITIAL_PEEK_LEN];
+    loop {
+        stream.readable(false).await?;
+        let n = match recv(fd, &mut buf, libc::MSG_PEEK) {
+            Ok(0) => return Err("connection closed before the PROXY header".into()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        match parse(&buf[..n]) {
+            Parsed::Header { len, addr } => {
+                consume(fd, len)?;
+                return Ok(addr);
+            }
+            Parsed::NotProxy if mode == ProxyProtocolMode::Optional => return Ok(None),
+            Parsed::NotProxy => return Err("connection didn't start with a PROXY header".into()),
+            Parsed::Invalid(reason) => return Err(reason.into()),
+            Parsed::Incomplete(needed) => {
+                if let Some(needed) = needed {
+                    if needed > buf.len() {
+                        buf.resize(needed, 0);
+                    }
+                }
+                monoio::time::sleep(PARTIAL_POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// What the start of a connection's data holds.
+#[derive(Debug, PartialEq)]
+enum Parsed {
+    /// A complete header of `len` bytes.
+    Header {
+        len: usize,
+
+        /// The client address, or [None] if the header doesn't name one.
+        addr: Option<SocketAddr>,
+    },
+
+    /// Not a PROXY header.
+    NotProxy,
+
+    /// The start of a header, and how long it is if that's known yet.
+    Incomplete(Option<usize>),
+
+    Invalid(&'static str),
+}
+
+fn parse(data: &[u8]) -> Parsed {
+    let n = data.len().min(V2_SIGNATURE.len());
+    if data[..n] == V2_SIGNATURE[..n] {
+        if data.len() < V2_HEADER_LEN {
+            return Parsed::Incomplete(Some(V2_HEADER_LEN));
+        }
+        return parse_v2(data);
+    }
+
+    let n = data.len().min(b"PROXY ".len());
+    if data[..n] == b"PROXY "[..n] {
+        return parse_v1(data);
+    }
+
+    Parsed::NotProxy
+}
+
+/// Parses a v1 header, such as `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(data: &[u8]) -> Parsed {
+    let Some(end) = data[..data.len().min(V1_MAX_LEN)]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+    else {
+        return if data.len() >= V1_MAX_LEN {
+            Parsed::Invalid("PROXY v1 header too long")
+        } else {
+            Parsed::Incomplete(None)
+        };
+    };
+    let Ok(line) = std::str::from_utf8(&data[..end]) else {
+        return Parsed::Invalid("PROXY v1 header isn't text");
+    };
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addr = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let (Ok(ip), Ok(port)) = (src.parse::<IpAddr>(), src_port.parse::<u16>()) else {
+                return Parsed::Invalid("invalid address in PROXY v1 header");
+            };
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Parsed::Invalid("PROXY v1 address doesn't match its protocol");
+            }
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Parsed::Invalid("malformed PROXY v1 header"),
+    };
+
+    Parsed::Header { len: end + 2, addr }
+}
+
+/// Parses a v2 header, whose fixed part is known to be complete.
+fn parse_v2(data: &[u8]) -> Parsed {
+    let version_command = data[12];
+    if version_command >> 4 != 2 {
+        return Parsed::Invalid("unsupported PROXY protocol version");
+    }
+
+    let len = V2_HEADER_LEN + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if data.len() < len {
+        return Parsed::Incomplete(Some(len));
+    }
+    let addresses = &data[V2_HEADER_LEN..len];
+
+    match version_command & 0x0f {
+        // LOCAL: the load balancer's own connection, such as a health check.
+        0x0 => return Parsed::Header { len, addr: None },
+        // PROXY
+        0x1 => {}
+        _ => return Parsed::Invalid("unsupported PROXY v2 command"),
+    }
+
+    // The high nibble is the address family; the low one, the transport, doesn't matter here.
+    let addr = match data[13] >> 4 {
+        // AF_INET: source and destination addresses, then source and destination ports.
+        0x1 => {
+            let Some(addresses) = addresses.get(..12) else {
+                return Parsed::Invalid("truncated PROXY v2 addresses");
+            };
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // AF_INET6
+        0x2 => {
+            let Some(addresses) = addresses.get(..36) else {
+                return Parsed::Invalid("truncated PROXY v2 addresses");
+            };
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(ip), port))
+        }
+        // AF_UNSPEC and AF_UNIX don't name a client address.
+        _ => None,
+    };
+
+    Parsed::Header { len, addr }
+}
+
+fn recv(fd: RawFd, buf: &mut [u8], flags: libc::c_int) -> io::Result<usize> {
+    let n = unsafe {
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            flags | libc::MSG_DONTWAIT,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Reads off `len` bytes that were already peeked at.
+fn consume(fd: RawFd, len: usize) -> io::Result<()> {
+    let mut buf = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match recv(fd, &mut buf[read..], 0)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+    use monoio::net::TcpListener;
+
+    const V1_TCP4: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+
+    /// Builds a v2 header with the PROXY (1) or LOCAL (0) command.
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn inet_addresses() -> Vec<u8> {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        addresses
+    }
+
+    fn inet6_addresses() -> Vec<u8> {
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        addresses
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let mut data = V1_TCP4.to_vec();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        assert_eq!(
+            parse(&data),
+            Parsed::Header {
+                len: V1_TCP4.len(),
+                addr: Some("192.0.2.1:56324".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            parse(header),
+            Parsed::Header {
+                len: header.len(),
+                addr: Some("[2001:db8::1]:56324".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let header = b"PROXY UNKNOWN\r\n";
+        assert_eq!(
+            parse(header),
+            Parsed::Header {
+                len: header.len(),
+                addr: None,
+            }
+        );
+
+        let header = b"PROXY UNKNOWN 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            parse(header),
+            Parsed::Header {
+                len: header.len(),
+                addr: None,
+            }
+        );
+    }
+
+    #[test]
+    fn v1_truncated() {
+        assert_eq!(parse(b"PRO"), Parsed::Incomplete(None));
+        assert_eq!(parse(&V1_TCP4[..20]), Parsed::Incomplete(None));
+        assert_eq!(
+            parse(&V1_TCP4[..V1_TCP4.len() - 1]),
+            Parsed::Incomplete(None)
+        );
+    }
+
+    #[test]
+    fn v1_overlong() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.resize(V1_MAX_LEN + 10, b'1');
+        header.extend_from_slice(b"\r\n");
+        assert_eq!(parse(&header), Parsed::Invalid("PROXY v1 header too long"));
+        assert_eq!(
+            parse(&header[..V1_MAX_LEN]),
+            Parsed::Invalid("PROXY v1 header too long")
+        );
+    }
+
+    #[test]
+    fn v1_protocol_mismatch() {
+        assert_eq!(
+            parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n"),
+            Parsed::Invalid("PROXY v1 address doesn't match its protocol")
+        );
+        assert_eq!(
+            parse(b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n"),
+            Parsed::Invalid("PROXY v1 address doesn't match its protocol")
+        );
+    }
+
+    #[test]
+    fn v1_malformed() {
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"),
+            Parsed::Invalid("malformed PROXY v1 header")
+        );
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 port 443\r\n"),
+            Parsed::Invalid("invalid address in PROXY v1 header")
+        );
+    }
+
+    #[test]
+    fn not_proxy() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Parsed::NotProxy);
+        assert_eq!(parse(b"\x16\x03\x01"), Parsed::NotProxy);
+    }
+
+    #[test]
+    fn v2_proxy_inet() {
+        let header = v2(0x1, 0x11, &inet_addresses());
+        let mut data = header.clone();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        assert_eq!(
+            parse(&data),
+            Parsed::Header {
+                len: header.len(),
+                addr: Some("192.0.2.1:56324".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn v2_proxy_inet6() {
+        let header = v2(0x1, 0x21, &inet6_addresses());
+        assert_eq!(
+            parse(&header),
+            Parsed::Header {
+                len: header.len(),
+                addr: Some("[2001:db8::1]:56324".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn v2_proxy_with_tlvs() {
+        let mut addresses = inet_addresses();
+        // PP2_TYPE_AUTHORITY
+        addresses.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        addresses.extend_from_slice(b"example.com");
+        let header = v2(0x1, 0x11, &addresses);
+        assert_eq!(
+            parse(&header),
+            Parsed::Header {
+                len: header.len(),
+                addr: Some("192.0.2.1:56324".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn v2_proxy_unspec() {
+        let header = v2(0x1, 0x00, &[]);
+        assert_eq!(
+            parse(&header),
+            Parsed::Header {
+                len: header.len(),
+                addr: None,
+            }
+        );
+    }
+
+    #[test]
+    fn v2_local() {
+        for (family, addresses) in [
+            (0x00, Vec::new()),
+            (0x11, inet_addresses()),
+            (0x21, inet6_addresses()),
+        ] {
+            let header = v2(0x0, family, &addresses);
+            assert_eq!(
+                parse(&header),
+                Parsed::Header {
+                    len: header.len(),
+                    addr: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let header = v2(0x1, 0x11, &inet_addresses());
+        assert_eq!(parse(&header[..8]), Parsed::Incomplete(Some(V2_HEADER_LEN)));
+        assert_eq!(
+            parse(&header[..V2_HEADER_LEN - 1]),
+            Parsed::Incomplete(Some(V2_HEADER_LEN))
+        );
+        assert_eq!(
+            parse(&header[..header.len() - 1]),
+            Parsed::Incomplete(Some(header.len()))
+        );
+    }
+
+    #[test]
+    fn v2_family_mismatch() {
+        // The length covers AF_INET addresses, but the family says AF_INET6.
+        assert_eq!(
+            parse(&v2(0x1, 0x21, &inet_addresses())),
+            Parsed::Invalid("truncated PROXY v2 addresses")
+        );
+        assert_eq!(
+            parse(&v2(0x1, 0x11, &inet_addresses()[..8])),
+            Parsed::Invalid("truncated PROXY v2 addresses")
+        );
+    }
+
+    #[test]
+    fn v2_unsupported() {
+        let mut header = v2(0x1, 0x11, &inet_addresses());
+        header[12] = 0x11;
+        assert_eq!(
+            parse(&header),
+            Parsed::Invalid("unsupported PROXY protocol version")
+        );
+        header[12] = 0x22;
+        assert_eq!(
+            parse(&header),
+            Parsed::Invalid("unsupported PROXY v2 command")
+        );
+    }
+
+    /// Connects to a listener, sends `data` and returns the accepted end and the client address it saw.
+    async fn connected(data: &'static [u8]) -> (TcpStream, TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (res, _) = client.write_all(data).await;
+        res.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        (stream, client, peer)
+    }
+
+    async fn read_rest(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while data.len() < len {
+            let (res, buf) = stream.read(Vec::with_capacity(len)).await;
+            assert!(res.unwrap() > 0);
+            data.extend_from_slice(&buf);
+        }
+        data
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn optional_passes_through() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (mut stream, _client, peer) = connected(request).await;
+        let addr = accept(
+            &stream,
+            peer,
+            ProxyProtocolMode::Optional,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, peer);
+        assert_eq!(read_rest(&mut stream, request.len()).await, request);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn required_consumes_only_the_header() {
+        let data = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n\r\n";
+        let (mut stream, _client, peer) = connected(data).await;
+        let addr = accept(
+            &stream,
+            peer,
+            ProxyProtocolMode::Required,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, "192.0.2.1:56324".parse().unwrap());
+        let rest = &data[V1_TCP4.len()..];
+        assert_eq!(read_rest(&mut stream, rest.len()).await, rest);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn required_rejects_missing_header() {
+        let (stream, _client, peer) = connected(b"GET / HTTP/1.1\r\n\r\n").await;
+        let res = accept(
+            &stream,
+            peer,
+            ProxyProtocolMode::Required,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
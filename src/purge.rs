@@ -0,0 +1,272 @@
+use crate::cachestate::{
+    block_file_path, meta_file_path, root_for_object, ObjectMeta, ObjectMetaPreamble,
+    META_FILE_SUFFIX, OBJECT_META_EXP_TS_OFFSET,
+};
+use crate::hash::{create_file_block_hash, create_object_hash, FileBlockInfo, ObjectHash};
+use crate::metrics::record_cache_usage;
+use crate::tagindex::TagIndex;
+use http::Uri;
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// Which objects a purge applies to.
+///
+/// Objects are stored under their request URL, so a site's `http` and `https` objects are stored separately. URLs and
+/// prefixes with either scheme select both. Objects a hook stored under a key of its own are selected by that key, as
+/// the `url` or a prefix of it.
+pub enum PurgeSelector {
+    /// The object stored under exactly this URL.
+    Url(String),
+
+    /// Every object whose URL starts with this prefix (e.g. `https://example.com/videos/`).
+    Prefix(String),
+
+    /// Every object stored for this hostname.
+    Host(String),
+
+    /// Every object tagged with this surrogate key.
+    Tag(String),
+}
+
+impl PurgeSelector {
+    /// Returns whether the object described by the preamble is selected.
+    pub fn matches(&self, preamble: &ObjectMetaPreamble) -> bool {
+        match self {
+            PurgeSelector::Url(url) => scheme_variants(url).any(|url| preamble.key == url),
+            PurgeSelector::Prefix(prefix) => {
+                scheme_variants(prefix).any(|prefix| preamble.key.starts_with(&prefix))
+            }
+            PurgeSelector::Host(host) => preamble
+                .key
+                .parse::<Uri>()
+                .ok()
+                .and_then(|uri| uri.host().map(|h| h.eq_ignore_ascii_case(host)))
+                .unwrap_or(false),
+            PurgeSelector::Tag(tag) => preamble.tags.iter().any(|t| t == tag),
+        }
+    }
+}
+
+/// Returns a URL, or a prefix of one, followed by the same with the other scheme if it is `http` or `https`.
+fn scheme_variants(url: &str) -> impl Iterator<Item = String> {
+    let other = if let Some(rest) = url.strip_prefix("http://") {
+        Some(format!("https://{}", rest))
+    } else {
+        url.strip_prefix("https://")
+            .map(|rest| format!("http://{}", rest))
+    };
+    std::iter::once(url.to_owned()).chain(other)
+}
+
+/// How a purge treats selected objects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PurgeMode {
+    /// Mark the object as expired, keeping its blocks.
+    /// The next request for it asks the origin whether it changed, with `If-None-Match` and `If-Modified-Since` from
+    /// its stored validators, and is served from the stored blocks if the origin answers `304`, which makes the object
+    /// fresh again. Objects without a validator are fetched again in full.
+    Soft,
+
+    /// Delete the object's meta file and all of its blocks.
+    Hard,
+}
+
+/// A purge request.
+pub struct Purge {
+    pub selector: PurgeSelector,
+    pub mode: PurgeMode,
+}
+
+/// Executes purges against the on-disk cache.
+///
+/// The cache directories are shared by every worker thread, so applying a purge here applies it to all of them.
+/// Purges use blocking filesystem calls and must not be run on a worker thread.
+pub struct Purger {
+    cache_roots: Vec<PathBuf>,
+    tag_index: RefCell<TagIndex>,
+}
+
+impl Purger {
+    pub fn new(cache_roots: Vec<PathBuf>, tag_index: TagIndex) -> Self {
+        Self {
+            cache_roots,
+            tag_index: RefCell::new(tag_index),
+        }
+    }
+
+    /// Applies the tag index updates worker threads sent since the last call.
+    pub fn apply_tag_updates(&self) {
+        self.tag_index.borrow_mut().apply_updates();
+    }
+
+    /// Executes a purge and returns the number of objects it applied to.
+    pub fn purge(&self, purge: &Purge) -> io::Result<u64> {
+        match &purge.selector {
+            PurgeSelector::Url(url) => {
+                // Exact URLs map straight to a meta file per scheme, no need to scan.
+                let hashes = scheme_variants(url).map(|url| create_object_hash(&url));
+                Ok(self.purge_objects(hashes, purge))
+            }
+            PurgeSelector::Tag(tag) => {
+                let hashes = {
+                    let mut index = self.tag_index.borrow_mut();
+                    index.apply_updates();
+                    index.objects_with_tag(tag)
+                };
+                Ok(self.purge_objects(hashes, purge))
+            }
+            PurgeSelector::Prefix(_) | PurgeSelector::Host(_) => {
+                let mut count = 0;
+                for root in &self.cache_roots {
+                    for path in meta_files(root)? {
+                        count += self.try_purge_meta_file(&path, purge) as u64;
+                    }
+                }
+                Ok(count)
+            }
+        }
+    }
+
+    /// Applies a purge to each of the specified objects that it selects.
+    /// Returns the number of objects it was applied to.
+    fn purge_objects(&self, hashes: impl IntoIterator<Item = ObjectHash>, purge: &Purge) -> u64 {
+        let mut count = 0;
+        for hash in hashes {
+            let path = meta_file_path(&hash, root_for_object(&hash, &self.cache_roots));
+            count += self.try_purge_meta_file(&path, purge) as u64;
+        }
+        count
+    }
+
+    /// Applies a purge to a single meta file, logging failures.
+    /// Returns whether the purge was applied.
+    fn try_purge_meta_file(&self, path: &Path, purge: &Purge) -> bool {
+        match self.purge_meta_file(path, purge) {
+            Ok(applied) => applied,
+            // Files may disappear under us if another purge or an eviction is running.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => {
+                println!("failed to purge {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Applies a purge to a single meta file if it is selected.
+    /// Returns whether the purge was applied.
+    fn purge_meta_file(&self, path: &Path, purge: &Purge) -> io::Result<bool> {
+        let buf = std::fs::read(path)?;
+        let meta = ObjectMeta::from_bytes(&buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        if !purge.selector.matches(&meta.preamble) {
+            return Ok(false);
+        }
+
+        match purge.mode {
+            PurgeMode::Soft => {
+                // Same as `cachestate::set_exp_ts`, but blocking, as purges don't run on a worker thread.
+                let file = std::fs::OpenOptions::new().write(true).open(path)?;
+                file.write_all_at(&0u64.to_le_bytes(), OBJECT_META_EXP_TS_OFFSET)?;
+            }
+            PurgeMode::Hard => {
+                remove_object(path, &meta.preamble)?;
+
+                // The purge thread owns the index, so it's updated directly rather than through the channel.
+                if !meta.preamble.tags.is_empty() {
+                    let hash = create_object_hash(&meta.preamble.key);
+                    self.tag_index.borrow_mut().remove(&hash);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Deletes an object's meta file and then its blocks.
+/// The meta file goes first so a fill writing the object sees it was purged and stops, rather than writing blocks
+/// after they were deleted. Blocks that fail to delete are left for eviction, and the first error is returned.
+pub fn remove_object(meta_path: &Path, preamble: &ObjectMetaPreamble) -> io::Result<()> {
+    let root = meta_path
+        .ancestors()
+        .nth(3)
+        .expect("BUG: meta file path is not under a cache root");
+
+    std::fs::remove_file(meta_path)?;
+
+    // Objects stored before keys were recorded can't have their block names derived.
+    // Their blocks are left for eviction to clean up.
+    let mut result = Ok(());
+    if !preamble.key.is_empty() && preamble.block_size > 0 {
+        let block_count = preamble.size_bytes.div_ceil(preamble.block_size as u64);
+        for block_num in 0..block_count {
+            let hash = create_file_block_hash(
+                &preamble.key,
+                FileBlockInfo {
+                    block_size: preamble.block_size,
+                    block_num,
+                },
+            );
+            match remove_block_file(&block_file_path(&hash, root)) {
+                Ok(len) => record_cache_usage(root, -(len as i64)),
+                Err(e) if e.kind() != io::ErrorKind::NotFound && result.is_ok() => result = Err(e),
+                Err(_) => {}
+            }
+        }
+    }
+    result
+}
+
+/// Deletes a block file, returning its size.
+fn remove_block_file(path: &Path) -> io::Result<u64> {
+    let len = std::fs::metadata(path)?.len();
+    std::fs::remove_file(path)?;
+    Ok(len)
+}
+
+/// Lists every meta file under a cache root.
+pub fn meta_files(cache_root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    // Layout is <root>/<seg1>/<seg2>/<hash>.meta
+    for seg1 in read_dirs(cache_root)? {
+        for seg2 in read_dirs(&seg1)? {
+            for entry in std::fs::read_dir(&seg2)? {
+                let path = entry?.path();
+                let is_meta = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.ends_with(META_FILE_SUFFIX))
+                    .unwrap_or(false);
+                if is_meta {
+                    files.push(path);
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Lists the subdirectories of a directory.
+/// A missing directory is treated as empty.
+fn read_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+
+    Ok(dirs)
+}
@@ -0,0 +1,447 @@
+//! Routing requests to origin pools by host and path.
+//!
+//! Hosts are matched exactly or with a leading wildcard (`*.cdn.example.com`, which matches any subdomain but not
+//! `cdn.example.com` itself). Exact hosts win over wildcards, and longer wildcards win over shorter ones. Finding a
+//! host's routes is a handful of hash lookups however many hosts there are.
+//!
+//! Within a host, routes match path prefixes or regexes and the first matching route in the order they were added
+//! wins. Each host's paths are compiled into a single [RegexSet], so matching is one pass over the path.
+
+use crate::originpool::OriginPool;
+use regex::{Regex, RegexSet};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// The hosts a route applies to.
+pub enum HostPattern {
+    Exact(String),
+
+    /// Subdomains of the domain, which is stored without the `*.`.
+    Wildcard(String),
+}
+
+impl HostPattern {
+    /// Parses a hostname, which may start with `*.`.
+    pub fn parse(pattern: &str) -> HostPattern {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => HostPattern::Wildcard(domain.to_owned()),
+            None => HostPattern::Exact(pattern),
+        }
+    }
+}
+
+/// The paths a route applies to.
+#[derive(Clone)]
+pub enum PathPattern {
+    Any,
+    Prefix(String),
+
+    /// Matched against the path only, without the query string.
+    Regex(Regex),
+}
+
+impl PathPattern {
+    /// The pattern's regex source for the host's [RegexSet].
+    fn regex_source(&self) -> String {
+        match self {
+            PathPattern::Any => String::new(),
+            PathPattern::Prefix(prefix) => format!("^{}", regex::escape(prefix)),
+            PathPattern::Regex(regex) => regex.as_str().to_owned(),
+        }
+    }
+}
+
+/// Changes made to the path before it is sent to the origin.
+/// The prefix is stripped first, so a route can swap one prefix for another.
+#[derive(Clone, Default)]
+pub struct PathRewrite {
+    /// Removed from the start of the path, if present.
+    pub strip_prefix: Option<String>,
+
+    /// Added to the start of the path.
+    pub add_prefix: Option<String>,
+}
+
+impl PathRewrite {
+    /// Rewrites a path and query string.
+    fn apply(&self, path_and_query: &str) -> String {
+        let mut path = path_and_query;
+        if let Some(prefix) = &self.strip_prefix {
+            path = path.strip_prefix(prefix.as_str()).unwrap_or(path);
+        }
+
+        let mut out = String::with_capacity(path.len() + 16);
+        if let Some(prefix) = &self.add_prefix {
+            out.push_str(prefix);
+        }
+        out.push_str(path);
+
+        // The origin needs an absolute path, even if the whole path was stripped.
+        if !out.starts_with('/') {
+            out.insert(0, '/');
+        }
+        out
+    }
+}
+
+/// How responses for a route are cached.
+#[derive(Clone, Default)]
+pub struct CachePolicy {
+    /// Whether to skip the cache entirely and always go to the origin.
+    pub bypass: bool,
+
+    /// How long responses stay fresh, overriding the origin's headers.
+    /// Hooks can override this per request.
+    pub ttl: Option<Duration>,
+}
+
+/// A routing rule within a host.
+pub struct Route {
+    pub path: PathPattern,
+    pub pool: Rc<OriginPool>,
+    pub rewrite: PathRewrite,
+    pub cache: CachePolicy,
+}
+
+/// A host's routes and their compiled matcher.
+struct HostRoutes {
+    routes: Vec<Route>,
+    matcher: RegexSet,
+}
+
+impl HostRoutes {
+    fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            matcher: RegexSet::empty(),
+        }
+    }
+
+    fn push(&mut self, route: Route) -> Result<(), regex::Error> {
+        let matcher = RegexSet::new(
+            self.routes
+                .iter()
+                .chain(std::iter::once(&route))
+                .map(|r| r.path.regex_source()),
+        )?;
+        self.routes.push(route);
+        self.matcher = matcher;
+        Ok(())
+    }
+
+    fn find(&self, path: &str) -> Option<&Route> {
+        let index = self.matcher.matches(path).iter().next()?;
+        Some(&self.routes[index])
+    }
+}
+
+/// The route a request matched.
+pub struct RouteMatch<'a> {
+    pub pool: &'a Rc<OriginPool>,
+
+    /// The path and query string to request from the origin.
+    pub path_and_query: String,
+
+    pub cache: &'a CachePolicy,
+}
+
+/// Every host's routes.
+pub struct RouteTable {
+    exact: HashMap<String, HostRoutes>,
+
+    /// Key: the domain after `*.`
+    wildcard: HashMap<String, HostRoutes>,
+}
+
+impl RouteTable {
+    pub fn new() -> RouteTable {
+        RouteTable {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+
+    /// Adds a route for the hosts matching a pattern, after any routes already added for it.
+    /// Fails if the route's regex can't be compiled alongside the host's other routes.
+    pub fn add(&mut self, host: HostPattern, route: Route) -> Result<(), regex::Error> {
+        let routes = match host {
+            HostPattern::Exact(host) => self.exact.entry(host),
+            HostPattern::Wildcard(domain) => self.wildcard.entry(domain),
+        };
+        routes.or_insert_with(HostRoutes::new).push(route)
+    }
+
+    /// Finds the routes for a host: its exact entry, or else the longest wildcard matching it.
+    fn host_routes(&self, host: &str) -> Option<&HostRoutes> {
+        if let Some(routes) = self.exact.get(host) {
+            return Some(routes);
+        }
+
+        let mut domain = host;
+        while let Some((_, parent)) = domain.split_once('.') {
+            if let Some(routes) = self.wildcard.get(parent) {
+                return Some(routes);
+            }
+            domain = parent;
+        }
+        None
+    }
+
+    /// Routes a request.
+    /// The hostname must be lowercase and not contain a port number.
+    pub fn route(&self, host: &str, path_and_query: &str) -> Option<RouteMatch<'_>> {
+        let routes = self.host_routes(host)?;
+        let path = path_and_query
+            .split_once('?')
+            .map_or(path_and_query, |(path, _)| path);
+        let route = routes.find(path)?;
+
+        Some(RouteMatch {
+            pool: &route.pool,
+            path_and_query: route.rewrite.apply(path_and_query),
+            cache: &route.cache,
+        })
+    }
+
+    /// Returns every route's pool.
+    /// Pools shared by several routes are returned once.
+    pub fn pools(&self) -> Vec<Rc<OriginPool>> {
+        let mut pools: Vec<Rc<OriginPool>> = Vec::new();
+        for host in self.exact.values().chain(self.wildcard.values()) {
+            for route in &host.routes {
+                if !pools.iter().any(|p| Rc::ptr_eq(p, &route.pool)) {
+                    pools.push(route.pool.clone());
+                }
+            }
+        }
+        pools
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::originpool::{PoolHealth, PoolOptions};
+    use std::sync::Arc;
+
+    fn pool() -> Rc<OriginPool> {
+        Rc::new(OriginPool::new(
+            Vec::new(),
+            PoolOptions::default(),
+            Arc::new(PoolHealth::new(0)),
+        ))
+    }
+
+    fn route(path: PathPattern, pool: &Rc<OriginPool>) -> Route {
+        Route {
+            path,
+            pool: pool.clone(),
+            rewrite: PathRewrite::default(),
+            cache: CachePolicy::default(),
+        }
+    }
+
+    fn prefix(prefix: &str) -> PathPattern {
+        PathPattern::Prefix(prefix.to_owned())
+    }
+
+    /// Whether a request is routed to a pool.
+    fn routes_to(table: &RouteTable, host: &str, path: &str, pool: &Rc<OriginPool>) -> bool {
+        table
+            .route(host, path)
+            .is_some_and(|m| Rc::ptr_eq(m.pool, pool))
+    }
+
+    #[test]
+    fn host_patterns() {
+        let HostPattern::Exact(host) = HostPattern::parse("CDN.Example.com") else {
+            panic!("not an exact host");
+        };
+        assert_eq!(host, "cdn.example.com");
+
+        let HostPattern::Wildcard(domain) = HostPattern::parse("*.Example.com") else {
+            panic!("not a wildcard");
+        };
+        assert_eq!(domain, "example.com");
+    }
+
+    #[test]
+    fn exact_wins_over_wildcard() {
+        let (exact, wildcard) = (pool(), pool());
+        let mut table = RouteTable::new();
+        table
+            .add(
+                HostPattern::parse("*.example.com"),
+                route(PathPattern::Any, &wildcard),
+            )
+            .unwrap();
+        table
+            .add(
+                HostPattern::parse("cdn.example.com"),
+                route(PathPattern::Any, &exact),
+            )
+            .unwrap();
+
+        assert!(routes_to(&table, "cdn.example.com", "/", &exact));
+        assert!(routes_to(&table, "img.example.com", "/", &wildcard));
+        assert!(routes_to(&table, "a.b.example.com", "/", &wildcard));
+    }
+
+    #[test]
+    fn wildcard_excludes_its_own_domain() {
+        let mut table = RouteTable::new();
+        table
+            .add(
+                HostPattern::parse("*.example.com"),
+                route(PathPattern::Any, &pool()),
+            )
+            .unwrap();
+        assert!(table.route("example.com", "/").is_none());
+        assert!(table.route("notexample.com", "/").is_none());
+        assert!(table.route("example.org", "/").is_none());
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let (short, long) = (pool(), pool());
+        let mut table = RouteTable::new();
+        table
+            .add(
+                HostPattern::parse("*.example.com"),
+                route(PathPattern::Any, &short),
+            )
+            .unwrap();
+        table
+            .add(
+                HostPattern::parse("*.cdn.example.com"),
+                route(PathPattern::Any, &long),
+            )
+            .unwrap();
+
+        assert!(routes_to(&table, "img.cdn.example.com", "/", &long));
+        assert!(routes_to(&table, "cdn.example.com", "/", &short));
+        assert!(routes_to(&table, "www.example.com", "/", &short));
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let (videos, regex, fallback) = (pool(), pool(), pool());
+        let mut table = RouteTable::new();
+        let host = || HostPattern::parse("example.com");
+        table
+            .add(host(), route(prefix("/videos/"), &videos))
+            .unwrap();
+        table
+            .add(
+                host(),
+                route(PathPattern::Regex(Regex::new(r"\.mp4$").unwrap()), &regex),
+            )
+            .unwrap();
+        table
+            .add(host(), route(PathPattern::Any, &fallback))
+            .unwrap();
+
+        assert!(routes_to(&table, "example.com", "/videos/a.mp4", &videos));
+        assert!(routes_to(&table, "example.com", "/clips/a.mp4", &regex));
+        assert!(routes_to(&table, "example.com", "/index.html", &fallback));
+        // Regexes are matched against the path without the query string.
+        assert!(routes_to(&table, "example.com", "/a.mp4?t=10", &regex));
+        assert!(routes_to(
+            &table,
+            "example.com",
+            "/a.html?f=.mp4",
+            &fallback
+        ));
+    }
+
+    #[test]
+    fn prefixes_are_literal() {
+        let p = pool();
+        let mut table = RouteTable::new();
+        table
+            .add(
+                HostPattern::parse("example.com"),
+                route(prefix("/a.b/"), &p),
+            )
+            .unwrap();
+        assert!(routes_to(&table, "example.com", "/a.b/c", &p));
+        assert!(table.route("example.com", "/axb/c").is_none());
+        assert!(table.route("example.com", "/x/a.b/c").is_none());
+    }
+
+    #[test]
+    fn rewrites_paths() {
+        let rewrite = |strip: Option<&str>, add: Option<&str>| PathRewrite {
+            strip_prefix: strip.map(str::to_owned),
+            add_prefix: add.map(str::to_owned),
+        };
+
+        assert_eq!(rewrite(None, None).apply("/a?b=1"), "/a?b=1");
+        assert_eq!(rewrite(Some("/api"), None).apply("/api/v1?x"), "/v1?x");
+        assert_eq!(rewrite(Some("/api"), None).apply("/api"), "/");
+        assert_eq!(rewrite(Some("/api"), None).apply("/other"), "/other");
+        assert_eq!(
+            rewrite(Some("/api"), Some("/backend")).apply("/api/v1"),
+            "/backend/v1"
+        );
+        assert_eq!(
+            rewrite(None, Some("/static")).apply("/a.css"),
+            "/static/a.css"
+        );
+        assert_eq!(rewrite(Some("/"), None).apply("/a"), "/a");
+    }
+
+    #[test]
+    fn applies_route_rewrite_and_cache_policy() {
+        let mut table = RouteTable::new();
+        table
+            .add(
+                HostPattern::parse("example.com"),
+                Route {
+                    path: prefix("/api/"),
+                    pool: pool(),
+                    rewrite: PathRewrite {
+                        strip_prefix: Some("/api".to_owned()),
+                        add_prefix: None,
+                    },
+                    cache: CachePolicy {
+                        bypass: true,
+                        ttl: None,
+                    },
+                },
+            )
+            .unwrap();
+
+        let m = table.route("example.com", "/api/users?page=2").unwrap();
+        assert_eq!(m.path_and_query, "/users?page=2");
+        assert!(m.cache.bypass);
+    }
+
+    #[test]
+    fn pools_are_listed_once() {
+        let (a, b) = (pool(), pool());
+        let mut table = RouteTable::new();
+        table
+            .add(HostPattern::parse("a.example.com"), route(prefix("/x"), &a))
+            .unwrap();
+        table
+            .add(
+                HostPattern::parse("a.example.com"),
+                route(PathPattern::Any, &b),
+            )
+            .unwrap();
+        table
+            .add(
+                HostPattern::parse("*.example.org"),
+                route(PathPattern::Any, &a),
+            )
+            .unwrap();
+
+        let pools = table.pools();
+        assert_eq!(pools.len(), 2);
+        assert!(pools.iter().any(|p| Rc::ptr_eq(p, &a)));
+        assert!(pools.iter().any(|p| Rc::ptr_eq(p, &b)));
+    }
+}
@@ -0,0 +1,143 @@
+//! Graceful shutdown on `SIGTERM` or `SIGINT`, or once a binary upgrade has handed the listeners to a new process.
+//!
+//! The signal handler only sets a flag, which each worker thread polls for. Once it's set, a thread stops accepting
+//! connections, closes keep-alive connections after their current response, and waits for its connections to finish
+//! so that responses and the cache writes made while serving them complete. After the drain deadline, whatever is
+//! left is cut off.
+//!
+//! The process exits once every worker thread has drained and flushed its access log, with status 0 if every
+//! connection finished in time and 1 if any were cut off. A second signal exits immediately.
+
+use crate::connlimit::ConnectionLimiter;
+use std::cell::{Cell, RefCell};
+use std::future::poll_fn;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// How often worker threads check whether a shutdown signal arrived.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set by the signal handler, or once a binary upgrade has handed the listeners over.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the signal handler, to tell a second signal apart.
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+/// The worker threads that haven't finished draining.
+static RUNNING_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Cleared by any worker thread that had to cut connections off.
+static ALL_DRAINED: AtomicBool = AtomicBool::new(true);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    if SIGNALED.swap(true, Ordering::Relaxed) {
+        unsafe { libc::_exit(1) };
+    }
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Installs the `SIGTERM` and `SIGINT` handlers that start a graceful shutdown.
+pub fn install_handler() -> io::Result<()> {
+    let handler = on_signal as extern "C" fn(libc::c_int);
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let ret = unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        if ret == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Starts a graceful shutdown, as if a signal arrived.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Whether a graceful shutdown has started.
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// A worker thread's view of shutdown, which connections check to wind down.
+pub struct Shutdown {
+    draining: Cell<bool>,
+
+    /// Tasks waiting for draining to start.
+    wakers: RefCell<Vec<Waker>>,
+}
+
+impl Shutdown {
+    /// Creates the current thread's shutdown state.
+    /// The process only exits once every thread that created one has finished.
+    pub fn register() -> Shutdown {
+        RUNNING_THREADS.fetch_add(1, Ordering::Relaxed);
+        Shutdown {
+            draining: Cell::new(false),
+            wakers: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Whether the thread is draining, so no new connections or requests should be taken on.
+    pub fn is_draining(&self) -> bool {
+        self.draining.get()
+    }
+
+    /// Returns [Poll::Ready] once the thread is draining, otherwise waking the task when it starts.
+    pub fn poll_draining(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.draining.get() {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.wakers.borrow_mut();
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Waits until the thread is draining.
+    pub async fn draining(&self) {
+        poll_fn(|cx| self.poll_draining(cx)).await
+    }
+
+    /// Waits for a shutdown signal, then for the thread's connections to finish.
+    /// Returns whether they all finished before the drain deadline.
+    pub async fn run(&self, connections: &ConnectionLimiter, drain_timeout: Duration) -> bool {
+        while !REQUESTED.load(Ordering::Relaxed) {
+            monoio::time::sleep(SIGNAL_POLL_INTERVAL).await;
+        }
+
+        let deadline = Instant::now() + drain_timeout;
+        self.draining.set(true);
+        for waker in self.wakers.take() {
+            waker.wake();
+        }
+
+        while connections.open() > 0 {
+            if Instant::now() >= deadline {
+                println!(
+                    "drain deadline passed with {} connections open",
+                    connections.open()
+                );
+                return false;
+            }
+            monoio::time::sleep(SIGNAL_POLL_INTERVAL).await;
+        }
+        true
+    }
+}
+
+/// Marks the current thread as done with shutdown, exiting the process if it's the last one.
+/// Never returns, so the thread doesn't end the process before the others are done.
+pub async fn finish(drained: bool) {
+    if !drained {
+        ALL_DRAINED.store(false, Ordering::Relaxed);
+    }
+    if RUNNING_THREADS.fetch_sub(1, Ordering::AcqRel) == 1 {
+        let drained = ALL_DRAINED.load(Ordering::Relaxed);
+        println!("shut down{}", if drained { "" } else { " before draining" });
+        std::process::exit(if drained { 0 } else { 1 });
+    }
+    std::future::pending::<()>().await;
+}
@@ -0,0 +1,175 @@
+use crate::cachestate::{ObjectMeta, META_FILE_SUFFIX};
+use crate::hash::ObjectHash;
+use crate::purge::meta_files;
+use http::HeaderMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::OnceLock;
+
+/// The maximum number of tags kept per object.
+/// Anything beyond this is dropped so a misbehaving origin can't bloat meta files.
+pub const MAX_TAGS_PER_OBJECT: usize = 128;
+
+/// Parses surrogate keys from the specified origin response header.
+/// Keys are separated by whitespace, and the header may appear multiple times.
+pub fn tags_from_headers(headers: &HeaderMap, tag_header: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+
+    for value in headers.get_all(tag_header) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+
+        for tag in value.split_ascii_whitespace() {
+            if tags.len() >= MAX_TAGS_PER_OBJECT {
+                return tags;
+            }
+            if !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_owned());
+            }
+        }
+    }
+
+    tags
+}
+
+/// A change to the set of stored objects that the tag index needs to know about.
+pub enum TagIndexUpdate {
+    /// An object was stored (or restored) with the specified tags.
+    Stored { hash: ObjectHash, tags: Vec<String> },
+
+    /// An object was removed from the cache, such as when storing a new version over it failed.
+    /// Hard purges update the index directly, as it lives on the purge thread.
+    Removed { hash: ObjectHash },
+}
+
+/// The sending half of the tag index update channel, shared by every worker thread.
+static UPDATE_TX: OnceLock<Sender<TagIndexUpdate>> = OnceLock::new();
+
+/// Creates the tag index update channel and returns its receiving half.
+/// Returns [None] if it was already created.
+pub fn init_updates() -> Option<Receiver<TagIndexUpdate>> {
+    let (tx, rx) = channel();
+    UPDATE_TX.set(tx).ok()?;
+    Some(rx)
+}
+
+/// Notifies the tag index of a change.
+/// Does nothing if there is no tag index (the admin API is disabled).
+/// Sending never blocks, so this is safe to call from worker threads.
+pub fn notify(update: TagIndexUpdate) {
+    if let Some(tx) = UPDATE_TX.get() {
+        // The receiver only goes away when the process is exiting.
+        let _ = tx.send(update);
+    }
+}
+
+/// An index from surrogate keys to the objects tagged with them.
+///
+/// Built from meta files on startup and kept up to date with [TagIndexUpdate]s from worker threads,
+/// so purging a tag only touches the objects that carry it.
+pub struct TagIndex {
+    by_tag: HashMap<String, HashSet<ObjectHash>>,
+    by_object: HashMap<ObjectHash, Vec<String>>,
+    updates: Option<Receiver<TagIndexUpdate>>,
+}
+
+impl TagIndex {
+    pub fn new(updates: Option<Receiver<TagIndexUpdate>>) -> Self {
+        Self {
+            by_tag: HashMap::new(),
+            by_object: HashMap::new(),
+            updates,
+        }
+    }
+
+    /// Rebuilds the index by reading every meta file under the specified cache roots.
+    /// Uses blocking filesystem calls.
+    pub fn rebuild(&mut self, cache_roots: &[PathBuf]) -> io::Result<()> {
+        self.by_tag.clear();
+        self.by_object.clear();
+
+        for root in cache_roots {
+            for path in meta_files(root)? {
+                let Some(hash) = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_suffix(META_FILE_SUFFIX))
+                else {
+                    continue;
+                };
+
+                let buf = match std::fs::read(&path) {
+                    Ok(buf) => buf,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                match ObjectMeta::from_bytes(&buf) {
+                    Ok(meta) => self.insert(hash.to_owned(), meta.preamble.tags),
+                    Err(e) => println!("skipping unreadable meta file {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies any updates sent by worker threads since the last call.
+    pub fn apply_updates(&mut self) {
+        let Some(updates) = self.updates.take() else {
+            return;
+        };
+
+        while let Ok(update) = updates.try_recv() {
+            match update {
+                TagIndexUpdate::Stored { hash, tags } => self.insert(hash, tags),
+                TagIndexUpdate::Removed { hash } => self.remove(&hash),
+            }
+        }
+
+        self.updates = Some(updates);
+    }
+
+    /// Records an object's tags, replacing any it had before.
+    pub fn insert(&mut self, hash: ObjectHash, tags: Vec<String>) {
+        self.remove(&hash);
+
+        if tags.is_empty() {
+            return;
+        }
+
+        for tag in &tags {
+            self.by_tag
+                .entry(tag.clone())
+                .or_default()
+                .insert(hash.clone());
+        }
+        self.by_object.insert(hash, tags);
+    }
+
+    /// Forgets an object.
+    pub fn remove(&mut self, hash: &ObjectHash) {
+        let Some(tags) = self.by_object.remove(hash) else {
+            return;
+        };
+
+        for tag in tags {
+            if let Some(objects) = self.by_tag.get_mut(&tag) {
+                objects.remove(hash);
+                if objects.is_empty() {
+                    self.by_tag.remove(&tag);
+                }
+            }
+        }
+    }
+
+    /// Returns the hashes of every object tagged with the specified tag.
+    pub fn objects_with_tag(&self, tag: &str) -> Vec<ObjectHash> {
+        self.by_tag
+            .get(tag)
+            .map(|objects| objects.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
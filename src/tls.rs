@@ -0,0 +1,327 @@
+//! TLS termination for client connections.
+//!
+//! Certificates are picked by the SNI server name: an exact hostname first, then a wildcard certificate for the
+//! parent domain, then the default certificate. Certificate, key and OCSP response files are polled for changes and
+//! reloaded without a restart; handshakes already in progress keep the certificate they picked.
+//!
+//! Certificates issued with ACME are handed over by [crate::acme] and take precedence over wildcard file certificates.
+//!
+//! The server config is built once for the process and shared by every worker thread, so session tickets issued on
+//! one thread can be resumed on any other, and ticket keys rotate for all of them at once.
+//!
+//! The PEM files behind each certificate are kept too, so the HTTP/3 listener, whose TLS stack isn't rustls, can
+//! serve the same certificates through [find_certificate].
+
+use crate::config::{AcmeChallenge, AcmeConfig, CertificateConfig, TlsConfig};
+use monoio_rustls::TlsAcceptor;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
+
+/// The ALPN protocol ACME servers offer when validating TLS-ALPN-01 challenges (RFC 8737).
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+static SERVER_CONFIG: OnceLock<Arc<ServerConfig>> = OnceLock::new();
+static RESOLVER: OnceLock<Arc<CertResolver>> = OnceLock::new();
+
+/// Loads the certificates, builds the server config and starts watching the certificate files.
+/// Does nothing if no certificates are configured and ACME is not enabled.
+pub fn init(config: &TlsConfig, acme: &AcmeConfig) -> Result<(), Box<dyn Error>> {
+    if config.certificates.is_empty() && acme.hosts.is_empty() {
+        return Ok(());
+    }
+
+    let mut loaded = Vec::with_capacity(config.certificates.len());
+    for cert in &config.certificates {
+        let key = load_certificate(cert)
+            .map_err(|e| format!("failed to load {}: {}", cert.cert_path.display(), e))?;
+        loaded.push((modified_times(cert), key));
+    }
+
+    let resolver = Arc::new(CertResolver {
+        files: RwLock::new(Arc::new(CertStore::new(
+            config,
+            loaded.iter().map(|(_, key)| key.clone()),
+        ))),
+        managed: RwLock::new(HashMap::new()),
+        challenges: RwLock::new(HashMap::new()),
+    });
+    RESOLVER.get_or_init(|| resolver.clone());
+
+    let mut server = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server.alpn_protocols = config
+        .alpn_protocols
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
+    if !acme.hosts.is_empty() && acme.challenge == AcmeChallenge::TlsAlpn01 {
+        server.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+    }
+    if config.session_tickets {
+        server.ticketer = rustls::crypto::aws_lc_rs::Ticketer::new()?;
+    }
+    SERVER_CONFIG.get_or_init(|| Arc::new(server));
+
+    let config = config.clone();
+    std::thread::spawn(move || watch(&config, resolver, loaded));
+    Ok(())
+}
+
+/// Returns an acceptor for client connections, or [None] if TLS is not enabled.
+pub fn acceptor() -> Option<TlsAcceptor> {
+    SERVER_CONFIG
+        .get()
+        .map(|config| TlsAcceptor::from(config.clone()))
+}
+
+/// A certificate being served, along with the PEM it was parsed from.
+#[derive(Debug)]
+pub struct ServedCert {
+    pub key: Arc<CertifiedKey>,
+    pub chain_pem: Vec<u8>,
+    pub key_pem: Vec<u8>,
+}
+
+impl ServedCert {
+    /// Parses a PEM certificate chain, leaf first, and its PEM private key.
+    pub fn parse(chain_pem: Vec<u8>, key_pem: Vec<u8>) -> Result<ServedCert, Box<dyn Error>> {
+        let key = Arc::new(parse_certified_key(&chain_pem, &key_pem)?);
+        Ok(ServedCert {
+            key,
+            chain_pem,
+            key_pem,
+        })
+    }
+}
+
+/// Returns the certificate for an SNI server name, or [None] if TLS is not enabled or no certificate covers it.
+pub fn find_certificate(server_name: Option<&str>) -> Option<Arc<ServedCert>> {
+    let name = server_name.map(|n| n.to_ascii_lowercase());
+    RESOLVER.get()?.find(name.as_deref())
+}
+
+/// Serves a certificate issued with ACME for a hostname, replacing any it was served before.
+/// Does nothing if TLS is not enabled.
+pub fn set_managed_certificate(hostname: &str, cert: Arc<ServedCert>) {
+    if let Some(resolver) = RESOLVER.get() {
+        let mut managed = resolver.managed.write().unwrap();
+        managed.insert(hostname.to_ascii_lowercase(), cert);
+    }
+}
+
+/// Sets or, if [None], removes the certificate answering TLS-ALPN-01 validation for a hostname.
+pub fn set_alpn_challenge(hostname: &str, key: Option<Arc<CertifiedKey>>) {
+    if let Some(resolver) = RESOLVER.get() {
+        let mut challenges = resolver.challenges.write().unwrap();
+        match key {
+            Some(key) => challenges.insert(hostname.to_ascii_lowercase(), key),
+            None => challenges.remove(&hostname.to_ascii_lowercase()),
+        };
+    }
+}
+
+/// Parses a PEM certificate chain, leaf first, and its PEM private key.
+pub fn parse_certified_key(
+    chain_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<CertifiedKey, Box<dyn Error>> {
+    let chain = rustls_pemfile::certs(&mut BufReader::new(chain_pem))
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()?;
+    if chain.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no certificates found").into());
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_pem))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+    let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)?;
+
+    let certified = CertifiedKey::new(chain, key);
+    certified.keys_match()?;
+    Ok(certified)
+}
+
+/// Loads a certificate chain, its private key and its stapled OCSP response.
+fn load_certificate(cert: &CertificateConfig) -> Result<Arc<ServedCert>, Box<dyn Error>> {
+    let chain_pem = std::fs::read(&cert.cert_path)?;
+    let key_pem = std::fs::read(&cert.key_path)?;
+    let mut certified = parse_certified_key(&chain_pem, &key_pem)?;
+    if let Some(path) = &cert.ocsp_path {
+        certified.ocsp = Some(std::fs::read(path)?);
+    }
+    Ok(Arc::new(ServedCert {
+        key: Arc::new(certified),
+        chain_pem,
+        key_pem,
+    }))
+}
+
+/// The modification times of a certificate's files, to notice when any of them change.
+/// Files that can't be read have no time, so they are picked up again once they reappear.
+fn modified_times(cert: &CertificateConfig) -> Vec<Option<SystemTime>> {
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    [
+        Some(&cert.cert_path),
+        Some(&cert.key_path),
+        cert.ocsp_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| modified(path))
+    .collect()
+}
+
+/// Polls the certificate files forever, swapping in certificates whose files changed.
+///
+/// New files should be renamed into place, so a half-written file is never loaded. A certificate that fails to load
+/// keeps being served from its previous files, and isn't retried until its files change again.
+fn watch(
+    config: &TlsConfig,
+    resolver: Arc<CertResolver>,
+    mut loaded: Vec<(Vec<Option<SystemTime>>, Arc<ServedCert>)>,
+) {
+    loop {
+        std::thread::sleep(config.reload_interval);
+
+        let mut changed = false;
+        for (cert, (times, key)) in config.certificates.iter().zip(&mut loaded) {
+            let current = modified_times(cert);
+            if current == *times {
+                continue;
+            }
+            *times = current;
+
+            match load_certificate(cert) {
+                Ok(new) => {
+                    println!("reloaded TLS certificate {}", cert.cert_path.display());
+                    *key = new;
+                    changed = true;
+                }
+                Err(e) => {
+                    println!(
+                        "failed to reload TLS certificate {}: {}",
+                        cert.cert_path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        if changed {
+            let store = CertStore::new(config, loaded.iter().map(|(_, key)| key.clone()));
+            *resolver.files.write().unwrap() = Arc::new(store);
+        }
+    }
+}
+
+/// The certificates to serve, by hostname.
+#[derive(Debug)]
+struct CertStore {
+    exact: HashMap<String, Arc<ServedCert>>,
+
+    /// Key: the domain after `*.`
+    wildcard: HashMap<String, Arc<ServedCert>>,
+
+    default: Option<Arc<ServedCert>>,
+}
+
+impl CertStore {
+    /// Indexes loaded certificates, given in the same order as the config's.
+    /// When several certificates claim a hostname, the first one wins.
+    fn new(config: &TlsConfig, keys: impl Iterator<Item = Arc<ServedCert>>) -> CertStore {
+        let mut store = CertStore {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            default: None,
+        };
+
+        for (i, (cert, key)) in config.certificates.iter().zip(keys).enumerate() {
+            for hostname in &cert.hostnames {
+                let hostname = hostname.to_ascii_lowercase();
+                let map = match hostname.strip_prefix("*.") {
+                    Some(domain) => store.wildcard.entry(domain.to_owned()),
+                    None => store.exact.entry(hostname),
+                };
+                map.or_insert_with(|| key.clone());
+            }
+            if config.default_certificate == Some(i) {
+                store.default = Some(key);
+            }
+        }
+
+        store
+    }
+
+    /// Finds the wildcard certificate for a lowercase hostname.
+    /// A wildcard only covers one label, so `*.example.com` serves `a.example.com` but not `a.b.example.com`.
+    fn find_wildcard(&self, name: &str) -> Option<&Arc<ServedCert>> {
+        let (_, parent) = name.split_once('.')?;
+        self.wildcard.get(parent)
+    }
+}
+
+/// Picks certificates for handshakes.
+#[derive(Debug)]
+struct CertResolver {
+    /// Certificates from files, which the watcher replaces on reload.
+    files: RwLock<Arc<CertStore>>,
+
+    /// Certificates issued with ACME.
+    /// Key: lowercase hostname
+    managed: RwLock<HashMap<String, Arc<ServedCert>>>,
+
+    /// Certificates answering TLS-ALPN-01 validation.
+    /// Key: lowercase hostname
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    /// Finds the certificate for a lowercase server name.
+    fn find(&self, name: Option<&str>) -> Option<Arc<ServedCert>> {
+        let files = self.files.read().unwrap().clone();
+        if let Some(name) = name {
+            if let Some(cert) = files.exact.get(name) {
+                return Some(cert.clone());
+            }
+            if let Some(cert) = self.managed.read().unwrap().get(name) {
+                return Some(cert.clone());
+            }
+            if let Some(cert) = files.find_wildcard(name) {
+                return Some(cert.clone());
+            }
+        }
+        files.default.clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().map(|n| n.to_ascii_lowercase());
+
+        // ACME validation handshakes only ever get a challenge certificate.
+        if client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN))
+        {
+            return self
+                .challenges
+                .read()
+                .unwrap()
+                .get(name.as_deref()?)
+                .cloned();
+        }
+
+        self.find(name.as_deref()).map(|cert| cert.key.clone())
+    }
+}
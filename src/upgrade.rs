@@ -0,0 +1,318 @@
+//! Zero-downtime binary upgrades.
+//!
+//! On `SIGUSR2`, the running process starts the binary at its own path again, with the same arguments, and hands it
+//! every listening socket by inheritance: duplicates of the sockets are left open across `exec`, and their numbers are
+//! passed in [LISTENERS_ENV]. Each worker thread of the new process takes one socket for each address it listens on
+//! instead of binding a new one, so the `SO_REUSEPORT` group stays the same and no queued connection is lost. Sockets
+//! still left once every worker thread is up are closed, so none keeps queueing connections nobody accepts.
+//!
+//! Once every worker thread of the new process has its listeners up, it reports in over a pipe named by [READY_ENV].
+//! The old process then drains and exits the same way as on `SIGTERM`. If the new process doesn't report in within
+//! the ready timeout, or exits, it is killed and the old process carries on serving.
+//!
+//! Both processes serve from the cache directories while the old one drains. Meta files are versioned and every
+//! version is read by later binaries, so nothing on disk is rewritten or cleared across an upgrade.
+//!
+//! An inherited UDP socket is the same socket in both processes, and a datagram goes to whichever process reads it
+//! first. So once it has handed over, the old process stops receiving on its UDP sockets, leaving every datagram to
+//! the new one. QUIC connections can't move between processes, so the old process closes its own then, and clients
+//! reconnect to the new one.
+
+use crate::config::UpgradeConfig;
+use crate::shutdown;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The environment variable listing inherited sockets, as `kind@addr#fd` entries separated by `;`.
+const LISTENERS_ENV: &str = "STAVKA_INHERITED_LISTENERS";
+
+/// The environment variable holding the pipe each worker thread of a new process reports in on.
+const READY_ENV: &str = "STAVKA_UPGRADE_READY_FD";
+
+/// How often the upgrade thread checks whether an upgrade was requested.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set by the signal handler.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set once a new process has taken over the listeners.
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+/// The listening sockets open in this process, to hand over on upgrade.
+static LISTENERS: Mutex<Vec<(SocketKind, String, RawFd)>> = Mutex::new(Vec::new());
+
+/// Sockets inherited from the previous process and not yet taken by a worker thread.
+static INHERITED: OnceLock<Mutex<HashMap<(SocketKind, String), Vec<OwnedFd>>>> = OnceLock::new();
+
+/// The worker threads that have their listeners up.
+static READY_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// What a listening socket speaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+impl SocketKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            SocketKind::Tcp => "tcp",
+            SocketKind::Udp => "udp",
+        }
+    }
+
+    fn parse(s: &str) -> Option<SocketKind> {
+        match s {
+            "tcp" => Some(SocketKind::Tcp),
+            "udp" => Some(SocketKind::Udp),
+            _ => None,
+        }
+    }
+}
+
+extern "C" fn on_sigusr2(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Installs the `SIGUSR2` handler and starts the thread that carries out upgrades.
+pub fn launch(config: UpgradeConfig) -> io::Result<std::thread::JoinHandle<()>> {
+    let handler = on_sigusr2 as extern "C" fn(libc::c_int);
+    let ret = unsafe { libc::signal(libc::SIGUSR2, handler as libc::sighandler_t) };
+    if ret == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(std::thread::spawn(move || watch(&config)))
+}
+
+/// Whether a new process has taken over the listeners, which is why this one is draining.
+pub fn handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Relaxed)
+}
+
+/// Takes a socket inherited from the previous process for an address, if there's one left.
+/// The caller owns it from then on, and should [register] it like a socket it bound itself.
+pub fn take_inherited(kind: SocketKind, bind_addr: &str) -> Option<OwnedFd> {
+    let inherited = INHERITED.get_or_init(|| Mutex::new(parse_inherited()));
+    let fd = inherited
+        .lock()
+        .unwrap()
+        .get_mut(&(kind, bind_addr.to_owned()))?
+        .pop()?;
+
+    // Sockets are only passed on deliberately, as duplicates made at upgrade time.
+    unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    Some(fd)
+}
+
+/// Reads the sockets passed in [LISTENERS_ENV].
+fn parse_inherited() -> HashMap<(SocketKind, String), Vec<OwnedFd>> {
+    let mut inherited: HashMap<_, Vec<OwnedFd>> = HashMap::new();
+    let Ok(list) = std::env::var(LISTENERS_ENV) else {
+        return inherited;
+    };
+
+    for entry in list.split(';').filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('@').and_then(|(kind, rest)| {
+            let (addr, fd) = rest.rsplit_once('#')?;
+            Some((SocketKind::parse(kind)?, addr, fd.parse::<RawFd>().ok()?))
+        });
+        match parsed {
+            Some((kind, addr, fd)) => {
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                inherited
+                    .entry((kind, addr.to_owned()))
+                    .or_default()
+                    .push(fd);
+            }
+            None => println!("ignoring invalid inherited listener {}", entry),
+        }
+    }
+    inherited
+}
+
+/// Records a listening socket to hand over on upgrade.
+/// It has to stay open until the process exits, which listeners only stop doing while draining, when no upgrade
+/// can start.
+pub fn register(kind: SocketKind, bind_addr: &str, fd: RawFd) {
+    LISTENERS
+        .lock()
+        .unwrap()
+        .push((kind, bind_addr.to_owned(), fd));
+}
+
+/// Reports that a worker thread has its listeners up, telling the previous process if it's waiting on an upgrade.
+/// Once all `workers` threads have, inherited sockets nobody took are closed.
+pub fn thread_ready(workers: usize) {
+    if READY_THREADS.fetch_add(1, Ordering::Relaxed) + 1 == workers {
+        close_leftovers();
+    }
+
+    let Some(fd) = std::env::var(READY_ENV)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    let ret = unsafe { libc::write(fd, b"r".as_ptr() as *const libc::c_void, 1) };
+    if ret != 1 {
+        println!(
+            "failed to report readiness for upgrade: {}",
+            io::Error::last_os_error()
+        );
+    }
+    // Later upgrades make their own pipe, so this one isn't passed on.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+}
+
+/// Closes the inherited sockets no thread took, such as for addresses no longer configured or threads the previous
+/// process had more of. Kept open, they would go on queueing connections that are never accepted.
+fn close_leftovers() {
+    let inherited = INHERITED.get_or_init(|| Mutex::new(parse_inherited()));
+    for ((kind, addr), fds) in inherited.lock().unwrap().drain() {
+        if !fds.is_empty() {
+            println!(
+                "closing {} inherited {} listener(s) for {}",
+                fds.len(),
+                kind.as_str(),
+                addr
+            );
+        }
+    }
+}
+
+/// Waits for upgrade requests forever, handing over to a new process once one succeeds.
+fn watch(config: &UpgradeConfig) {
+    loop {
+        std::thread::sleep(SIGNAL_POLL_INTERVAL);
+        if !REQUESTED.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        if shutdown::is_requested() {
+            println!("not upgrading, since the process is shutting down");
+            continue;
+        }
+
+        println!("upgrading");
+        match upgrade(config) {
+            Ok(pid) => {
+                println!("upgraded to process {}, draining", pid);
+                HANDED_OVER.store(true, Ordering::Relaxed);
+                shutdown::request();
+                return;
+            }
+            Err(e) => println!("upgrade failed, the running process carries on: {}", e),
+        }
+    }
+}
+
+/// Starts the new process with the listeners and waits for it to report in.
+/// Returns the new process's ID.
+fn upgrade(config: &UpgradeConfig) -> Result<u32, Box<dyn Error>> {
+    // Duplicates don't have close-on-exec set, so only they are inherited; the originals keep serving here.
+    let mut passed = Vec::new();
+    let mut list = String::new();
+    for (kind, addr, fd) in LISTENERS.lock().unwrap().iter() {
+        let dup = dup(*fd)?;
+        list.push_str(&format!("{}@{}#{};", kind.as_str(), addr, dup.as_raw_fd()));
+        passed.push(dup);
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let ready_read = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+    let ready_write = {
+        let cloexec = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+        dup(cloexec.as_raw_fd())?
+    };
+
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(LISTENERS_ENV, list)
+        .env(READY_ENV, ready_write.as_raw_fd().to_string())
+        .spawn()?;
+    // The new process has its own copies now.
+    drop(passed);
+    drop(ready_write);
+
+    let expected = READY_THREADS.load(Ordering::Relaxed);
+    match wait_ready(&ready_read, expected, config.ready_timeout) {
+        Ok(()) => Ok(child.id()),
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
+
+/// Duplicates a file descriptor, without close-on-exec.
+fn dup(fd: RawFd) -> io::Result<OwnedFd> {
+    let dup = unsafe { libc::dup(fd) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(dup) })
+}
+
+/// Waits until `expected` worker threads of the new process have reported in.
+/// The new process running fewer threads than this one counts as a failure, since sockets would be left unserved.
+fn wait_ready(ready: &OwnedFd, expected: usize, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+    let mut reported = 0;
+    while reported < expected {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(format!(
+                "{} of {} worker threads were ready in time",
+                reported, expected
+            )
+            .into());
+        }
+
+        let mut poll = libc::pollfd {
+            fd: ready.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut poll, 1, left.as_millis() as libc::c_int) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+        if ret == 0 {
+            continue;
+        }
+
+        let mut buf = [0u8; 64];
+        let n = unsafe {
+            libc::read(
+                ready.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        match n {
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e.into());
+                }
+            }
+            0 => return Err("the new process exited before it was ready".into()),
+            n => reported += n as usize,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Host capabilities a module can be granted: WASI clocks, randomness and directories, and outbound HTTP.
+//!
+//! Everything is denied unless the module's [ModulePermissions] allow it. Denied WASI functions are replaced with
+//! stubs that log the attempt and fail with `ENOTCAPABLE`, so a module probing for capabilities it wasn't granted
+//! shows up in the log instead of silently getting an error.
+//!
+//! WASI calls run synchronously on the worker thread, so granting directory access lets a module block it on disk
+//! I/O; the per-phase time limit only applies to guest code.
+
+use super::fetch::http_origin;
+use super::host::GuestContext;
+use crate::config::ModulePermissions;
+use http::Uri;
+use wasmtime::{Caller, Linker, Module};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{DirPerms, FilePerms, WasiCtxBuilder};
+
+/// The import namespace of WASI preview 1.
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// The WASI errno for a capability that isn't available.
+const ERRNO_NOTCAPABLE: i32 = 76;
+
+/// Store data that can carry a WASI context.
+pub(super) trait WasiContext: GuestContext {
+    fn wasi(&mut self) -> &mut WasiP1Ctx;
+
+    /// The name of the module, for violation log lines.
+    fn module_name(&self) -> &str;
+}
+
+/// Whether a module imports any WASI functions.
+pub(super) fn imports_wasi(module: &Module) -> bool {
+    module
+        .imports()
+        .any(|import| import.module() == WASI_MODULE)
+}
+
+/// Builds a WASI context with only the granted directories.
+/// No arguments, environment variables or stdio are exposed.
+pub(super) fn build_wasi_ctx(permissions: &ModulePermissions) -> wasmtime::Result<WasiP1Ctx> {
+    let mut builder = WasiCtxBuilder::new();
+    for dir in &permissions.preopened_dirs {
+        let (dir_perms, file_perms) = if dir.read_only {
+            (DirPerms::READ, FilePerms::READ)
+        } else {
+            (DirPerms::all(), FilePerms::all())
+        };
+        builder.preopened_dir(&dir.host_path, &dir.guest_path, dir_perms, file_perms)?;
+    }
+    Ok(builder.build_p1())
+}
+
+fn log_violation<T: WasiContext>(caller: &Caller<'_, T>, capability: &str) {
+    println!(
+        "[wasm {}] module {} denied {} capability",
+        caller.data().site(),
+        caller.data().module_name(),
+        capability
+    );
+}
+
+/// Adds WASI to a linker, replacing the functions behind denied capabilities with logging stubs.
+pub(super) fn add_wasi_to_linker<T: WasiContext>(
+    linker: &mut Linker<T>,
+    permissions: &ModulePermissions,
+) -> wasmtime::Result<()> {
+    preview1::add_to_linker_sync(linker, |state: &mut T| state.wasi())?;
+    linker.allow_shadowing(true);
+
+    if !permissions.clock {
+        linker.func_wrap(
+            WASI_MODULE,
+            "clock_res_get",
+            |caller: Caller<'_, T>, _id: i32, _out: i32| -> i32 {
+                log_violation(&caller, "clock");
+                ERRNO_NOTCAPABLE
+            },
+        )?;
+        linker.func_wrap(
+            WASI_MODULE,
+            "clock_time_get",
+            |caller: Caller<'_, T>, _id: i32, _precision: i64, _out: i32| -> i32 {
+                log_violation(&caller, "clock");
+                ERRNO_NOTCAPABLE
+            },
+        )?;
+        // Sleeping is waiting on a clock.
+        linker.func_wrap(
+            WASI_MODULE,
+            "poll_oneoff",
+            |caller: Caller<'_, T>, _in: i32, _out: i32, _count: i32, _events: i32| -> i32 {
+                log_violation(&caller, "clock");
+                ERRNO_NOTCAPABLE
+            },
+        )?;
+    }
+
+    if !permissions.random {
+        linker.func_wrap(
+            WASI_MODULE,
+            "random_get",
+            |caller: Caller<'_, T>, _buf: i32, _len: i32| -> i32 {
+                log_violation(&caller, "random");
+                ERRNO_NOTCAPABLE
+            },
+        )?;
+    }
+
+    if permissions.preopened_dirs.is_empty() {
+        linker.func_wrap(
+            WASI_MODULE,
+            "path_open",
+            |caller: Caller<'_, T>,
+             _fd: i32,
+             _dirflags: i32,
+             _path: i32,
+             _path_len: i32,
+             _oflags: i32,
+             _rights_base: i64,
+             _rights_inheriting: i64,
+             _fdflags: i32,
+             _out: i32|
+             -> i32 {
+                log_violation(&caller, "filesystem");
+                ERRNO_NOTCAPABLE
+            },
+        )?;
+    }
+
+    linker.allow_shadowing(false);
+    Ok(())
+}
+
+/// Returns whether a module may send HTTP requests to a URI's origin.
+/// The scheme and port have to match as well as the host.
+pub(super) fn http_allowed(permissions: &ModulePermissions, uri: &Uri) -> bool {
+    let Some(origin) = http_origin(uri) else {
+        return false;
+    };
+    permissions
+        .http_hosts
+        .iter()
+        .any(|allowed| *allowed == origin)
+}
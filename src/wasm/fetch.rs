@@ -0,0 +1,139 @@
+//! Outbound HTTP requests from request hooks.
+//!
+//! Hooks run synchronously, so they can't wait on the network themselves. Instead, a hook queues requests with
+//! `http_send` and returns [AWAIT_FETCHES]; the host sends them with stavka's own client and runs the phase again,
+//! at which point `http_status` and friends return the responses. Identical requests made on a rerun map to the
+//! same fetch, so hooks can simply run top to bottom each time.
+
+use bytes::{Bytes, BytesMut};
+use http::header::CONTENT_LENGTH;
+use http::uri::Scheme;
+use http::{HeaderMap, Method, Uri};
+use monoio_http::common::body::{Body, HttpBody};
+use monoio_http::h1::payload::{FixedPayload, Payload};
+use monoio_http_client::Client;
+use std::time::Duration;
+
+/// What a hook returns to have the host perform its queued fetches and run it again.
+/// Distinct from the `1` the SDK returns for a failed hook.
+pub const AWAIT_FETCHES: i32 = 2;
+
+/// The most fetches a single request may make.
+pub const MAX_FETCHES_PER_REQUEST: usize = 8;
+
+/// The most times a phase may ask to await fetches before it is failed.
+pub const MAX_FETCH_ROUNDS: u32 = 4;
+
+/// The largest response body a fetch returns to the hook.
+const MAX_FETCH_BODY_SIZE: usize = 1024 * 1024;
+
+/// How long a fetch may take, including reading its response body.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A response to a fetch.
+pub struct FetchResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// A request a hook made and its response, once there is one.
+pub struct Fetch {
+    pub method: Method,
+    pub uri: Uri,
+    pub body: Bytes,
+
+    /// [None] until the fetch is performed, then the response or [None] inside if it failed.
+    pub response: Option<Option<FetchResponse>>,
+}
+
+impl Fetch {
+    /// Whether this fetch is the same request as the specified one.
+    pub fn is_same(&self, method: &Method, uri: &Uri, body: &[u8]) -> bool {
+        self.method == *method && self.uri == *uri && self.body == body
+    }
+}
+
+/// Returns the `scheme://host:port` origin of a URI, with the scheme's default port filled in, as
+/// [crate::config::ModulePermissions::http_hosts] lists them.
+pub fn http_origin(uri: &Uri) -> Option<String> {
+    let scheme = uri.scheme()?;
+    let default_port = if *scheme == Scheme::HTTPS {
+        443
+    } else if *scheme == Scheme::HTTP {
+        80
+    } else {
+        return None;
+    };
+    Some(format!(
+        "{}://{}:{}",
+        scheme,
+        uri.host()?.to_ascii_lowercase(),
+        uri.port_u16().unwrap_or(default_port)
+    ))
+}
+
+/// Performs a fetch with stavka's client.
+/// Returns [None] if the request failed, took too long or the response body was too large.
+pub async fn perform(client: &Client, fetch: &Fetch) -> Option<FetchResponse> {
+    match monoio::time::timeout(FETCH_TIMEOUT, send(client, fetch)).await {
+        Ok(res) => res,
+        Err(_) => {
+            println!("wasm fetch of {} timed out", fetch.uri);
+            None
+        }
+    }
+}
+
+async fn send(client: &Client, fetch: &Fetch) -> Option<FetchResponse> {
+    let req = http::Request::builder()
+        .method(fetch.method.clone())
+        .uri(fetch.uri.clone())
+        .body(HttpBody::from(Payload::Fixed(FixedPayload::new(
+            fetch.body.clone(),
+        ))))
+        .ok()?;
+
+    let res = match client.send_request(req).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("wasm fetch of {} failed: {}", fetch.uri, e);
+            return None;
+        }
+    };
+
+    let too_large = || {
+        println!(
+            "wasm fetch of {} returned a body over the {} byte limit",
+            fetch.uri, MAX_FETCH_BODY_SIZE
+        );
+    };
+
+    let (parts, mut body) = res.into_parts();
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > MAX_FETCH_BODY_SIZE as u64) {
+        too_large();
+        return None;
+    }
+
+    // Bodies without a Content-Length are capped as they arrive.
+    let mut buf = BytesMut::new();
+    while let Some(data) = body.next_data().await {
+        let data = data.ok()?;
+        if buf.len() + data.len() > MAX_FETCH_BODY_SIZE {
+            too_large();
+            return None;
+        }
+        buf.extend_from_slice(&data);
+    }
+
+    Some(FetchResponse {
+        status: parts.status.as_u16(),
+        headers: parts.headers,
+        body: buf.freeze(),
+    })
+}
@@ -0,0 +1,784 @@
+//! The host side of the module ABI.
+//!
+//! Every host function lives in the [ABI_MODULE] import namespace. Strings are passed as `(ptr, len)` pairs into
+//! the guest's exported `memory`. Functions that return data take an output `(ptr, cap)` buffer and return the
+//! full length of the value, only writing it if it fits; the guest retries with a larger buffer if the returned
+//! length exceeds `cap`. Missing values are reported as `-1`, invalid arguments as `-2`, operations that
+//! failed, such as a resident call trapping or the key/value store being full, as `-3`, and uses of capabilities
+//! the module wasn't granted as `-4`.
+//!
+//! The logging and key/value functions are also available to resident instances; see [super::resident].
+
+use super::capability::{self, WasiContext};
+use super::fetch::{Fetch, MAX_FETCHES_PER_REQUEST};
+use super::kv::{self, KvSetError};
+use super::resident::ResidentInstance;
+use crate::config::ModulePermissions;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use wasmtime::{Caller, Linker};
+use wasmtime_wasi::preview1::WasiP1Ctx;
+
+/// The import namespace host functions are provided under.
+/// The suffix is the ABI version; incompatible changes get a new namespace.
+pub const ABI_MODULE: &str = "stavka_v1";
+
+/// The ABI version modules must report from their `stavka_abi_version` export.
+pub const ABI_VERSION: i32 = 1;
+
+pub(super) const MISSING: i32 = -1;
+pub(super) const INVALID: i32 = -2;
+pub(super) const FAILED: i32 = -3;
+pub(super) const DENIED: i32 = -4;
+
+/// Store data that the functions shared by every kind of instance need.
+pub(super) trait GuestContext: 'static {
+    /// The site the instance is currently acting for, which namespaces its key/value store access.
+    fn site(&self) -> &str;
+}
+
+/// The point in request handling a hook runs at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// After the client request is received, before an origin is chosen.
+    OnRequest,
+
+    /// Right before the request is sent to the origin.
+    OnOriginRequest,
+
+    /// After the origin response head is received, before it is cached.
+    OnOriginResponse,
+
+    /// Right before the response is sent to the client.
+    OnResponse,
+}
+
+impl Phase {
+    /// Every phase, in the order they run.
+    pub const ALL: [Phase; 4] = [
+        Phase::OnRequest,
+        Phase::OnOriginRequest,
+        Phase::OnOriginResponse,
+        Phase::OnResponse,
+    ];
+
+    /// The position of the phase in [Phase::ALL], which is also the number reported by `get_phase`.
+    pub fn index(self) -> usize {
+        match self {
+            Phase::OnRequest => 0,
+            Phase::OnOriginRequest => 1,
+            Phase::OnOriginResponse => 2,
+            Phase::OnResponse => 3,
+        }
+    }
+
+    /// The name of the guest export called for this phase.
+    pub fn export_name(self) -> &'static str {
+        match self {
+            Phase::OnRequest => "on_request",
+            Phase::OnOriginRequest => "on_origin_request",
+            Phase::OnOriginResponse => "on_origin_response",
+            Phase::OnResponse => "on_response",
+        }
+    }
+
+    /// Whether the phase operates on the response rather than the request.
+    fn is_response(self) -> bool {
+        matches!(self, Phase::OnOriginResponse | Phase::OnResponse)
+    }
+}
+
+/// Values a module can override for the current request.
+#[derive(Default)]
+pub struct Overrides {
+    /// The origin to fetch from, as `scheme://authority`.
+    pub origin: Option<Uri>,
+
+    /// The cache key to store the object under.
+    /// Purges select the object by this key rather than by its URL.
+    pub cache_key: Option<String>,
+
+    /// The number of seconds the object stays fresh.
+    pub ttl: Option<u64>,
+
+    /// The number of bytes to keep reading from the origin past the end of the requested range.
+    pub readahead: Option<u64>,
+}
+
+/// A response produced by a module instead of going to the origin, such as a denial or redirect.
+pub struct EarlyResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// The state a module instance can see and modify for a single request.
+pub struct HookState {
+    pub phase: Phase,
+
+    pub method: Method,
+    pub uri: Uri,
+    pub request_headers: HeaderMap,
+
+    pub status: StatusCode,
+    pub response_headers: HeaderMap,
+
+    pub overrides: Overrides,
+    pub early_response: Option<EarlyResponse>,
+
+    /// The host the request is for, used to prefix log lines.
+    pub host: String,
+
+    /// The client's address, after trusted forwarding headers and PROXY protocol headers are taken into account.
+    /// [None] outside of client requests.
+    pub client_ip: Option<IpAddr>,
+
+    /// This thread's resident instance, if the module has one.
+    pub resident: Option<Rc<RefCell<ResidentInstance>>>,
+
+    /// The result of the last resident call, kept so a guest with too small a buffer can fetch it without calling again.
+    resident_result: Vec<u8>,
+
+    /// Outbound HTTP requests the module has made; see [super::fetch].
+    pub fetches: Vec<Fetch>,
+
+    /// Set when the module imports WASI.
+    pub(super) wasi: Option<WasiP1Ctx>,
+    pub(super) permissions: Arc<ModulePermissions>,
+    pub(super) module_name: Arc<str>,
+}
+
+impl HookState {
+    pub fn new(method: Method, uri: Uri, request_headers: HeaderMap, host: String) -> Self {
+        Self {
+            phase: Phase::OnRequest,
+            method,
+            uri,
+            request_headers,
+            status: StatusCode::OK,
+            response_headers: HeaderMap::new(),
+            overrides: Overrides::default(),
+            early_response: None,
+            host,
+            client_ip: None,
+            resident: None,
+            resident_result: Vec::new(),
+            fetches: Vec::new(),
+            wasi: None,
+            permissions: Arc::default(),
+            module_name: Arc::from(""),
+        }
+    }
+
+    /// The headers of the message the current phase operates on.
+    fn headers(&self) -> &HeaderMap {
+        if self.phase.is_response() {
+            &self.response_headers
+        } else {
+            &self.request_headers
+        }
+    }
+
+    fn headers_mut(&mut self) -> &mut HeaderMap {
+        if self.phase.is_response() {
+            &mut self.response_headers
+        } else {
+            &mut self.request_headers
+        }
+    }
+}
+
+impl GuestContext for HookState {
+    fn site(&self) -> &str {
+        &self.host
+    }
+}
+
+impl WasiContext for HookState {
+    fn wasi(&mut self) -> &mut WasiP1Ctx {
+        self.wasi
+            .as_mut()
+            .expect("WASI context should have been set for a module importing WASI")
+    }
+
+    fn module_name(&self) -> &str {
+        &self.module_name
+    }
+}
+
+/// Reads guest memory into a byte vector.
+pub(super) fn read_bytes<T>(caller: &mut Caller<'_, T>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    if ptr < 0 || len < 0 {
+        return None;
+    }
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let mut buf = vec![0u8; len as usize];
+    memory.read(&*caller, ptr as usize, &mut buf).ok()?;
+    Some(buf)
+}
+
+/// Reads a UTF-8 string from guest memory.
+pub(super) fn read_str<T>(caller: &mut Caller<'_, T>, ptr: i32, len: i32) -> Option<String> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).ok()
+}
+
+/// Writes a value to a guest output buffer if it fits, returning its full length.
+pub(super) fn write_out<T>(caller: &mut Caller<'_, T>, ptr: i32, cap: i32, data: &[u8]) -> i32 {
+    if ptr < 0 || cap < 0 {
+        return INVALID;
+    }
+    if data.len() <= cap as usize {
+        let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+            return INVALID;
+        };
+        if memory.write(&mut *caller, ptr as usize, data).is_err() {
+            return INVALID;
+        }
+    }
+    data.len() as i32
+}
+
+/// Registers the functions available to every kind of instance.
+pub(super) fn add_shared_to_linker<T: GuestContext>(
+    linker: &mut Linker<T>,
+) -> wasmtime::Result<()> {
+    linker.func_wrap(
+        ABI_MODULE,
+        "log",
+        |mut caller: Caller<'_, T>, ptr: i32, len: i32| {
+            if let Some(msg) = read_str(&mut caller, ptr, len) {
+                println!("[wasm {}] {}", caller.data().site(), msg);
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "kv_get",
+        |mut caller: Caller<'_, T>, key_ptr: i32, key_len: i32, ptr: i32, cap: i32| -> i32 {
+            let Some(key) = read_bytes(&mut caller, key_ptr, key_len) else {
+                return INVALID;
+            };
+            let Some(store) = kv::get() else {
+                return FAILED;
+            };
+            match store.get(caller.data().site(), &key) {
+                Some(value) => write_out(&mut caller, ptr, cap, &value),
+                None => MISSING,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "kv_set",
+        |mut caller: Caller<'_, T>,
+         key_ptr: i32,
+         key_len: i32,
+         value_ptr: i32,
+         value_len: i32,
+         ttl_secs: i64|
+         -> i32 {
+            let (Some(key), Some(value)) = (
+                read_bytes(&mut caller, key_ptr, key_len),
+                read_bytes(&mut caller, value_ptr, value_len),
+            ) else {
+                return INVALID;
+            };
+            let Some(store) = kv::get() else {
+                return FAILED;
+            };
+            // A TTL of zero or less means the value never expires.
+            let ttl = u64::try_from(ttl_secs)
+                .ok()
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs);
+            match store.set(caller.data().site(), &key, value, ttl) {
+                Ok(()) => 0,
+                Err(KvSetError::TooLarge) => INVALID,
+                Err(KvSetError::Full) => FAILED,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "kv_delete",
+        |mut caller: Caller<'_, T>, key_ptr: i32, key_len: i32| -> i32 {
+            let Some(key) = read_bytes(&mut caller, key_ptr, key_len) else {
+                return INVALID;
+            };
+            let Some(store) = kv::get() else {
+                return FAILED;
+            };
+            if store.delete(caller.data().site(), &key) {
+                0
+            } else {
+                MISSING
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Registers every host function available to per-request instances with the linker.
+pub fn add_to_linker(linker: &mut Linker<HookState>) -> wasmtime::Result<()> {
+    add_shared_to_linker(linker)?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_phase",
+        |caller: Caller<'_, HookState>| -> i32 { caller.data().phase.index() as i32 },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_method",
+        |mut caller: Caller<'_, HookState>, ptr: i32, cap: i32| -> i32 {
+            let method = caller.data().method.as_str().to_owned();
+            write_out(&mut caller, ptr, cap, method.as_bytes())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_host",
+        |mut caller: Caller<'_, HookState>, ptr: i32, cap: i32| -> i32 {
+            let host = caller.data().host.clone();
+            write_out(&mut caller, ptr, cap, host.as_bytes())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_client_ip",
+        |mut caller: Caller<'_, HookState>, ptr: i32, cap: i32| -> i32 {
+            let Some(ip) = caller.data().client_ip else {
+                return MISSING;
+            };
+            write_out(&mut caller, ptr, cap, ip.to_string().as_bytes())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_path",
+        |mut caller: Caller<'_, HookState>, ptr: i32, cap: i32| -> i32 {
+            let path = caller
+                .data()
+                .uri
+                .path_and_query()
+                .map(|p| p.as_str().to_owned())
+                .unwrap_or_else(|| "/".to_owned());
+            write_out(&mut caller, ptr, cap, path.as_bytes())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_path",
+        |mut caller: Caller<'_, HookState>, ptr: i32, len: i32| -> i32 {
+            let Some(path) = read_str(&mut caller, ptr, len) else {
+                return INVALID;
+            };
+            let mut parts = caller.data().uri.clone().into_parts();
+            parts.path_and_query = match path.parse() {
+                Ok(p) => Some(p),
+                Err(_) => return INVALID,
+            };
+            match Uri::from_parts(parts) {
+                Ok(uri) => {
+                    caller.data_mut().uri = uri;
+                    0
+                }
+                Err(_) => INVALID,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_header",
+        |mut caller: Caller<'_, HookState>,
+         name_ptr: i32,
+         name_len: i32,
+         ptr: i32,
+         cap: i32|
+         -> i32 {
+            let Some(name) = read_str(&mut caller, name_ptr, name_len) else {
+                return INVALID;
+            };
+            let value = match caller.data().headers().get(name.as_str()) {
+                Some(v) => v.as_bytes().to_vec(),
+                None => return MISSING,
+            };
+            write_out(&mut caller, ptr, cap, &value)
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_header",
+        |mut caller: Caller<'_, HookState>,
+         name_ptr: i32,
+         name_len: i32,
+         value_ptr: i32,
+         value_len: i32|
+         -> i32 {
+            let Some(name) = read_bytes(&mut caller, name_ptr, name_len) else {
+                return INVALID;
+            };
+            let Some(value) = read_bytes(&mut caller, value_ptr, value_len) else {
+                return INVALID;
+            };
+            let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(&name),
+                HeaderValue::from_bytes(&value),
+            ) else {
+                return INVALID;
+            };
+            caller.data_mut().headers_mut().insert(name, value);
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "remove_header",
+        |mut caller: Caller<'_, HookState>, name_ptr: i32, name_len: i32| -> i32 {
+            let Some(name) = read_str(&mut caller, name_ptr, name_len) else {
+                return INVALID;
+            };
+            match caller.data_mut().headers_mut().remove(name.as_str()) {
+                Some(_) => 0,
+                None => MISSING,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_status",
+        |caller: Caller<'_, HookState>| -> i32 {
+            if caller.data().phase.is_response() {
+                caller.data().status.as_u16() as i32
+            } else {
+                MISSING
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_status",
+        |mut caller: Caller<'_, HookState>, status: i32| -> i32 {
+            if !caller.data().phase.is_response() {
+                return INVALID;
+            }
+            match u16::try_from(status)
+                .ok()
+                .and_then(|s| StatusCode::from_u16(s).ok())
+            {
+                Some(status) => {
+                    caller.data_mut().status = status;
+                    0
+                }
+                None => INVALID,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_origin",
+        |mut caller: Caller<'_, HookState>, ptr: i32, len: i32| -> i32 {
+            if caller.data().phase != Phase::OnRequest {
+                return INVALID;
+            }
+            let Some(origin) = read_str(&mut caller, ptr, len) else {
+                return INVALID;
+            };
+            match origin.parse::<Uri>() {
+                Ok(uri) if uri.scheme().is_some() && uri.authority().is_some() => {
+                    caller.data_mut().overrides.origin = Some(uri);
+                    0
+                }
+                _ => INVALID,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_cache_key",
+        |mut caller: Caller<'_, HookState>, ptr: i32, len: i32| -> i32 {
+            let Some(key) = read_str(&mut caller, ptr, len) else {
+                return INVALID;
+            };
+            caller.data_mut().overrides.cache_key = Some(key);
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_ttl",
+        |mut caller: Caller<'_, HookState>, secs: i64| -> i32 {
+            match u64::try_from(secs) {
+                Ok(secs) => {
+                    caller.data_mut().overrides.ttl = Some(secs);
+                    0
+                }
+                Err(_) => INVALID,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_readahead",
+        |mut caller: Caller<'_, HookState>, bytes: i64| -> i32 {
+            match u64::try_from(bytes) {
+                Ok(bytes) => {
+                    caller.data_mut().overrides.readahead = Some(bytes);
+                    0
+                }
+                Err(_) => INVALID,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "respond",
+        |mut caller: Caller<'_, HookState>, status: i32, body_ptr: i32, body_len: i32| -> i32 {
+            let Some(status) = u16::try_from(status)
+                .ok()
+                .and_then(|s| StatusCode::from_u16(s).ok())
+            else {
+                return INVALID;
+            };
+            let Some(body) = read_bytes(&mut caller, body_ptr, body_len) else {
+                return INVALID;
+            };
+            caller.data_mut().early_response = Some(EarlyResponse {
+                status,
+                headers: HeaderMap::new(),
+                body: Bytes::from(body),
+            });
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "redirect",
+        |mut caller: Caller<'_, HookState>, status: i32, loc_ptr: i32, loc_len: i32| -> i32 {
+            let Some(status) = u16::try_from(status)
+                .ok()
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .filter(|s| s.is_redirection())
+            else {
+                return INVALID;
+            };
+            let Some(location) = read_bytes(&mut caller, loc_ptr, loc_len)
+                .and_then(|l| HeaderValue::from_bytes(&l).ok())
+            else {
+                return INVALID;
+            };
+            let mut headers = HeaderMap::new();
+            headers.insert(http::header::LOCATION, location);
+            caller.data_mut().early_response = Some(EarlyResponse {
+                status,
+                headers,
+                body: Bytes::new(),
+            });
+            0
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "call_resident",
+        |mut caller: Caller<'_, HookState>,
+         name_ptr: i32,
+         name_len: i32,
+         arg_ptr: i32,
+         arg_len: i32,
+         ptr: i32,
+         cap: i32|
+         -> i32 {
+            let (Some(name), Some(arg)) = (
+                read_str(&mut caller, name_ptr, name_len),
+                read_bytes(&mut caller, arg_ptr, arg_len),
+            ) else {
+                return INVALID;
+            };
+            let Some(resident) = caller.data().resident.clone() else {
+                return MISSING;
+            };
+            // The resident can't call back into a request, so it is never already borrowed here.
+            let Ok(mut resident) = resident.try_borrow_mut() else {
+                return FAILED;
+            };
+
+            let site = caller.data().host.clone();
+            match resident.call(&name, &site, arg) {
+                Ok(Some(result)) => {
+                    let len = write_out(&mut caller, ptr, cap, &result);
+                    caller.data_mut().resident_result = result;
+                    len
+                }
+                Ok(None) => MISSING,
+                Err(e) => {
+                    println!("[wasm {}] resident call {} failed: {:#}", site, name, e);
+                    FAILED
+                }
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "http_send",
+        |mut caller: Caller<'_, HookState>,
+         method_ptr: i32,
+         method_len: i32,
+         url_ptr: i32,
+         url_len: i32,
+         body_ptr: i32,
+         body_len: i32|
+         -> i32 {
+            let (Some(method), Some(url), Some(body)) = (
+                read_bytes(&mut caller, method_ptr, method_len),
+                read_str(&mut caller, url_ptr, url_len),
+                read_bytes(&mut caller, body_ptr, body_len),
+            ) else {
+                return INVALID;
+            };
+            let (Ok(method), Ok(uri)) = (Method::from_bytes(&method), url.parse::<Uri>()) else {
+                return INVALID;
+            };
+            if uri.host().is_none() {
+                return INVALID;
+            }
+
+            let state = caller.data();
+            if !capability::http_allowed(&state.permissions, &uri) {
+                println!(
+                    "[wasm {}] module {} denied http capability for {}",
+                    state.host, state.module_name, uri
+                );
+                return DENIED;
+            }
+
+            // A phase rerun after awaiting fetches makes the same requests again.
+            if let Some(id) = state
+                .fetches
+                .iter()
+                .position(|f| f.is_same(&method, &uri, &body))
+            {
+                return id as i32;
+            }
+            if state.fetches.len() >= MAX_FETCHES_PER_REQUEST {
+                return FAILED;
+            }
+
+            let fetches = &mut caller.data_mut().fetches;
+            fetches.push(Fetch {
+                method,
+                uri,
+                body: Bytes::from(body),
+                response: None,
+            });
+            (fetches.len() - 1) as i32
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "http_status",
+        |caller: Caller<'_, HookState>, id: i32| -> i32 {
+            let Some(fetch) = usize::try_from(id)
+                .ok()
+                .and_then(|id| caller.data().fetches.get(id))
+            else {
+                return INVALID;
+            };
+            match &fetch.response {
+                Some(Some(res)) => res.status as i32,
+                Some(None) => FAILED,
+                None => MISSING,
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "http_header",
+        |mut caller: Caller<'_, HookState>,
+         id: i32,
+         name_ptr: i32,
+         name_len: i32,
+         ptr: i32,
+         cap: i32|
+         -> i32 {
+            let Some(name) = read_str(&mut caller, name_ptr, name_len) else {
+                return INVALID;
+            };
+            let Some(fetch) = usize::try_from(id)
+                .ok()
+                .and_then(|id| caller.data().fetches.get(id))
+            else {
+                return INVALID;
+            };
+            let value = match &fetch.response {
+                Some(Some(res)) => match res.headers.get(name.as_str()) {
+                    Some(v) => v.as_bytes().to_vec(),
+                    None => return MISSING,
+                },
+                Some(None) => return FAILED,
+                None => return MISSING,
+            };
+            write_out(&mut caller, ptr, cap, &value)
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "http_body",
+        |mut caller: Caller<'_, HookState>, id: i32, ptr: i32, cap: i32| -> i32 {
+            let Some(fetch) = usize::try_from(id)
+                .ok()
+                .and_then(|id| caller.data().fetches.get(id))
+            else {
+                return INVALID;
+            };
+            let body = match &fetch.response {
+                Some(Some(res)) => res.body.clone(),
+                Some(None) => return FAILED,
+                None => return MISSING,
+            };
+            write_out(&mut caller, ptr, cap, &body)
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_resident_result",
+        |mut caller: Caller<'_, HookState>, ptr: i32, cap: i32| -> i32 {
+            let result = std::mem::take(&mut caller.data_mut().resident_result);
+            let len = write_out(&mut caller, ptr, cap, &result);
+            caller.data_mut().resident_result = result;
+            len
+        },
+    )?;
+
+    Ok(())
+}
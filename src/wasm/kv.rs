@@ -0,0 +1,130 @@
+//! The key/value store modules use to remember decisions across requests, such as "video 123 is private".
+//!
+//! The store is process-wide, so a value set while handling a request on one worker thread is visible to every
+//! other thread. Keys are namespaced by site, so modules serving one host can't read or clobber another's values.
+//! Values survive module hot swaps, but not restarts.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The number of independently locked shards, to keep worker threads from contending on one lock.
+const SHARD_COUNT: usize = 16;
+
+struct KvEntry {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+}
+
+impl KvEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.map_or(false, |exp| exp <= now)
+    }
+}
+
+type Shard = HashMap<(String, Vec<u8>), KvEntry>;
+
+/// Why a value could not be stored.
+#[derive(Debug)]
+pub enum KvSetError {
+    /// The value is larger than the configured maximum.
+    TooLarge,
+
+    /// The store has no room left, even after dropping expired entries.
+    Full,
+}
+
+pub struct KvStore {
+    shards: Vec<Mutex<Shard>>,
+    max_entries_per_shard: usize,
+    max_value_bytes: usize,
+}
+
+static KV: OnceLock<KvStore> = OnceLock::new();
+
+/// Creates the process-wide store.
+pub fn init(max_entries: usize, max_value_bytes: usize) {
+    let _ = KV.set(KvStore::new(max_entries, max_value_bytes));
+}
+
+/// Returns the process-wide store, or [None] if hooks are not enabled.
+pub fn get() -> Option<&'static KvStore> {
+    KV.get()
+}
+
+impl KvStore {
+    pub fn new(max_entries: usize, max_value_bytes: usize) -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            max_entries_per_shard: max_entries.div_ceil(SHARD_COUNT),
+            max_value_bytes,
+        }
+    }
+
+    fn shard(&self, site: &str, key: &[u8]) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        site.hash(&mut hasher);
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+
+    /// Returns the value of a key, if it is set and has not expired.
+    pub fn get(&self, site: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let mut shard = self.shard(site, key).lock().unwrap();
+        let id = (site.to_owned(), key.to_owned());
+
+        let entry = shard.get(&id)?;
+        if entry.is_expired(Instant::now()) {
+            shard.remove(&id);
+            return None;
+        }
+        Some(entry.value.clone())
+    }
+
+    /// Sets a key, optionally expiring it after a TTL.
+    pub fn set(
+        &self,
+        site: &str,
+        key: &[u8],
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), KvSetError> {
+        if value.len() > self.max_value_bytes {
+            return Err(KvSetError::TooLarge);
+        }
+
+        let now = Instant::now();
+        let mut shard = self.shard(site, key).lock().unwrap();
+        let id = (site.to_owned(), key.to_owned());
+
+        if shard.len() >= self.max_entries_per_shard && !shard.contains_key(&id) {
+            // Expired entries are otherwise only dropped when read, so sweep them before giving up.
+            shard.retain(|_, entry| !entry.is_expired(now));
+            if shard.len() >= self.max_entries_per_shard {
+                return Err(KvSetError::Full);
+            }
+        }
+
+        shard.insert(
+            id,
+            KvEntry {
+                value,
+                expires: ttl.map(|ttl| now + ttl),
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes a key, returning whether it was set.
+    pub fn delete(&self, site: &str, key: &[u8]) -> bool {
+        let mut shard = self.shard(site, key).lock().unwrap();
+        match shard.remove(&(site.to_owned(), key.to_owned())) {
+            Some(entry) => !entry.is_expired(Instant::now()),
+            None => false,
+        }
+    }
+}
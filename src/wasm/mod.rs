@@ -0,0 +1,422 @@
+//! Programmable request handling with WebAssembly modules.
+//!
+//! A module exports any of the phase functions named in [Phase::export_name], each taking no arguments and
+//! returning an `i32` (`0` to continue, [fetch::AWAIT_FETCHES] to wait for outbound requests, anything else to fail
+//! the request). Modules inspect and modify the request through the host functions in [host]. Each request gets its
+//! own instance, so no state leaks between requests. Modules get no host capabilities beyond the stavka ABI unless
+//! granted; see [capability].
+//!
+//! Modules can be replaced while running; see [registry]. State that must outlive a request lives in a per-thread
+//! [resident] instance or the [kv] store.
+
+mod capability;
+pub mod fetch;
+pub mod host;
+pub mod kv;
+pub mod registry;
+pub mod resident;
+
+use crate::config::{ModulePermissions, WasmConfig};
+use fetch::{AWAIT_FETCHES, MAX_FETCH_ROUNDS};
+use host::{HookState, Phase, ABI_VERSION};
+use monoio_http_client::Client;
+use registry::ModuleVersion;
+use resident::{ResidentEvent, ResidentInstance};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+use wasmtime::{
+    Engine, Instance, InstanceAllocationStrategy, InstancePre, Linker, Module,
+    PoolingAllocationConfig, Store, TypedFunc,
+};
+
+/// How often the epoch ticker advances the engine epoch.
+/// Time limits are measured in ticks of this length.
+const EPOCH_TICK: Duration = Duration::from_millis(1);
+
+/// A compiled hook module, shared by every worker thread.
+pub struct SharedModule {
+    engine: Engine,
+    module: Module,
+    fuel_per_phase: u64,
+    epoch_ticks_per_phase: u64,
+
+    /// The name permissions are looked up by.
+    name: Arc<str>,
+    permissions: Arc<ModulePermissions>,
+    uses_wasi: bool,
+}
+
+/// How a phase finished.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PhaseStatus {
+    Done,
+
+    /// The hook queued fetches and wants to run again once they complete.
+    AwaitingFetches,
+}
+
+/// Builds the engine used for hook modules.
+pub fn build_engine(config: &WasmConfig) -> wasmtime::Result<Engine> {
+    let mut engine_config = wasmtime::Config::new();
+    engine_config.consume_fuel(true);
+    engine_config.epoch_interruption(true);
+
+    if config.pooled {
+        let mut pooling = PoolingAllocationConfig::default();
+        pooling.total_core_instances(config.max_instances);
+        pooling.total_memories(config.max_instances);
+        pooling.total_tables(config.max_instances);
+        pooling.max_memory_size(config.max_memory_bytes);
+        engine_config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+    }
+
+    let engine = Engine::new(&engine_config)?;
+
+    // Epoch interruption needs something to advance the epoch.
+    let ticker = engine.clone();
+    std::thread::Builder::new()
+        .name("stavka-wasm-epoch".to_owned())
+        .spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker.increment_epoch();
+        })?;
+
+    Ok(engine)
+}
+
+/// Compiles a module and checks that it targets a supported ABI version.
+/// `name` selects the module's permissions.
+pub fn compile(
+    engine: &Engine,
+    config: &WasmConfig,
+    name: &str,
+    bytes: &[u8],
+) -> wasmtime::Result<SharedModule> {
+    let module = Module::new(engine, bytes)?;
+    let permissions = config.permissions.get(name).cloned().unwrap_or_default();
+
+    let shared = SharedModule {
+        engine: engine.clone(),
+        fuel_per_phase: config.fuel_per_phase,
+        epoch_ticks_per_phase: (config.time_limit_per_phase.as_micros() / EPOCH_TICK.as_micros())
+            .max(1) as u64,
+        name: Arc::from(name),
+        permissions: Arc::new(permissions),
+        uses_wasi: capability::imports_wasi(&module),
+        module,
+    };
+
+    // Instantiate once up front to validate imports and the ABI version.
+    let pre = shared.instance_pre()?;
+    let (mut store, instance) = shared.instantiate_raw(
+        &pre,
+        HookState::new(
+            http::Method::GET,
+            http::Uri::from_static("/"),
+            http::HeaderMap::new(),
+            String::new(),
+        ),
+    )?;
+    let version = instance
+        .get_typed_func::<(), i32>(&mut store, "stavka_abi_version")?
+        .call(&mut store, ())?;
+    if version != ABI_VERSION {
+        wasmtime::bail!(
+            "module targets ABI version {}, but only {} is supported",
+            version,
+            ABI_VERSION
+        );
+    }
+
+    Ok(shared)
+}
+
+impl SharedModule {
+    /// Creates a store with the per-phase limits applied.
+    fn new_store<T>(&self, state: T) -> wasmtime::Result<Store<T>> {
+        let mut store = Store::new(&self.engine, state);
+        store.set_fuel(self.fuel_per_phase)?;
+        store.set_epoch_deadline(self.epoch_ticks_per_phase);
+        Ok(store)
+    }
+
+    /// Links the module against the host functions.
+    fn instance_pre(&self) -> wasmtime::Result<InstancePre<HookState>> {
+        let mut linker = Linker::new(&self.engine);
+        host::add_to_linker(&mut linker)?;
+        if self.uses_wasi {
+            capability::add_wasi_to_linker(&mut linker, &self.permissions)?;
+        }
+        // The module also imports resident-only functions, which make no sense during a request.
+        linker.define_unknown_imports_as_traps(&self.module)?;
+        linker.instantiate_pre(&self.module)
+    }
+
+    /// Instantiates the module for a single request.
+    fn instantiate(
+        &self,
+        pre: &InstancePre<HookState>,
+        state: HookState,
+    ) -> wasmtime::Result<(Store<HookState>, [Option<TypedFunc<(), i32>>; 4])> {
+        let (mut store, instance) = self.instantiate_raw(pre, state)?;
+
+        let mut phases: [Option<TypedFunc<(), i32>>; 4] = Default::default();
+        for phase in Phase::ALL {
+            phases[phase.index()] = instance
+                .get_typed_func::<(), i32>(&mut store, phase.export_name())
+                .ok();
+        }
+
+        Ok((store, phases))
+    }
+
+    /// Instantiates the module with its granted capabilities.
+    fn instantiate_raw(
+        &self,
+        pre: &InstancePre<HookState>,
+        mut state: HookState,
+    ) -> wasmtime::Result<(Store<HookState>, Instance)> {
+        state.permissions = self.permissions.clone();
+        state.module_name = self.name.clone();
+        if self.uses_wasi {
+            state.wasi = Some(capability::build_wasi_ctx(&self.permissions)?);
+        }
+
+        let mut store = self.new_store(state)?;
+        let instance = pre.instantiate(&mut store)?;
+        Ok((store, instance))
+    }
+
+    /// Runs an instance's hook for a phase, if it has one.
+    fn run_phase(
+        &self,
+        store: &mut Store<HookState>,
+        phases: &[Option<TypedFunc<(), i32>>; 4],
+        phase: Phase,
+    ) -> wasmtime::Result<PhaseStatus> {
+        let Some(func) = &phases[phase.index()] else {
+            return Ok(PhaseStatus::Done);
+        };
+
+        store.data_mut().phase = phase;
+        store.set_fuel(self.fuel_per_phase)?;
+        store.set_epoch_deadline(self.epoch_ticks_per_phase);
+
+        let ret = func.call(&mut *store, ())?;
+        let has_pending = store.data().fetches.iter().any(|f| f.response.is_none());
+        match ret {
+            0 => Ok(PhaseStatus::Done),
+            AWAIT_FETCHES if has_pending => Ok(PhaseStatus::AwaitingFetches),
+            _ => wasmtime::bail!("{} returned {}", phase.export_name(), ret),
+        }
+    }
+}
+
+/// Loads the configured module and starts watching for new versions.
+/// Must be called once at startup before worker threads create their [ThreadHooks].
+pub fn init(config: &WasmConfig) -> wasmtime::Result<()> {
+    registry::init(config)
+}
+
+/// The per-thread entry point for running hooks.
+///
+/// Each thread links the live module version lazily and relinks when the registry switches versions,
+/// so a new version is picked up by the next request without blocking the event loop.
+pub struct ThreadHooks {
+    registry: &'static registry::Registry,
+    linked: RefCell<Option<(Arc<ModuleVersion>, InstancePre<HookState>)>>,
+    resident: RefCell<Option<Rc<RefCell<ResidentInstance>>>>,
+    events: Receiver<Arc<ResidentEvent>>,
+}
+
+impl ThreadHooks {
+    /// Returns [None] if hooks are not enabled.
+    pub fn new() -> wasmtime::Result<Option<Self>> {
+        let Some(registry) = registry::get() else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            registry,
+            linked: RefCell::new(None),
+            resident: RefCell::new(None),
+            events: resident::subscribe(),
+        }))
+    }
+
+    /// Brings the thread's linked module and resident instance up to date with the live version.
+    fn sync(&self) -> wasmtime::Result<()> {
+        let live = self.registry.live_generation();
+
+        let mut linked = self.linked.borrow_mut();
+        if linked.as_ref().map(|(version, _)| version.generation) != Some(live) {
+            *linked = match self.registry.current() {
+                Some(version) => {
+                    let pre = version.module.instance_pre()?;
+                    Some((version, pre))
+                }
+                None => None,
+            };
+        }
+
+        let mut resident = self.resident.borrow_mut();
+        let resident_stale = match &*resident {
+            Some(r) => {
+                let r = r.borrow();
+                r.generation != live || r.is_failed()
+            }
+            None => true,
+        };
+        if resident_stale {
+            // Clear first so a module that fails to create a resident doesn't keep the old one around.
+            *resident = None;
+            if let Some((version, _)) = &*linked {
+                *resident = ResidentInstance::new(&version.module, version.generation)?
+                    .map(|r| Rc::new(RefCell::new(r)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Instantiates the live module version for a new request.
+    /// Returns [None] if no version is live.
+    pub fn start_request(&self, mut state: HookState) -> wasmtime::Result<Option<HookInstance>> {
+        if let Err(e) = self.sync() {
+            // A resident that can't be created shouldn't take every request down with it.
+            println!("failed to update wasm hooks: {:#}", e);
+        }
+
+        let linked = self.linked.borrow();
+        let Some((version, pre)) = &*linked else {
+            return Ok(None);
+        };
+
+        state.resident = self.resident.borrow().clone();
+
+        version.record_request();
+        let (store, phases) = match version.module.instantiate(pre, state) {
+            Ok(instance) => instance,
+            Err(e) => {
+                self.registry.record_failure(version);
+                return Err(e);
+            }
+        };
+
+        Ok(Some(HookInstance {
+            store,
+            phases,
+            version: version.clone(),
+            registry: self.registry,
+        }))
+    }
+
+    /// Delivers broadcast events and timer ticks to this thread's resident instance forever.
+    pub async fn run_resident_events(&self) {
+        loop {
+            monoio::time::sleep(self.registry.config().resident_tick).await;
+            self.deliver_events();
+        }
+    }
+
+    /// Delivers pending broadcast events, followed by a timer event, to this thread's resident instance.
+    pub fn deliver_events(&self) {
+        if let Err(e) = self.sync() {
+            println!("failed to update wasm hooks: {:#}", e);
+        }
+
+        let resident = self.resident.borrow().clone();
+        let Some(resident) = resident else {
+            // Nothing would handle them, and the channel shouldn't grow without bound.
+            while self.events.try_recv().is_ok() {}
+            return;
+        };
+
+        let mut resident = resident.borrow_mut();
+        let events = std::iter::from_fn(|| self.events.try_recv().ok())
+            .chain(std::iter::once(Arc::new(ResidentEvent::Timer)));
+        for event in events {
+            if let Err(e) = resident.deliver(event) {
+                println!("wasm resident event failed: {:#}", e);
+            }
+        }
+    }
+}
+
+/// A module instance bound to a single request.
+/// The same instance runs every phase, so modules can carry state from one phase to the next.
+/// The instance keeps its module version alive, so in-flight requests finish on the version they started with.
+pub struct HookInstance {
+    store: Store<HookState>,
+    phases: [Option<TypedFunc<(), i32>>; 4],
+    version: Arc<ModuleVersion>,
+    registry: &'static registry::Registry,
+}
+
+impl HookInstance {
+    /// Runs the module's hook for a phase, if it has one.
+    /// Fails if the hook traps, runs out of fuel or time, or returns a non-zero status.
+    /// Modules that make outbound HTTP requests need [HookInstance::run_with_fetches] instead.
+    pub fn run(&mut self, phase: Phase) -> wasmtime::Result<()> {
+        match self.run_once(phase)? {
+            PhaseStatus::Done => Ok(()),
+            PhaseStatus::AwaitingFetches => {
+                self.registry.record_failure(&self.version);
+                wasmtime::bail!(
+                    "{} awaited fetches where they aren't supported",
+                    phase.export_name()
+                )
+            }
+        }
+    }
+
+    /// Runs the module's hook for a phase, performing any fetches it awaits with the specified client and running it
+    /// again until it finishes.
+    pub async fn run_with_fetches(
+        &mut self,
+        phase: Phase,
+        client: &Client,
+    ) -> wasmtime::Result<()> {
+        for _ in 0..=MAX_FETCH_ROUNDS {
+            if self.run_once(phase)? == PhaseStatus::Done {
+                return Ok(());
+            }
+
+            // Fetches are few and capped per request, so they are simply sent one after another.
+            let mut fetches = std::mem::take(&mut self.state_mut().fetches);
+            for fetch in fetches.iter_mut().filter(|f| f.response.is_none()) {
+                fetch.response = Some(fetch::perform(client, fetch).await);
+            }
+            self.state_mut().fetches = fetches;
+        }
+
+        self.registry.record_failure(&self.version);
+        wasmtime::bail!(
+            "{} awaited fetches more than {} times",
+            phase.export_name(),
+            MAX_FETCH_ROUNDS
+        )
+    }
+
+    fn run_once(&mut self, phase: Phase) -> wasmtime::Result<PhaseStatus> {
+        let res = self
+            .version
+            .module
+            .run_phase(&mut self.store, &self.phases, phase);
+        if res.is_err() {
+            self.registry.record_failure(&self.version);
+        }
+        res
+    }
+
+    pub fn state(&self) -> &HookState {
+        self.store.data()
+    }
+
+    pub fn state_mut(&mut self) -> &mut HookState {
+        self.store.data_mut()
+    }
+}
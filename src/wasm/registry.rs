@@ -0,0 +1,294 @@
+//! Versioned hook modules that can be replaced without downtime.
+//!
+//! New versions come from the watched module directory or the admin API. They are compiled and smoke-tested on
+//! the thread that supplied them, never on a worker thread, and only then published. Worker threads notice the
+//! new generation on their next request, while requests already running finish on the version they started with.
+//! If the live version fails too many requests, the previous version is restored automatically.
+
+use super::host::{HookState, Phase};
+use super::{build_engine, compile, kv, PhaseStatus, SharedModule};
+use crate::config::WasmConfig;
+use http::{HeaderMap, Method};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use wasmtime::Engine;
+
+/// The file extension of modules in the watched directory.
+const MODULE_EXTENSION: &str = "wasm";
+
+/// A published version of the hook module.
+pub struct ModuleVersion {
+    /// Increases with every installed version, starting at 1.
+    pub generation: u64,
+
+    /// The name the module's permissions are looked up by.
+    pub name: String,
+
+    /// Where the module came from, such as its file path.
+    pub source: String,
+
+    pub(super) module: SharedModule,
+
+    requests: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl ModuleVersion {
+    pub(super) fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of requests the version has started.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests the version has failed.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// The live version and the one it replaced, kept for rollback.
+#[derive(Default)]
+struct Versions {
+    current: Option<Arc<ModuleVersion>>,
+    previous: Option<Arc<ModuleVersion>>,
+}
+
+impl Versions {
+    /// Makes the previous version live again, returning the version that was replaced.
+    fn roll_back(&mut self, live: &AtomicU64) -> Option<Arc<ModuleVersion>> {
+        let previous = self.previous.take()?;
+        live.store(previous.generation, Ordering::Release);
+        self.current.replace(previous)
+    }
+}
+
+/// The process-wide set of hook module versions.
+pub struct Registry {
+    engine: Engine,
+    config: WasmConfig,
+
+    last_generation: AtomicU64,
+
+    /// The generation of the live version, or 0 if there is none.
+    /// Worker threads check this on every request, so it is readable without taking the lock.
+    live_generation: AtomicU64,
+
+    versions: Mutex<Versions>,
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// Returns the registry, or [None] if hooks are not enabled.
+pub fn get() -> Option<&'static Registry> {
+    REGISTRY.get()
+}
+
+/// Creates the registry, loads the startup module and starts watching the module directory.
+/// Does nothing if neither a module path nor a module directory is configured.
+pub fn init(config: &WasmConfig) -> wasmtime::Result<()> {
+    if config.module_path.is_none() && config.module_dir.is_none() {
+        return Ok(());
+    }
+
+    kv::init(config.kv_max_entries, config.kv_max_value_bytes);
+
+    let engine = build_engine(config)?;
+    let registry = REGISTRY.get_or_init(|| Registry {
+        engine,
+        config: config.clone(),
+        last_generation: AtomicU64::new(0),
+        live_generation: AtomicU64::new(0),
+        versions: Mutex::new(Versions::default()),
+    });
+
+    if let Some(path) = &config.module_path {
+        let bytes = std::fs::read(path)?;
+        registry.install(&bytes, &module_name(path), path.display().to_string())?;
+    }
+
+    if let Some(dir) = config.module_dir.clone() {
+        std::thread::Builder::new()
+            .name("stavka-wasm-watch".to_owned())
+            .spawn(move || registry.watch(&dir))?;
+    }
+
+    Ok(())
+}
+
+impl Registry {
+    pub fn config(&self) -> &WasmConfig {
+        &self.config
+    }
+
+    /// The generation of the live version, or 0 if there is none.
+    pub fn live_generation(&self) -> u64 {
+        self.live_generation.load(Ordering::Acquire)
+    }
+
+    /// Returns the live version.
+    pub fn current(&self) -> Option<Arc<ModuleVersion>> {
+        self.versions.lock().unwrap().current.clone()
+    }
+
+    /// Compiles and smoke-tests a module, then makes it live.
+    /// `name` selects the module's permissions.
+    /// Blocks while compiling, so it must not be called from a worker thread.
+    /// Returns the generation of the new version.
+    pub fn install(&self, bytes: &[u8], name: &str, source: String) -> wasmtime::Result<u64> {
+        let module = compile(&self.engine, &self.config, name, bytes)?;
+        self.smoke_test(&module)
+            .map_err(|e| e.context("smoke test failed"))?;
+
+        let generation = self.last_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let version = Arc::new(ModuleVersion {
+            generation,
+            name: name.to_owned(),
+            source,
+            module,
+            requests: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        });
+
+        let mut versions = self.versions.lock().unwrap();
+        println!(
+            "wasm module {} generation {} from {} is live",
+            version.name, generation, version.source
+        );
+        versions.previous = versions.current.replace(version);
+        self.live_generation.store(generation, Ordering::Release);
+
+        Ok(generation)
+    }
+
+    /// Makes the previous version live again.
+    /// Returns the generation now live, or [None] if there is no previous version.
+    pub fn rollback(&self) -> Option<u64> {
+        let mut versions = self.versions.lock().unwrap();
+        versions.roll_back(&self.live_generation)?;
+        let generation = self.live_generation();
+        println!("wasm module rolled back to generation {}", generation);
+        Some(generation)
+    }
+
+    /// Records a failed request, rolling the version back if it fails too often.
+    pub(super) fn record_failure(&self, version: &ModuleVersion) {
+        let failures = version.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let requests = version.requests();
+        if requests < self.config.rollback_min_requests
+            || failures as f64 <= requests as f64 * self.config.rollback_failure_ratio
+        {
+            return;
+        }
+
+        let mut versions = self.versions.lock().unwrap();
+
+        // Another thread may have already rolled back this version.
+        if versions.current.as_ref().map(|v| v.generation) != Some(version.generation) {
+            return;
+        }
+        if versions.roll_back(&self.live_generation).is_none() {
+            return;
+        }
+
+        println!(
+            "wasm module generation {} failed {} of {} requests, rolled back to generation {}",
+            version.generation,
+            failures,
+            requests,
+            self.live_generation()
+        );
+    }
+
+    /// Runs every phase of a module against synthetic requests for the configured paths.
+    fn smoke_test(&self, module: &SharedModule) -> wasmtime::Result<()> {
+        let pre = module.instance_pre()?;
+
+        for path in &self.config.smoke_test_paths {
+            let state = HookState::new(
+                Method::GET,
+                path.parse()?,
+                HeaderMap::new(),
+                "smoke-test".to_owned(),
+            );
+            let (mut store, phases) = module.instantiate(&pre, state)?;
+
+            for phase in Phase::ALL {
+                let status = module
+                    .run_phase(&mut store, &phases, phase)
+                    .map_err(|e| e.context(format!("path {}", path)))?;
+
+                // A module answering the request itself ends it, same as for a real request.
+                // Fetches aren't performed here, so a module waiting on one can't be tested any further.
+                if store.data().early_response.is_some() || status == PhaseStatus::AwaitingFetches {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Polls a directory forever, installing the most recently modified module whenever it changes.
+    ///
+    /// Modules should be written elsewhere and renamed into the directory, so a partially written file is never
+    /// picked up. A module that fails to install is not retried until it changes again.
+    fn watch(&self, dir: &Path) {
+        let mut seen: Option<(PathBuf, SystemTime)> = None;
+
+        loop {
+            match newest_module(dir) {
+                Ok(Some(newest)) if seen.as_ref() != Some(&newest) => {
+                    let path = &newest.0;
+                    let res =
+                        std::fs::read(path)
+                            .map_err(wasmtime::Error::from)
+                            .and_then(|bytes| {
+                                self.install(&bytes, &module_name(path), path.display().to_string())
+                            });
+                    if let Err(e) = res {
+                        println!("failed to install wasm module {}: {:#}", path.display(), e);
+                    }
+                    seen = Some(newest);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    println!("failed to scan wasm module dir {}: {}", dir.display(), e);
+                }
+            }
+
+            std::thread::sleep(self.config.watch_interval);
+        }
+    }
+}
+
+/// Returns the name of a module loaded from a file, which is its file name without the extension.
+fn module_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns the most recently modified module in a directory and its modification time.
+fn newest_module(dir: &Path) -> std::io::Result<Option<(PathBuf, SystemTime)>> {
+    let mut newest: Option<(PathBuf, SystemTime)> = None;
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(MODULE_EXTENSION) {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+        if newest.as_ref().map_or(true, |(_, t)| modified > *t) {
+            newest = Some((path, modified));
+        }
+    }
+
+    Ok(newest)
+}
@@ -0,0 +1,348 @@
+//! Long-lived instances for logic that outlives a single request.
+//!
+//! If the hook module exports `on_event` or any function prefixed with [RESIDENT_EXPORT_PREFIX], every worker
+//! thread also keeps one resident instance of it. Unlike per-request instances, a resident keeps its memory between
+//! requests, so it can hold things like access-control lists or rate counters.
+//!
+//! Residents receive events through `on_event(kind)`, with the details available from `get_site`,
+//! `get_event_name` and `get_event_data`. Per-request instances call into the resident with
+//! `call_resident(name, arg)`, which runs its `resident_<name>` export; the export reads the argument with
+//! `get_call_arg` and returns data with `set_call_result`.
+//!
+//! A resident is recreated, losing its memory, when the module is swapped or after it traps. State that must
+//! survive that belongs in the key/value store. Residents get the same WASI capabilities as the module's request
+//! instances, but can't make outbound HTTP requests, since nothing awaits them.
+
+use super::capability::{self, WasiContext};
+use super::host::{self, GuestContext, ABI_MODULE, INVALID, MISSING};
+use super::SharedModule;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use wasmtime::{Caller, Instance, Linker, Store, TypedFunc};
+use wasmtime_wasi::preview1::WasiP1Ctx;
+
+/// The prefix of exports per-request instances can call.
+/// Only these can be called, so a request can't invoke the resident's event handler or ABI exports.
+pub const RESIDENT_EXPORT_PREFIX: &str = "resident_";
+
+/// The export events are delivered to.
+const EVENT_EXPORT: &str = "on_event";
+
+/// Something that happened outside of any single request.
+pub enum ResidentEvent {
+    /// The resident was just created, either at startup or after a module swap.
+    Start,
+
+    /// An operator pushed a named update, such as a new access-control list, through the admin API.
+    Config {
+        site: String,
+        name: String,
+        data: Vec<u8>,
+    },
+
+    /// Cached objects were purged.
+    /// The name is the selector kind (`url`, `prefix`, `host` or `tag`) and the data is its value.
+    Purge {
+        site: String,
+        selector: &'static str,
+        value: String,
+    },
+
+    /// Sent every [crate::config::WasmConfig::resident_tick].
+    Timer,
+}
+
+impl ResidentEvent {
+    /// The kind passed to `on_event`.
+    fn kind(&self) -> i32 {
+        match self {
+            ResidentEvent::Start => 0,
+            ResidentEvent::Config { .. } => 1,
+            ResidentEvent::Purge { .. } => 2,
+            ResidentEvent::Timer => 3,
+        }
+    }
+
+    fn site(&self) -> &str {
+        match self {
+            ResidentEvent::Config { site, .. } | ResidentEvent::Purge { site, .. } => site,
+            ResidentEvent::Start | ResidentEvent::Timer => "",
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            ResidentEvent::Config { name, .. } => name,
+            ResidentEvent::Purge { selector, .. } => selector,
+            ResidentEvent::Start | ResidentEvent::Timer => "",
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self {
+            ResidentEvent::Config { data, .. } => data,
+            ResidentEvent::Purge { value, .. } => value.as_bytes(),
+            ResidentEvent::Start | ResidentEvent::Timer => &[],
+        }
+    }
+}
+
+/// Every worker thread's event channel.
+static SUBSCRIBERS: Mutex<Vec<Sender<Arc<ResidentEvent>>>> = Mutex::new(Vec::new());
+
+/// Registers a worker thread to receive broadcast events.
+pub fn subscribe() -> Receiver<Arc<ResidentEvent>> {
+    let (tx, rx) = channel();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+/// Sends an event to the resident on every worker thread.
+/// Threads pick it up on their next tick.
+pub fn broadcast(event: ResidentEvent) {
+    let event = Arc::new(event);
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+/// The state a resident instance can see.
+pub struct ResidentState {
+    /// The site the current event or call is for, or empty if it isn't for any one site.
+    site: String,
+
+    event: Option<Arc<ResidentEvent>>,
+    call_arg: Vec<u8>,
+    call_result: Option<Vec<u8>>,
+
+    /// Set when the module imports WASI.
+    wasi: Option<WasiP1Ctx>,
+    module_name: Arc<str>,
+}
+
+impl GuestContext for ResidentState {
+    fn site(&self) -> &str {
+        &self.site
+    }
+}
+
+impl WasiContext for ResidentState {
+    fn wasi(&mut self) -> &mut WasiP1Ctx {
+        self.wasi
+            .as_mut()
+            .expect("WASI context should have been set for a module importing WASI")
+    }
+
+    fn module_name(&self) -> &str {
+        &self.module_name
+    }
+}
+
+/// Registers the host functions available to resident instances.
+fn add_to_linker(linker: &mut Linker<ResidentState>) -> wasmtime::Result<()> {
+    host::add_shared_to_linker(linker)?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_site",
+        |mut caller: Caller<'_, ResidentState>, ptr: i32, cap: i32| -> i32 {
+            let site = caller.data().site.clone();
+            host::write_out(&mut caller, ptr, cap, site.as_bytes())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_event_name",
+        |mut caller: Caller<'_, ResidentState>, ptr: i32, cap: i32| -> i32 {
+            let Some(event) = caller.data().event.clone() else {
+                return MISSING;
+            };
+            host::write_out(&mut caller, ptr, cap, event.name().as_bytes())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_event_data",
+        |mut caller: Caller<'_, ResidentState>, ptr: i32, cap: i32| -> i32 {
+            let Some(event) = caller.data().event.clone() else {
+                return MISSING;
+            };
+            host::write_out(&mut caller, ptr, cap, event.data())
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "get_call_arg",
+        |mut caller: Caller<'_, ResidentState>, ptr: i32, cap: i32| -> i32 {
+            let arg = std::mem::take(&mut caller.data_mut().call_arg);
+            let len = host::write_out(&mut caller, ptr, cap, &arg);
+            caller.data_mut().call_arg = arg;
+            len
+        },
+    )?;
+
+    linker.func_wrap(
+        ABI_MODULE,
+        "set_call_result",
+        |mut caller: Caller<'_, ResidentState>, ptr: i32, len: i32| -> i32 {
+            match host::read_bytes(&mut caller, ptr, len) {
+                Some(result) => {
+                    caller.data_mut().call_result = Some(result);
+                    0
+                }
+                None => INVALID,
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// A worker thread's long-lived instance of the hook module.
+pub struct ResidentInstance {
+    /// The module version the instance was created from.
+    pub generation: u64,
+
+    store: Store<ResidentState>,
+    instance: Instance,
+    on_event: Option<TypedFunc<i32, i32>>,
+    fuel_per_call: u64,
+    epoch_ticks_per_call: u64,
+
+    /// Set after a trap, since the instance's memory may be left inconsistent.
+    failed: bool,
+}
+
+impl ResidentInstance {
+    /// Creates a resident from a module version.
+    /// Returns [None] if the module doesn't export anything a resident would run.
+    pub(super) fn new(module: &SharedModule, generation: u64) -> wasmtime::Result<Option<Self>> {
+        let wants_resident = module.module.exports().any(|export| {
+            export.name() == EVENT_EXPORT || export.name().starts_with(RESIDENT_EXPORT_PREFIX)
+        });
+        if !wants_resident {
+            return Ok(None);
+        }
+
+        let mut linker = Linker::new(&module.engine);
+        add_to_linker(&mut linker)?;
+        if module.uses_wasi {
+            capability::add_wasi_to_linker(&mut linker, &module.permissions)?;
+        }
+        // The module also imports per-request functions, which make no sense outside of a request.
+        linker.define_unknown_imports_as_traps(&module.module)?;
+
+        let mut store = module.new_store(ResidentState {
+            site: String::new(),
+            event: None,
+            call_arg: Vec::new(),
+            call_result: None,
+            wasi: if module.uses_wasi {
+                Some(capability::build_wasi_ctx(&module.permissions)?)
+            } else {
+                None
+            },
+            module_name: module.name.clone(),
+        })?;
+        let instance = linker.instantiate(&mut store, &module.module)?;
+        let on_event = instance
+            .get_typed_func::<i32, i32>(&mut store, EVENT_EXPORT)
+            .ok();
+
+        let mut resident = Self {
+            generation,
+            store,
+            instance,
+            on_event,
+            fuel_per_call: module.fuel_per_phase,
+            epoch_ticks_per_call: module.epoch_ticks_per_phase,
+            failed: false,
+        };
+        resident.deliver(Arc::new(ResidentEvent::Start))?;
+
+        Ok(Some(resident))
+    }
+
+    /// Whether the instance trapped and needs to be recreated.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Applies the per-call limits before running guest code.
+    fn prepare(&mut self, site: &str) -> wasmtime::Result<()> {
+        if self.failed {
+            wasmtime::bail!("resident instance previously failed");
+        }
+
+        let state = self.store.data_mut();
+        state.site.clear();
+        state.site.push_str(site);
+
+        self.store.set_fuel(self.fuel_per_call)?;
+        self.store.set_epoch_deadline(self.epoch_ticks_per_call);
+        Ok(())
+    }
+
+    /// Delivers an event to the instance's `on_event` export, if it has one.
+    pub fn deliver(&mut self, event: Arc<ResidentEvent>) -> wasmtime::Result<()> {
+        let Some(func) = self.on_event.clone() else {
+            return Ok(());
+        };
+
+        self.prepare(event.site())?;
+        let kind = event.kind();
+        self.store.data_mut().event = Some(event);
+
+        let res = func.call(&mut self.store, kind);
+        self.store.data_mut().event = None;
+
+        match res {
+            Ok(0) => Ok(()),
+            Ok(ret) => wasmtime::bail!("{} returned {}", EVENT_EXPORT, ret),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Calls the `resident_<name>` export on behalf of a request for a site.
+    /// Returns [None] if there is no such export.
+    pub fn call(
+        &mut self,
+        name: &str,
+        site: &str,
+        arg: Vec<u8>,
+    ) -> wasmtime::Result<Option<Vec<u8>>> {
+        let export = format!("{}{}", RESIDENT_EXPORT_PREFIX, name);
+        let Ok(func) = self
+            .instance
+            .get_typed_func::<(), i32>(&mut self.store, &export)
+        else {
+            return Ok(None);
+        };
+
+        self.prepare(site)?;
+        let state = self.store.data_mut();
+        state.call_arg = arg;
+        state.call_result = None;
+
+        let res = func.call(&mut self.store, ());
+        let state = self.store.data_mut();
+        state.call_arg = Vec::new();
+        let result = state.call_result.take().unwrap_or_default();
+
+        match res {
+            Ok(0) => Ok(Some(result)),
+            Ok(ret) => wasmtime::bail!("{} returned {}", export, ret),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+}
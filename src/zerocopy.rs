@@ -0,0 +1,213 @@
+use monoio::buf::IoBufMut;
+use monoio::io::splice::SpliceDestination;
+use monoio::io::{AsyncWriteRent, AsyncWriteRentExt, OwnedWriteHalf};
+use monoio::net::unix::{new_pipe, Pipe};
+use monoio::net::TcpStream;
+use monoio_rustls::ServerTlsStream;
+use std::io;
+use std::os::fd::AsRawFd;
+
+/// The maximum number of bytes to move through the pipe per splice.
+/// This matches the default Linux pipe capacity, so a single file-to-pipe splice never has to wait on the pipe draining.
+const SPLICE_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// The size of the buffer used for buffered block copies.
+const BUFFERED_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How a connection sends cached block ranges to its client.
+pub enum BlockServeMode {
+    /// Move block file pages directly to the client socket through a pipe without copying into userspace.
+    /// The connection keeps one pipe for every response, created the first time a block is spliced.
+    Splice(Option<BlockPipe>),
+
+    /// Read block file contents into a userspace buffer and write it out.
+    /// Required when the bytes are transformed before hitting the socket, as with TLS.
+    Buffered,
+}
+
+impl BlockServeMode {
+    /// Selects the serve mode for a connection.
+    /// Splicing is only possible when the bytes on disk are exactly the bytes that go on the wire.
+    pub fn select(is_tls: bool) -> Self {
+        if is_tls {
+            BlockServeMode::Buffered
+        } else {
+            BlockServeMode::Splice(None)
+        }
+    }
+}
+
+/// The pipe a connection splices block files through.
+/// It is empty between splices, so it can be reused for the next range.
+pub struct BlockPipe {
+    read: Pipe,
+    write: Pipe,
+}
+
+/// A client connection's write half that cached block ranges can be sent to.
+pub trait BlockDestination: AsyncWriteRent {
+    /// Moves up to `len` bytes from the pipe to the destination.
+    /// Only used in [BlockServeMode::Splice], which is never selected for destinations that can't be spliced into.
+    async fn splice_from_pipe(&mut self, pipe: &mut Pipe, len: u32) -> io::Result<u32>;
+}
+
+impl BlockDestination for TcpStream {
+    async fn splice_from_pipe(&mut self, pipe: &mut Pipe, len: u32) -> io::Result<u32> {
+        SpliceDestination::splice_from_pipe(self, pipe, len).await
+    }
+}
+
+impl BlockDestination for OwnedWriteHalf<TcpStream> {
+    async fn splice_from_pipe(&mut self, pipe: &mut Pipe, len: u32) -> io::Result<u32> {
+        SpliceDestination::splice_from_pipe(self, pipe, len).await
+    }
+}
+
+impl BlockDestination for OwnedWriteHalf<ServerTlsStream<TcpStream>> {
+    async fn splice_from_pipe(&mut self, _pipe: &mut Pipe, _len: u32) -> io::Result<u32> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS connections can't be spliced into",
+        ))
+    }
+}
+
+/// Splices `len` bytes of the specified block file starting at `offset` directly to the destination socket, through
+/// the connection's pipe.
+/// The file-to-pipe half is a plain `splice`, which only waits if pages have to come from disk; readahead for the whole
+/// range is started first so that rarely happens. The pipe-to-socket half goes through io_uring.
+/// Returns the number of bytes written to the destination.
+pub async fn splice_block_range<D: BlockDestination>(
+    dst: &mut D,
+    pipe: &mut Option<BlockPipe>,
+    file: &monoio::fs::File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    if pipe.is_none() {
+        let (read, write) = new_pipe()?;
+        *pipe = Some(BlockPipe { read, write });
+    }
+    let res = splice_through(dst, pipe.as_mut().unwrap(), file, offset, len).await;
+    if res.is_err() {
+        // Whatever didn't make it to the socket is still in the pipe.
+        *pipe = None;
+    }
+    res
+}
+
+async fn splice_through<D: BlockDestination>(
+    dst: &mut D,
+    pipe: &mut BlockPipe,
+    file: &monoio::fs::File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        );
+    }
+
+    let mut pos = offset;
+    let end = offset + len;
+    while pos < end {
+        let chunk = (end - pos).min(SPLICE_CHUNK_SIZE as u64) as usize;
+        let mut off = pos as libc::loff_t;
+        let filled = unsafe {
+            libc::splice(
+                file.as_raw_fd(),
+                &mut off,
+                pipe.write.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if filled < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if filled == 0 {
+            // The block file is shorter than the range we were asked to serve.
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "block file ended before the requested range",
+            ));
+        }
+
+        drain_pipe(dst, &mut pipe.read, filled as u32).await?;
+        pos += filled as u64;
+    }
+
+    Ok(len)
+}
+
+/// Writes `len` bytes from the pipe to the destination.
+async fn drain_pipe<D: BlockDestination>(dst: &mut D, pipe: &mut Pipe, len: u32) -> io::Result<()> {
+    let mut written = 0;
+    while written < len {
+        match dst.splice_from_pipe(pipe, len - written).await? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "client connection closed",
+                ))
+            }
+            n => written += n,
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies `len` bytes of the specified block file starting at `offset` to the destination through a userspace buffer.
+/// This is the fallback for responses that cannot be spliced.
+/// Returns the number of bytes written to the destination.
+pub async fn copy_block_range<D: AsyncWriteRent>(
+    dst: &mut D,
+    file: &monoio::fs::File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    let mut buf = Vec::with_capacity(BUFFERED_CHUNK_SIZE);
+
+    let mut pos = offset;
+    let end = offset + len;
+    while pos < end {
+        let chunk = (end - pos).min(BUFFERED_CHUNK_SIZE as u64) as usize;
+
+        buf.clear();
+        let (res, slice) = file.read_exact_at(buf.slice_mut(0..chunk), pos).await;
+        res?;
+        buf = slice.into_inner();
+
+        let (res, returned) = dst.write_all(buf).await;
+        res?;
+        buf = returned;
+
+        pos += chunk as u64;
+    }
+
+    Ok(len)
+}
+
+/// Sends a block range to the destination using the specified mode.
+pub async fn send_block_range<D: BlockDestination>(
+    mode: &mut BlockServeMode,
+    dst: &mut D,
+    file: &monoio::fs::File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    match mode {
+        BlockServeMode::Splice(pipe) => splice_block_range(dst, pipe, file, offset, len).await,
+        BlockServeMode::Buffered => copy_block_range(dst, file, offset, len).await,
+    }
+}
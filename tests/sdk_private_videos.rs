@@ -0,0 +1,302 @@
+//! Builds the SDK's `private_videos` example for wasm32 and runs stavka with it in front of a local stand-in origin,
+//! so requests go through the same pipeline as in production.
+//!
+//! Needs the `wasm32-unknown-unknown` target (`rustup target add wasm32-unknown-unknown`), and is skipped without it.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const HOST: &str = "videos.example.com";
+const ADMIN_TOKEN: &str = "test-token";
+
+/// How long stavka gets to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether the standard library for `wasm32-unknown-unknown` is installed.
+fn wasm_target_installed() -> bool {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let Ok(output) = Command::new(rustc).args(["--print", "sysroot"]).output() else {
+        return false;
+    };
+    let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    Path::new(&sysroot)
+        .join("lib")
+        .join("rustlib")
+        .join("wasm32-unknown-unknown")
+        .exists()
+}
+
+/// Builds the example module, returning the path of the `.wasm` file.
+fn build_example() -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // A separate target directory keeps this from waiting on the lock held by the `cargo test` running us.
+    let target_dir = root.join("target").join("sdk-wasm");
+
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+        .current_dir(&root)
+        .args([
+            "build",
+            "-p",
+            "stavka-sdk",
+            "--example",
+            "private_videos",
+            "--target",
+            "wasm32-unknown-unknown",
+            "--release",
+        ])
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "building the example failed");
+
+    target_dir
+        .join("wasm32-unknown-unknown")
+        .join("release")
+        .join("examples")
+        .join("private_videos.wasm")
+}
+
+/// Starts an origin that marks video 123 private and serves everything else, counting the requests it gets.
+fn start_origin() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let counter = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            let path = request_line.split(' ').nth(1).unwrap_or("/");
+            let extra = if path.starts_with("/videos/123/") {
+                "x-video-private: 1\r\n"
+            } else {
+                ""
+            };
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n{}content-length: 5\r\nconnection: close\r\n\r\nvideo",
+                extra
+            )
+            .unwrap();
+        }
+    });
+
+    (addr, requests)
+}
+
+/// Returns a port nothing is listening on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A running stavka process, killed when dropped.
+struct Stavka {
+    child: Child,
+    addr: SocketAddr,
+    admin_addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl Stavka {
+    /// Starts stavka with the example module, routing [HOST] to the origin.
+    fn start(module_path: &Path, origin: SocketAddr) -> Stavka {
+        let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        let admin_addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        let dir = std::env::temp_dir().join(format!("stavka-sdk-test-{}", std::process::id()));
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+
+        let config = format!(
+            r#"
+[cache]
+roots = [{cache_dir:?}]
+
+[connection]
+bind_addr = "{addr}"
+
+[tls]
+bind_addr = "127.0.0.1:{tls_port}"
+
+[admin]
+bind_addr = "{admin_addr}"
+token = "{ADMIN_TOKEN}"
+
+[wasm]
+module_path = {module_path:?}
+pooled = false
+resident_tick = "50ms"
+
+[pools.videos]
+servers = [{{ address = "http://{origin}" }}]
+
+[[routes]]
+host = "{HOST}"
+pool = "videos"
+
+[hosts."{HOST}"]
+cache_status_headers = true
+"#,
+            tls_port = free_port(),
+        );
+        let config_path = dir.join("stavka.toml");
+        std::fs::write(&config_path, config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_stavka"))
+            .arg("--config")
+            .arg(&config_path)
+            .spawn()
+            .expect("failed to start stavka");
+        let mut stavka = Stavka {
+            child,
+            addr,
+            admin_addr,
+            dir,
+        };
+
+        let deadline = Instant::now() + START_TIMEOUT;
+        while TcpStream::connect(addr).is_err() || TcpStream::connect(admin_addr).is_err() {
+            if let Some(status) = stavka.child.try_wait().unwrap() {
+                panic!("stavka exited with {}", status);
+            }
+            assert!(Instant::now() < deadline, "stavka didn't start listening");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        stavka
+    }
+
+    /// Requests a path from [HOST] through stavka, returning the status and the `Cache-Status` header.
+    fn get(&self, path: &str) -> (u16, Option<String>) {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nhost: {}\r\nconnection: close\r\n\r\n",
+            path, HOST
+        )
+        .unwrap();
+        let (status, headers) = read_response(stream);
+        let cache_status = headers
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("cache-status"))
+            .map(|(_, value)| value);
+        (status, cache_status)
+    }
+
+    /// Pushes a config event to the module's resident instances through the admin API.
+    fn push_event(&self, name: &str, data: &str) {
+        let mut stream = TcpStream::connect(self.admin_addr).unwrap();
+        write!(
+            stream,
+            "POST /wasm/event?name={}&site={} HTTP/1.1\r\nhost: localhost\r\nauthorization: Bearer {}\r\n\
+             content-length: {}\r\nconnection: close\r\n\r\n{}",
+            name,
+            HOST,
+            ADMIN_TOKEN,
+            data.len(),
+            data
+        )
+        .unwrap();
+        assert_eq!(read_response(stream).0, 200);
+    }
+}
+
+impl Drop for Stavka {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Reads a response up to the end of the connection, returning the status and headers.
+fn read_response(mut stream: TcpStream) -> (u16, Vec<(String, String)>) {
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).unwrap();
+    let res = String::from_utf8_lossy(&res);
+    let (head, _) = res.split_once("\r\n\r\n").unwrap();
+    let mut lines = head.split("\r\n");
+
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+    let headers = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').unwrap();
+            (name.to_owned(), value.trim().to_owned())
+        })
+        .collect();
+
+    (status.parse().unwrap(), headers)
+}
+
+#[test]
+fn private_videos_example() {
+    if !wasm_target_installed() {
+        eprintln!("skipping: the wasm32-unknown-unknown target isn't installed");
+        return;
+    }
+    let module_path = build_example();
+    let (origin, origin_requests) = start_origin();
+    let stavka = Stavka::start(&module_path, origin);
+
+    // Public videos go to the origin, with tracking parameters dropped from the cache key.
+    let (status, cache_status) = stavka.get("/videos/456/720p.mp4?quality=720&utm_source=x");
+    assert_eq!(status, 200);
+    let cache_status = cache_status.expect("no Cache-Status header");
+    assert!(
+        cache_status.contains(r#"key="http://videos.example.com/videos/456/720p.mp4?quality=720""#),
+        "{}",
+        cache_status
+    );
+    assert_eq!(origin_requests.load(Ordering::SeqCst), 1);
+
+    // Other tracking parameters map to the same key, which is cached for the module's TTL.
+    let (status, cache_status) = stavka.get("/videos/456/720p.mp4?quality=720&utm_source=y");
+    assert_eq!(status, 200);
+    let cache_status = cache_status.expect("no Cache-Status header");
+    assert!(cache_status.contains("; hit"), "{}", cache_status);
+    let ttl: u64 = cache_status
+        .split("; ")
+        .find_map(|param| param.strip_prefix("ttl="))
+        .expect("no ttl")
+        .parse()
+        .unwrap();
+    assert!((3500..=3600).contains(&ttl), "{}", cache_status);
+    assert_eq!(origin_requests.load(Ordering::SeqCst), 1);
+
+    // The origin says video 123 is private.
+    let (status, _) = stavka.get("/videos/123/720p.mp4");
+    assert_eq!(status, 404);
+    assert_eq!(origin_requests.load(Ordering::SeqCst), 2);
+
+    // The decision is remembered, so the origin isn't asked again.
+    let (status, _) = stavka.get("/videos/123/1080p.mp4");
+    assert_eq!(status, 404);
+    assert_eq!(origin_requests.load(Ordering::SeqCst), 2);
+
+    // Videos pushed to the resident instances are private without the origin ever seeing them.
+    stavka.push_event("private-videos", "789\n");
+    // Each worker thread picks the event up on its next resident tick.
+    std::thread::sleep(Duration::from_millis(500));
+    let (status, _) = stavka.get("/videos/789/720p.mp4");
+    assert_eq!(status, 404);
+    assert_eq!(origin_requests.load(Ordering::SeqCst), 2);
+}